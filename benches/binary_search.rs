@@ -0,0 +1,41 @@
+use dbil::lsm_tree::{LSMTree, LSMTreeOptions};
+use glommio::LocalExecutor;
+use std::{env::temp_dir, time::Instant};
+
+const NUM_KEYS: usize = 20000;
+const KEY_SIZE: usize = 16;
+
+async fn bench(name: &str, options: LSMTreeOptions) {
+    let mut dir = temp_dir();
+    dir.push(format!("dbil-bench-{}", name));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    let mut tree = LSMTree::with_options(dir.clone(), options).await.unwrap();
+    for i in 0..NUM_KEYS {
+        let key = format!("{:01$}", i, KEY_SIZE);
+        tree.set(key, i.to_string()).await.unwrap();
+    }
+
+    let start = Instant::now();
+    for i in (0..NUM_KEYS).step_by(7) {
+        let key = format!("{:01$}", i, KEY_SIZE);
+        assert!(tree.get(&key).await.unwrap().is_some());
+    }
+    let elapsed = start.elapsed();
+    println!("{}: {:?} per lookup", name, elapsed / (NUM_KEYS / 7) as u32);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn main() {
+    LocalExecutor::default().run(async {
+        bench("variable_key_size", LSMTreeOptions::new()).await;
+        bench(
+            "fixed_key_size",
+            LSMTreeOptions::new().with_fixed_key_size(KEY_SIZE),
+        )
+        .await;
+    });
+}
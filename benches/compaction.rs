@@ -0,0 +1,71 @@
+use dbil::lsm_tree::LSMTree;
+use glommio::LocalExecutor;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env::temp_dir,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+const NUM_SSTABLES: usize = 8;
+// Less than the capacity of the memtable, so only the explicit flushes run.
+const KEYS_PER_SSTABLE: usize = 1000;
+const KEY_SIZE: usize = 16;
+const VALUE_SIZE: usize = 100;
+
+// Counts the allocations, to see the allocations done per compacted entry.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    LocalExecutor::default().run(async {
+        let mut dir = temp_dir();
+        dir.push("dbil-bench-compaction");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+        let value = "x".repeat(VALUE_SIZE);
+        for sstable in 0..NUM_SSTABLES {
+            // Interleaved keys, so the compaction merges all inputs.
+            for i in 0..KEYS_PER_SSTABLE {
+                let key =
+                    format!("{:01$}", i * NUM_SSTABLES + sstable, KEY_SIZE);
+                tree.set(key, value.clone()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+        }
+
+        let indices: Vec<usize> = (0..NUM_SSTABLES).map(|i| i * 2).collect();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        tree.compact(indices, NUM_SSTABLES * 2 + 1).await.unwrap();
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+        let entries = (NUM_SSTABLES * KEYS_PER_SSTABLE) as f64;
+        println!(
+            "compaction: {:?}, {:.1} allocations per entry",
+            elapsed,
+            allocations as f64 / entries
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    });
+}
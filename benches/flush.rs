@@ -0,0 +1,42 @@
+use dbil::lsm_tree::LSMTree;
+use glommio::LocalExecutor;
+use std::{env::temp_dir, time::Duration, time::Instant};
+
+const NUM_FLUSHES: usize = 50;
+// Less than the capacity of the memtable, so only the explicit flushes run.
+const KEYS_PER_FLUSH: usize = 1000;
+const KEY_SIZE: usize = 16;
+const VALUE_SIZE: usize = 100;
+
+fn main() {
+    LocalExecutor::default().run(async {
+        let mut dir = temp_dir();
+        dir.push("dbil-bench-flush");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+        let value = "x".repeat(VALUE_SIZE);
+        let mut elapsed = Duration::ZERO;
+        for flush in 0..NUM_FLUSHES {
+            for i in 0..KEYS_PER_FLUSH {
+                let key =
+                    format!("{:01$}", flush * KEYS_PER_FLUSH + i, KEY_SIZE);
+                tree.set(key, value.clone()).await.unwrap();
+            }
+
+            let start = Instant::now();
+            tree.flush().await.unwrap();
+            elapsed += start.elapsed();
+        }
+        let entries = (NUM_FLUSHES * KEYS_PER_FLUSH) as f64;
+        println!(
+            "flush: {:?} per flush, {:.0} entries/s",
+            elapsed / NUM_FLUSHES as u32,
+            entries / elapsed.as_secs_f64()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    });
+}
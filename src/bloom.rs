@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+const MAX_NUMBER_OF_HASHES: u32 = 30;
+
+// A set of keys that answers whether a key might be in it, without false
+// negatives, used to skip reading sstables that can't hold a key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    number_of_hashes: u32,
+}
+
+// FNV-1a, followed by the splitmix64 finalizer to spread close keys apart.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+impl BloomFilter {
+    // Sized for the false positive rate once it holds expected_items keys:
+    // -n * ln(p) / ln(2)^2 bits, and ln(2) hashes per bit per key.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let items = expected_items.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 1.0);
+        let ln2 = std::f64::consts::LN_2;
+        let number_of_bits =
+            ((-items * rate.ln() / (ln2 * ln2)).ceil() as usize).max(64);
+        let number_of_hashes = (number_of_bits as f64 / items * ln2).round();
+        Self {
+            bits: vec![0; number_of_bits.div_ceil(64)],
+            number_of_hashes: (number_of_hashes as u32)
+                .clamp(1, MAX_NUMBER_OF_HASHES),
+        }
+    }
+
+    // For when the number of keys is unknown until they were all seen, like
+    // in a compaction dropping versions: their hashes (see hash) are
+    // collected, and the filter is sized for them at the end.
+    pub fn from_hashes(hashes: &[u64], false_positive_rate: f64) -> Self {
+        let mut filter = Self::new(hashes.len(), false_positive_rate);
+        for hash in hashes {
+            filter.insert_hash(*hash);
+        }
+        filter
+    }
+
+    // The bit positions of a key hash, derived from it by double hashing.
+    fn positions(&self, h1: u64) -> impl Iterator<Item = usize> {
+        let number_of_bits = self.bits.len() as u64 * 64;
+        let h2 = h1.rotate_left(32) | 1;
+        (0..self.number_of_hashes as u64).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % number_of_bits) as usize
+        })
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash(key));
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        for position in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(hash(key)).all(|position| {
+            self.bits[position / 64] & (1 << (position % 64)) != 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = BloomFilter::new(1000, DEFAULT_FALSE_POSITIVE_RATE);
+        for i in 0..1000 {
+            filter.insert(i.to_string().as_bytes());
+        }
+        for i in 0..1000 {
+            assert!(filter.may_contain(i.to_string().as_bytes()));
+        }
+
+        let false_positives = (1000..11000)
+            .filter(|i| filter.may_contain(i.to_string().as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn false_positive_rate_close_to_target() {
+        for (items, rate) in [(100, 0.1), (1000, 0.01), (10000, 0.001)] {
+            let keys: Vec<u64> =
+                (0..items).map(|i| hash(i.to_string().as_bytes())).collect();
+            let filter = BloomFilter::from_hashes(&keys, rate);
+            for i in 0..items {
+                assert!(filter.may_contain(i.to_string().as_bytes()));
+            }
+
+            let tries = 100_000;
+            let false_positives = (items..items + tries)
+                .filter(|i| filter.may_contain(i.to_string().as_bytes()))
+                .count();
+            let measured = false_positives as f64 / tries as f64;
+            assert!(
+                measured > rate / 2.0 && measured < rate * 1.5,
+                "{} items at {}: measured {}",
+                items,
+                rate,
+                measured
+            );
+        }
+
+        // Sized by the items, not by a fixed number of bits per item.
+        let small = BloomFilter::new(1000, 0.01).bits.len();
+        assert!(BloomFilter::new(1000, 0.001).bits.len() > small);
+        assert!(BloomFilter::new(10000, 0.01).bits.len() > small * 9);
+    }
+}
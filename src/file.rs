@@ -0,0 +1,738 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    ops::Deref,
+    path::{Path, PathBuf},
+    pin::Pin,
+    rc::Rc,
+    task::{ready, Context, Poll},
+};
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use glommio::io::{BufferedFile, DmaFile, DmaStreamWriterBuilder, OpenOptions};
+
+// The bytes written by a LocalStorage writer at once.
+const WRITE_BUFFER_SIZE: usize = 128 * 1024;
+// The bytes read by a FileReader at once.
+const READ_SIZE: usize = 128 * 1024;
+
+// The operations sstable lookups do on a file, so that they can run on the
+// files of other runtimes than glommio, or on files in memory.
+// Files are written sequentially through futures_lite::AsyncWrite, see
+// Storage::create, and read sequentially by FileReader.
+pub trait AsyncFile {
+    type Buffer: Deref<Target = [u8]>;
+
+    fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> impl Future<Output = std::io::Result<Self::Buffer>>;
+
+    fn file_size(&self) -> impl Future<Output = std::io::Result<u64>>;
+
+    fn close(self) -> impl Future<Output = std::io::Result<()>>;
+}
+
+impl AsyncFile for DmaFile {
+    type Buffer = glommio::io::ReadResult;
+
+    async fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> std::io::Result<Self::Buffer> {
+        Ok(DmaFile::read_at(self, pos, size).await?)
+    }
+
+    async fn file_size(&self) -> std::io::Result<u64> {
+        Ok(DmaFile::file_size(self).await?)
+    }
+
+    async fn close(self) -> std::io::Result<()> {
+        Ok(DmaFile::close(self).await?)
+    }
+}
+
+impl AsyncFile for BufferedFile {
+    type Buffer = glommio::io::ReadResult;
+
+    async fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> std::io::Result<Self::Buffer> {
+        Ok(BufferedFile::read_at(self, pos, size).await?)
+    }
+
+    async fn file_size(&self) -> std::io::Result<u64> {
+        Ok(BufferedFile::file_size(self).await?)
+    }
+
+    async fn close(self) -> std::io::Result<()> {
+        Ok(BufferedFile::close(self).await?)
+    }
+}
+
+// A future of an IO operation of a Storage, boxed so that storages are
+// objects, held by the options of a tree.
+pub type IoFuture<'a, T> =
+    Pin<Box<dyn Future<Output = std::io::Result<T>> + 'a>>;
+
+// The bytes read by a StorageFile.
+pub struct FileBuffer(Box<dyn Deref<Target = [u8]>>);
+
+impl Deref for FileBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// A file written sequentially, complete once it's closed.
+pub type StorageWriter = Box<dyn AsyncWrite + Unpin>;
+
+// AsyncFile as an object.
+trait DynFile {
+    fn read_boxed(&self, pos: u64, size: usize) -> IoFuture<'_, FileBuffer>;
+
+    fn file_size_boxed(&self) -> IoFuture<'_, u64>;
+
+    fn close_boxed(self: Box<Self>) -> IoFuture<'static, ()>;
+}
+
+impl<F: AsyncFile + 'static> DynFile for F {
+    fn read_boxed(&self, pos: u64, size: usize) -> IoFuture<'_, FileBuffer> {
+        Box::pin(async move {
+            let buffer = self.read_at(pos, size).await?;
+            Ok(FileBuffer(Box::new(buffer)))
+        })
+    }
+
+    fn file_size_boxed(&self) -> IoFuture<'_, u64> {
+        Box::pin(self.file_size())
+    }
+
+    fn close_boxed(self: Box<Self>) -> IoFuture<'static, ()> {
+        Box::pin(self.close())
+    }
+}
+
+// A file opened by a Storage, of any AsyncFile.
+pub struct StorageFile(Box<dyn DynFile>);
+
+impl StorageFile {
+    pub fn new(file: impl AsyncFile + 'static) -> Self {
+        Self(Box::new(file))
+    }
+}
+
+impl AsyncFile for StorageFile {
+    type Buffer = FileBuffer;
+
+    async fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> std::io::Result<Self::Buffer> {
+        self.0.read_boxed(pos, size).await
+    }
+
+    async fn file_size(&self) -> std::io::Result<u64> {
+        self.0.file_size_boxed().await
+    }
+
+    async fn close(self) -> std::io::Result<()> {
+        self.0.close_boxed().await
+    }
+}
+
+// Where the files of sstables (data, index, meta and filter files, and value
+// logs) are kept, with only the operations flushes, compactions and gets do on
+// them: flushes and compactions write new files sequentially to temporary
+// paths, and rename them once complete, gets read ranges of them, and
+// compactions remove their inputs once no read uses them.
+// The tree directory itself is always on the local disk: the WAL, as it's
+// written on every write, the format file and the compaction actions, which
+// are what a crashed tree recovers from.
+// The operations on paths alone are synchronous, like the std::fs calls of
+// the local disk. Other storages block the executor for their duration.
+//
+// An object store (like S3) fits it with the paths as keys, ranged GETs for
+// read_at, multipart uploads for create, and copy + delete for rename (which
+// is not atomic there, the compaction action renames again on open what a
+// crash left). Every read_at is then a request of milliseconds instead of a
+// local read of microseconds, and a binary search does about log2(entries) of
+// them per sstable, so the index files are better kept in the index cache
+// (see LSMTreeOptions::with_index_cache_budget), and reads aligned to larger
+// blocks (see LSMTreeOptions::with_read_block_size) than on a local disk.
+// MemoryStorage is an object store of this shape, in memory.
+pub trait Storage {
+    fn open<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageFile>;
+
+    // Replaces the file at path, if there is one.
+    fn create<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageWriter>;
+
+    // Overwrites bytes of a complete file, for headers that are only known
+    // once the rest of the file is written. An object store writes the whole
+    // file again.
+    fn write_at<'a>(
+        &'a self,
+        path: &'a Path,
+        pos: u64,
+        bytes: &'a [u8],
+    ) -> IoFuture<'a, ()>;
+
+    // Makes the bytes of a complete file, or the names of the files in a
+    // directory, survive a power loss.
+    fn sync<'a>(&'a self, path: &'a Path) -> IoFuture<'a, ()>;
+
+    fn size(&self, path: &Path) -> std::io::Result<u64>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    // The names of all files in a directory, in no particular order.
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<String>>;
+
+    fn remove(&self, path: &Path) -> std::io::Result<()>;
+
+    // Replaces the file at to, if there is one.
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+}
+
+// The files on the local file system, read and written with DMA.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn open<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageFile> {
+        Box::pin(
+            async move { Ok(StorageFile::new(DmaFile::open(path).await?)) },
+        )
+    }
+
+    fn create<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageWriter> {
+        Box::pin(async move {
+            let file = DmaFile::create(path).await?;
+            let writer = DmaStreamWriterBuilder::new(file)
+                .with_write_behind(10)
+                .with_buffer_size(WRITE_BUFFER_SIZE)
+                .build();
+            Ok(Box::new(writer) as StorageWriter)
+        })
+    }
+
+    fn write_at<'a>(
+        &'a self,
+        path: &'a Path,
+        pos: u64,
+        bytes: &'a [u8],
+    ) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let file =
+                OpenOptions::new().write(true).buffered_open(path).await?;
+            file.write_at(bytes.to_vec(), pos).await?;
+            Ok(file.close().await?)
+        })
+    }
+
+    fn sync<'a>(&'a self, path: &'a Path) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            if path.is_dir() {
+                return std::fs::File::open(path)?.sync_all();
+            }
+            let file = BufferedFile::open(path).await?;
+            file.fdatasync().await?;
+            Ok(file.close().await?)
+        })
+    }
+
+    fn size(&self, path: &Path) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<String>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+}
+
+// An object store in memory, with the semantics of one (like S3): an object is
+// written whole, and only exists once its writer is closed, it's never modified
+// in place (write_at writes it whole again), reads are ranges of it, like
+// ranged GETs, and a rename is a copy and a delete.
+// For running trees on a storage other than the local disk, like in tests, the
+// objects are lost once the last clone of the storage is dropped.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    objects: Rc<RefCell<HashMap<PathBuf, Rc<Vec<u8>>>>>,
+    counts: Rc<ReadCounts>,
+}
+
+impl MemoryStorage {
+    // The ranged reads of objects made so far, and the bytes they read.
+    pub fn read_counts(&self) -> &ReadCounts {
+        &self.counts
+    }
+
+    fn get(&self, path: &Path) -> std::io::Result<Rc<Vec<u8>>> {
+        self.objects.borrow().get(path).cloned().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no object at '{}'", path.display()),
+            )
+        })
+    }
+
+    fn put(&self, path: &Path, bytes: Vec<u8>) {
+        self.objects
+            .borrow_mut()
+            .insert(path.to_path_buf(), Rc::new(bytes));
+    }
+}
+
+// An object of a MemoryStorage, as it was when it was opened.
+struct MemoryObject {
+    bytes: Rc<Vec<u8>>,
+    counts: Rc<ReadCounts>,
+}
+
+impl AsyncFile for MemoryObject {
+    type Buffer = BlockSlice;
+
+    // Like a ranged GET, a range past the end of the object is cut at its end.
+    async fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> std::io::Result<Self::Buffer> {
+        let start = (pos as usize).min(self.bytes.len());
+        let end = (start + size).min(self.bytes.len());
+        self.counts.reads.set(self.counts.reads.get() + 1);
+        self.counts
+            .bytes
+            .set(self.counts.bytes.get() + (end - start) as u64);
+        Ok(BlockSlice {
+            block: self.bytes.clone(),
+            start,
+            end,
+        })
+    }
+
+    async fn file_size(&self) -> std::io::Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    async fn close(self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Buffers the bytes of an object, which is put whole once it's closed.
+struct MemoryObjectWriter {
+    storage: MemoryStorage,
+    path: PathBuf,
+    bytes: Vec<u8>,
+}
+
+impl AsyncWrite for MemoryObjectWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.bytes.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let bytes = std::mem::take(&mut self.bytes);
+        self.storage.put(&self.path, bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageFile> {
+        Box::pin(async move {
+            Ok(StorageFile::new(MemoryObject {
+                bytes: self.get(path)?,
+                counts: self.counts.clone(),
+            }))
+        })
+    }
+
+    fn create<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageWriter> {
+        Box::pin(async move {
+            Ok(Box::new(MemoryObjectWriter {
+                storage: self.clone(),
+                path: path.to_path_buf(),
+                bytes: Vec::new(),
+            }) as StorageWriter)
+        })
+    }
+
+    fn write_at<'a>(
+        &'a self,
+        path: &'a Path,
+        pos: u64,
+        bytes: &'a [u8],
+    ) -> IoFuture<'a, ()> {
+        Box::pin(async move {
+            let mut object = self.get(path)?.to_vec();
+            let (start, end) = (pos as usize, pos as usize + bytes.len());
+            if object.len() < end {
+                object.resize(end, 0);
+            }
+            object[start..end].copy_from_slice(bytes);
+            self.put(path, object);
+            Ok(())
+        })
+    }
+
+    // An object is durable once it's put.
+    fn sync<'a>(&'a self, _: &'a Path) -> IoFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn size(&self, path: &Path) -> std::io::Result<u64> {
+        Ok(self.get(path)?.len() as u64)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.objects.borrow().contains_key(path)
+    }
+
+    // Like listing the objects of a prefix, up to a delimiter.
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<String>> {
+        Ok(self
+            .objects
+            .borrow()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        self.get(path)?;
+        self.objects.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let object = self.get(from)?;
+        self.objects.borrow_mut().insert(to.to_path_buf(), object);
+        self.objects.borrow_mut().remove(from);
+        Ok(())
+    }
+}
+
+// Reads a file from a position to its end through futures_lite::AsyncRead,
+// READ_SIZE bytes at a time, for compactions reading their inputs.
+pub struct FileReader {
+    file: Rc<StorageFile>,
+    pos: u64,
+    // Read with the first block, as reads past the end of a file aren't cut
+    // at its end by every file.
+    size: Option<u64>,
+    buffer: FileBuffer,
+    offset: usize,
+    read: Option<IoFuture<'static, (u64, FileBuffer)>>,
+}
+
+impl FileReader {
+    pub fn new(file: StorageFile, pos: u64) -> Self {
+        Self {
+            file: Rc::new(file),
+            pos,
+            size: None,
+            buffer: FileBuffer(Box::new(Vec::new())),
+            offset: 0,
+            read: None,
+        }
+    }
+
+    pub async fn close(mut self) -> std::io::Result<()> {
+        // The read in progress holds the file too.
+        self.read = None;
+        match Rc::try_unwrap(self.file) {
+            Ok(file) => file.close().await,
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if this.offset == this.buffer.len() {
+            if this.size.is_some_and(|size| this.pos >= size) {
+                return Poll::Ready(Ok(0));
+            }
+            let read = this.read.get_or_insert_with(|| {
+                let file = this.file.clone();
+                let (pos, size) = (this.pos, this.size);
+                Box::pin(async move {
+                    let size = match size {
+                        Some(size) => size,
+                        None => file.file_size().await?,
+                    };
+                    let len =
+                        (size.saturating_sub(pos) as usize).min(READ_SIZE);
+                    if len == 0 {
+                        return Ok((size, FileBuffer(Box::new(Vec::new()))));
+                    }
+                    let bytes = file.read_at(pos, len).await?;
+                    if bytes.len() > len {
+                        return Ok((
+                            size,
+                            FileBuffer(Box::new(bytes[..len].to_vec())),
+                        ));
+                    }
+                    Ok((size, bytes))
+                })
+            });
+            let result = ready!(read.as_mut().poll(cx));
+            this.read = None;
+            let (size, bytes) = result?;
+            this.size = Some(size);
+            this.buffer = bytes;
+            this.offset = 0;
+            this.pos += this.buffer.len() as u64;
+        }
+
+        let size = buf.len().min(this.buffer.len() - this.offset);
+        buf[..size]
+            .copy_from_slice(&this.buffer[this.offset..this.offset + size]);
+        this.offset += size;
+        Poll::Ready(Ok(size))
+    }
+}
+
+// The reads done from files, and the bytes they read.
+#[derive(Default, Debug)]
+pub struct ReadCounts {
+    pub reads: Cell<u64>,
+    pub bytes: Cell<u64>,
+}
+
+// Reads a file in blocks aligned to a block size, keeping the last block read,
+// so that reads close to each other, like the last probes of a binary search,
+// are served from memory instead of reading the file again.
+// A read crossing the end of a block reads all the blocks it spans. Without a
+// block size, reads go to the file as they are.
+pub struct BlockReader<'a, F: AsyncFile> {
+    file: &'a F,
+    block_size: Option<u64>,
+    // The offset of the last block read, and its bytes.
+    block: RefCell<Option<(u64, Rc<Vec<u8>>)>>,
+    counts: &'a ReadCounts,
+}
+
+impl<'a, F: AsyncFile> BlockReader<'a, F> {
+    pub fn new(
+        file: &'a F,
+        block_size: Option<u64>,
+        counts: &'a ReadCounts,
+    ) -> Self {
+        Self {
+            file,
+            block_size,
+            block: RefCell::new(None),
+            counts,
+        }
+    }
+
+    fn count(&self, bytes: usize) {
+        self.counts.reads.set(self.counts.reads.get() + 1);
+        self.counts
+            .bytes
+            .set(self.counts.bytes.get() + bytes as u64);
+    }
+}
+
+// A range of a block read by a BlockReader.
+pub struct BlockSlice {
+    block: Rc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Deref for BlockSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.block[self.start..self.end]
+    }
+}
+
+impl<F: AsyncFile> AsyncFile for BlockReader<'_, F> {
+    type Buffer = BlockSlice;
+
+    async fn read_at(
+        &self,
+        pos: u64,
+        size: usize,
+    ) -> std::io::Result<Self::Buffer> {
+        let slice = |block_offset: u64, block: Rc<Vec<u8>>| {
+            let start = ((pos - block_offset) as usize).min(block.len());
+            let end = (start + size).min(block.len());
+            BlockSlice { block, start, end }
+        };
+
+        let Some(block_size) = self.block_size else {
+            let bytes = self.file.read_at(pos, size).await?.to_vec();
+            self.count(bytes.len());
+            return Ok(slice(pos, Rc::new(bytes)));
+        };
+
+        if let Some((offset, block)) = &*self.block.borrow() {
+            // A block shorter than the block size ends at the end of the
+            // file, so reads past it are served from it too.
+            let full = (block.len() as u64).is_multiple_of(block_size);
+            let block_end = offset + block.len() as u64;
+            if pos >= *offset && (pos + size as u64 <= block_end || !full) {
+                return Ok(slice(*offset, block.clone()));
+            }
+        }
+
+        let offset = pos - pos % block_size;
+        let end = (pos + size as u64).div_ceil(block_size) * block_size;
+        let block = Rc::new(
+            self.file
+                .read_at(offset, (end - offset) as usize)
+                .await?
+                .to_vec(),
+        );
+        self.count(block.len());
+        *self.block.borrow_mut() = Some((offset, block.clone()));
+        Ok(slice(offset, block))
+    }
+
+    async fn file_size(&self) -> std::io::Result<u64> {
+        self.file.file_size().await
+    }
+
+    // The file is borrowed, closing it is left to its owner.
+    async fn close(self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use glommio::LocalExecutor;
+
+    #[test]
+    fn local_storage() {
+        LocalExecutor::default().run(async {
+            let mut dir = std::env::temp_dir();
+            dir.push("dbil-local_storage");
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).unwrap();
+            }
+            std::fs::create_dir(&dir).unwrap();
+            let storage = LocalStorage;
+
+            let (temp, path) = (dir.join("a.tmp"), dir.join("a"));
+            let mut writer = storage.create(&temp).await.unwrap();
+            writer.write_all(b"hello world").await.unwrap();
+            writer.close().await.unwrap();
+            storage.rename(&temp, &path).unwrap();
+            assert_eq!(storage.list(&dir).unwrap(), vec!["a".to_string()]);
+            storage.write_at(&path, 0, b"jello").await.unwrap();
+            storage.sync(&path).await.unwrap();
+            storage.sync(&dir).await.unwrap();
+            assert_eq!(storage.size(&path).unwrap(), 11);
+
+            let file = storage.open(&path).await.unwrap();
+            assert_eq!(file.file_size().await.unwrap(), 11);
+            let bytes = file.read_at(6, 5).await.unwrap();
+            assert_eq!(&*bytes, b"world");
+            let mut reader = FileReader::new(file, 3);
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await.unwrap();
+            assert_eq!(bytes, b"lo world");
+            reader.close().await.unwrap();
+
+            storage.remove(&path).unwrap();
+            assert!(!storage.exists(&path));
+            assert!(storage.list(&dir).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn memory_storage() {
+        LocalExecutor::default().run(async {
+            let dir = Path::new("/objects");
+            let storage = MemoryStorage::default();
+            let (temp, path) = (dir.join("a.tmp"), dir.join("a"));
+
+            // Only put once the writer is closed.
+            let mut writer = storage.create(&temp).await.unwrap();
+            writer.write_all(b"hello world").await.unwrap();
+            assert!(!storage.exists(&temp));
+            writer.close().await.unwrap();
+            storage.rename(&temp, &path).unwrap();
+            assert_eq!(storage.list(dir).unwrap(), vec!["a".to_string()]);
+            assert!(storage.list(Path::new("/")).unwrap().is_empty());
+
+            // An opened object keeps the bytes it was opened with.
+            let file = storage.open(&path).await.unwrap();
+            storage.write_at(&path, 0, b"jello").await.unwrap();
+            storage.sync(&path).await.unwrap();
+            assert_eq!(storage.size(&path).unwrap(), 11);
+            assert_eq!(&*file.read_at(0, 5).await.unwrap(), b"hello");
+            file.close().await.unwrap();
+
+            let file = storage.open(&path).await.unwrap();
+            assert_eq!(file.file_size().await.unwrap(), 11);
+            assert_eq!(&*file.read_at(6, 10).await.unwrap(), b"world");
+            assert_eq!(storage.read_counts().reads.get(), 2);
+            assert_eq!(storage.read_counts().bytes.get(), 10);
+            let mut reader = FileReader::new(file, 3);
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await.unwrap();
+            assert_eq!(bytes, b"lo world");
+            reader.close().await.unwrap();
+
+            storage.remove(&path).unwrap();
+            assert!(!storage.exists(&path));
+            assert!(storage.remove(&path).is_err());
+            assert!(storage.open(&path).await.is_err());
+        });
+    }
+}
@@ -1 +1,5 @@
+mod bloom;
+pub mod file;
 pub mod lsm_tree;
+mod sha256;
+pub mod typed;
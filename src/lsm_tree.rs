@@ -1,30 +1,186 @@
 use std::{
-    cmp::Ordering, collections::BinaryHeap, fs::DirEntry, marker::PhantomData,
-    path::PathBuf, rc::Rc,
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
+    future::Future,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    pin::Pin,
+    rc::Rc,
+    task::{Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use crate::{
+    bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE},
+    file::{
+        AsyncFile, BlockReader, FileReader, LocalStorage, ReadCounts, Storage,
+        StorageFile, StorageWriter,
+    },
+    sha256::Sha256,
+    typed::{Key, TypedLSMTree, Value as TypedValue},
+};
 use bincode::{
     config::{
         FixintEncoding, RejectTrailing, WithOtherIntEncoding, WithOtherTrailing,
     },
     DefaultOptions, Options,
 };
-use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use futures_lite::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Stream, StreamExt,
+};
 use glommio::io::{
-    BufferedFile, DmaFile, DmaStreamWriterBuilder, OpenOptions, StreamReader,
-    StreamReaderBuilder, StreamWriter, StreamWriterBuilder,
+    BufferedFile, OpenOptions, StreamReader, StreamReaderBuilder, StreamWriter,
+    StreamWriterBuilder,
 };
 use redblacktree::RedBlackTree;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 const TREE_CAPACITY: usize = 1024;
+// The bytes of WAL entries sorted in memory at once when converting a WAL
+// straight to an sstable, see LSMTree::external_sort.
+const WAL_SORT_MEMORY_BUDGET: usize = 1024 * 1024;
+// The number of sstables a compaction opens at once, as each open takes a
+// few file descriptors until it's done.
+const SSTABLE_OPEN_CONCURRENCY: usize = 16;
+// The number of bytes read from a WAL at once.
+const WAL_READ_SIZE: usize = 64 * 1024;
 const INDEX_PADDING: usize = 20; // Number of integers in max u64.
 
+// The extensions of the files in the directory of a tree, which are named by a
+// zero padded number (an sstable index, a WAL index or a value log id) and
+// the extension of their kind, see FileKind::of.
+// Files of new kinds (like other sidecars of an sstable) follow the same
+// scheme.
+pub const DATA_EXTENSION: &str = "data";
+pub const INDEX_EXTENSION: &str = "index";
+pub const META_EXTENSION: &str = "meta";
+pub const FILTER_EXTENSION: &str = "filter";
+pub const WAL_EXTENSION: &str = "memtable";
+pub const VALUE_LOG_EXTENSION: &str = "vlog";
+pub const COMPACTION_ACTION_EXTENSION: &str = "compact_action";
+// The extensions of the files written by compactions and flushes before they
+// are renamed to the files of an sstable.
+pub const TEMPORARY_EXTENSIONS: [&str; 8] = [
+    "compact_data",
+    "compact_index",
+    "compact_meta",
+    "compact_filter",
+    "flush_data",
+    "flush_index",
+    "flush_meta",
+    "flush_filter",
+];
+// The only file that is not named by a number.
+pub const FORMAT_FILE_NAME: &str = "format";
+const MIGRATED_FORMAT_FILE_NAME: &str = "format.migrated";
+// The subdirectory flushed WALs are moved to, see
+// LSMTreeOptions::with_wal_archive.
+pub const WAL_ARCHIVE_DIR_NAME: &str = "archive";
+
+// The kinds of files in the directory of a tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    // The files of an sstable.
+    Data,
+    Index,
+    Meta,
+    Filter,
+    Wal,
+    ValueLog,
+    // Completes a compaction on open, when the tree crashed while applying it.
+    CompactionAction,
+    // Written by a flush, a compaction or a migration that is not done yet,
+    // removed on open.
+    Temporary,
+    Format,
+}
+
+impl FileKind {
+    // The kind of a file of a tree by its name, with the number it's named
+    // by, None when it's not a file of a tree.
+    pub fn of(file_name: &str) -> Option<(FileKind, Option<usize>)> {
+        match file_name {
+            FORMAT_FILE_NAME => return Some((FileKind::Format, None)),
+            MIGRATED_FORMAT_FILE_NAME => {
+                return Some((FileKind::Temporary, None))
+            }
+            _ => {}
+        }
+
+        let (number, extension) = file_name.split_once('.')?;
+        if !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let number = number.parse().ok()?;
+        let kind = match extension {
+            DATA_EXTENSION => FileKind::Data,
+            INDEX_EXTENSION => FileKind::Index,
+            META_EXTENSION => FileKind::Meta,
+            FILTER_EXTENSION => FileKind::Filter,
+            WAL_EXTENSION => FileKind::Wal,
+            VALUE_LOG_EXTENSION => FileKind::ValueLog,
+            COMPACTION_ACTION_EXTENSION => FileKind::CompactionAction,
+            // The sorted runs of a WAL that is written straight to an
+            // sstable are named after the WAL.
+            _ if TEMPORARY_EXTENSIONS.contains(&extension)
+                || extension
+                    .strip_prefix(WAL_EXTENSION)
+                    .is_some_and(|rest| rest.starts_with('.')) =>
+            {
+                FileKind::Temporary
+            }
+            _ => return None,
+        };
+        Some((kind, Some(number)))
+    }
+}
+
+// The path of a file of a tree, named by the given number and extension.
+fn numbered_file_path(dir: &Path, number: usize, extension: &str) -> PathBuf {
+    dir.join(format!("{:01$}.{2}", number, INDEX_PADDING, extension))
+}
+
+// The path of the filter file written next to a meta file, named like it with
+// "filter" in place of "meta" at the end, so that the filters of temporary
+// metas are temporary too.
+fn filter_path(meta_path: &Path) -> PathBuf {
+    let name = meta_path.file_name().unwrap().to_string_lossy();
+    let name = name.strip_suffix("meta").unwrap_or(&name);
+    meta_path.with_file_name(format!("{}{}", name, FILTER_EXTENSION))
+}
+
+// The numbers and paths of the files of a kind in the directory of a tree,
+// sorted by number, listed from the storage of the kind (see file_storage).
+fn numbered_files(
+    storage: &dyn Storage,
+    dir: &Path,
+    kind: FileKind,
+) -> std::io::Result<Vec<(usize, PathBuf)>> {
+    let mut files: Vec<(usize, PathBuf)> = storage
+        .list(dir)?
+        .into_iter()
+        .filter_map(|name| match FileKind::of(&name)? {
+            (file_kind, Some(number)) if file_kind == kind => {
+                Some((number, dir.join(name)))
+            }
+            _ => None,
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Entry {
     key: String,
-    value: String,
+    value: Value,
+    // A number that is incremented on every write, determines which version
+    // of a key is the newest.
+    seq: u64,
+    // The time of the write, in nanoseconds since the unix epoch, 8 bytes per
+    // entry in the WAL and in the sstables.
+    timestamp: u64,
 }
 
 impl Ord for Entry {
@@ -47,17 +203,190 @@ impl PartialEq for Entry {
 
 impl Eq for Entry {}
 
-#[derive(Debug, Serialize, Deserialize)]
+// Values are only in a value log in sstables written by a flush with the
+// value_log_threshold option set (and in their compaction outputs), see
+// LSMTreeOptions::with_value_log_threshold.
+// A tombstone is written by LSMTree::delete, and hides the older versions of
+// its key until a compaction drops it with them, see TombstonePolicy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Value {
+    Inline(Vec<u8>),
+    Log(ValuePointer),
+    Tombstone,
+}
+
+impl Value {
+    fn len(&self) -> usize {
+        match self {
+            Value::Inline(value) => value.len(),
+            Value::Log(_) => std::mem::size_of::<ValuePointer>(),
+            Value::Tombstone => 0,
+        }
+    }
+
+    // None for a tombstone.
+    fn into_inline(self) -> std::io::Result<Option<Vec<u8>>> {
+        match self {
+            Value::Inline(value) => Ok(Some(value)),
+            Value::Log(pointer) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("value is in value log {}", pointer.log),
+            )),
+            Value::Tombstone => Ok(None),
+        }
+    }
+}
+
+// Values are bytes, only read as utf-8 by the methods of LSMTree that return
+// strings, see LSMTree::set_bytes.
+fn utf8_value(value: Option<Vec<u8>>) -> std::io::Result<Option<String>> {
+    value.map(utf8_string).transpose()
+}
+
+fn utf8_string(bytes: Vec<u8>) -> std::io::Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("value is not utf-8: {}", e),
+        )
+    })
+}
+
+fn utf8_str(bytes: &[u8]) -> std::io::Result<&str> {
+    std::str::from_utf8(bytes).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("value is not utf-8: {}", e),
+        )
+    })
+}
+
+// A value log holds the values written by a single flush, one after the
+// other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ValuePointer {
+    log: usize,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct EntryOffset {
     entry_offset: u64,
     entry_size: usize,
 }
 
-impl Default for EntryOffset {
-    fn default() -> Self {
+// A value stored in a memtable, with the sequence number and the timestamp of
+// the write that set it.
+#[derive(Debug, PartialEq, Eq)]
+struct MemtableValue {
+    // None for a deleted key.
+    value: Option<Vec<u8>>,
+    seq: u64,
+    timestamp: u64,
+}
+
+impl MemtableValue {
+    fn stored(&self) -> Value {
+        self.value.clone().map_or(Value::Tombstone, Value::Inline)
+    }
+}
+
+fn nanos_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+// The data and index paths of the sstables a compaction merges, in the
+// storage they're in, with the offsets added to their sequence numbers.
+struct CompactionInputs {
+    storage: Rc<dyn Storage>,
+    sstables: Vec<(PathBuf, PathBuf, u64)>,
+}
+
+// The data, index and meta paths an sstable is written to.
+#[derive(Clone)]
+struct SstablePaths {
+    data_path: PathBuf,
+    index_path: PathBuf,
+    meta_path: PathBuf,
+}
+
+impl SstablePaths {
+    // All the files of the sstable, including the filter next to the meta.
+    fn files(&self) -> [PathBuf; 4] {
+        [
+            self.data_path.clone(),
+            self.index_path.clone(),
+            self.meta_path.clone(),
+            filter_path(&self.meta_path),
+        ]
+    }
+}
+
+// The options of the tree that decide how its sstables are written.
+#[derive(Clone, Copy)]
+struct SstableLayout {
+    fixed_key_size: Option<usize>,
+    restart_interval: u64,
+    false_positive_rate: f64,
+}
+
+// Whether compaction keeps an entry, given its key and value.
+type EntryFilter<'a> = dyn Fn(&str, &str) -> bool + 'a;
+
+// Which of the entries of the merged sstables a merge writes: the ones whose
+// keys are in [start, end), of the newest versions_to_keep versions of every
+// key that pass the filter, when given, and that are not of a key whose
+// tombstone is dropped.
+struct MergeSettings<'a> {
+    start: Option<String>,
+    end: Option<String>,
+    versions_to_keep: usize,
+    filter: Option<&'a EntryFilter<'a>>,
+    tombstones: TombstonePolicy,
+}
+
+impl MergeSettings<'_> {
+    // Every key, in the given number of versions, and no filter.
+    fn all_keys(versions_to_keep: usize, tombstones: TombstonePolicy) -> Self {
         Self {
-            entry_offset: Default::default(),
-            entry_size: Default::default(),
+            start: None,
+            end: None,
+            versions_to_keep,
+            filter: None,
+            tombstones,
+        }
+    }
+}
+
+// Whether a merge of sstables drops the tombstones that are the newest
+// version of their key, together with the older versions of the key, which is
+// only safe when no sstable outside of the merge might hold an older version
+// of the key, as the key would have that value again.
+#[derive(Clone)]
+enum TombstonePolicy {
+    Keep,
+    // Dropped unless one of these sstables (the live sstables that are not
+    // merged, None for the ones without a meta) might hold the key, or the
+    // tombstone is not older than the grace period, see
+    // LSMTree::tombstone_grace_bounds.
+    DropUnlessIn(Vec<Option<Rc<SstableMeta>>>, (u64, u64)),
+}
+
+impl TombstonePolicy {
+    fn drops(&self, tombstone: &Entry) -> bool {
+        match self {
+            TombstonePolicy::Keep => false,
+            TombstonePolicy::DropUnlessIn(metas, (seq, timestamp)) => {
+                tombstone.seq < *seq
+                    && tombstone.timestamp < *timestamp
+                    && !metas.iter().any(|meta| {
+                        meta.as_ref()
+                            .is_none_or(|m| m.may_contain(&tombstone.key))
+                    })
+            }
         }
     }
 }
@@ -68,12 +397,17 @@ struct CompactionItem {
     index: usize,
 }
 
+// Pops the smallest key first, and for the same key the newest version first.
+// Versions of the same sequence number (in trees merged by merge_from) are
+// popped by the order of their sstables, so the output doesn't depend on the
+// order they were pushed at.
 impl Ord for CompactionItem {
     fn cmp(&self, other: &Self) -> Ordering {
         other
             .entry
             .cmp(&self.entry)
-            .then(self.index.cmp(&other.index))
+            .then(self.entry.seq.cmp(&other.entry.seq))
+            .then(other.index.cmp(&self.index))
     }
 }
 
@@ -89,600 +423,11805 @@ struct CompactionAction {
     deletes: Vec<PathBuf>,
 }
 
-fn bincode_options() -> WithOtherIntEncoding<
-    WithOtherTrailing<DefaultOptions, RejectTrailing>,
-    FixintEncoding,
-> {
-    return DefaultOptions::new()
-        .reject_trailing_bytes()
-        .with_fixint_encoding();
+// Written next to every sstable, to skip searching sstables that can't hold a
+// key.
+// The filter is written to a file of its own (see filter_path), so it can be
+// shipped with the sstable to a tree that opens it without building it again.
+// It's None only while an opened sstable has no filter file, see
+// LSMTree::load_sstable_filter.
+#[derive(Serialize, Deserialize)]
+struct SstableMeta {
+    #[serde(skip)]
+    filter: Option<BloomFilter>,
+    // The first and last keys of the sstable, None when it's empty.
+    key_range: Option<(String, String)>,
+    // The value logs that values of the sstable are in.
+    value_logs: BTreeSet<usize>,
 }
 
-async fn binary_search(
-    data_file: &DmaFile,
-    index_file: &DmaFile,
-    key: &String,
-) -> glommio::Result<Option<Entry>, ()> {
-    let item_size = bincode_options()
-        .serialized_size(&EntryOffset::default())
-        .unwrap();
-    let length = index_file.file_size().await? / item_size;
-
-    let mut half = length / 2;
-    let mut hind = length - 1;
-    let mut lind = 0;
+impl SstableMeta {
+    fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        Self {
+            filter: Some(BloomFilter::new(expected_items, false_positive_rate)),
+            key_range: None,
+            value_logs: BTreeSet::new(),
+        }
+    }
 
-    let mut current: EntryOffset = bincode_options()
-        .deserialize(
-            &index_file
-                .read_at(half * item_size, item_size as usize)
-                .await?,
-        )
-        .unwrap();
+    // Must be called with keys in ascending order.
+    fn insert(&mut self, key: &str, value: &Value) {
+        if let Some(filter) = &mut self.filter {
+            filter.insert(key.as_bytes());
+        }
+        self.record(key, value);
+    }
 
-    while lind <= hind {
-        let value: Entry = bincode_options()
-            .deserialize(
-                &data_file
-                    .read_at(current.entry_offset as u64, current.entry_size)
-                    .await?,
-            )
-            .unwrap();
+    // Same as insert, without inserting the key to the filter, for when the
+    // filter is built once all keys are known.
+    fn record(&mut self, key: &str, value: &Value) {
+        if let Value::Log(pointer) = value {
+            self.value_logs.insert(pointer.log);
+        }
+        match &mut self.key_range {
+            Some((_, max_key)) => *max_key = key.to_string(),
+            None => self.key_range = Some((key.to_string(), key.to_string())),
+        }
+    }
 
-        match value.key.cmp(&key) {
-            std::cmp::Ordering::Equal => {
-                return Ok(Some(value));
+    fn may_contain(&self, key: &str) -> bool {
+        match &self.key_range {
+            Some((min_key, max_key)) => {
+                min_key.as_str() <= key
+                    && key <= max_key.as_str()
+                    && self
+                        .filter
+                        .as_ref()
+                        .is_none_or(|filter| filter.may_contain(key.as_bytes()))
             }
-            std::cmp::Ordering::Less => lind = half + 1,
-            std::cmp::Ordering::Greater => hind = half - 1,
+            None => false,
         }
-        half = (hind + lind) / 2;
-        current = bincode_options()
-            .deserialize(
-                &index_file
-                    .read_at(half * item_size, item_size as usize)
-                    .await?,
-            )
-            .unwrap();
     }
+}
 
-    Ok(None)
+// Files retired by a compaction, that are deleted once nothing reads from them
+// anymore.
+struct PendingDelete {
+    files: Vec<PathBuf>,
+    // Deleted last, so a crash before all files are deleted deletes them on
+    // the next open.
+    compact_action_path: PathBuf,
+    // The reads counters of the retired sstables.
+    reads: Vec<Rc<()>>,
 }
 
-pub struct LSMTree {
-    dir: PathBuf,
-    // The memtable that is currently being written to.
-    active_memtable: RedBlackTree<String, String>,
-    // The memtable that is currently being flushed to disk.
-    flush_memtable: Option<RedBlackTree<String, String>>,
-    // The next sstable index that is going to be written.
-    write_sstable_index: usize,
-    // The sstable indices to query from.
-    read_sstable_indices: Vec<usize>,
-    // Track the number of sstable file reads are happening.
-    // The reason for tracking is that when ending a compaction, there are
-    // sstable files that should be removed / replaced, but there could be
-    // reads to the same files concurrently, so the compaction process will
-    // wait for the number of reads to reach 0.
-    number_of_sstable_reads: Rc<PhantomData<usize>>,
-    // The next memtable index.
-    memtable_index: usize,
-    // The memtable WAL for durability in case the process crashes without
-    // flushing the memtable to disk.
-    wal_writer: StreamWriter,
+// Keeps the files of the sstables it was created for on disk while it is
+// alive, even if they are compacted away in the meantime.
+pub struct SstableFilesGuard {
+    _reads: Vec<Rc<()>>,
 }
 
-impl LSMTree {
-    pub async fn new(dir: PathBuf) -> std::io::Result<Self> {
-        if !dir.is_dir() {
-            std::fs::create_dir_all(&dir)?;
+// Pauses the merge of the compactions it is passed to, between writing two
+// entries, until it is resumed.
+#[derive(Clone, Default)]
+pub struct PauseToken {
+    paused: Rc<Cell<bool>>,
+    waiters: Rc<RefCell<Vec<Waker>>>,
+}
+
+impl PauseToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.set(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.set(false);
+        for waker in self.waiters.borrow_mut().drain(..) {
+            waker.wake();
         }
+    }
 
-        let pattern = Regex::new(r#"^(\d+)\.compact_action"#).unwrap();
-        let compact_action_paths: Vec<PathBuf> = std::fs::read_dir(&dir)?
-            .filter_map(Result::ok)
-            .filter(|entry| Self::get_first_capture(&pattern, &entry).is_some())
-            .map(|entry| entry.path())
-            .collect();
-        for compact_action_path in &compact_action_paths {
-            let file = BufferedFile::open(compact_action_path).await?;
-            let mut reader = StreamReaderBuilder::new(file).build();
+    pub fn is_paused(&self) -> bool {
+        self.paused.get()
+    }
 
-            let mut buf = Vec::new();
-            reader.read_to_end(&mut buf).await?;
-            let mut cursor = std::io::Cursor::new(&buf[..]);
-            while let Ok(action) = bincode_options()
-                .deserialize_from::<_, CompactionAction>(&mut cursor)
-            {
-                Self::run_compaction_action(&action)?;
+    async fn wait_while_paused(&self) {
+        futures_lite::future::poll_fn(|cx| {
+            if self.is_paused() {
+                self.waiters.borrow_mut().push(cx.waker().clone());
+                Poll::Pending
+            } else {
+                Poll::Ready(())
             }
-            reader.close().await?;
-            Self::remove_file_log_on_err(compact_action_path);
-        }
+        })
+        .await
+    }
+}
 
-        let pattern = Regex::new(r#"^(\d+)\.data"#).unwrap();
-        let data_file_indices = {
-            let mut vec: Vec<usize> = std::fs::read_dir(&dir)?
-                .filter_map(Result::ok)
-                .filter_map(|entry| Self::get_first_capture(&pattern, &entry))
-                .collect();
-            vec.sort();
-            vec
-        };
+// Marks the inputs and outputs of a running compaction, so that compactions
+// are only started concurrently on disjoint sstables. Released on drop.
+struct CompactionReservation {
+    indices: Vec<usize>,
+    compacting: Rc<RefCell<HashSet<usize>>>,
+}
 
-        let write_file_index =
-            data_file_indices.iter().max().map(|i| *i + 1).unwrap_or(0);
+impl Drop for CompactionReservation {
+    fn drop(&mut self) {
+        let mut compacting = self.compacting.borrow_mut();
+        for index in &self.indices {
+            compacting.remove(index);
+        }
+    }
+}
 
-        let pattern = Regex::new(r#"^(\d+)\.memtable"#).unwrap();
-        let wal_indices: Vec<usize> = {
-            let mut vec: Vec<usize> = std::fs::read_dir(&dir)?
-                .filter_map(Result::ok)
-                .filter_map(|entry| Self::get_first_capture(&pattern, &entry))
-                .collect();
-            vec.sort();
-            vec
-        };
-
-        let wal_file_index = match wal_indices.len() {
-            0 => 0,
-            1 => wal_indices[0],
-            2 => {
-                // A flush did not finish for some reason, do it now.
-                let wal_file_index = wal_indices[1];
-                let unflashed_file_index = wal_indices[0];
-                let mut unflashed_file_path = dir.clone();
-                unflashed_file_path.push(format!(
-                    "{:01$}.memtable",
-                    unflashed_file_index, INDEX_PADDING
-                ));
-                let (data_file_path, index_file_path) =
-                    Self::get_data_file_paths(
-                        dir.clone(),
-                        unflashed_file_index,
-                    );
-                let memtable =
-                    Self::read_memtable_from_wal_file(&unflashed_file_path)
-                        .await?;
-                let data_file = DmaFile::open(&data_file_path).await?;
-                let index_file = DmaFile::open(&index_file_path).await?;
-                Self::flush_memtable_to_disk(&memtable, data_file, index_file)
-                    .await?;
-                std::fs::remove_file(&unflashed_file_path)?;
-                wal_file_index
-            }
-            _ => panic!("Cannot have more than 2 WAL files"),
-        };
+// The key ranges held by RangeGuards, see LSMTree::acquire_range.
+#[derive(Clone, Default)]
+struct RangeLocks {
+    state: Rc<RefCell<RangeLocksState>>,
+}
 
-        let mut wal_path = dir.clone();
-        wal_path
-            .push(format!("{:01$}.memtable", wal_file_index, INDEX_PADDING));
+#[derive(Default)]
+struct RangeLocksState {
+    // The held ranges by their start, with their end (None when unbounded).
+    // Held ranges never overlap, so they are ordered by their ends too, and a
+    // range can only overlap the last held range that starts before its end.
+    held: BTreeMap<String, Option<String>>,
+    waiters: Vec<Waker>,
+}
 
-        let (wal_writer, active_memtable) = if wal_path.exists() {
-            let memtable = Self::read_memtable_from_wal_file(&wal_path).await?;
-            let file = OpenOptions::new()
-                .append(true)
-                .buffered_open(&wal_path)
-                .await?;
-            let wal_writer = StreamWriterBuilder::new(file).build();
-            (wal_writer, memtable)
-        } else {
-            let memtable = RedBlackTree::with_capacity(TREE_CAPACITY);
-            let wal_writer = StreamWriterBuilder::new(
-                BufferedFile::create(&wal_path).await?,
-            )
-            .build();
-            (wal_writer, memtable)
+impl RangeLocksState {
+    fn overlaps_held(&self, start: &String, end: Option<&String>) -> bool {
+        let last_before_end = match end {
+            Some(end) => self.held.range::<String, _>(..end).next_back(),
+            None => self.held.iter().next_back(),
         };
-
-        Ok(Self {
-            dir,
-            active_memtable,
-            flush_memtable: None,
-            write_sstable_index: write_file_index,
-            read_sstable_indices: data_file_indices,
-            number_of_sstable_reads: Rc::new(PhantomData::<usize>),
-            memtable_index: wal_file_index,
-            wal_writer,
+        last_before_end.is_some_and(|(_, held_end)| {
+            held_end.as_ref().is_none_or(|held_end| held_end > start)
         })
     }
+}
 
-    fn get_first_capture(pattern: &Regex, entry: &DirEntry) -> Option<usize> {
-        let file_name = entry.file_name();
-        file_name.to_str().and_then(|file_str| {
-            pattern.captures(&file_str).and_then(|captures| {
-                captures.get(1).and_then(|number_capture| {
-                    number_capture.as_str().parse::<usize>().ok()
-                })
-            })
+impl RangeLocks {
+    // None when the range overlaps a held range. An empty range holds no key,
+    // so it never overlaps.
+    fn try_acquire(
+        &self,
+        start: &String,
+        end: Option<&String>,
+    ) -> Option<RangeGuard> {
+        if end.is_some_and(|end| end <= start) {
+            return Some(RangeGuard {
+                state: self.state.clone(),
+                start: None,
+            });
+        }
+        let mut state = self.state.borrow_mut();
+        if state.overlaps_held(start, end) {
+            return None;
+        }
+        state.held.insert(start.clone(), end.cloned());
+        Some(RangeGuard {
+            state: self.state.clone(),
+            start: Some(start.clone()),
         })
     }
 
-    async fn read_memtable_from_wal_file(
-        wal_path: &PathBuf,
-    ) -> std::io::Result<RedBlackTree<String, String>> {
-        let mut memtable = RedBlackTree::with_capacity(TREE_CAPACITY);
-        let wal_file = BufferedFile::open(&wal_path).await?;
-        let mut reader = StreamReaderBuilder::new(wal_file).build();
-
-        let mut wal_buf = Vec::new();
-        reader.read_to_end(&mut wal_buf).await?;
-        let mut cursor = std::io::Cursor::new(&wal_buf[..]);
-        while let Ok(entry) =
-            bincode_options().deserialize_from::<_, Entry>(&mut cursor)
-        {
-            memtable.set(entry.key, entry.value).unwrap();
-        }
-        reader.close().await?;
-        Ok(memtable)
+    async fn acquire(
+        &self,
+        start: &String,
+        end: Option<&String>,
+    ) -> RangeGuard {
+        futures_lite::future::poll_fn(|cx| match self.try_acquire(start, end) {
+            Some(guard) => Poll::Ready(guard),
+            None => {
+                self.state.borrow_mut().waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
     }
+}
 
-    fn run_compaction_action(action: &CompactionAction) -> std::io::Result<()> {
-        for path_to_delete in &action.deletes {
-            if path_to_delete.exists() {
-                Self::remove_file_log_on_err(path_to_delete);
-            }
-        }
+// Holds a key range, see LSMTree::acquire_range. Released on drop.
+pub struct RangeGuard {
+    state: Rc<RefCell<RangeLocksState>>,
+    // The start of the held range, None for an empty range.
+    start: Option<String>,
+}
 
-        for (source_path, destination_path) in &action.renames {
-            if source_path.exists() {
-                std::fs::rename(source_path, destination_path)?;
-            }
+impl Drop for RangeGuard {
+    fn drop(&mut self) {
+        let Some(start) = &self.start else {
+            return;
+        };
+        let mut state = self.state.borrow_mut();
+        state.held.remove(start);
+        // Every waiter checks its range again, the ones that still overlap
+        // wait for the next release.
+        for waker in state.waiters.drain(..) {
+            waker.wake();
         }
-
-        Ok(())
     }
+}
 
-    fn get_data_file_paths(dir: PathBuf, index: usize) -> (PathBuf, PathBuf) {
-        let mut data_filename = dir.clone();
-        data_filename.push(format!("{:01$}.data", index, INDEX_PADDING));
-        let mut index_filename = dir.clone();
-        index_filename.push(format!("{:01$}.index", index, INDEX_PADDING));
-        (data_filename, index_filename)
-    }
+// A compaction started by LSMTree::start_compaction, its merge runs without
+// borrowing the tree, so the tree keeps serving reads and writes meanwhile.
+// The inputs are held on disk until the compaction is dropped.
+pub struct Compaction {
+    indices_to_compact: Vec<usize>,
+    output_index: usize,
+    sstable_paths: Vec<(PathBuf, PathBuf)>,
+    compact_paths: SstablePaths,
+    layout: SstableLayout,
+    config: BincodeConfig,
+    versions_to_keep: usize,
+    tombstones: TombstonePolicy,
+    storage: Rc<dyn Storage>,
+    // Set once the merge is done.
+    output: Option<(IndexHeader, SstableMeta)>,
+    _files_guard: SstableFilesGuard,
+    _reservation: CompactionReservation,
+}
 
-    fn get_compaction_file_paths(
-        dir: PathBuf,
-        index: usize,
-    ) -> (PathBuf, PathBuf) {
-        let mut data_filename = dir.clone();
-        data_filename
-            .push(format!("{:01$}.compact_data", index, INDEX_PADDING));
-        let mut index_filename = dir.clone();
-        index_filename
-            .push(format!("{:01$}.compact_index", index, INDEX_PADDING));
-        (data_filename, index_filename)
+impl Compaction {
+    // Merge the inputs into the output files, pausing whenever the token is
+    // paused. Nothing is visible to the tree until the compaction is passed to
+    // LSMTree::finish_compaction_job.
+    pub async fn run(&mut self, pause: &PauseToken) -> std::io::Result<()> {
+        let output = LSMTree::write_compaction_output(
+            CompactionInputs {
+                storage: self.storage.clone(),
+                sstables: without_seq_offsets(&self.sstable_paths),
+            },
+            self.compact_paths.clone(),
+            self.layout,
+            self.config,
+            MergeSettings::all_keys(
+                self.versions_to_keep,
+                self.tombstones.clone(),
+            ),
+            Some(pause),
+        )
+        .await?;
+        self.output = Some(output);
+        Ok(())
     }
 
-    pub async fn get(
-        &self,
-        key: &String,
-    ) -> glommio::Result<Option<String>, ()> {
-        // Query the active tree first.
-        let result = self.active_memtable.get(key);
-        if result.is_some() {
-            return Ok(result.map(|s| s.clone()));
-        }
+    // Mark the output as written by a tombstone rewrite in its index header,
+    // before it's renamed to the output index, see
+    // IndexHeader::tombstone_rewrite.
+    async fn mark_tombstone_rewrite(&mut self) -> std::io::Result<()> {
+        let Some((header, _)) = &mut self.output else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "compaction did not run",
+            ));
+        };
+        header.tombstone_rewrite = true;
+        self.storage
+            .write_at(&self.compact_paths.index_path, 0, &header.encode())
+            .await
+    }
+}
 
-        // Key not found in active tree, query the flushed tree.
-        if let Some(tree) = &self.flush_memtable {
-            let result = tree.get(key);
-            if result.is_some() {
-                return Ok(result.map(|s| s.clone()));
-            }
-        }
+pub(crate) fn bincode_options() -> WithOtherIntEncoding<
+    WithOtherTrailing<DefaultOptions, RejectTrailing>,
+    FixintEncoding,
+> {
+    DefaultOptions::new()
+        .reject_trailing_bytes()
+        .with_fixint_encoding()
+}
 
-        // Key not found in memory, query all files from the newest to the
-        // oldest.
-        let _counter = self.number_of_sstable_reads.clone();
+// How integers are encoded by bincode.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum IntEncoding {
+    #[default]
+    Fixint,
+    Varint,
+}
 
-        for i in self.read_sstable_indices.iter().rev() {
-            let (data_filename, index_filename) =
-                Self::get_data_file_paths(self.dir.clone(), *i);
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
 
-            let data_file = DmaFile::open(&data_filename).await?;
-            let index_file = DmaFile::open(&index_filename).await?;
+// The bincode configuration entries are encoded with in the WAL and the data
+// files, trailing bytes are always rejected.
+// Index files, metas and compaction actions always use the default
+// configuration, as index records must be of a fixed size.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct BincodeConfig {
+    pub int_encoding: IntEncoding,
+    pub endian: Endian,
+}
 
-            if let Some(result) =
-                binary_search(&data_file, &index_file, key).await?
-            {
-                return Ok(Some(result.value));
+// Runs the body with `options` bound to the bincode options of a config, as
+// each configuration is a different type.
+macro_rules! with_bincode_options {
+    ($config:expr, $options:ident => $body:expr) => {{
+        let options = DefaultOptions::new().reject_trailing_bytes();
+        match ($config.int_encoding, $config.endian) {
+            (IntEncoding::Fixint, Endian::Little) => {
+                let $options =
+                    options.with_fixint_encoding().with_little_endian();
+                $body
+            }
+            (IntEncoding::Fixint, Endian::Big) => {
+                let $options = options.with_fixint_encoding().with_big_endian();
+                $body
+            }
+            (IntEncoding::Varint, Endian::Little) => {
+                let $options =
+                    options.with_varint_encoding().with_little_endian();
+                $body
+            }
+            (IntEncoding::Varint, Endian::Big) => {
+                let $options = options.with_varint_encoding().with_big_endian();
+                $body
             }
         }
+    }};
+}
 
-        Ok(None)
+impl BincodeConfig {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        with_bincode_options!(self, options => options.serialize(value))
+            .unwrap()
     }
 
-    pub async fn set(
-        &mut self,
-        key: String,
-        value: String,
-    ) -> glommio::Result<Option<String>, ()> {
-        // Write to memtable in memory.
-        let result = self
-            .active_memtable
-            .set(key.clone(), value.clone())
-            .unwrap();
-
-        // Write to WAL for persistance.
-        let entry = Entry { key, value };
-        let entry_encoded = bincode_options().serialize(&entry).unwrap();
-        self.wal_writer.write_all(&entry_encoded).await?;
-        self.wal_writer.flush().await?;
+    fn deserialize<'a, T: Deserialize<'a>>(
+        &self,
+        bytes: &'a [u8],
+    ) -> bincode::Result<T> {
+        with_bincode_options!(self, options => options.deserialize(bytes))
+    }
+}
 
-        if self.active_memtable.capacity() == self.active_memtable.len() {
-            // Capacity is full, flush the active tree to disk.
-            self.flush().await?;
+// CRC-32 (IEEE), of the records of the WAL.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
         }
-
-        Ok(result)
+        table[i] = crc;
+        i += 1;
     }
+    table
+};
 
-    async fn flush(&mut self) -> glommio::Result<(), ()> {
-        if self.active_memtable.len() == 0 {
-            return Ok(());
-        }
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize]
+    })
+}
 
-        // Wait until the previous flush is finished.
-        while self.flush_memtable.is_some() {
-            futures_lite::future::yield_now().await;
+// The length of the encoded entry and its CRC-32, both little endian u32.
+const WAL_RECORD_HEADER_SIZE: usize = 8;
+
+// An encoded entry as written to the WAL, after the header of its record.
+fn wal_record(entry_encoded: &[u8]) -> Vec<u8> {
+    let mut record =
+        Vec::with_capacity(WAL_RECORD_HEADER_SIZE + entry_encoded.len());
+    record.extend((entry_encoded.len() as u32).to_le_bytes());
+    record.extend(crc32(entry_encoded).to_le_bytes());
+    record.extend(entry_encoded);
+    record
+}
+
+pub type WalFuture<'a> =
+    Pin<Box<dyn Future<Output = std::io::Result<()>> + 'a>>;
+
+// Where writes are logged before they are acknowledged, so that the writes of
+// the active memtable survive a crash.
+// Entries are encoded the same way as by LSMTree::get_raw_entry, so that a
+// WAL forwarding them to a replica can apply them there with
+// LSMTree::apply_raw_entry, in the order they were appended.
+//
+// The tree always writes a local WAL file (see FileWal) to recover from on
+// open, and rotates it on every flush for a new one, as the entries of the
+// flushed memtable are then in an sstable. A WAL set with
+// LSMTreeOptions::with_wal wraps the file of every WAL, and can ship the
+// entries elsewhere in addition to writing them to the file.
+// A WAL replacing the local file is not supported: recovery, the flush of a
+// WAL on open and the WAL archive all read the local file, and nothing is
+// ever recovered through this trait.
+pub trait WriteAheadLog {
+    // The write is acknowledged once this returns, an error fails the write,
+    // which is then still in the memtable.
+    fn append<'a>(&'a mut self, entry_encoded: &'a [u8]) -> WalFuture<'a>;
+
+    // Makes the appended entries durable, see LSMTree::sync_wal.
+    fn sync(&mut self) -> WalFuture<'_>;
+}
+
+// The default WAL: records (see wal_record) appended to a local file, through
+// the page cache, so an append survives a crash of the process, but not a
+// power loss until synced.
+pub struct FileWal {
+    writer: StreamWriter,
+    path: PathBuf,
+}
+
+impl FileWal {
+    fn new(file: BufferedFile, path: PathBuf) -> Self {
+        Self {
+            writer: StreamWriterBuilder::new(file).build(),
+            path,
         }
+    }
+}
 
-        let mut flush_wal_path = self.dir.clone();
-        flush_wal_path.push(format!(
-            "{:01$}.memtable",
-            self.memtable_index, INDEX_PADDING
-        ));
+impl WriteAheadLog for FileWal {
+    fn append<'a>(&'a mut self, entry_encoded: &'a [u8]) -> WalFuture<'a> {
+        Box::pin(async move {
+            self.writer.write_all(&wal_record(entry_encoded)).await?;
+            self.writer.flush().await
+        })
+    }
 
-        self.memtable_index += 2;
+    // The writer only syncs on close, so the written file is synced through
+    // a file of its own, like sstable files after a flush.
+    fn sync(&mut self) -> WalFuture<'_> {
+        Box::pin(async move {
+            let file = BufferedFile::open(&self.path).await?;
+            file.fdatasync().await?;
+            file.close().await?;
+            Ok(())
+        })
+    }
+}
 
-        let mut next_wal_path = self.dir.clone();
-        next_wal_path.push(format!(
-            "{:01$}.memtable",
-            self.memtable_index, INDEX_PADDING
-        ));
+// Wraps the FileWal of every WAL of a tree, see LSMTreeOptions::with_wal.
+#[derive(Clone)]
+struct WalWrapper(Rc<dyn Fn(FileWal) -> Box<dyn WriteAheadLog>>);
 
-        self.wal_writer = StreamWriterBuilder::new(
-            BufferedFile::create(&next_wal_path).await?,
-        )
-        .build();
+impl std::fmt::Debug for WalWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WalWrapper")
+    }
+}
 
-        let (data_filename, index_filename) = Self::get_data_file_paths(
-            self.dir.clone(),
-            self.write_sstable_index,
-        );
-        let data_file = DmaFile::create(&data_filename).await?;
-        let index_file = DmaFile::create(&index_filename).await?;
+// The storage of the sstables of a tree, see LSMTreeOptions::with_storage.
+#[derive(Clone)]
+struct SharedStorage(Rc<dyn Storage>);
 
-        let mut memtable_to_flush =
-            RedBlackTree::with_capacity(self.active_memtable.capacity());
-        std::mem::swap(&mut memtable_to_flush, &mut self.active_memtable);
-        self.flush_memtable = Some(memtable_to_flush);
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self(Rc::new(LocalStorage))
+    }
+}
 
-        Self::flush_memtable_to_disk(
-            self.flush_memtable.as_ref().unwrap(),
-            data_file,
-            index_file,
-        )
-        .await?;
+impl std::fmt::Debug for SharedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedStorage")
+    }
+}
 
-        self.flush_memtable = None;
-        self.read_sstable_indices.push(self.write_sstable_index);
-        self.write_sstable_index += 2;
+// The storage of a file of a tree: the WAL, the format file and the
+// compaction actions are always on the local disk, the other files are in the
+// storage of the tree, see Storage.
+fn file_storage<'a>(storage: &'a dyn Storage, path: &Path) -> &'a dyn Storage {
+    let kind = path
+        .file_name()
+        .and_then(|name| FileKind::of(name.to_str()?));
+    match kind {
+        Some((FileKind::Wal | FileKind::Format | FileKind::CompactionAction, _))
+        // The migrated format file, the only temporary file without a number.
+        | Some((FileKind::Temporary, None)) => &LocalStorage,
+        _ => storage,
+    }
+}
 
-        std::fs::remove_file(&flush_wal_path)?;
+// Reads the entries of a WAL one by one, without reading the whole file to
+// memory.
+// Stops at the first record that is cut short, like a record that was only
+// partially written before a crash, and at the first record that doesn't match
+// its CRC or can't be decoded (which is logged, as the writes after it are
+// lost), so only the writes before it are replayed.
+struct WalReader {
+    reader: StreamReader,
+    buf: Vec<u8>,
+    eof: bool,
+    config: BincodeConfig,
+    path: PathBuf,
+    // The offset in the WAL of the start of buf.
+    offset: u64,
+}
 
-        Ok(())
+impl WalReader {
+    async fn open(
+        wal_path: &PathBuf,
+        config: BincodeConfig,
+    ) -> std::io::Result<Self> {
+        let wal_file = BufferedFile::open(wal_path).await?;
+        Ok(Self {
+            reader: StreamReaderBuilder::new(wal_file).build(),
+            buf: Vec::new(),
+            eof: false,
+            config,
+            path: wal_path.clone(),
+            offset: 0,
+        })
     }
 
-    async fn flush_memtable_to_disk(
-        memtable: &RedBlackTree<String, String>,
-        data_file: DmaFile,
-        index_file: DmaFile,
-    ) -> glommio::Result<(), ()> {
-        let mut data_write_stream = DmaStreamWriterBuilder::new(data_file)
-            .with_write_behind(10)
-            .with_buffer_size(512)
-            .build();
-        let mut index_write_stream = DmaStreamWriterBuilder::new(index_file)
-            .with_write_behind(10)
-            .with_buffer_size(512)
-            .build();
-
-        for (key, value) in memtable.iter() {
-            let entry_offset = data_write_stream.current_pos();
-            let entry = Entry {
-                key: key.to_string(),
-                value: value.to_string(),
-            };
-            let entry_encoded = bincode_options().serialize(&entry).unwrap();
-            let entry_size = entry_encoded.len();
-            data_write_stream.write_all(&entry_encoded).await?;
+    async fn next(&mut self) -> std::io::Result<Option<Entry>> {
+        loop {
+            if let Some(record) = self.buf.get(..WAL_RECORD_HEADER_SIZE) {
+                let size = u32::from_le_bytes(record[..4].try_into().unwrap())
+                    as usize;
+                let crc = u32::from_le_bytes(record[4..].try_into().unwrap());
+                if let Some(entry_encoded) = self
+                    .buf
+                    .get(WAL_RECORD_HEADER_SIZE..WAL_RECORD_HEADER_SIZE + size)
+                {
+                    if crc32(entry_encoded) != crc {
+                        self.log_bad_record("doesn't match its CRC");
+                        return Ok(None);
+                    }
+                    let Ok(entry) = self.config.deserialize(entry_encoded)
+                    else {
+                        self.log_bad_record("can't be decoded");
+                        return Ok(None);
+                    };
+                    self.buf.drain(..WAL_RECORD_HEADER_SIZE + size);
+                    self.offset += (WAL_RECORD_HEADER_SIZE + size) as u64;
+                    return Ok(Some(entry));
+                }
+            }
+            if self.eof {
+                return Ok(None);
+            }
 
-            let entry_index = EntryOffset {
-                entry_offset,
-                entry_size,
-            };
-            let index_encoded =
-                bincode_options().serialize(&entry_index).unwrap();
-            index_write_stream.write_all(&index_encoded).await?;
+            let start = self.buf.len();
+            self.buf.resize(start + WAL_READ_SIZE, 0);
+            let read = self.reader.read(&mut self.buf[start..]).await?;
+            self.buf.truncate(start + read);
+            self.eof = read == 0;
         }
-        data_write_stream.close().await?;
-        index_write_stream.close().await?;
+    }
+
+    fn log_bad_record(&self, reason: &str) {
+        eprintln!(
+            "WAL record at offset {} of '{}' {}, ignoring the rest of the WAL",
+            self.offset,
+            self.path.display(),
+            reason
+        );
+    }
 
+    async fn close(self) -> std::io::Result<()> {
+        self.reader.close().await?;
         Ok(())
     }
+}
 
-    // Compact all sstables in the given list of sstable files, write the result
-    // to the output file given.
-    pub async fn compact(
-        &mut self,
-        indices_to_compact: Vec<usize>,
-        output_index: usize,
-    ) -> std::io::Result<()> {
-        let sstable_paths: Vec<(PathBuf, PathBuf)> = indices_to_compact
-            .iter()
-            .map(|i| Self::get_data_file_paths(self.dir.clone(), *i))
-            .collect();
+// Bumped on every change to the layout of files.
+const FORMAT_VERSION: u32 = 13;
 
-        // No stable AsyncIterator yet...
-        // If there was, itertools::kmerge would probably solve it all.
-        let mut sstable_readers = Vec::with_capacity(sstable_paths.len());
-        for (data_path, index_path) in &sstable_paths {
-            let data_file = BufferedFile::open(data_path).await?;
-            let index_file = BufferedFile::open(index_path).await?;
-            let data_reader = StreamReaderBuilder::new(data_file).build();
-            let index_reader = StreamReaderBuilder::new(index_file).build();
-            sstable_readers.push((data_reader, index_reader));
-        }
-
-        let (compact_data_path, compact_index_path) =
-            Self::get_compaction_file_paths(self.dir.clone(), output_index);
-        let compact_data_file =
-            BufferedFile::create(&compact_data_path).await?;
-        let compact_index_file =
-            BufferedFile::create(&compact_index_path).await?;
-        let mut compact_data_writer =
-            StreamWriterBuilder::new(compact_data_file).build();
-        let mut compact_index_writer =
-            StreamWriterBuilder::new(compact_index_file).build();
+// Written to the format file of a directory, with the options that change the
+// layout of files, which a directory must be reopened with.
+// Other options (like LSMTreeOptions::with_index_cache_budget) can change
+// across reopens, as can LSMTreeOptions::with_key_prefix_compression and
+// LSMTreeOptions::with_value_log_threshold, as every sstable records how its
+// entries are written.
+#[derive(Serialize, Deserialize)]
+struct Format {
+    version: u32,
+    bincode_config: BincodeConfig,
+    fixed_key_size: Option<usize>,
+}
 
-        let item_size = bincode_options()
-            .serialized_size(&EntryOffset::default())
-            .unwrap();
+// Written at the start of every index file, before the index records.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+struct IndexHeader {
+    version: u32,
+    entries: u64,
+    // Also the number of entries for now, as an sstable holds a single
+    // version of each key.
+    keys: u64,
+    // The largest sequence number of the entries, 0 when there are none.
+    max_seq: u64,
+    // The latest write timestamp of the entries, 0 when there are none, see
+    // LSMTreeOptions::with_tombstone_grace_period.
+    max_timestamp: u64,
+    // When not 0, keys are prefix compressed in the data file: every entry
+    // stores only the part of its key after the prefix it shares with the key
+    // of the previous entry, except every restart_interval-th entry (a
+    // restart point), which stores its key whole.
+    // An entry is then decoded by decoding the entries from the restart point
+    // before it.
+    restart_interval: u64,
+    // When the entries of the sstable were first written to an sstable, in
+    // nanoseconds since the unix epoch: the time of the flush that wrote it,
+    // or the creation time of the oldest input of the compaction that wrote
+    // it, so that the age of an sstable is the age of its oldest data, and
+    // compacting old sstables together keeps them old, see
+    // LSMTree::compact_older_than.
+    created_at: u64,
+    // The number of entries that are tombstones, see
+    // LSMTreeOptions::with_tombstone_rewrite_threshold.
+    tombstones: u64,
+    // Whether the sstable was written by a tombstone rewrite, which is not
+    // rewritten alone again, even after a reopen, see
+    // LSMTree::pick_tombstone_rewrite.
+    tombstone_rewrite: bool,
+}
 
-        let mut offset_bytes = vec![0; item_size as usize];
-        let mut heap = BinaryHeap::new();
+impl IndexHeader {
+    fn size() -> u64 {
+        bincode_options()
+            .serialized_size(&IndexHeader::default())
+            .unwrap()
+    }
 
-        for (index, (data_reader, index_reader)) in
-            sstable_readers.iter_mut().enumerate()
-        {
-            let entry_result = Self::read_next_entry(
-                data_reader,
-                index_reader,
-                &mut offset_bytes,
-            )
-            .await;
-            if let Ok(entry) = entry_result {
-                heap.push(CompactionItem { entry, index });
-            }
+    fn encode(&self) -> Vec<u8> {
+        bincode_options().serialize(self).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let header: IndexHeader =
+            bincode_options().deserialize(bytes).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("index header is malformed: {}", e),
+                )
+            })?;
+        if header.version != FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "index is of format version {}, expected {}",
+                    header.version, FORMAT_VERSION
+                ),
+            ));
         }
+        Ok(header)
+    }
 
-        let mut entry_offset = 0u64;
+    async fn read(index_file: &impl AsyncFile) -> std::io::Result<Self> {
+        let bytes = index_file.read_at(0, Self::size() as usize).await?;
+        Self::decode(&bytes)
+    }
 
-        while let Some(next) = heap.pop() {
-            let index = next.index;
+    async fn read_from_path(
+        storage: &dyn Storage,
+        index_path: &Path,
+    ) -> std::io::Result<Self> {
+        let index_file = storage.open(index_path).await?;
+        let header = Self::read(&index_file).await;
+        index_file.close().await?;
+        header
+    }
+}
 
-            let next_data_encoded =
-                bincode_options().serialize(&next.entry).unwrap();
-            let entry_size = next_data_encoded.len();
-            let entry_index = EntryOffset {
-                entry_offset,
-                entry_size,
-            };
-            entry_offset += entry_size as u64;
-            let next_index_encoded =
-                bincode_options().serialize(&entry_index).unwrap();
+// The offset of an index record in an index file.
+fn index_item_offset(position: u64, fixed_key_size: Option<usize>) -> u64 {
+    IndexHeader::size() + position * index_item_size(fixed_key_size)
+}
 
-            compact_data_writer.write(&next_data_encoded).await?;
-            compact_index_writer.write(&next_index_encoded).await?;
+fn without_seq_offsets(
+    sstable_paths: &[(PathBuf, PathBuf)],
+) -> Vec<(PathBuf, PathBuf, u64)> {
+    sstable_paths
+        .iter()
+        .map(|(data_path, index_path)| {
+            (data_path.clone(), index_path.clone(), 0)
+        })
+        .collect()
+}
 
-            let (data_reader, index_reader): &mut (StreamReader, StreamReader) =
-                sstable_readers.get_mut(index).unwrap();
+// The size of a single record in an index file.
+// By default a record is only an `EntryOffset`, but when keys are fixed in
+// size, the key is stored inline right after it.
+fn index_item_size(fixed_key_size: Option<usize>) -> u64 {
+    let offset_size = bincode_options()
+        .serialized_size(&EntryOffset::default())
+        .unwrap();
+    match fixed_key_size {
+        Some(key_size) => {
+            // A String is encoded as a u64 length followed by its bytes.
+            offset_size + std::mem::size_of::<u64>() as u64 + key_size as u64
+        }
+        None => offset_size,
+    }
+}
 
-            let entry_result = Self::read_next_entry(
-                data_reader,
-                index_reader,
-                &mut offset_bytes,
-            )
-            .await;
-            if let Ok(entry) = entry_result {
-                heap.push(CompactionItem { entry, index });
-            }
+fn encode_index_item(
+    entry_offset: &EntryOffset,
+    key: &String,
+    fixed_key_size: Option<usize>,
+) -> Vec<u8> {
+    match fixed_key_size {
+        Some(_) => bincode_options().serialize(&(entry_offset, key)),
+        None => bincode_options().serialize(entry_offset),
+    }
+    .unwrap()
+}
+
+fn decode_index_item(
+    bytes: &[u8],
+    fixed_key_size: Option<usize>,
+) -> std::io::Result<(EntryOffset, Option<String>)> {
+    let malformed = |e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("index item is malformed: {}", e),
+        )
+    };
+    Ok(match fixed_key_size {
+        Some(_) => {
+            let (entry_offset, key) = bincode_options()
+                .deserialize::<(EntryOffset, String)>(bytes)
+                .map_err(malformed)?;
+            (entry_offset, Some(key))
         }
+        None => (
+            bincode_options().deserialize(bytes).map_err(malformed)?,
+            None,
+        ),
+    })
+}
 
-        compact_data_writer.close().await?;
-        compact_index_writer.close().await?;
+fn decode_entry(bytes: &[u8], config: BincodeConfig) -> std::io::Result<Entry> {
+    config.deserialize(bytes).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("entry is malformed: {}", e),
+        )
+    })
+}
 
-        let mut files_to_delete = Vec::with_capacity(sstable_paths.len() * 2);
-        for (data_path, index_path) in sstable_paths {
-            files_to_delete.push(data_path);
-            files_to_delete.push(index_path);
+// The length of the longest common prefix of two keys, that ends on a char
+// boundary.
+fn shared_prefix_len(a: &str, b: &str) -> usize {
+    let mut shared =
+        a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+    while !b.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    shared
+}
+
+// Encodes the entries of an sstable in the order they are written, see
+// IndexHeader::restart_interval.
+struct EntryEncoder {
+    config: BincodeConfig,
+    restart_interval: u64,
+    position: u64,
+    previous_key: String,
+}
+
+impl EntryEncoder {
+    fn new(config: BincodeConfig, restart_interval: u64) -> Self {
+        Self {
+            config,
+            restart_interval,
+            position: 0,
+            previous_key: String::new(),
         }
+    }
 
-        let (output_data_path, output_index_path) =
-            Self::get_data_file_paths(self.dir.clone(), output_index);
+    fn encode(&mut self, entry: &Entry) -> Vec<u8> {
+        if self.restart_interval == 0 {
+            return self.config.serialize(entry);
+        }
 
-        let action = CompactionAction {
-            renames: vec![
-                (compact_data_path, output_data_path),
-                (compact_index_path, output_index_path),
-            ],
-            deletes: files_to_delete,
+        let shared = if self.position.is_multiple_of(self.restart_interval) {
+            0
+        } else {
+            shared_prefix_len(&self.previous_key, &entry.key)
         };
-        let action_encoded = bincode_options().serialize(&action).unwrap();
-
-        let mut compact_action_path = self.dir.clone();
-        compact_action_path.push(format!(
-            "{:01$}.compact_action",
-            output_index, INDEX_PADDING
+        // Encoded like an Entry, with the length of the shared prefix first.
+        let encoded = self.config.serialize(&(
+            shared as u32,
+            &entry.key[shared..],
+            &entry.value,
+            entry.seq,
+            entry.timestamp,
         ));
-        let compact_action_file =
-            BufferedFile::create(&compact_action_path).await?;
-        let mut compact_action_writer =
-            StreamWriterBuilder::new(compact_action_file).build();
-        compact_action_writer.write_all(&action_encoded).await?;
-        compact_action_writer.close().await?;
+        self.position += 1;
+        self.previous_key.clone_from(&entry.key);
+        encoded
+    }
+}
 
-        let counter = self.number_of_sstable_reads.clone();
-        self.number_of_sstable_reads = Rc::new(PhantomData::<usize>);
+// Decodes the entries of an sstable in order, from a restart point, see
+// IndexHeader::restart_interval.
+struct EntryDecoder {
+    config: BincodeConfig,
+    restart_interval: u64,
+    previous_key: String,
+}
 
-        self.read_sstable_indices
-            .retain(|x| !indices_to_compact.contains(x));
-        self.read_sstable_indices.push(output_index);
+impl EntryDecoder {
+    fn new(config: BincodeConfig, restart_interval: u64) -> Self {
+        Self {
+            config,
+            restart_interval,
+            previous_key: String::new(),
+        }
+    }
 
-        for (source_path, destination_path) in &action.renames {
-            std::fs::rename(source_path, destination_path)?;
+    fn decode(&mut self, bytes: &[u8]) -> std::io::Result<Entry> {
+        if self.restart_interval == 0 {
+            return decode_entry(bytes, self.config);
         }
 
-        // Block the current execution task until all currently running read
-        // tasks finish, to make sure we don't delete files that are being read.
-        while Rc::strong_count(&counter) > 1 {
-            futures_lite::future::yield_now().await;
+        let malformed = |e: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("entry is malformed: {}", e),
+            )
+        };
+        let (shared, suffix, value, seq, timestamp): (
+            u32,
+            String,
+            Value,
+            u64,
+            u64,
+        ) = self
+            .config
+            .deserialize(bytes)
+            .map_err(|e| malformed(e.to_string()))?;
+        let shared = shared as usize;
+        let Some(prefix) = self.previous_key.get(..shared) else {
+            return Err(malformed(format!(
+                "shares {} bytes with the previous key",
+                shared
+            )));
+        };
+        let key = prefix.to_string() + &suffix;
+        self.previous_key.clone_from(&key);
+        Ok(Entry {
+            key,
+            value,
+            seq,
+            timestamp,
+        })
+    }
+}
+
+// Reads the entries of an sstable in order, from streams of its data and index
+// files, see LSMTree::open_sstable_readers.
+struct SstableReader {
+    data_reader: FileReader,
+    index_reader: FileReader,
+    decoder: EntryDecoder,
+    // The entries left to read, by the header of the sstable, so that its end
+    // is not mistaken for a failed read, or the other way around.
+    remaining: u64,
+}
+
+impl SstableReader {
+    // The next entry, None once all entries of the sstable were read. A read
+    // that fails, or a malformed entry, is an error, so that no entry is ever
+    // skipped silently.
+    async fn next_entry(
+        &mut self,
+        offset_bytes: &mut [u8],
+        data_bytes: &mut Vec<u8>,
+        fixed_key_size: Option<usize>,
+    ) -> std::io::Result<Option<Entry>> {
+        if self.remaining == 0 {
+            return Ok(None);
         }
+        let entry = LSMTree::read_next_entry(
+            &mut self.data_reader,
+            &mut self.index_reader,
+            offset_bytes,
+            data_bytes,
+            fixed_key_size,
+            &mut self.decoder,
+        )
+        .await?;
+        self.remaining -= 1;
+        Ok(Some(entry))
+    }
 
-        for path_to_delete in &action.deletes {
-            if path_to_delete.exists() {
-                Self::remove_file_log_on_err(path_to_delete);
+    async fn close(self) -> std::io::Result<()> {
+        self.data_reader.close().await?;
+        self.index_reader.close().await
+    }
+}
+
+async fn read_entry(
+    data_file: &impl AsyncFile,
+    entry_offset: &EntryOffset,
+    config: BincodeConfig,
+) -> glommio::Result<Entry, ()> {
+    let bytes = data_file
+        .read_at(entry_offset.entry_offset, entry_offset.entry_size)
+        .await?;
+    Ok(decode_entry(&bytes, config)?)
+}
+
+// The entries at positions [start, end) of an sstable, decoded in order from a
+// single read of the data file. When keys are prefix compressed, start must be
+// a restart point.
+async fn read_entries<F: AsyncFile>(
+    data_file: &impl AsyncFile,
+    index: &IndexSource<F>,
+    (start, end): (u64, u64),
+    restart_interval: u64,
+    fixed_key_size: Option<usize>,
+    config: BincodeConfig,
+) -> glommio::Result<Vec<Entry>, ()> {
+    let mut offsets = Vec::with_capacity(end.saturating_sub(start) as usize);
+    for position in start..end {
+        offsets.push(index.read_item(position, fixed_key_size).await?.0);
+    }
+    let (Some(first), Some(last)) = (offsets.first(), offsets.last()) else {
+        return Ok(Vec::new());
+    };
+    let data_start = first.entry_offset;
+    let data_end = last.entry_offset + last.entry_size as u64;
+    let bytes = data_file
+        .read_at(data_start, data_end.saturating_sub(data_start) as usize)
+        .await?;
+
+    let mut decoder = EntryDecoder::new(config, restart_interval);
+    let mut entries = Vec::with_capacity(offsets.len());
+    for entry_offset in &offsets {
+        let start = entry_offset.entry_offset.saturating_sub(data_start);
+        let end = start + entry_offset.entry_size as u64;
+        let Some(entry_bytes) = bytes.get(start as usize..end as usize) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "entry at offset {} is out of the data file",
+                    entry_offset.entry_offset
+                ),
+            )
+            .into());
+        };
+        entries.push(decoder.decode(entry_bytes)?);
+    }
+    Ok(entries)
+}
+
+// The entry at a position of an sstable, decoding the entries from the restart
+// point before it when keys are prefix compressed.
+async fn read_entry_at<F: AsyncFile>(
+    data_file: &impl AsyncFile,
+    index: &IndexSource<F>,
+    position: u64,
+    restart_interval: u64,
+    fixed_key_size: Option<usize>,
+    config: BincodeConfig,
+) -> glommio::Result<Entry, ()> {
+    if restart_interval == 0 {
+        let (entry_offset, _) =
+            index.read_item(position, fixed_key_size).await?;
+        return read_entry(data_file, &entry_offset, config).await;
+    }
+
+    let restart_point = position - position % restart_interval;
+    let mut entries = read_entries(
+        data_file,
+        index,
+        (restart_point, position + 1),
+        restart_interval,
+        fixed_key_size,
+        config,
+    )
+    .await?;
+    Ok(entries.pop().unwrap())
+}
+
+// Where binary_search reads the index items of an sstable from.
+enum IndexSource<F: AsyncFile = StorageFile> {
+    File(F),
+    // The whole index file, from the index cache.
+    Cached(Rc<Vec<u8>>),
+}
+
+impl<F: AsyncFile> IndexSource<F> {
+    // Reads the index file in blocks of the given size, see BlockReader.
+    fn in_blocks<'a>(
+        &'a self,
+        block_size: Option<u64>,
+        counts: &'a ReadCounts,
+    ) -> IndexSource<BlockReader<'a, F>> {
+        match self {
+            IndexSource::File(file) => {
+                IndexSource::File(BlockReader::new(file, block_size, counts))
             }
+            IndexSource::Cached(bytes) => IndexSource::Cached(bytes.clone()),
         }
+    }
 
-        Self::remove_file_log_on_err(&compact_action_path);
+    async fn header(&self) -> std::io::Result<IndexHeader> {
+        match self {
+            IndexSource::File(file) => IndexHeader::read(file).await,
+            IndexSource::Cached(bytes) => {
+                IndexHeader::decode(&bytes[..IndexHeader::size() as usize])
+            }
+        }
+    }
 
-        Ok(())
+    async fn read_item(
+        &self,
+        position: u64,
+        fixed_key_size: Option<usize>,
+    ) -> glommio::Result<(EntryOffset, Option<String>), ()> {
+        let item_size = index_item_size(fixed_key_size);
+        let offset = index_item_offset(position, fixed_key_size);
+        Ok(match self {
+            IndexSource::File(file) => decode_index_item(
+                &file.read_at(offset, item_size as usize).await?,
+                fixed_key_size,
+            )?,
+            IndexSource::Cached(bytes) => decode_index_item(
+                &bytes[offset as usize..(offset + item_size) as usize],
+                fixed_key_size,
+            )?,
+        })
     }
+}
 
-    async fn read_next_entry(
-        data_reader: &mut StreamReader,
-        index_reader: &mut StreamReader,
-        offset_bytes: &mut Vec<u8>,
-    ) -> std::io::Result<Entry> {
-        index_reader.read_exact(offset_bytes).await?;
-        let entry_offset: EntryOffset =
-            bincode_options().deserialize(&offset_bytes).unwrap();
-        let mut data_bytes = vec![0; entry_offset.entry_size];
-        data_reader.read_exact(&mut data_bytes).await?;
-        let entry: Entry = bincode_options().deserialize(&data_bytes).unwrap();
-        Ok(entry)
+// The files are read in blocks of the given size, keeping the last block of
+// each file for the lookup, and the reads from disk are added to the counts.
+async fn binary_search<F: AsyncFile>(
+    data_file: &impl AsyncFile,
+    index: &IndexSource<F>,
+    key: &String,
+    fixed_key_size: Option<usize>,
+    config: BincodeConfig,
+    block_size: Option<u64>,
+    counts: &ReadCounts,
+) -> glommio::Result<Option<Entry>, ()> {
+    let (_, entry) = binary_search_position(
+        data_file,
+        index,
+        key,
+        fixed_key_size,
+        config,
+        block_size,
+        counts,
+    )
+    .await?;
+    Ok(entry)
+}
+
+// Same as binary_search, but also returns the position in the index file of the
+// first entry with a key that is not less than the given key (the newest
+// version of the key when it's found), or the number of entries if there is
+// none, to start reading from it, like lower_bound.
+async fn binary_search_position<F: AsyncFile>(
+    data_file: &impl AsyncFile,
+    index: &IndexSource<F>,
+    key: &String,
+    fixed_key_size: Option<usize>,
+    config: BincodeConfig,
+    block_size: Option<u64>,
+    counts: &ReadCounts,
+) -> glommio::Result<(u64, Option<Entry>), ()> {
+    let data_file = &BlockReader::new(data_file, block_size, counts);
+    let index = index.in_blocks(block_size, counts);
+
+    let header = index.header().await?;
+    let length = header.entries;
+    if length == 0 {
+        return Ok((0, None));
+    }
+    // With prefix compressed keys, only the keys of restart points are known
+    // without decoding the entries before them, so the restart points are
+    // binary searched instead, and the block of entries the key is in is
+    // scanned.
+    if header.restart_interval > 0 && fixed_key_size.is_none() {
+        let position =
+            lower_bound(data_file, &index, key, fixed_key_size, config).await?;
+        if position == length {
+            return Ok((position, None));
+        }
+        let entry = read_entry_at(
+            data_file,
+            &index,
+            position,
+            header.restart_interval,
+            fixed_key_size,
+            config,
+        )
+        .await?;
+        return Ok((position, (entry.key == *key).then_some(entry)));
     }
 
-    fn remove_file_log_on_err(file_path: &PathBuf) {
-        if let Err(e) = std::fs::remove_file(file_path) {
-            eprintln!(
-                "Failed to remove file '{}', that is irrelevant after \
-                 compaction: {}",
-                file_path.display(),
-                e
-            );
+    // Versions of a key are ordered from the newest, so when an sstable holds
+    // more than one version of a key, the search continues to the first one.
+    let mut found = None;
+
+    // The key is searched in [lind, hind), so that no bound is ever moved
+    // below the first entry.
+    let mut lind = 0;
+    let mut hind = length;
+
+    while lind < hind {
+        let half = lind + (hind - lind) / 2;
+        // When the key is stored inline in the index, there is no need to
+        // read from the data file until the key is found.
+        let (entry_offset, index_key) =
+            index.read_item(half, fixed_key_size).await?;
+        let (current_key, entry) = match index_key {
+            Some(index_key) => (index_key, None),
+            None => {
+                let entry =
+                    read_entry(data_file, &entry_offset, config).await?;
+                (entry.key.clone(), Some(entry))
+            }
+        };
+
+        match current_key.cmp(key) {
+            std::cmp::Ordering::Equal => {
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => {
+                        read_entry_at(
+                            data_file,
+                            &index,
+                            half,
+                            header.restart_interval,
+                            fixed_key_size,
+                            config,
+                        )
+                        .await?
+                    }
+                };
+                if header.keys == header.entries || half == 0 {
+                    return Ok((half, Some(entry)));
+                }
+                found = Some(entry);
+                hind = half;
+            }
+            std::cmp::Ordering::Less => lind = half + 1,
+            std::cmp::Ordering::Greater => hind = half,
+        }
+    }
+
+    // The entries before lind are less than the key, and the ones from it are
+    // not.
+    Ok((lind, found))
+}
+
+// Returns the position in the index file of the first entry with a key that is
+// not less than the given key, or the number of entries if there is none.
+async fn lower_bound<F: AsyncFile>(
+    data_file: &impl AsyncFile,
+    index: &IndexSource<F>,
+    key: &String,
+    fixed_key_size: Option<usize>,
+    config: BincodeConfig,
+) -> glommio::Result<u64, ()> {
+    let header = index.header().await?;
+    let restart_interval = match fixed_key_size {
+        // The keys in the index are whole.
+        Some(_) => 0,
+        None => header.restart_interval,
+    };
+    // Every entry is a restart point when keys are not prefix compressed.
+    let step = restart_interval.max(1);
+    let mut lind = 0;
+    let mut hind = header.entries.div_ceil(step);
+
+    // The first restart point whose key is not less than the given key.
+    while lind < hind {
+        let half = (lind + hind) / 2;
+        let (entry_offset, index_key) =
+            index.read_item(half * step, fixed_key_size).await?;
+        let current_key = match index_key {
+            Some(index_key) => index_key,
+            None => {
+                // A restart point, its key is whole.
+                let bytes = data_file
+                    .read_at(entry_offset.entry_offset, entry_offset.entry_size)
+                    .await?;
+                EntryDecoder::new(config, restart_interval)
+                    .decode(&bytes)?
+                    .key
+            }
+        };
+        if current_key < *key {
+            lind = half + 1;
+        } else {
+            hind = half;
         }
     }
+    if restart_interval == 0 || lind == 0 {
+        return Ok(lind * step);
+    }
+
+    // The key is in the block before that restart point, or at it.
+    let start = (lind - 1) * step;
+    let end = (lind * step).min(header.entries);
+    let entries = read_entries(
+        data_file,
+        index,
+        (start, end),
+        restart_interval,
+        fixed_key_size,
+        config,
+    )
+    .await?;
+    Ok(start + entries.iter().take_while(|e| e.key < *key).count() as u64)
+}
+
+// Information about an sstable that is queried from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SstableInfo {
+    pub index: usize,
+    pub entries: u64,
+    pub data_size: u64,
+    // In nanoseconds since the unix epoch, see LSMTree::compact_older_than.
+    pub created_at: u64,
+    // The entries that are tombstones.
+    pub tombstones: u64,
+}
+
+// What compacting a set of sstables would read and write, see
+// LSMTree::compaction_plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionPlan {
+    pub indices: Vec<usize>,
+    // The bytes of the data and index files of the inputs.
+    pub input_bytes: u64,
+    pub input_entries: u64,
+    // Exact when the inputs were scanned, otherwise upper bounds, assuming no
+    // key is in more than one input.
+    pub output_entries: u64,
+    pub output_bytes: u64,
+    pub scanned: bool,
+}
+
+// Picks the sstables a compaction merges, for CompactionPolicy::Custom.
+// Given the sstables that are not being compacted already, sorted by index,
+// it returns the indices of the ones to compact, None for no compaction.
+pub trait CompactionPicker {
+    fn pick(&self, sstables: &[SstableInfo]) -> Option<Vec<usize>>;
+}
+
+// How LSMTree::run_compaction picks the sstables to compact.
+pub enum CompactionPolicy {
+    // The sstables of the largest group of sstables of about the same data
+    // size (up to 1.5 times the average of the group), when it has at least
+    // min_sstables_to_compact sstables (4 when the option is not set).
+    // Entries are then rewritten about once per size tier they go through,
+    // instead of on every compaction like with Full.
+    SizeTiered,
+    // The n oldest sstables, by their creation time.
+    OldestN(usize),
+    // All sstables.
+    Full,
+    Custom(Box<dyn CompactionPicker>),
+}
+
+// The number of sstables of about the same size needed for a
+// CompactionPolicy::SizeTiered compaction, when min_sstables_to_compact is not
+// set.
+const SIZE_TIERED_MIN_SSTABLES: usize = 4;
+
+impl CompactionPolicy {
+    fn pick(
+        &self,
+        sstables: &[SstableInfo],
+        min_sstables_to_compact: Option<usize>,
+    ) -> Option<Vec<usize>> {
+        let indices = |sstables: &[&SstableInfo]| {
+            sstables.iter().map(|sstable| sstable.index).collect()
+        };
+        match self {
+            CompactionPolicy::SizeTiered => {
+                let min_sstables = min_sstables_to_compact
+                    .unwrap_or(SIZE_TIERED_MIN_SSTABLES)
+                    .max(2);
+                let mut by_size: Vec<&SstableInfo> = sstables.iter().collect();
+                by_size
+                    .sort_by_key(|sstable| (sstable.data_size, sstable.index));
+                // Sorted by size, so an sstable is never smaller than the
+                // average of the group before it.
+                let mut groups: Vec<(Vec<&SstableInfo>, u64)> = Vec::new();
+                for sstable in by_size {
+                    match groups.last_mut() {
+                        Some((group, total))
+                            if sstable.data_size as f64
+                                <= *total as f64 / group.len() as f64 * 1.5 =>
+                        {
+                            group.push(sstable);
+                            *total += sstable.data_size;
+                        }
+                        _ => groups.push((vec![sstable], sstable.data_size)),
+                    }
+                }
+                // The first of the largest groups, the one of the smallest
+                // sstables, is the cheapest to compact.
+                let (group, _) = groups
+                    .iter()
+                    .filter(|(group, _)| group.len() >= min_sstables)
+                    .rev()
+                    .max_by_key(|(group, _)| group.len())?;
+                let mut picked: Vec<usize> = indices(group);
+                picked.sort();
+                Some(picked)
+            }
+            CompactionPolicy::OldestN(n) => {
+                let mut by_age: Vec<&SstableInfo> = sstables.iter().collect();
+                by_age
+                    .sort_by_key(|sstable| (sstable.created_at, sstable.index));
+                if *n < 2 || by_age.len() < *n {
+                    return None;
+                }
+                let mut picked: Vec<usize> = indices(&by_age[..*n]);
+                picked.sort();
+                Some(picked)
+            }
+            CompactionPolicy::Full => (sstables.len() >= 2).then(|| {
+                sstables.iter().map(|sstable| sstable.index).collect()
+            }),
+            CompactionPolicy::Custom(picker) => picker.pick(sstables),
+        }
+    }
+}
+
+// What a compaction of LSMTree::run_compaction did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionResult {
+    pub inputs: Vec<usize>,
+    pub output: usize,
+    // The bytes of the data and index files of the inputs, and the bytes of
+    // the files of the output.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+// The sizes of the values in the sstables, see LSMTree::value_size_histogram.
+// buckets[0] counts the empty values, and buckets[i] the values of
+// [2^(i - 1), 2^i) bytes, up to the bucket of the largest value.
+// Only every sample_every'th entry is counted, so the number of values in a
+// bucket is about buckets[i] * sample_every.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueSizeHistogram {
+    pub sample_every: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl ValueSizeHistogram {
+    fn insert(&mut self, size: u64) {
+        let bucket = (u64::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+}
+
+// The tree whose versions of a key are newer than the versions of the other
+// tree, when merging trees with LSMTree::merge_from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeWinner {
+    Current,
+    Other,
+}
+
+// Where a value returned by get_with_source was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    ActiveMemtable,
+    FlushMemtable,
+    // The index of the sstable the value was read from.
+    Sstable(usize),
+    NotFound,
+}
+
+#[derive(Default)]
+struct IndexCache {
+    // The index file of each cached sstable, with the tick it was last used.
+    indices: HashMap<usize, (Rc<Vec<u8>>, u64)>,
+    bytes: usize,
+    tick: u64,
+    // The sstables whose index file is never evicted, see
+    // LSMTree::pin_sstable.
+    pinned: HashSet<usize>,
+}
+
+impl IndexCache {
+    fn get(&mut self, index: usize) -> Option<Rc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.indices.get_mut(&index).map(|(bytes, last_used)| {
+            *last_used = tick;
+            bytes.clone()
+        })
+    }
+
+    // Pinned index files are inserted even when they don't fit in the budget
+    // with all other index files evicted, others are then not inserted.
+    fn insert(&mut self, index: usize, bytes: Rc<Vec<u8>>, budget: usize) {
+        while self.bytes + bytes.len() > budget {
+            let Some(lru) = self
+                .indices
+                .iter()
+                .filter(|(index, _)| !self.pinned.contains(index))
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(index, _)| *index)
+            else {
+                if self.pinned.contains(&index) {
+                    break;
+                }
+                return;
+            };
+            self.remove(lru);
+        }
+        self.tick += 1;
+        self.bytes += bytes.len();
+        self.indices.insert(index, (bytes, self.tick));
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some((bytes, _)) = self.indices.remove(&index) {
+            self.bytes -= bytes.len();
+        }
+    }
+
+    // Unpins the sstable too.
+    fn retire(&mut self, index: usize) {
+        self.pinned.remove(&index);
+        self.remove(index);
+    }
+}
+
+// Counters of the work done by the tree since it was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    // The number of times the files of an sstable were opened to search it.
+    pub sstable_opens: u64,
+    // The number of times an sstable was not searched, because its filter
+    // showed it holds none of the keys queried.
+    pub sstables_skipped_by_filter: u64,
+    // The number of writes that had to wait for a flush before returning,
+    // either for the flush they triggered by filling the active memtable, or
+    // for a previous flush that was still running.
+    pub write_stalls: u64,
+    // The total time writes spent waiting in those stalls.
+    pub write_stall_duration: Duration,
+    // The bytes of the keys and values of all writes.
+    pub bytes_set: u64,
+    // The bytes written to the WAL, to the sstables written by flushes, and to
+    // the sstables written by compactions.
+    pub wal_bytes_written: u64,
+    pub flush_bytes_written: u64,
+    pub compaction_bytes_written: u64,
+    // The bytes read from disk by gets, and by compactions reading their
+    // inputs.
+    pub get_bytes_read: u64,
+    pub compaction_bytes_read: u64,
+    // The number of reads from disk by gets.
+    pub get_reads: u64,
+}
+
+// The write amplification is
+// (flush_bytes_written + compaction_bytes_written) / bytes_set,
+// adding wal_bytes_written to the written bytes to include the WAL.
+// The read amplification of gets is get_bytes_read divided by the bytes of the
+// values they returned, which are known only to the caller.
+
+// Options to tune the behaviour of an LSMTree, the defaults are used by
+// LSMTree::new.
+// Options that change the layout of files on disk must stay the same across
+// reopens of the same directory.
+#[derive(Clone, Debug, Default)]
+pub struct LSMTreeOptions {
+    fixed_key_size: Option<usize>,
+    idle_flush_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    index_cache_budget: usize,
+    bincode_config: BincodeConfig,
+    min_sstables_to_compact: Option<usize>,
+    versions_to_keep: usize,
+    value_log_threshold: Option<usize>,
+    sync_on_flush: bool,
+    wal_replay_threshold: Option<u64>,
+    read_block_size: Option<u64>,
+    corruption_policy: CorruptionPolicy,
+    restart_interval: u64,
+    wal_archive: Option<WalRetention>,
+    read_amplification_limit: Option<(usize, f64)>,
+    compaction_dir: Option<PathBuf>,
+    manual_flush_only: bool,
+    false_positive_rate: Option<f64>,
+    wal: Option<WalWrapper>,
+    tombstone_rewrite_threshold: Option<f64>,
+    tombstone_grace_period: Option<TombstoneGracePeriod>,
+    storage: SharedStorage,
+}
+
+impl LSMTreeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every key must be exactly this number of bytes, and is stored inline in
+    // the index files.
+    // A lookup then binary searches the index file alone, reading the data
+    // file only once for the matched entry, instead of once per probe.
+    // Useful for keys like UUIDs or hashes.
+    pub fn with_fixed_key_size(mut self, key_size: usize) -> Self {
+        self.fixed_key_size = Some(key_size);
+        self
+    }
+
+    // Flush the active memtable once no writes were made to it for this long,
+    // so a quiet tree doesn't keep its writes only in the WAL.
+    // The tree doesn't spawn a timer, the caller drives it by sleeping until
+    // idle_flush_deadline() and calling flush_if_idle().
+    // A capacity based flush empties the memtable, which resets the timer, so
+    // the idle flush only ever writes memtables that are not full.
+    pub fn with_idle_flush_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_flush_timeout = Some(timeout);
+        self
+    }
+
+    // Retry the file opens and reads of get, and the file creations of flush
+    // when they fail with a transient error.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // Keep whole index files in memory up to this number of bytes, evicting
+    // the least recently searched ones, so a get binary searches the index
+    // without reading it from disk.
+    // Disabled (0) by default.
+    pub fn with_index_cache_budget(mut self, bytes: usize) -> Self {
+        self.index_cache_budget = bytes;
+        self
+    }
+
+    // Read the files of an sstable in blocks of this number of bytes when
+    // searching it, aligned to the block size, instead of reading only the
+    // bytes of each probe.
+    // A search keeps the last block it read of each file, so the probes at
+    // the end of a binary search, which are close to each other, usually read
+    // nothing more from disk.
+    // Should be a multiple of the page size of the disk.
+    pub fn with_read_block_size(mut self, bytes: u64) -> Self {
+        self.read_block_size = Some(bytes);
+        self
+    }
+
+    // The directory remembers the config it was created with, and refuses to
+    // be opened with another.
+    pub fn with_bincode_config(
+        mut self,
+        bincode_config: BincodeConfig,
+    ) -> Self {
+        self.bincode_config = bincode_config;
+        self
+    }
+
+    // Compact all sstables together into one once there are at least this many
+    // (and at least 2), checked after every flush of a full memtable.
+    // A low count keeps reads fast, as a get searches less sstables, but
+    // rewrites the same entries many times as small sstables are merged again
+    // and again. A high count writes each entry less times, at the cost of
+    // reads searching more sstables between compactions.
+    // Disabled by default, see LSMTree::maybe_compact.
+    pub fn with_min_sstables_to_compact(mut self, count: usize) -> Self {
+        self.min_sstables_to_compact = Some(count);
+        self
+    }
+
+    // Compactions keep up to this many of the newest versions of every key
+    // (1 by default), older versions are read by LSMTree::get_version.
+    // Only versions that were flushed are kept, as a write replaces the
+    // version in the memtable.
+    // The sstables take up to this many times the space of a single version
+    // per key, and every version is rewritten by every compaction.
+    pub fn with_versions_to_keep(mut self, versions: usize) -> Self {
+        self.versions_to_keep = versions;
+        self
+    }
+
+    fn versions_to_keep(&self) -> usize {
+        self.versions_to_keep.max(1)
+    }
+
+    // Flushes write values of at least this many bytes to a value log next to
+    // the sstable, which only holds their position in the log, so compactions
+    // don't rewrite them.
+    // A value log is deleted once no live sstable points into it, values are
+    // never moved out of a value log, so it takes up space as long as one of
+    // its values is live.
+    // The memtables and the WAL always hold the values themselves, and so do
+    // the sstables written by a flush of a WAL on open.
+    pub fn with_value_log_threshold(mut self, bytes: usize) -> Self {
+        self.value_log_threshold = Some(bytes);
+        self
+    }
+
+    // Flushes fdatasync the files they wrote and the directory before removing
+    // the WAL of the flushed memtable, so that a flushed write survives a
+    // power loss, either in the sstable or in the WAL.
+    // Without it, a power loss right after a flush can lose the writes of the
+    // flushed memtable, as the page cache of the written files (and the
+    // directory entries) might not have reached the disk yet.
+    pub fn with_sync_on_flush(mut self, sync: bool) -> Self {
+        self.sync_on_flush = sync;
+        self
+    }
+
+    // On open, a WAL bigger than this many bytes is written straight to an
+    // sstable, the same way as the WAL of a flush that didn't finish, instead
+    // of being replayed to the memtable, so opening takes memory bounded by
+    // the size of a sorted run and not by the size of the WAL.
+    // Without it, a WAL with more distinct keys than the memtable can hold
+    // can't be opened.
+    pub fn with_wal_replay_threshold(mut self, bytes: u64) -> Self {
+        self.wal_replay_threshold = Some(bytes);
+        self
+    }
+
+    // Store the keys of the sstables written from now on prefix compressed,
+    // with a whole key every restart_interval entries, see
+    // IndexHeader::restart_interval.
+    // Saves the space of long common prefixes of adjacent keys
+    // (like "user:1000:name" and "user:1000:email"), while a lookup decodes
+    // up to a block of restart_interval entries.
+    // Each sstable records whether its keys are compressed, so this can be
+    // changed across reopens.
+    pub fn with_key_prefix_compression(
+        mut self,
+        restart_interval: u64,
+    ) -> Self {
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    // What get does when an sstable it searches is malformed, fails by
+    // default.
+    pub fn with_corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.corruption_policy = policy;
+        self
+    }
+
+    // Move the WAL of a flushed memtable into the archive subdirectory of the
+    // tree (see WAL_ARCHIVE_DIR_NAME) instead of removing it, keeping the
+    // history of all writes, to audit them or to replay them on top of a
+    // backup, see LSMTree::archived_wals.
+    // Every write then takes disk space for as long as its WAL is kept by
+    // the retention, on top of the space of the sstables, which is the size
+    // of all writes made in that time (not only the live keys), and more in
+    // a tree that overwrites the same keys often.
+    // The WALs of replace_with are removed, as its writes are discarded.
+    pub fn with_wal_archive(mut self, retention: WalRetention) -> Self {
+        self.wal_archive = Some(retention);
+        self
+    }
+
+    // Compact all sstables together (see LSMTree::maybe_compact) once the
+    // gets searched more than max_searched sstables on average, over the last
+    // window gets, even when there are less than min_sstables_to_compact.
+    // Gets of keys that are in neither the memtables nor the sstables search
+    // the most, as only the filters of the sstables skip them.
+    // The average of a window starts over after every compaction.
+    pub fn with_read_amplification_limit(
+        mut self,
+        window: usize,
+        max_searched: f64,
+    ) -> Self {
+        self.read_amplification_limit = Some((window.max(1), max_searched));
+        self
+    }
+
+    // Write the outputs of compactions to another directory (created when it
+    // doesn't exist), like a volume of cheaper storage for cold data, while
+    // flushes keep writing the recent sstables to the directory of the tree,
+    // with the WAL and the value logs.
+    // Compaction outputs are written and renamed in the compaction directory,
+    // so it can be on another file system.
+    // Must be set on every open of a tree that has sstables in it, otherwise
+    // they are not found.
+    pub fn with_compaction_dir(mut self, dir: PathBuf) -> Self {
+        self.compaction_dir = Some(dir);
+        self
+    }
+
+    // Never flush the active memtable when it fills up, only on calls to
+    // LSMTree::flush (and the other flushing methods), so that flushes happen
+    // at the same points on every run, like in benchmarks.
+    // Once the memtable is full, writes of keys that are not in it fail with
+    // an error (overwrites of keys that are in it still succeed) until it's
+    // flushed. No compaction runs after flushes either, see
+    // LSMTree::maybe_compact.
+    pub fn with_manual_flush_only(mut self, manual: bool) -> Self {
+        self.manual_flush_only = manual;
+        self
+    }
+
+    // The false positive rate the filters of sstables are sized for (1% by
+    // default), from the number of keys each sstable has: flushes know it
+    // from the memtable, compactions from the keys they wrote, once the merge
+    // is done.
+    // Lower rates skip more sstables on gets of keys they don't have, for
+    // about 4.8 more bits of filter per key for every 10 times lower rate.
+    // Every sstable keeps the size of its filter, so the rate can change
+    // across reopens, applying to the sstables written from then on.
+    pub fn with_false_positive_rate(mut self, rate: f64) -> Self {
+        self.false_positive_rate = Some(rate);
+        self
+    }
+
+    // Rewrite the oldest sstable alone once more than this fraction of its
+    // entries are tombstones, checked by LSMTree::maybe_compact, so that the
+    // space and the reads of the tombstones of a delete heavy workload are
+    // given back without compacting the other sstables.
+    // Only the oldest sstable is rewritten, as the versions a tombstone hides
+    // are in older sstables, and a rewrite keeps the tombstones of the keys
+    // another sstable might hold (see TombstonePolicy). An sstable written by
+    // such a rewrite is not rewritten alone again.
+    // Disabled by default.
+    pub fn with_tombstone_rewrite_threshold(mut self, fraction: f64) -> Self {
+        self.tombstone_rewrite_threshold = Some(fraction);
+        self
+    }
+
+    // Compactions keep a tombstone (and so the versions it hides from the
+    // merge) until it's older than the grace period, even when it could be
+    // dropped, so that readers lagging behind the tree, like a snapshot or a
+    // replica catching up, still see the delete instead of missing it.
+    // The tombstone rewrite of the oldest sstable (see
+    // with_tombstone_rewrite_threshold) waits until all of its entries are
+    // past the grace period.
+    // Tombstones are dropped as soon as possible by default.
+    pub fn with_tombstone_grace_period(
+        mut self,
+        grace_period: TombstoneGracePeriod,
+    ) -> Self {
+        self.tombstone_grace_period = Some(grace_period);
+        self
+    }
+
+    // Where the files of sstables and value logs are kept, the local disk (in
+    // the directory of the tree) by default. The WAL, the format file and the
+    // compaction actions are always in the directory of the tree on the
+    // local disk, see Storage.
+    // Must be the same on every open of a tree, like the compaction
+    // directory, otherwise the sstables are not found.
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = SharedStorage(Rc::new(storage));
+        self
+    }
+
+    fn storage(&self) -> &Rc<dyn Storage> {
+        &self.storage.0
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        self.false_positive_rate
+            .unwrap_or(DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    fn sstable_layout(&self) -> SstableLayout {
+        SstableLayout {
+            fixed_key_size: self.fixed_key_size,
+            restart_interval: self.restart_interval,
+            false_positive_rate: self.false_positive_rate(),
+        }
+    }
+
+    // A WAL wrapping the file WAL of the tree, called with every new WAL file:
+    // on open, and on every flush, which rotates the WAL. Used to ship the
+    // written entries elsewhere, like to replicas, see WriteAheadLog.
+    // The wrapper must append the entries to the FileWal too, as the entries
+    // of a crashed tree are recovered from it on open: a wrapper that ships
+    // the entries instead of writing them to the file loses the writes of
+    // the memtables on a crash.
+    pub fn with_wal(
+        mut self,
+        wrap: impl Fn(FileWal) -> Box<dyn WriteAheadLog> + 'static,
+    ) -> Self {
+        self.wal = Some(WalWrapper(Rc::new(wrap)));
+        self
+    }
+
+    fn wal(&self, file: BufferedFile, path: PathBuf) -> Box<dyn WriteAheadLog> {
+        let wal = FileWal::new(file, path);
+        match &self.wal {
+            Some(WalWrapper(wrap)) => wrap(wal),
+            None => Box::new(wal),
+        }
+    }
+}
+
+// The number of sstables searched by each of the last gets.
+#[derive(Default)]
+struct SearchWindow {
+    searched: VecDeque<u32>,
+    sum: u64,
+}
+
+impl SearchWindow {
+    fn record(&mut self, searched: u32, window: usize) {
+        if self.searched.len() == window {
+            self.sum -= self.searched.pop_front().unwrap() as u64;
+        }
+        self.searched.push_back(searched);
+        self.sum += searched as u64;
+    }
+
+    // None until the window is full.
+    fn average(&self, window: usize) -> Option<f64> {
+        (self.searched.len() == window).then(|| self.sum as f64 / window as f64)
+    }
+}
+
+// Which archived WALs are kept, checked every time a WAL is archived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalRetention {
+    All,
+    // The newest archived WALs, up to this many.
+    Last(usize),
+    // The WALs archived in this duration.
+    For(Duration),
+}
+
+// How long a tombstone is kept, see LSMTreeOptions::with_tombstone_grace_period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TombstoneGracePeriod {
+    // Until at least this many writes were made after it, by its sequence
+    // number.
+    Writes(u64),
+    // For this duration, by its write timestamp.
+    For(Duration),
+}
+
+// A write read from an archived WAL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedWrite {
+    pub key: String,
+    // None for a delete.
+    pub value: Option<String>,
+    pub seq: u64,
+    // In nanoseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+// What get does when an sstable it searches is malformed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    // The get fails with the error.
+    #[default]
+    Fail,
+    // The error is logged and the get continues to the older sstables,
+    // trading correctness for availability: when the newest version of the
+    // key is in the malformed sstable, an older version (or none) is returned.
+    SkipAndLog,
+}
+
+// How many times to try an IO operation that failed with a transient error
+// (interrupted, would block or timed out), sleeping between attempts for a
+// backoff that doubles after every attempt.
+// Other errors (like NotFound) are returned right away.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    // No retries.
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+fn io_error_kind(
+    error: &glommio::GlommioError<()>,
+) -> Option<std::io::ErrorKind> {
+    match error {
+        glommio::GlommioError::IoError(source) => Some(source.kind()),
+        glommio::GlommioError::EnhancedIoError { source, .. } => {
+            Some(source.kind())
+        }
+        _ => None,
+    }
+}
+
+fn is_transient_error(error: &glommio::GlommioError<()>) -> bool {
+    matches!(
+        io_error_kind(error),
+        Some(
+            std::io::ErrorKind::Interrupted
+                | std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::TimedOut
+        )
+    )
+}
+
+// Malformed bytes were read, from a file that was corrupted.
+fn is_corruption_error(error: &glommio::GlommioError<()>) -> bool {
+    io_error_kind(error) == Some(std::io::ErrorKind::InvalidData)
+}
+
+// The operation must be safe to run again after failing.
+async fn with_retries<T, F, Fut>(
+    retry_policy: &RetryPolicy,
+    mut operation: F,
+) -> glommio::Result<T, ()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = glommio::Result<T, ()>>,
+{
+    let mut backoff = retry_policy.backoff;
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e)
+                if attempt < retry_policy.max_attempts
+                    && is_transient_error(&e) =>
+            {
+                glommio::timer::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// The smallest key that is greater than all the keys starting with prefix,
+// None when there is no such key (a prefix of only char::MAX).
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_string();
+    while let Some(last) = end.pop() {
+        // Skips the surrogates, that are not chars.
+        let next =
+            (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+pub struct PrefixedLSMTree<'a> {
+    tree: &'a mut LSMTree,
+    prefix: String,
+}
+
+impl PrefixedLSMTree<'_> {
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn prefixed(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    pub async fn get(&self, key: &str) -> glommio::Result<Option<String>, ()> {
+        self.tree.get(&self.prefixed(key)).await
+    }
+
+    pub async fn set(
+        &mut self,
+        key: &str,
+        value: String,
+    ) -> glommio::Result<Option<String>, ()> {
+        let key = self.prefixed(key);
+        self.tree.set(key, value).await
+    }
+
+    // The keys (without the prefix) and values in [start, end).
+    pub async fn range(
+        &self,
+        start: &str,
+        end: &str,
+    ) -> std::io::Result<Vec<(String, String)>> {
+        self.collect(&self.prefixed(start), Some(&self.prefixed(end)))
+            .await
+    }
+
+    // The keys (without the prefix) starting with key_prefix, and their
+    // values.
+    pub async fn scan_prefix(
+        &self,
+        key_prefix: &str,
+    ) -> std::io::Result<Vec<(String, String)>> {
+        let start = self.prefixed(key_prefix);
+        let end = prefix_end(&start);
+        self.collect(&start, end.as_ref()).await
+    }
+
+    async fn collect(
+        &self,
+        start: &String,
+        end: Option<&String>,
+    ) -> std::io::Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        self.tree
+            .for_each_from(start, end, utf8_string, |(key, value)| {
+                pairs.push((key[self.prefix.len()..].to_string(), value));
+                std::future::ready(ControlFlow::Continue(()))
+            })
+            .await?;
+        Ok(pairs)
+    }
+}
+
+// There is no shutdown to call before dropping a tree: every write is written
+// to the WAL before it returns, so dropping the tree (or the process exiting
+// or crashing) loses no write that returned, and the next open replays the
+// WAL. A write that didn't return when the tree is dropped (its future was
+// dropped) may or may not be replayed.
+// Writes are in the page cache of the WAL, and not synced to the disk, so a
+// power loss can still lose the last writes.
+pub struct LSMTree {
+    dir: PathBuf,
+    // The memtable that is currently being written to.
+    active_memtable: RedBlackTree<String, MemtableValue>,
+    // The keys written to the active memtable, from the oldest write to the
+    // newest, bounded to the capacity of the active memtable.
+    recent_writes: VecDeque<String>,
+    // The memtable that is currently being flushed to disk.
+    flush_memtable: Option<RedBlackTree<String, MemtableValue>>,
+    // The next sstable index that is going to be written.
+    write_sstable_index: usize,
+    // The sstable indices to query from.
+    read_sstable_indices: Vec<usize>,
+    // The index header of each sstable that is queried from.
+    sstable_headers: HashMap<usize, IndexHeader>,
+    // The meta of each sstable that is queried from, sstables written before
+    // metas existed have none, and are always searched.
+    // Shared with the merges that check them for the keys of tombstones, see
+    // TombstonePolicy.
+    sstable_metas: HashMap<usize, Rc<SstableMeta>>,
+    // The sequence number of the next write.
+    next_seq: u64,
+    // The time of the last write to the active memtable, None when it's empty.
+    last_write: Option<Instant>,
+    // Track the reads happening from each sstable, by a counter per sstable
+    // that is cloned by every read.
+    // The reason for tracking is that when ending a compaction, there are
+    // sstable files that should be removed / replaced, but there could be
+    // reads to the same files concurrently, so the compaction process will
+    // wait for the number of reads of its inputs to reach 0, without waiting
+    // for reads of other sstables.
+    // Created on the first read of an sstable.
+    sstable_reads: RefCell<HashMap<usize, Rc<()>>>,
+    // Files retired by compactions that are still possibly read from.
+    pending_deletes: Vec<PendingDelete>,
+    // The inputs and outputs of the compactions that are running.
+    compacting: Rc<RefCell<HashSet<usize>>>,
+    // See acquire_range.
+    range_locks: RangeLocks,
+    // A cell, as it's updated by reads too.
+    stats: Cell<Stats>,
+    index_cache: RefCell<IndexCache>,
+    // See LSMTreeOptions::with_read_amplification_limit.
+    search_window: RefCell<SearchWindow>,
+    // The sstables in the compaction directory, see sstable_dir.
+    cold_sstables: HashSet<usize>,
+    // The id of the last value log created, value log ids are the time they
+    // were created at, so they don't collide with the value logs of another
+    // tree that are moved in by replace_with.
+    last_value_log: usize,
+    // The next memtable index.
+    memtable_index: usize,
+    // The memtable WAL for durability in case the process crashes without
+    // flushing the memtable to disk.
+    wal: Box<dyn WriteAheadLog>,
+    options: LSMTreeOptions,
+}
+
+impl LSMTree {
+    pub async fn new(dir: PathBuf) -> std::io::Result<Self> {
+        Self::with_options(dir, LSMTreeOptions::default()).await
+    }
+
+    pub async fn with_options(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+    ) -> std::io::Result<Self> {
+        Self::open(dir, options, true).await
+    }
+
+    // Same as with_options, but refuses directories that are not a tree: the
+    // directory must exist, and be either empty or hold a format file and the
+    // files of a tree alone.
+    // with_options creates the directory instead, and ignores the files it
+    // doesn't know.
+    pub async fn open_existing(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+    ) -> std::io::Result<Self> {
+        let names = Self::dir_entry_names(&dir)?;
+        if let Some(name) = names.iter().find(|name| {
+            name.as_str() != WAL_ARCHIVE_DIR_NAME
+                && FileKind::of(name).is_none()
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' is not a tree, '{}' is not a file of a tree",
+                    dir.display(),
+                    name
+                ),
+            ));
+        }
+        if !names.is_empty() && !dir.join(FORMAT_FILE_NAME).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' is not a tree, it has no format file",
+                    dir.display()
+                ),
+            ));
+        }
+        Self::with_options(dir, options).await
+    }
+
+    // Same as with_options, but for a new tree alone: the directory is created
+    // when it doesn't exist, and must be empty otherwise.
+    pub async fn create(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+    ) -> std::io::Result<Self> {
+        if dir.exists() && !Self::dir_entry_names(&dir)?.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("'{}' is not empty", dir.display()),
+            ));
+        }
+        Self::with_options(dir, options).await
+    }
+
+    fn dir_entry_names(dir: &Path) -> std::io::Result<Vec<String>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    // Open the tree from its sstables alone, ignoring the writes that are only
+    // in the WAL, for debugging a bad write.
+    // The WAL files are kept as they are, and writes go to a new WAL after
+    // them. The old WAL files must be moved out of the directory before the
+    // tree is opened normally again, otherwise they are replayed, flushed to
+    // sstables from the oldest.
+    // The options must be the ones the tree was written with, like in
+    // with_options.
+    pub async fn open_without_wal_replay(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+    ) -> std::io::Result<Self> {
+        Self::open(dir, options, false).await
+    }
+
+    async fn open(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+        replay_wal: bool,
+    ) -> std::io::Result<Self> {
+        if !dir.is_dir() {
+            std::fs::create_dir_all(&dir)?;
+        }
+        let storage = options.storage().clone();
+
+        let compact_action_paths: Vec<PathBuf> =
+            numbered_files(&LocalStorage, &dir, FileKind::CompactionAction)?
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect();
+        for compact_action_path in &compact_action_paths {
+            let file = BufferedFile::open(compact_action_path).await?;
+            let mut reader = StreamReaderBuilder::new(file).build();
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await?;
+            let mut cursor = std::io::Cursor::new(&buf[..]);
+            while let Ok(action) = bincode_options()
+                .deserialize_from::<_, CompactionAction>(&mut cursor)
+            {
+                Self::run_compaction_action(&*storage, &action)?;
+            }
+            reader.close().await?;
+            Self::remove_file_log_on_err(&*storage, compact_action_path);
+        }
+        // After the actions, as the action of LSMTree::migrate replaces the
+        // format file.
+        Self::check_format(&dir, &options).await?;
+
+        // All actions ran, the compaction files that are left are of
+        // compactions that crashed before writing their action, and are never
+        // used, same for the temporary files of flushes (and migrations) that
+        // crashed.
+        if let Some(compaction_dir) = &options.compaction_dir {
+            std::fs::create_dir_all(compaction_dir)?;
+        }
+        let migrated_format_path = dir.join(MIGRATED_FORMAT_FILE_NAME);
+        if migrated_format_path.exists() {
+            Self::remove_file_log_on_err(&*storage, &migrated_format_path);
+        }
+        for sstables_dir in [Some(&dir), options.compaction_dir.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            for name in storage.list(sstables_dir)? {
+                if let Some((FileKind::Temporary, Some(_))) =
+                    FileKind::of(&name)
+                {
+                    Self::remove_file_log_on_err(
+                        &*storage,
+                        &sstables_dir.join(name),
+                    );
+                }
+            }
+        }
+
+        let cold_sstables: HashSet<usize> = match &options.compaction_dir {
+            Some(compaction_dir) => {
+                numbered_files(&*storage, compaction_dir, FileKind::Data)?
+                    .into_iter()
+                    .map(|(index, _)| index)
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+        let sstable_dir = |index: usize| match &options.compaction_dir {
+            Some(compaction_dir) if cold_sstables.contains(&index) => {
+                compaction_dir.clone()
+            }
+            _ => dir.clone(),
+        };
+        let mut data_file_indices: Vec<usize> =
+            numbered_files(&*storage, &dir, FileKind::Data)?
+                .into_iter()
+                .map(|(index, _)| index)
+                .chain(cold_sstables.iter().copied())
+                .collect();
+        data_file_indices.sort();
+        let wal_indices: Vec<usize> =
+            numbered_files(&LocalStorage, &dir, FileKind::Wal)?
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect();
+
+        let mut max_seq = None;
+
+        let wal_file_index = match wal_indices.split_last() {
+            _ if !replay_wal => wal_indices.last().map_or(0, |i| i + 2),
+            None => 0,
+            Some((&wal_file_index, unflushed_indices)) => {
+                // Every WAL but the newest is of a flush that did not finish
+                // for some reason, do them now, from the oldest.
+                for &unflushed_file_index in unflushed_indices {
+                    Self::recover_unflushed_wal(
+                        &storage,
+                        &dir,
+                        &options,
+                        unflushed_file_index,
+                        &mut data_file_indices,
+                        sstable_dir(unflushed_file_index),
+                    )
+                    .await?;
+                }
+                wal_file_index
+            }
+        };
+
+        let mut wal_file_index = wal_file_index;
+        let mut wal_path = Self::get_wal_path(dir.clone(), wal_file_index);
+        let wal_size = std::fs::metadata(&wal_path).map_or(0, |m| m.len());
+        if replay_wal
+            && options
+                .wal_replay_threshold
+                .is_some_and(|threshold| wal_size > threshold)
+        {
+            let index =
+                data_file_indices.iter().max().map(|i| *i + 1).unwrap_or(0);
+            Self::flush_wal_to_disk(
+                &storage,
+                &dir,
+                &wal_path,
+                Self::get_sstable_file_paths(dir.clone(), index),
+                options.sstable_layout(),
+                options.bincode_config,
+            )
+            .await?;
+            data_file_indices.push(index);
+            // The next WAL is created before the flushed one is removed, so
+            // that a crash in between recovers it like an unfinished flush.
+            wal_file_index += 2;
+            let flushed_wal_path = std::mem::replace(
+                &mut wal_path,
+                Self::get_wal_path(dir.clone(), wal_file_index),
+            );
+            BufferedFile::create(&wal_path).await?.close().await?;
+            Self::remove_or_archive_wal(
+                &dir,
+                &flushed_wal_path,
+                options.wal_archive,
+            )?;
+        }
+
+        let write_file_index =
+            data_file_indices.iter().max().map(|i| *i + 1).unwrap_or(0);
+
+        let mut sstable_headers = HashMap::new();
+        let mut sstable_metas = HashMap::new();
+        for index in &data_file_indices {
+            let (_, index_path) =
+                Self::get_data_file_paths(sstable_dir(*index), *index);
+            let header =
+                IndexHeader::read_from_path(&*storage, &index_path).await?;
+            max_seq = max_seq.max(Some(header.max_seq));
+            sstable_headers.insert(*index, header);
+            let meta_path =
+                Self::get_meta_file_path(sstable_dir(*index), *index);
+            if let Some(mut meta) =
+                Self::read_sstable_meta(&*storage, &meta_path).await?
+            {
+                meta.filter = Some(
+                    Self::load_sstable_filter(
+                        &Self::get_data_file_paths(sstable_dir(*index), *index),
+                        &filter_path(&meta_path),
+                        &options,
+                    )
+                    .await?,
+                );
+                sstable_metas.insert(*index, Rc::new(meta));
+            }
+        }
+
+        // Value logs of flushes that crashed before their sstable was written,
+        // or of sstables that were compacted away.
+        for path in Self::unreferenced_value_logs(
+            &*storage,
+            &dir,
+            &data_file_indices,
+            &sstable_metas,
+        )? {
+            Self::remove_file_log_on_err(&*storage, &path);
+        }
+        let last_value_log = Self::value_log_ids(&*storage, &dir)?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+
+        let (wal, active_memtable, recent_writes) = if wal_path.exists() {
+            let (memtable, recent_writes) = Self::read_memtable_from_wal_file(
+                &wal_path,
+                options.bincode_config,
+            )
+            .await?;
+            let file = OpenOptions::new()
+                .append(true)
+                .buffered_open(&wal_path)
+                .await?;
+            let wal = options.wal(file, wal_path.clone());
+            max_seq = Self::memtable_max_seq(&memtable).max(max_seq);
+            (wal, memtable, recent_writes)
+        } else {
+            let memtable = RedBlackTree::with_capacity(TREE_CAPACITY);
+            let file = BufferedFile::create(&wal_path).await?;
+            let wal = options.wal(file, wal_path.clone());
+            (wal, memtable, VecDeque::new())
+        };
+
+        Ok(Self {
+            dir,
+            active_memtable,
+            recent_writes,
+            flush_memtable: None,
+            write_sstable_index: write_file_index,
+            read_sstable_indices: data_file_indices,
+            sstable_headers,
+            sstable_metas,
+            next_seq: max_seq.map(|seq| seq + 1).unwrap_or(0),
+            last_write: None,
+            sstable_reads: RefCell::new(HashMap::new()),
+            pending_deletes: Vec::new(),
+            compacting: Rc::new(RefCell::new(HashSet::new())),
+            range_locks: RangeLocks::default(),
+            stats: Cell::new(Stats::default()),
+            index_cache: RefCell::new(IndexCache::default()),
+            search_window: RefCell::new(SearchWindow::default()),
+            cold_sstables,
+            last_value_log,
+            memtable_index: wal_file_index,
+            wal,
+            options,
+        })
+    }
+
+    // Written once when a directory is first opened.
+    // Directories written before the format file existed are of the first
+    // format version, where index files had no header, so they are refused.
+    async fn check_format(
+        dir: &Path,
+        options: &LSMTreeOptions,
+    ) -> std::io::Result<()> {
+        let bincode_config = options.bincode_config;
+        let format_path = dir.join(FORMAT_FILE_NAME);
+
+        let existing_format = if format_path.exists() {
+            Some(Self::read_format(&format_path).await?)
+        } else {
+            let has_files =
+                !numbered_files(&**options.storage(), dir, FileKind::Data)?
+                    .is_empty()
+                    || !numbered_files(&LocalStorage, dir, FileKind::Wal)?
+                        .is_empty();
+            has_files.then_some(Format {
+                version: 1,
+                bincode_config: BincodeConfig::default(),
+                fixed_key_size: None,
+            })
+        };
+
+        match existing_format {
+            Some(format) if format.version != FORMAT_VERSION => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "'{}' is of format version {}, expected {}",
+                        dir.display(),
+                        format.version,
+                        FORMAT_VERSION
+                    ),
+                ))
+            }
+            Some(format) if format.bincode_config != bincode_config => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "'{}' was written with {:?}, cannot open it with {:?}",
+                        dir.display(),
+                        format.bincode_config,
+                        bincode_config
+                    ),
+                ))
+            }
+            Some(format) if format.fixed_key_size != options.fixed_key_size => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "'{}' was written with a fixed key size of {:?}, cannot \
+                         open it with {:?}",
+                        dir.display(),
+                        format.fixed_key_size,
+                        options.fixed_key_size
+                    ),
+                ))
+            }
+            Some(_) => Ok(()),
+            None => {
+                let format = Format {
+                    version: FORMAT_VERSION,
+                    bincode_config,
+                    fixed_key_size: options.fixed_key_size,
+                };
+                Self::write_format(&format_path, &format).await
+            }
+        }
+    }
+
+    async fn read_format(format_path: &PathBuf) -> std::io::Result<Format> {
+        let file = BufferedFile::open(format_path).await?;
+        let mut reader = StreamReaderBuilder::new(file).build();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await?;
+        reader.close().await?;
+        let malformed = |e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("format file is malformed: {}", e),
+            )
+        };
+        // The version is first in the format files of every version, read it
+        // alone so that files of other versions are refused by their version,
+        // instead of as malformed.
+        let version = bincode_options()
+            .allow_trailing_bytes()
+            .deserialize::<u32>(&buf)
+            .map_err(malformed)?;
+        if version != FORMAT_VERSION {
+            return Ok(Format {
+                version,
+                bincode_config: BincodeConfig::default(),
+                fixed_key_size: None,
+            });
+        }
+        bincode_options()
+            .deserialize::<Format>(&buf)
+            .map_err(malformed)
+    }
+
+    async fn write_format(
+        format_path: &PathBuf,
+        format: &Format,
+    ) -> std::io::Result<()> {
+        let file = BufferedFile::create(format_path).await?;
+        let mut writer = StreamWriterBuilder::new(file).build();
+        writer
+            .write_all(&bincode_options().serialize(format).unwrap())
+            .await?;
+        writer.close().await?;
+        Ok(())
+    }
+
+    // Rewrite the tree at dir, written with another bincode config, to the
+    // bincode config of the options, and open it.
+    // The WAL is flushed with the old config first, and every sstable is then
+    // transcoded to a compaction output. All outputs and a new format file
+    // replace the old ones by a single compaction action, so a crash leaves
+    // either the old config (and migrate can be called again), or the new one.
+    // Metas, filters, index headers and value logs don't depend on the bincode
+    // config, and are kept as they are.
+    pub async fn migrate(
+        dir: PathBuf,
+        options: LSMTreeOptions,
+    ) -> std::io::Result<Self> {
+        let format_path = dir.join(FORMAT_FILE_NAME);
+        if !format_path.exists() {
+            return Self::with_options(dir, options).await;
+        }
+        let from = Self::read_format(&format_path).await?.bincode_config;
+        let to = options.bincode_config;
+        if from == to {
+            return Self::with_options(dir, options).await;
+        }
+
+        let mut tree = Self::with_options(
+            dir.clone(),
+            options.clone().with_bincode_config(from),
+        )
+        .await?;
+        tree.flush().await?;
+        let indices = tree.read_sstable_indices.clone();
+        let sstable_dirs: Vec<PathBuf> =
+            indices.iter().map(|i| tree.sstable_dir(*i)).collect();
+        drop(tree);
+
+        let storage = options.storage().clone();
+        let mut renames = Vec::with_capacity(indices.len() * 2 + 1);
+        for (index, sstable_dir) in indices.iter().zip(sstable_dirs) {
+            let sstable_paths =
+                Self::get_data_file_paths(sstable_dir.clone(), *index);
+            let compact_paths =
+                Self::get_compaction_file_paths(sstable_dir, *index);
+            Self::transcode_sstable(
+                &storage,
+                &sstable_paths,
+                &compact_paths,
+                options.fixed_key_size,
+                (from, to),
+            )
+            .await?;
+            renames.push((compact_paths.0, sstable_paths.0));
+            renames.push((compact_paths.1, sstable_paths.1));
+        }
+        let migrated_format_path = dir.join(MIGRATED_FORMAT_FILE_NAME);
+        let format = Format {
+            version: FORMAT_VERSION,
+            bincode_config: to,
+            fixed_key_size: options.fixed_key_size,
+        };
+        Self::write_format(&migrated_format_path, &format).await?;
+        renames.push((migrated_format_path, format_path));
+
+        // The flushed WAL holds no entries, but is encoded with the old config.
+        let deletes = numbered_files(&LocalStorage, &dir, FileKind::Wal)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect();
+
+        let action = CompactionAction { renames, deletes };
+        let action_index = indices.iter().max().map_or(0, |i| i + 1);
+        let action_path =
+            Self::write_compaction_action(dir.clone(), &action, action_index)
+                .await?;
+        Self::run_compaction_action(&*storage, &action)?;
+        Self::remove_file_log_on_err(&*storage, &action_path);
+
+        Self::with_options(dir, options).await
+    }
+
+    // Write the entries of an sstable encoded with one bincode config to the
+    // given data and index paths, encoded with another.
+    async fn transcode_sstable(
+        storage: &Rc<dyn Storage>,
+        (data_path, index_path): &(PathBuf, PathBuf),
+        (output_data_path, output_index_path): &(PathBuf, PathBuf),
+        fixed_key_size: Option<usize>,
+        (from, to): (BincodeConfig, BincodeConfig),
+    ) -> std::io::Result<()> {
+        let header =
+            IndexHeader::read_from_path(&**storage, index_path).await?;
+        let mut sstable_reader = Self::open_sstable_readers(
+            storage,
+            &[(data_path.clone(), index_path.clone())],
+            None,
+            fixed_key_size,
+            from,
+        )
+        .await?
+        .pop()
+        .unwrap();
+        let mut data_writer = storage.create(output_data_path).await?;
+        let mut index_writer = storage.create(output_index_path).await?;
+        index_writer.write_all(&header.encode()).await?;
+
+        let mut encoder = EntryEncoder::new(to, header.restart_interval);
+        let mut offset_bytes =
+            vec![0; index_item_size(fixed_key_size) as usize];
+        let mut data_bytes = Vec::new();
+        let mut entry_offset = 0;
+        while let Some(entry) = sstable_reader
+            .next_entry(&mut offset_bytes, &mut data_bytes, fixed_key_size)
+            .await?
+        {
+            let entry_encoded = encoder.encode(&entry);
+            data_writer.write_all(&entry_encoded).await?;
+            let entry_index = EntryOffset {
+                entry_offset,
+                entry_size: entry_encoded.len(),
+            };
+            entry_offset += entry_encoded.len() as u64;
+            index_writer
+                .write_all(&encode_index_item(
+                    &entry_index,
+                    &entry.key,
+                    fixed_key_size,
+                ))
+                .await?;
+        }
+
+        sstable_reader.close().await?;
+        data_writer.close().await?;
+        index_writer.close().await?;
+        Ok(())
+    }
+
+    // Returns the memtable written in the WAL file, and the last keys written,
+    // up to the capacity of the memtable, in the order they were written.
+    async fn read_memtable_from_wal_file(
+        wal_path: &PathBuf,
+        config: BincodeConfig,
+    ) -> std::io::Result<(RedBlackTree<String, MemtableValue>, VecDeque<String>)>
+    {
+        let mut written_keys = VecDeque::new();
+        let mut memtable = RedBlackTree::with_capacity(TREE_CAPACITY);
+        let mut reader = WalReader::open(wal_path, config).await?;
+        while let Some(entry) = reader.next().await? {
+            if written_keys.len() == memtable.capacity() {
+                written_keys.pop_front();
+            }
+            written_keys.push_back(entry.key.clone());
+            let value = MemtableValue {
+                value: entry.value.into_inline()?,
+                seq: entry.seq,
+                timestamp: entry.timestamp,
+            };
+            memtable.set(entry.key, value).unwrap();
+        }
+        reader.close().await?;
+        Ok((memtable, written_keys))
+    }
+
+    fn memtable_max_seq(
+        memtable: &RedBlackTree<String, MemtableValue>,
+    ) -> Option<u64> {
+        memtable.iter().map(|(_, value)| value.seq).max()
+    }
+
+    fn run_compaction_action(
+        storage: &dyn Storage,
+        action: &CompactionAction,
+    ) -> std::io::Result<()> {
+        for path_to_delete in &action.deletes {
+            if file_storage(storage, path_to_delete).exists(path_to_delete) {
+                Self::remove_file_log_on_err(storage, path_to_delete);
+            }
+        }
+
+        for (source_path, destination_path) in &action.renames {
+            let storage = file_storage(storage, source_path);
+            if storage.exists(source_path) {
+                storage.rename(source_path, destination_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_data_file_paths(dir: PathBuf, index: usize) -> (PathBuf, PathBuf) {
+        (
+            numbered_file_path(&dir, index, DATA_EXTENSION),
+            numbered_file_path(&dir, index, INDEX_EXTENSION),
+        )
+    }
+
+    fn get_meta_file_path(dir: PathBuf, index: usize) -> PathBuf {
+        numbered_file_path(&dir, index, META_EXTENSION)
+    }
+
+    // The directory of the files of an sstable, the compaction directory for
+    // the outputs of compactions, see LSMTreeOptions::with_compaction_dir.
+    fn sstable_dir(&self, index: usize) -> PathBuf {
+        if self.cold_sstables.contains(&index) {
+            self.compaction_output_dir()
+        } else {
+            self.dir.clone()
+        }
+    }
+
+    fn compaction_output_dir(&self) -> PathBuf {
+        self.options
+            .compaction_dir
+            .clone()
+            .unwrap_or_else(|| self.dir.clone())
+    }
+
+    fn sstable_paths(&self, index: usize) -> (PathBuf, PathBuf) {
+        Self::get_data_file_paths(self.sstable_dir(index), index)
+    }
+
+    fn sstable_meta_path(&self, index: usize) -> PathBuf {
+        Self::get_meta_file_path(self.sstable_dir(index), index)
+    }
+
+    fn sstable_file_paths(&self, index: usize) -> SstablePaths {
+        let (data_path, index_path) = self.sstable_paths(index);
+        SstablePaths {
+            data_path,
+            index_path,
+            meta_path: self.sstable_meta_path(index),
+        }
+    }
+
+    fn get_wal_path(dir: PathBuf, index: usize) -> PathBuf {
+        numbered_file_path(&dir, index, WAL_EXTENSION)
+    }
+
+    // The temporary data, index and meta paths a flush writes to.
+    fn get_flush_file_paths(dir: PathBuf, index: usize) -> SstablePaths {
+        let path = |extension: &str| numbered_file_path(&dir, index, extension);
+        SstablePaths {
+            data_path: path("flush_data"),
+            index_path: path("flush_index"),
+            meta_path: path("flush_meta"),
+        }
+    }
+
+    // The data, index and meta paths of the sstable at the given index.
+    fn get_sstable_file_paths(dir: PathBuf, index: usize) -> SstablePaths {
+        let (data_path, index_path) =
+            Self::get_data_file_paths(dir.clone(), index);
+        SstablePaths {
+            data_path,
+            index_path,
+            meta_path: Self::get_meta_file_path(dir, index),
+        }
+    }
+
+    // Moves the data, index, meta and filter files of an sstable, the data
+    // file last, as an sstable is live once its data file exists.
+    fn rename_sstable_files(
+        storage: &dyn Storage,
+        from: &SstablePaths,
+        to: &SstablePaths,
+    ) -> std::io::Result<()> {
+        storage.rename(&from.index_path, &to.index_path)?;
+        let filter = filter_path(&from.meta_path);
+        if storage.exists(&filter) {
+            storage.rename(&filter, &filter_path(&to.meta_path))?;
+        }
+        storage.rename(&from.meta_path, &to.meta_path)?;
+        storage.rename(&from.data_path, &to.data_path)
+    }
+
+    fn get_compaction_meta_file_path(dir: PathBuf, index: usize) -> PathBuf {
+        numbered_file_path(&dir, index, "compact_meta")
+    }
+
+    async fn write_sstable_meta(
+        storage: &dyn Storage,
+        meta_path: &Path,
+        meta: &SstableMeta,
+    ) -> std::io::Result<()> {
+        if let Some(filter) = &meta.filter {
+            Self::write_sstable_filter(
+                storage,
+                &filter_path(meta_path),
+                filter,
+            )
+            .await?;
+        }
+        let meta_encoded = bincode_options().serialize(meta).unwrap();
+        let mut meta_writer = storage.create(meta_path).await?;
+        meta_writer.write_all(&meta_encoded).await?;
+        meta_writer.close().await?;
+        Ok(())
+    }
+
+    async fn write_sstable_filter(
+        storage: &dyn Storage,
+        filter_path: &Path,
+        filter: &BloomFilter,
+    ) -> std::io::Result<()> {
+        let filter_encoded = bincode_options().serialize(filter).unwrap();
+        let mut filter_writer = storage.create(filter_path).await?;
+        filter_writer.write_all(&filter_encoded).await?;
+        filter_writer.close().await?;
+        Ok(())
+    }
+
+    // The filter of an sstable from its filter file, or built from the keys
+    // of the sstable when the file is missing or partially written (like for
+    // sstables copied to the tree without it), written to the filter file
+    // for the next opens.
+    async fn load_sstable_filter(
+        (data_path, index_path): &(PathBuf, PathBuf),
+        filter_path: &Path,
+        options: &LSMTreeOptions,
+    ) -> std::io::Result<BloomFilter> {
+        let storage = options.storage();
+        if storage.exists(filter_path) {
+            let buf = Self::read_file(&**storage, filter_path).await?;
+            if let Ok(filter) = bincode_options().deserialize(&buf) {
+                return Ok(filter);
+            }
+        }
+
+        let fixed_key_size = options.fixed_key_size;
+        let header =
+            IndexHeader::read_from_path(&**storage, index_path).await?;
+        let mut sstable_reader = Self::open_sstable_readers(
+            storage,
+            &[(data_path.clone(), index_path.clone())],
+            None,
+            fixed_key_size,
+            options.bincode_config,
+        )
+        .await?
+        .pop()
+        .unwrap();
+        let mut filter = BloomFilter::new(
+            header.keys as usize,
+            options.false_positive_rate(),
+        );
+        let mut offset_bytes =
+            vec![0; index_item_size(fixed_key_size) as usize];
+        let mut data_bytes = Vec::new();
+        while let Some(entry) = sstable_reader
+            .next_entry(&mut offset_bytes, &mut data_bytes, fixed_key_size)
+            .await?
+        {
+            filter.insert(entry.key.as_bytes());
+        }
+        sstable_reader.close().await?;
+
+        Self::write_sstable_filter(&**storage, filter_path, &filter).await?;
+        Ok(filter)
+    }
+
+    // A missing or partially written meta is not an error, the sstable is then
+    // always searched.
+    async fn read_sstable_meta(
+        storage: &dyn Storage,
+        meta_path: &Path,
+    ) -> std::io::Result<Option<SstableMeta>> {
+        if !storage.exists(meta_path) {
+            return Ok(None);
+        }
+        let buf = Self::read_file(storage, meta_path).await?;
+        Ok(bincode_options().deserialize(&buf).ok())
+    }
+
+    // The whole file, for the small files of sstables.
+    async fn read_file(
+        storage: &dyn Storage,
+        path: &Path,
+    ) -> std::io::Result<Vec<u8>> {
+        let file = storage.open(path).await?;
+        let size = file.file_size().await?;
+        let bytes = file.read_at(0, size as usize).await?.to_vec();
+        file.close().await?;
+        Ok(bytes)
+    }
+
+    // Copies the file at from to to, read and written sequentially, for value
+    // logs that can't fit in memory.
+    async fn copy_file(
+        storage: &dyn Storage,
+        from: &Path,
+        to: &Path,
+    ) -> std::io::Result<()> {
+        let mut reader = FileReader::new(storage.open(from).await?, 0);
+        let mut writer = storage.create(to).await?;
+        futures_lite::io::copy(&mut reader, &mut writer).await?;
+        writer.close().await?;
+        reader.close().await
+    }
+
+    fn get_value_log_path(dir: PathBuf, log: usize) -> PathBuf {
+        numbered_file_path(&dir, log, VALUE_LOG_EXTENSION)
+    }
+
+    fn value_log_ids(
+        storage: &dyn Storage,
+        dir: &Path,
+    ) -> std::io::Result<Vec<usize>> {
+        Ok(numbered_files(storage, dir, FileKind::ValueLog)?
+            .into_iter()
+            .map(|(log, _)| log)
+            .collect())
+    }
+
+    // The value logs in dir that none of the given sstables point into.
+    // When one of the sstables has no meta, it could point into any value log,
+    // so none are returned.
+    fn unreferenced_value_logs(
+        storage: &dyn Storage,
+        dir: &Path,
+        sstable_indices: &[usize],
+        sstable_metas: &HashMap<usize, Rc<SstableMeta>>,
+    ) -> std::io::Result<Vec<PathBuf>> {
+        let mut referenced: BTreeSet<usize> = BTreeSet::new();
+        for index in sstable_indices {
+            match sstable_metas.get(index) {
+                Some(meta) => referenced.extend(&meta.value_logs),
+                None => return Ok(Vec::new()),
+            }
+        }
+        Ok(Self::value_log_ids(storage, dir)?
+            .into_iter()
+            .filter(|log| !referenced.contains(log))
+            .map(|log| Self::get_value_log_path(dir.to_path_buf(), log))
+            .collect())
+    }
+
+    fn next_value_log(&mut self) -> usize {
+        let now = nanos_since_epoch() as usize;
+        self.last_value_log = now.max(self.last_value_log + 1);
+        self.last_value_log
+    }
+
+    // The value itself, read from its value log when it's not inline, None for
+    // a tombstone.
+    // Must be called while holding the sstable the value was read from, as
+    // value logs are deleted like the sstables pointing into them.
+    async fn read_value(
+        &self,
+        value: Value,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        if let Value::Log(pointer) = &value {
+            self.update_stats(|stats| {
+                stats.get_bytes_read += pointer.size;
+                stats.get_reads += 1;
+            });
+        }
+        Self::read_value_in_dir(&**self.options.storage(), &self.dir, value)
+            .await
+    }
+
+    async fn read_value_in_dir(
+        storage: &dyn Storage,
+        dir: &Path,
+        value: Value,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        let pointer = match value {
+            Value::Log(pointer) => pointer,
+            value => return value.into_inline(),
+        };
+        let value_log = storage
+            .open(&Self::get_value_log_path(dir.to_path_buf(), pointer.log))
+            .await?;
+        let bytes = value_log
+            .read_at(pointer.offset, pointer.size as usize)
+            .await?
+            .to_vec();
+        value_log.close().await?;
+        Ok(Some(bytes))
+    }
+
+    fn get_compaction_file_paths(
+        dir: PathBuf,
+        index: usize,
+    ) -> (PathBuf, PathBuf) {
+        (
+            numbered_file_path(&dir, index, "compact_data"),
+            numbered_file_path(&dir, index, "compact_index"),
+        )
+    }
+
+    // The data, index and meta paths a compaction to the given index writes.
+    fn get_compaction_output_paths(dir: PathBuf, index: usize) -> SstablePaths {
+        let (data_path, index_path) =
+            Self::get_compaction_file_paths(dir.clone(), index);
+        SstablePaths {
+            data_path,
+            index_path,
+            meta_path: Self::get_compaction_meta_file_path(dir, index),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        key: &String,
+    ) -> glommio::Result<Option<String>, ()> {
+        Ok(self.get_with_source(key).await?.0)
+    }
+
+    // Whether the active memtable holds exactly the entries recovered from its
+    // WAL (the same keys, values, sequence numbers and timestamps), which is
+    // what a crash right now would recover.
+    // Reads the whole WAL, meant for tests.
+    pub async fn verify_wal_consistency(&self) -> glommio::Result<bool, ()> {
+        let wal_path =
+            Self::get_wal_path(self.dir.clone(), self.memtable_index);
+        let (recovered, _) = Self::read_memtable_from_wal_file(
+            &wal_path,
+            self.options.bincode_config,
+        )
+        .await?;
+        Ok(recovered.len() == self.active_memtable.len()
+            && recovered.iter().eq(self.active_memtable.iter()))
+    }
+
+    // Same as get, but returns default when the key is not in the tree.
+    pub async fn get_or(
+        &self,
+        key: &String,
+        default: String,
+    ) -> glommio::Result<String, ()> {
+        Ok(self.get(key).await?.unwrap_or(default))
+    }
+
+    // Same as get_or, but the default is only computed when the key is not in
+    // the tree.
+    pub async fn get_or_else(
+        &self,
+        key: &String,
+        default: impl FnOnce() -> String,
+    ) -> glommio::Result<String, ()> {
+        Ok(self.get(key).await?.unwrap_or_else(default))
+    }
+
+    // Same as get, but also returns where the value was read from, for a
+    // deleted key where its tombstone was read from.
+    pub async fn get_with_source(
+        &self,
+        key: &String,
+    ) -> glommio::Result<(Option<String>, ValueSource), ()> {
+        let (entry, source) = self.get_entry(key).await?;
+        let value = match entry {
+            Some(entry) => utf8_value(entry.value.into_inline()?)?,
+            None => None,
+        };
+        Ok((value, source))
+    }
+
+    // Whether the key has a value, exact, as it searches the sstables like get.
+    pub async fn contains_key(
+        &self,
+        key: &String,
+    ) -> glommio::Result<bool, ()> {
+        Ok(self
+            .get_entry(key)
+            .await?
+            .0
+            .is_some_and(|entry| entry.value != Value::Tombstone))
+    }
+
+    // Same as contains_key, but without any IO: exact for the keys in the
+    // memtables, and for the others true when the filter of any sstable might
+    // contain the key (or an sstable has no meta), so false means the key
+    // surely has no value, and true means it probably has one.
+    pub fn probably_contains(&self, key: &String) -> bool {
+        if let Some((value, _)) = self.get_from_memtables(key) {
+            return value.value.is_some();
+        }
+        self.read_sstable_indices.iter().any(|i| {
+            self.sstable_metas
+                .get(i)
+                .is_none_or(|meta| meta.may_contain(key))
+        })
+    }
+
+    // Same as get, but also returns the time the value was written at.
+    pub async fn get_with_timestamp(
+        &self,
+        key: &String,
+    ) -> glommio::Result<Option<(String, SystemTime)>, ()> {
+        let (entry, _) = self.get_entry(key).await?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let timestamp = UNIX_EPOCH + Duration::from_nanos(entry.timestamp);
+        Ok(utf8_value(entry.value.into_inline()?)?
+            .map(|value| (value, timestamp)))
+    }
+
+    // The newest entry of a key, encoded the same way it is written to the WAL
+    // and the sstables, to be sent to a replica as is. The entry of a deleted
+    // key is its tombstone, so that the replica deletes it too.
+    pub async fn get_raw_entry(
+        &self,
+        key: &String,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        let (entry, _) = self.get_entry(key).await?;
+        Ok(entry.map(|entry| self.options.bincode_config.serialize(&entry)))
+    }
+
+    async fn get_entry(
+        &self,
+        key: &String,
+    ) -> glommio::Result<(Option<Entry>, ValueSource), ()> {
+        // Held until the value is read, so a compaction can't delete the
+        // value log it's in before.
+        let _guard = self.hold_sstable_files();
+        let (entry, source) = self.get_stored_entry(key).await?;
+        let entry = match entry {
+            Some(entry) => Some(Entry {
+                value: self
+                    .read_value(entry.value)
+                    .await?
+                    .map_or(Value::Tombstone, Value::Inline),
+                ..entry
+            }),
+            None => None,
+        };
+        Ok((entry, source))
+    }
+
+    // Same as get_entry, but the value can be a pointer into a value log,
+    // which is not read. The caller holds the sstable files for as long as it
+    // reads the pointed value.
+    async fn get_stored_entry(
+        &self,
+        key: &String,
+    ) -> glommio::Result<(Option<Entry>, ValueSource), ()> {
+        if let Some((value, source)) = self.get_from_memtables(key) {
+            let entry = Entry {
+                key: key.clone(),
+                value: value.stored(),
+                seq: value.seq,
+                timestamp: value.timestamp,
+            };
+            self.record_searches(0);
+            return Ok((Some(entry), source));
+        }
+
+        // Key not found in memory, query the files from the one holding the
+        // newest writes to the oldest, until no other file can have a newer
+        // version of the key than the one found.
+        let mut searched = 0;
+        let mut indices = self.read_sstable_indices.clone();
+        indices.sort_by_key(|i| {
+            std::cmp::Reverse(self.sstable_headers[i].max_seq)
+        });
+
+        let mut newest: Option<(Entry, usize)> = None;
+        for i in indices {
+            if let Some((entry, _)) = &newest {
+                if entry.seq >= self.sstable_headers[&i].max_seq {
+                    break;
+                }
+            }
+
+            if let Some(meta) = self.sstable_metas.get(&i) {
+                if !meta.may_contain(key) {
+                    self.update_stats(|stats| {
+                        stats.sstables_skipped_by_filter += 1
+                    });
+                    continue;
+                }
+            }
+
+            self.update_stats(|stats| stats.sstable_opens += 1);
+            searched += 1;
+            let result = match with_retries(&self.options.retry_policy, || {
+                self.search_sstable(i, key)
+            })
+            .await
+            {
+                Err(e)
+                    if self.options.corruption_policy
+                        == CorruptionPolicy::SkipAndLog
+                        && is_corruption_error(&e) =>
+                {
+                    eprintln!(
+                        "Skipping sstable {} that is malformed, searching \
+                         for key '{}': {}",
+                        i, key, e
+                    );
+                    continue;
+                }
+                result => result?,
+            };
+            if let Some(result) = result {
+                if newest.as_ref().is_none_or(|(e, _)| result.seq > e.seq) {
+                    newest = Some((result, i));
+                }
+            }
+        }
+
+        self.record_searches(searched);
+        Ok(match newest {
+            Some((entry, i)) => (Some(entry), ValueSource::Sstable(i)),
+            None => (None, ValueSource::NotFound),
+        })
+    }
+
+    fn record_searches(&self, searched: u32) {
+        if let Some((window, _)) = self.options.read_amplification_limit {
+            self.search_window.borrow_mut().record(searched, window);
+        }
+    }
+
+    // The average number of sstables searched by the last gets, over the
+    // window of LSMTreeOptions::with_read_amplification_limit, None when it's
+    // not set or less gets than the window ran since the last compaction.
+    pub fn read_amplification(&self) -> Option<f64> {
+        let (window, _) = self.options.read_amplification_limit?;
+        self.search_window.borrow().average(window)
+    }
+
+    // Up to len bytes of the value of a key, starting at offset, for example a
+    // preview of a large value. Empty when offset is past the end of the
+    // value.
+    // A value in a value log (see LSMTreeOptions::with_value_log_threshold and
+    // LSMTree::set_large) is read only at the range, values stored inline in
+    // an sstable are read whole with their entry.
+    // Returns bytes, as the range can split a character.
+    pub async fn get_range(
+        &self,
+        key: &String,
+        offset: u64,
+        len: u64,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        let _guard = self.hold_sstable_files();
+        let (entry, _) = self.get_stored_entry(key).await?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let pointer = match entry.value {
+            Value::Inline(value) => {
+                let bytes = value;
+                let start = (offset as usize).min(bytes.len());
+                let end = start.saturating_add(len as usize).min(bytes.len());
+                return Ok(Some(bytes[start..end].to_vec()));
+            }
+            Value::Log(pointer) => pointer,
+            Value::Tombstone => return Ok(None),
+        };
+
+        let start = offset.min(pointer.size);
+        let size = len.min(pointer.size - start);
+        if size == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        self.update_stats(|stats| {
+            stats.get_bytes_read += size;
+            stats.get_reads += 1;
+        });
+        let value_log = self
+            .options
+            .storage()
+            .open(&Self::get_value_log_path(self.dir.clone(), pointer.log))
+            .await?;
+        let bytes = value_log
+            .read_at(pointer.offset + start, size as usize)
+            .await?
+            .to_vec();
+        value_log.close().await?;
+        Ok(Some(bytes))
+    }
+
+    // The n-th newest version of a key (0 is the newest, same as get), out of
+    // the versions kept by compactions, see
+    // LSMTreeOptions::with_versions_to_keep. None too when that version is a
+    // delete.
+    pub async fn get_version(
+        &self,
+        key: &String,
+        n: usize,
+    ) -> glommio::Result<Option<String>, ()> {
+        let mut versions: Vec<(u64, Value)> =
+            [Some(&self.active_memtable), self.flush_memtable.as_ref()]
+                .into_iter()
+                .flatten()
+                .filter_map(|memtable| memtable.get(key))
+                .map(|value| (value.seq, value.stored()))
+                .collect();
+
+        let _guard = self.hold_sstable_files();
+        let fixed_key_size = self.options.fixed_key_size;
+        let config = self.options.bincode_config;
+        for i in &self.read_sstable_indices {
+            if let Some(meta) = self.sstable_metas.get(i) {
+                if !meta.may_contain(key) {
+                    continue;
+                }
+            }
+
+            let (data_path, index_path) = self.sstable_paths(*i);
+            let storage = self.options.storage();
+            let data_file = storage.open(&data_path).await?;
+            let index = IndexSource::File(storage.open(&index_path).await?);
+            let header = self.sstable_headers[i];
+            let mut position =
+                lower_bound(&data_file, &index, key, fixed_key_size, config)
+                    .await?;
+            while position < header.entries {
+                let entry = read_entry_at(
+                    &data_file,
+                    &index,
+                    position,
+                    header.restart_interval,
+                    fixed_key_size,
+                    config,
+                )
+                .await?;
+                if entry.key != *key {
+                    break;
+                }
+                versions.push((entry.seq, entry.value));
+                position += 1;
+            }
+            data_file.close().await?;
+            if let IndexSource::File(index_file) = index {
+                index_file.close().await?;
+            }
+        }
+
+        versions.sort_by_key(|(seq, _)| std::cmp::Reverse(*seq));
+        Ok(match versions.into_iter().nth(n) {
+            Some((_, value)) => utf8_value(self.read_value(value).await?)?,
+            None => None,
+        })
+    }
+
+    // An estimate of the fraction of the keys of the tree that are less than
+    // key, in [0, 1], for percentile-like queries.
+    // Sums the position key would be inserted at in each memtable and sstable
+    // (binary searching its index), divided by the sum of their entries.
+    // It's approximate, as a key that is in more than one of them (an
+    // overwrite), or more than once in an sstable (see
+    // LSMTreeOptions::with_versions_to_keep), is counted once for each
+    // version.
+    pub async fn rank(&self, key: &String) -> glommio::Result<f64, ()> {
+        let mut position = 0;
+        let mut entries = 0;
+        for memtable in
+            [Some(&self.active_memtable), self.flush_memtable.as_ref()]
+                .into_iter()
+                .flatten()
+        {
+            position +=
+                memtable.iter().take_while(|(k, _)| *k < key).count() as u64;
+            entries += memtable.len() as u64;
+        }
+
+        let _guard = self.hold_sstable_files();
+        for i in &self.read_sstable_indices {
+            let header = self.sstable_headers[i];
+            entries += header.entries;
+            // No need to search when the key is outside of the sstable.
+            if let Some((min_key, max_key)) =
+                self.sstable_metas.get(i).and_then(|m| m.key_range.as_ref())
+            {
+                if key <= min_key {
+                    continue;
+                }
+                if key > max_key {
+                    position += header.entries;
+                    continue;
+                }
+            }
+
+            position += self.search_sstable_position(*i, key).await?.0;
+        }
+
+        Ok(if entries == 0 {
+            0.0
+        } else {
+            position as f64 / entries as f64
+        })
+    }
+
+    // Same as get for many keys at once, returning the values in the order of
+    // the keys.
+    // Each sstable is opened at most once, and only when its filter passes for
+    // at least one of the keys that could have a newer version in it.
+    pub async fn get_many(
+        &self,
+        keys: &[String],
+    ) -> glommio::Result<Vec<Option<String>>, ()> {
+        let mut values: Vec<Option<Value>> = keys
+            .iter()
+            .map(|key| {
+                self.get_from_memtables(key)
+                    .map(|(value, _)| value.stored())
+            })
+            .collect();
+        let in_memory: Vec<bool> = values.iter().map(Option::is_some).collect();
+        let mut newest_seqs: Vec<Option<u64>> = vec![None; keys.len()];
+
+        let _guard = self.hold_sstable_files();
+
+        let mut indices = self.read_sstable_indices.clone();
+        indices.sort_by_key(|i| {
+            std::cmp::Reverse(self.sstable_headers[i].max_seq)
+        });
+
+        for i in indices {
+            let max_seq = self.sstable_headers[&i].max_seq;
+            let unresolved: Vec<usize> = (0..keys.len())
+                .filter(|k| {
+                    !in_memory[*k]
+                        && newest_seqs[*k].is_none_or(|s| s < max_seq)
+                })
+                .collect();
+            if unresolved.is_empty() {
+                break;
+            }
+
+            let candidates: Vec<usize> = match self.sstable_metas.get(&i) {
+                Some(meta) => unresolved
+                    .into_iter()
+                    .filter(|k| meta.may_contain(&keys[*k]))
+                    .collect(),
+                None => unresolved,
+            };
+            if candidates.is_empty() {
+                self.update_stats(|stats| {
+                    stats.sstables_skipped_by_filter += 1
+                });
+                continue;
+            }
+
+            let (data_filename, _) = self.sstable_paths(i);
+            let retry_policy = &self.options.retry_policy;
+            self.update_stats(|stats| stats.sstable_opens += 1);
+            let storage = self.options.storage();
+            let data_file = with_retries(retry_policy, || async {
+                Ok(storage.open(&data_filename).await?)
+            })
+            .await?;
+            let index =
+                with_retries(retry_policy, || self.open_index(i)).await?;
+
+            let counts = ReadCounts::default();
+            for k in candidates {
+                let result = with_retries(retry_policy, || {
+                    binary_search(
+                        &data_file,
+                        &index,
+                        &keys[k],
+                        self.options.fixed_key_size,
+                        self.options.bincode_config,
+                        self.options.read_block_size,
+                        &counts,
+                    )
+                })
+                .await?;
+                if let Some(entry) = result {
+                    if newest_seqs[k].is_none_or(|s| entry.seq > s) {
+                        newest_seqs[k] = Some(entry.seq);
+                        values[k] = Some(entry.value);
+                    }
+                }
+            }
+            self.update_stats(|stats| {
+                stats.get_bytes_read += counts.bytes.get();
+                stats.get_reads += counts.reads.get();
+            });
+        }
+
+        let mut resolved = Vec::with_capacity(values.len());
+        for value in values {
+            resolved.push(match value {
+                Some(value) => utf8_value(self.read_value(value).await?)?,
+                None => None,
+            });
+        }
+        Ok(resolved)
+    }
+
+    // Call f with every key and its newest value in [start, end), in ascending
+    // key order, without collecting the range in memory (other than the
+    // entries of the memtables in the range).
+    // Stops early once f returns ControlFlow::Break, and fails when an entry of
+    // an sstable can't be read, instead of ending the range early.
+    pub async fn for_each_range<F, Fut>(
+        &self,
+        start: &String,
+        end: &String,
+        f: F,
+    ) -> std::io::Result<()>
+    where
+        F: FnMut((String, String)) -> Fut,
+        Fut: Future<Output = ControlFlow<()>>,
+    {
+        self.for_each_from(start, Some(end), utf8_string, f).await
+    }
+
+    // Same as for_each_range, with the values as they are stored, see
+    // set_bytes.
+    pub async fn for_each_range_bytes<F, Fut>(
+        &self,
+        start: &String,
+        end: &String,
+        f: F,
+    ) -> std::io::Result<()>
+    where
+        F: FnMut((String, Vec<u8>)) -> Fut,
+        Fut: Future<Output = ControlFlow<()>>,
+    {
+        self.for_each_from(start, Some(end), Ok, f).await
+    }
+
+    // Every key and its newest value in [start, end), in ascending key order,
+    // collected from for_each_range. Empty when end is not after start.
+    pub async fn range(
+        &self,
+        start: &String,
+        end: &String,
+    ) -> glommio::Result<Vec<(String, String)>, ()> {
+        let mut pairs = Vec::new();
+        self.for_each_range(start, end, |pair| {
+            pairs.push(pair);
+            std::future::ready(ControlFlow::Continue(()))
+        })
+        .await?;
+        Ok(pairs)
+    }
+
+    // Call f with every key whose newest version was written after the
+    // sequence number seq, with its newest value and sequence number, in
+    // ascending key order (not in the order they were written), for
+    // incremental replication.
+    // A key overwritten more than once since seq is passed once, with its
+    // newest value, the older values are not passed even when they are still
+    // in sstables.
+    // Compactions keep the newest version of every key, so no change is lost
+    // to them, unless dropped by compact_with_filter, after which an older
+    // version of the key (written before seq) could be the newest.
+    // Keys deleted since seq are not passed, a replica learns of deletes
+    // from their raw entries, see get_raw_entry.
+    // Stops early once f returns ControlFlow::Break.
+    pub async fn for_each_change_since<F, Fut>(
+        &self,
+        seq: u64,
+        f: F,
+    ) -> std::io::Result<()>
+    where
+        F: FnMut((String, String, u64)) -> Fut,
+        Fut: Future<Output = ControlFlow<()>>,
+    {
+        self.for_each_entry_from(
+            (&String::new(), None),
+            Some(seq),
+            utf8_string,
+            f,
+        )
+        .await
+    }
+
+    // A SHA-256 digest of the newest value of every key, in ascending key
+    // order, so that two trees holding the same keys and values have the same
+    // digest, however their entries are spread across the memtables and the
+    // sstables, like a replica and its source.
+    // Every key and value is hashed after its length (a little endian u64),
+    // so that no two different sets of pairs hash the same bytes.
+    // Fails when any entry can't be read, never returning the digest of part
+    // of the tree.
+    pub async fn content_digest(&self) -> glommio::Result<[u8; 32], ()> {
+        let mut hasher = Sha256::default();
+        self.for_each_from(&String::new(), None, Ok, |(key, value)| {
+            for bytes in [key.as_bytes(), &value] {
+                hasher.update(&(bytes.len() as u64).to_le_bytes());
+                hasher.update(bytes);
+            }
+            std::future::ready(ControlFlow::Continue(()))
+        })
+        .await?;
+        Ok(hasher.finish())
+    }
+
+    // Same as for_each_range, but without an end when end is None, and with
+    // the values decoded by decode, which stops the iteration at its first
+    // error.
+    async fn for_each_from<V, D, F, Fut>(
+        &self,
+        start: &String,
+        end: Option<&String>,
+        decode: D,
+        mut f: F,
+    ) -> std::io::Result<()>
+    where
+        D: Fn(Vec<u8>) -> std::io::Result<V>,
+        F: FnMut((String, V)) -> Fut,
+        Fut: Future<Output = ControlFlow<()>>,
+    {
+        self.for_each_entry_from(
+            (start, end),
+            None,
+            decode,
+            |(key, value, _)| f((key, value)),
+        )
+        .await
+    }
+
+    // Same as for_each_from, but passes the sequence number of the values too,
+    // and only the keys whose newest version was written after after_seq, when
+    // it's set.
+    async fn for_each_entry_from<V, D, F, Fut>(
+        &self,
+        (start, end): (&String, Option<&String>),
+        after_seq: Option<u64>,
+        decode: D,
+        mut f: F,
+    ) -> std::io::Result<()>
+    where
+        D: Fn(Vec<u8>) -> std::io::Result<V>,
+        F: FnMut((String, V, u64)) -> Fut,
+        Fut: Future<Output = ControlFlow<()>>,
+    {
+        if end.is_some_and(|end| end <= start) {
+            return Ok(());
+        }
+        let is_new = |seq: u64| after_seq.is_none_or(|after| seq > after);
+        let in_range =
+            |key: &String| key >= start && end.is_none_or(|end| key < end);
+
+        // The memtables are the first sources, so that their index is lower
+        // than the index of any sstable source.
+        let mut memtable_entries: Vec<std::vec::IntoIter<Entry>> =
+            [Some(&self.active_memtable), self.flush_memtable.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|memtable| {
+                    memtable
+                        .iter()
+                        .filter(|(key, value)| {
+                            in_range(key) && is_new(value.seq)
+                        })
+                        .map(|(key, value)| Entry {
+                            key: key.clone(),
+                            value: value.stored(),
+                            seq: value.seq,
+                            timestamp: value.timestamp,
+                        })
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                })
+                .collect();
+
+        let _guard = self.hold_sstable_files();
+        let sstable_paths: Vec<(PathBuf, PathBuf)> = self
+            .read_sstable_indices
+            .iter()
+            // An sstable written entirely before after_seq can only hold
+            // versions older than the newest version of a key, or keys whose
+            // newest version is not new.
+            .filter(|i| is_new(self.sstable_headers[i].max_seq))
+            .filter(|i| {
+                self.sstable_metas
+                    .get(i)
+                    .and_then(|meta| meta.key_range.as_ref())
+                    .is_none_or(|(min, max)| {
+                        max >= start && end.is_none_or(|end| min < end)
+                    })
+            })
+            .map(|i| self.sstable_paths(*i))
+            .collect();
+        let fixed_key_size = self.options.fixed_key_size;
+        let config = self.options.bincode_config;
+        let mut sstable_readers = Self::open_sstable_readers(
+            self.options.storage(),
+            &sstable_paths,
+            Some(start),
+            fixed_key_size,
+            config,
+        )
+        .await?;
+
+        let mut offset_bytes =
+            vec![0; index_item_size(fixed_key_size) as usize];
+        let mut data_bytes = Vec::new();
+        let mut heap = BinaryHeap::new();
+        // The sources to read the next entry of before popping the next item,
+        // all of them at first, and then the source of the popped item.
+        let mut sources_to_read: Vec<usize> =
+            (0..memtable_entries.len() + sstable_readers.len()).collect();
+        let mut last_key: Option<String> = None;
+        loop {
+            for index in sources_to_read.drain(..) {
+                let entry = match memtable_entries.get_mut(index) {
+                    Some(entries) => entries.next(),
+                    None => {
+                        sstable_readers[index - memtable_entries.len()]
+                            .next_entry(
+                                &mut offset_bytes,
+                                &mut data_bytes,
+                                fixed_key_size,
+                            )
+                            .await?
+                    }
+                };
+                if let Some(entry) = entry.filter(|e| in_range(&e.key)) {
+                    heap.push(CompactionItem { entry, index });
+                }
+            }
+
+            let Some(next) = heap.pop() else {
+                break;
+            };
+            sources_to_read.push(next.index);
+
+            // The newest version of a key is popped first, skip the older ones.
+            if last_key.as_ref() != Some(&next.entry.key) {
+                last_key = Some(next.entry.key.clone());
+                if !is_new(next.entry.seq) {
+                    continue;
+                }
+                // A deleted key is skipped, with its older versions.
+                let Some(value) = self.read_value(next.entry.value).await?
+                else {
+                    continue;
+                };
+                let seq = next.entry.seq;
+                if f((next.entry.key, decode(value)?, seq)).await.is_break() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A handle that works with keys and values of other types than strings,
+    // the keys encoded to strings, with integer keys sorting by value (see
+    // typed::Key), and the values to bytes (see typed::Value).
+    pub fn typed<K: Key, V: TypedValue>(&mut self) -> TypedLSMTree<'_, K, V> {
+        TypedLSMTree::new(self)
+    }
+
+    // A handle that works with the keys starting with prefix, without the
+    // prefix, so that keys of different prefixes can't collide.
+    pub fn with_prefix(&mut self, prefix: &str) -> PrefixedLSMTree<'_> {
+        PrefixedLSMTree {
+            tree: self,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    // The archived WALs, from the oldest to the newest, see
+    // LSMTreeOptions::with_wal_archive.
+    // Each WAL holds the writes of one flushed memtable in the order they
+    // were made, and they're named by the time they were archived, so an
+    // archived WAL only holds writes made before the WALs after it.
+    pub fn archived_wals(&self) -> std::io::Result<Vec<PathBuf>> {
+        let archive_dir = self.dir.join(WAL_ARCHIVE_DIR_NAME);
+        if !archive_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        Ok(numbered_files(&LocalStorage, &archive_dir, FileKind::Wal)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect())
+    }
+
+    // The writes of an archived WAL, in the order they were made.
+    // To recover to a point in time, open a tree from a copy of a backup
+    // (taken with live_files), and set (or delete) the writes of the WALs
+    // archived after the backup, oldest WAL first, skipping writes with a seq
+    // the backup already has (up to the max seq of its sstables) and stopping
+    // at the first write with a timestamp after the point in time.
+    pub async fn read_archived_wal(
+        path: &PathBuf,
+        config: BincodeConfig,
+    ) -> std::io::Result<Vec<ArchivedWrite>> {
+        let mut writes = Vec::new();
+        let mut reader = WalReader::open(path, config).await?;
+        while let Some(entry) = reader.next().await? {
+            writes.push(ArchivedWrite {
+                key: entry.key,
+                value: utf8_value(entry.value.into_inline()?)?,
+                seq: entry.seq,
+                timestamp: entry.timestamp,
+            });
+        }
+        reader.close().await?;
+        Ok(writes)
+    }
+
+    // Flush a WAL that is not the newest on open, to the sstable of its index,
+    // and remove it.
+    // The writes of the WAL are in the sstable then, so failing to remove it
+    // doesn't fail the open. It's left to be recovered again by the next open,
+    // which only removes it when the sstable of its index still holds its
+    // newest write, and otherwise flushes it again, to a new sstable when the
+    // index was reused by another sstable (like a compaction output).
+    async fn recover_unflushed_wal(
+        storage: &Rc<dyn Storage>,
+        dir: &Path,
+        options: &LSMTreeOptions,
+        wal_index: usize,
+        data_file_indices: &mut Vec<usize>,
+        sstable_dir: PathBuf,
+    ) -> std::io::Result<()> {
+        let wal_path = Self::get_wal_path(dir.to_path_buf(), wal_index);
+        let mut reader =
+            WalReader::open(&wal_path, options.bincode_config).await?;
+        let mut wal_max_seq = None;
+        while let Some(entry) = reader.next().await? {
+            wal_max_seq = wal_max_seq.max(Some(entry.seq));
+        }
+        reader.close().await?;
+
+        let mut sstable_index = Some(wal_index);
+        if data_file_indices.contains(&wal_index) {
+            let (_, index_path) =
+                Self::get_data_file_paths(sstable_dir, wal_index);
+            let header =
+                IndexHeader::read_from_path(&**storage, &index_path).await?;
+            sstable_index = if Some(header.max_seq) >= wal_max_seq {
+                None
+            } else {
+                data_file_indices.iter().max().map(|i| i + 1)
+            };
+        }
+
+        if let Some(index) = sstable_index {
+            Self::flush_wal_to_disk(
+                storage,
+                dir,
+                &wal_path,
+                Self::get_sstable_file_paths(dir.to_path_buf(), index),
+                options.sstable_layout(),
+                options.bincode_config,
+            )
+            .await?;
+            if !data_file_indices.contains(&index) {
+                data_file_indices.push(index);
+                data_file_indices.sort();
+            }
+        }
+
+        let removed = with_retries(&options.retry_policy, || async {
+            Ok(Self::remove_or_archive_wal(
+                dir,
+                &wal_path,
+                options.wal_archive,
+            )?)
+        })
+        .await;
+        if let Err(e) = removed {
+            eprintln!(
+                "Failed to remove WAL '{}' that was flushed on open, it's \
+                 recovered again on the next open: {}",
+                wal_path.display(),
+                e
+            );
+        }
+        Ok(())
+    }
+
+    // Called once the writes of a WAL are in an sstable.
+    fn remove_or_archive_wal(
+        dir: &Path,
+        wal_path: &PathBuf,
+        archive: Option<WalRetention>,
+    ) -> std::io::Result<()> {
+        let Some(retention) = archive else {
+            return std::fs::remove_file(wal_path);
+        };
+
+        let archive_dir = dir.join(WAL_ARCHIVE_DIR_NAME);
+        std::fs::create_dir_all(&archive_dir)?;
+        let archived =
+            numbered_files(&LocalStorage, &archive_dir, FileKind::Wal)?;
+        // Named by the time, after the newest archived WAL even when the
+        // clock went back.
+        let id = archived
+            .last()
+            .map_or(0, |(id, _)| id + 1)
+            .max(nanos_since_epoch() as usize);
+        std::fs::rename(
+            wal_path,
+            numbered_file_path(&archive_dir, id, WAL_EXTENSION),
+        )?;
+
+        let expired = match retention {
+            WalRetention::All => 0,
+            // Including the WAL that was just archived.
+            WalRetention::Last(count) => {
+                (archived.len() + 1).saturating_sub(count)
+            }
+            WalRetention::For(duration) => {
+                let oldest = id.saturating_sub(duration.as_nanos() as usize);
+                archived.iter().filter(|(id, _)| *id < oldest).count()
+            }
+        };
+        for (_, path) in archived.iter().take(expired) {
+            Self::remove_file_log_on_err(&LocalStorage, path);
+        }
+        Ok(())
+    }
+
+    // The files that make up the tree right now, for backup tools to copy:
+    // the format file, the files of each sstable that is read from (by
+    // sstable index), the value logs they point into (by id) and the WALs
+    // (oldest first), in that order.
+    // Temporary files, compaction actions and files retired by compactions
+    // are not part of it.
+    pub fn live_files(&self) -> std::io::Result<Vec<(FileKind, PathBuf)>> {
+        let mut files = Vec::new();
+
+        let format_path = self.dir.join(FORMAT_FILE_NAME);
+        if format_path.exists() {
+            files.push((FileKind::Format, format_path));
+        }
+
+        let mut indices = self.read_sstable_indices.clone();
+        indices.sort();
+        let mut value_logs = Some(BTreeSet::new());
+        for index in indices {
+            let (data_path, index_path) = self.sstable_paths(index);
+            files.push((FileKind::Data, data_path));
+            files.push((FileKind::Index, index_path));
+            match self.sstable_metas.get(&index) {
+                Some(meta) => {
+                    let meta_path = self.sstable_meta_path(index);
+                    files.push((FileKind::Filter, filter_path(&meta_path)));
+                    files.push((FileKind::Meta, meta_path));
+                    if let Some(value_logs) = &mut value_logs {
+                        value_logs.extend(&meta.value_logs);
+                    }
+                }
+                // Could point into any value log.
+                None => value_logs = None,
+            }
+        }
+        let value_logs = match value_logs {
+            Some(value_logs) => value_logs,
+            None => Self::value_log_ids(&**self.options.storage(), &self.dir)?
+                .into_iter()
+                .collect(),
+        };
+        for log in value_logs {
+            files.push((
+                FileKind::ValueLog,
+                Self::get_value_log_path(self.dir.clone(), log),
+            ));
+        }
+
+        if self.flush_memtable.is_some() {
+            files.push((
+                FileKind::Wal,
+                Self::get_wal_path(self.dir.clone(), self.memtable_index - 2),
+            ));
+        }
+        files.push((
+            FileKind::Wal,
+            Self::get_wal_path(self.dir.clone(), self.memtable_index),
+        ));
+
+        Ok(files)
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut Stats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    fn record_write_stall(&self, stall_start: Instant) {
+        self.update_stats(|stats| {
+            stats.write_stalls += 1;
+            stats.write_stall_duration += stall_start.elapsed();
+        });
+    }
+
+    // Same as get, but only looks in memory, without waiting for any IO.
+    // None means the key is not in the memtables, it could still be in an
+    // sstable, or that it's deleted in them.
+    // Fails with InvalidData for a value that is not utf-8, like get.
+    pub fn get_memtable(
+        &self,
+        key: &String,
+    ) -> std::io::Result<Option<String>> {
+        Ok(self.get_memtable_ref(key)?.map(str::to_string))
+    }
+
+    // Same as get_memtable, but borrows the value instead of cloning it.
+    // The value is borrowed from the tree, so it can't be held across a set or
+    // a flush, which take the tree mutably and could replace or drop it.
+    pub fn get_memtable_ref(
+        &self,
+        key: &String,
+    ) -> std::io::Result<Option<&str>> {
+        match self.get_from_memtables(key) {
+            Some((value, _)) => {
+                value.value.as_deref().map(utf8_str).transpose()
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_from_memtables(
+        &self,
+        key: &String,
+    ) -> Option<(&MemtableValue, ValueSource)> {
+        // Query the active tree first.
+        if let Some(value) = self.active_memtable.get(key) {
+            return Some((value, ValueSource::ActiveMemtable));
+        }
+
+        // Key not found in active tree, query the flushed tree.
+        self.flush_memtable
+            .as_ref()
+            .and_then(|tree| tree.get(key))
+            .map(|value| (value, ValueSource::FlushMemtable))
+    }
+
+    async fn search_sstable(
+        &self,
+        index: usize,
+        key: &String,
+    ) -> glommio::Result<Option<Entry>, ()> {
+        Ok(self.search_sstable_position(index, key).await?.1)
+    }
+
+    // See binary_search_position.
+    async fn search_sstable_position(
+        &self,
+        index: usize,
+        key: &String,
+    ) -> glommio::Result<(u64, Option<Entry>), ()> {
+        let (data_filename, _) = self.sstable_paths(index);
+        let data_file = self.options.storage().open(&data_filename).await?;
+        let index_source = self.open_index(index).await?;
+        let counts = ReadCounts::default();
+        let result = binary_search_position(
+            &data_file,
+            &index_source,
+            key,
+            self.options.fixed_key_size,
+            self.options.bincode_config,
+            self.options.read_block_size,
+            &counts,
+        )
+        .await;
+        self.update_stats(|stats| {
+            stats.get_bytes_read += counts.bytes.get();
+            stats.get_reads += counts.reads.get();
+        });
+        result
+    }
+
+    // Get the index file of an sstable from the index cache, reading it to the
+    // cache first when it fits in the budget.
+    async fn open_index(
+        &self,
+        index: usize,
+    ) -> glommio::Result<IndexSource, ()> {
+        if let Some(bytes) = self.index_cache.borrow_mut().get(index) {
+            return Ok(IndexSource::Cached(bytes));
+        }
+
+        let (_, index_filename) = self.sstable_paths(index);
+        let index_file = self.options.storage().open(&index_filename).await?;
+        let budget = self.options.index_cache_budget;
+        let size = index_file.file_size().await?;
+        let pinned = self.index_cache.borrow().pinned.contains(&index);
+        if size as usize > budget && !pinned {
+            return Ok(IndexSource::File(index_file));
+        }
+
+        let bytes =
+            Rc::new(index_file.read_at(0, size as usize).await?.to_vec());
+        index_file.close().await?;
+        self.update_stats(|stats| {
+            stats.get_bytes_read += size;
+            stats.get_reads += 1;
+        });
+        self.index_cache
+            .borrow_mut()
+            .insert(index, bytes.clone(), budget);
+        Ok(IndexSource::Cached(bytes))
+    }
+
+    // Keep the index file of a live sstable in the index cache until it's
+    // unpinned, reading it to the cache now.
+    // Pinned index files count towards the index cache budget, and are cached
+    // even over it, leaving less room (or none) to the other index files.
+    // A compaction of a pinned sstable pins its outputs instead, which are
+    // read to the cache on their first search.
+    pub async fn pin_sstable(&self, index: usize) -> glommio::Result<(), ()> {
+        if !self.read_sstable_indices.contains(&index) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("sstable {} is not live", index),
+            )
+            .into());
+        }
+        let _guard = self.hold_sstables(&[index]);
+        self.index_cache.borrow_mut().pinned.insert(index);
+        self.open_index(index).await?;
+        Ok(())
+    }
+
+    // Let the index file of an sstable be evicted from the index cache again,
+    // returns whether it was pinned.
+    pub fn unpin_sstable(&self, index: usize) -> bool {
+        self.index_cache.borrow_mut().pinned.remove(&index)
+    }
+
+    // Read the index files of the sstables to the index cache, from the sstable
+    // holding the newest writes to the oldest, until the cache budget is
+    // reached, without evicting already cached index files.
+    // The filters of all sstables are always in memory since open.
+    pub async fn warm(&self) -> std::io::Result<()> {
+        let _guard = self.hold_sstable_files();
+
+        let mut indices = self.read_sstable_indices.clone();
+        indices.sort_by_key(|i| {
+            std::cmp::Reverse(self.sstable_headers[i].max_seq)
+        });
+
+        let budget = self.options.index_cache_budget;
+        for i in indices {
+            if self.index_cache.borrow().indices.contains_key(&i) {
+                continue;
+            }
+            let (_, index_filename) = self.sstable_paths(i);
+            let size = self.options.storage().size(&index_filename)? as usize;
+            if self.index_cache.borrow().bytes + size > budget {
+                break;
+            }
+            self.open_index(i).await?;
+        }
+        Ok(())
+    }
+
+    // Fields are stored as regular entries, under a key that starts with a NUL
+    // character, followed by the length of the key, the key and the field name.
+    // That way each field of a key is updated separately, and a get of a field
+    // finds its newest version, even when other fields of the same key were
+    // written to other sstables.
+    // Keys starting with a NUL character are reserved for fields: the other
+    // writes refuse them, and apply_raw_entry only accepts the ones that are
+    // field keys.
+    // Field keys vary in size with their key and field name, so a tree with a
+    // fixed key size has no fields.
+    fn field_key(key: &str, field: &str) -> String {
+        format!("{}{}", Self::field_key_prefix(key), field)
+    }
+
+    // The prefix of the keys of all the fields of a key.
+    fn field_key_prefix(key: &str) -> String {
+        format!("\0{}\0{}", key.len(), key)
+    }
+
+    fn is_field_key(key: &str) -> bool {
+        let Some((len, rest)) =
+            key.strip_prefix('\0').and_then(|key| key.split_once('\0'))
+        else {
+            return false;
+        };
+        len.parse::<usize>().is_ok_and(|key_len| {
+            key_len.to_string() == len && rest.is_char_boundary(key_len)
+        })
+    }
+
+    fn check_fields_supported(&self) -> glommio::Result<(), ()> {
+        if self.options.fixed_key_size.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "fields are not supported with a fixed key size",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub async fn set_field(
+        &mut self,
+        key: String,
+        field: String,
+        value: String,
+    ) -> glommio::Result<Option<String>, ()> {
+        self.check_fields_supported()?;
+        self.check_user_key(&key)?;
+        let field_key = Self::field_key(&key, &field);
+        self.check_utf8_previous(&field_key)?;
+        let previous = self
+            .write_value(field_key, Value::Inline(value.into_bytes()))
+            .await?;
+        Ok(utf8_value(previous)?)
+    }
+
+    pub async fn get_field(
+        &self,
+        key: &str,
+        field: &str,
+    ) -> glommio::Result<Option<String>, ()> {
+        self.get(&Self::field_key(key, field)).await
+    }
+
+    // All the fields of a key, by name, each with its newest value, merged
+    // from the memtables and the sstables they were written to.
+    pub async fn get_fields(
+        &self,
+        key: &str,
+    ) -> glommio::Result<BTreeMap<String, String>, ()> {
+        let prefix = Self::field_key_prefix(key);
+        let end = prefix_end(&prefix);
+        let mut fields = BTreeMap::new();
+        self.for_each_from(
+            &prefix,
+            end.as_ref(),
+            utf8_string,
+            |(field_key, value)| {
+                fields.insert(field_key[prefix.len()..].to_string(), value);
+                std::future::ready(ControlFlow::Continue(()))
+            },
+        )
+        .await?;
+        Ok(fields)
+    }
+
+    // The number of sstables that are queried from. The inputs of a compaction
+    // are counted until it finishes, and its outputs from then on.
+    pub fn sstable_count(&self) -> usize {
+        self.read_sstable_indices.len()
+    }
+
+    // The index of the sstable with the oldest writes of the sstables that are
+    // queried from, by their newest sequence number, as compaction outputs can
+    // have lower indices than older sstables.
+    pub fn oldest_sstable(&self) -> Option<usize> {
+        self.read_sstable_indices
+            .iter()
+            .min_by_key(|i| (self.sstable_headers[i].max_seq, **i))
+            .copied()
+    }
+
+    // Same as oldest_sstable, for the sstable with the newest writes.
+    pub fn newest_sstable(&self) -> Option<usize> {
+        self.read_sstable_indices
+            .iter()
+            .max_by_key(|i| (self.sstable_headers[i].max_seq, **i))
+            .copied()
+    }
+
+    // Information about all sstables that are queried from, from the oldest
+    // index to the newest.
+    pub fn sstable_info(&self) -> std::io::Result<Vec<SstableInfo>> {
+        let mut indices = self.read_sstable_indices.clone();
+        indices.sort();
+        indices
+            .into_iter()
+            .map(|index| {
+                let (data_path, _) = self.sstable_paths(index);
+                let header = &self.sstable_headers[&index];
+                Ok(SstableInfo {
+                    index,
+                    entries: header.entries,
+                    data_size: self.options.storage().size(&data_path)?,
+                    created_at: header.created_at,
+                    tombstones: header.tombstones,
+                })
+            })
+            .collect()
+    }
+
+    // The cost of compacting the given sstables, without writing anything.
+    // Without a scan, it's computed from the file sizes and the index headers
+    // alone. With a scan, the inputs are merged like a compaction does (reading
+    // all input entries), to count exactly what would be written.
+    pub async fn compaction_plan(
+        &self,
+        indices: Vec<usize>,
+        scan: bool,
+    ) -> std::io::Result<CompactionPlan> {
+        let _guard = self.hold_sstables(&indices);
+        let sstable_paths: Vec<(PathBuf, PathBuf)> =
+            indices.iter().map(|i| self.sstable_paths(*i)).collect();
+        let mut input_bytes = 0;
+        let storage = self.options.storage();
+        for (data_path, index_path) in &sstable_paths {
+            input_bytes += storage.size(data_path)?;
+            input_bytes += storage.size(index_path)?;
+        }
+        let mut input_entries = 0;
+        for i in &indices {
+            let Some(header) = self.sstable_headers.get(i) else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("sstable {} is not queried from", i),
+                ));
+            };
+            input_entries += header.entries;
+        }
+
+        let mut plan = CompactionPlan {
+            indices,
+            input_bytes,
+            input_entries,
+            output_entries: input_entries,
+            output_bytes: input_bytes
+                - (sstable_paths.len().max(1) as u64 - 1) * IndexHeader::size(),
+            scanned: scan,
+        };
+        if !scan {
+            return Ok(plan);
+        }
+
+        let fixed_key_size = self.options.fixed_key_size;
+        let config = self.options.bincode_config;
+        let versions_to_keep = self.options.versions_to_keep();
+        let item_size = index_item_size(fixed_key_size);
+        let mut sstable_readers = Self::open_sstable_readers(
+            self.options.storage(),
+            &sstable_paths,
+            None,
+            fixed_key_size,
+            config,
+        )
+        .await?;
+
+        let mut encoder =
+            EntryEncoder::new(config, self.options.restart_interval);
+        let mut offset_bytes = vec![0; item_size as usize];
+        let mut data_bytes = Vec::new();
+        let mut heap = BinaryHeap::new();
+        let mut sources_to_read: Vec<usize> =
+            (0..sstable_readers.len()).collect();
+        let mut last_key: Option<String> = None;
+        let mut last_key_versions = 0;
+        plan.output_entries = 0;
+        plan.output_bytes = IndexHeader::size();
+        loop {
+            for index in sources_to_read.drain(..) {
+                if let Some(entry) = sstable_readers[index]
+                    .next_entry(
+                        &mut offset_bytes,
+                        &mut data_bytes,
+                        fixed_key_size,
+                    )
+                    .await?
+                {
+                    heap.push(CompactionItem { entry, index });
+                }
+            }
+
+            let Some(next) = heap.pop() else {
+                break;
+            };
+            sources_to_read.push(next.index);
+
+            if last_key.as_ref() != Some(&next.entry.key) {
+                last_key_versions = 0;
+            }
+            if last_key_versions < versions_to_keep {
+                plan.output_entries += 1;
+                plan.output_bytes +=
+                    encoder.encode(&next.entry).len() as u64 + item_size;
+                last_key_versions += 1;
+                last_key = Some(next.entry.key);
+            }
+        }
+
+        Ok(plan)
+    }
+
+    // Returns up to n of the most recently set keys with their values, from the
+    // newest to the oldest, regardless of key order.
+    // Only writes that were not flushed yet are covered, so after a flush this
+    // returns nothing until new keys are set. Deleted keys are skipped.
+    // Fails with InvalidData when one of the values is not utf-8, see
+    // set_bytes.
+    pub fn recent(&self, n: usize) -> std::io::Result<Vec<(String, String)>> {
+        let mut seen = HashSet::new();
+        self.recent_writes
+            .iter()
+            .rev()
+            .filter(|key| seen.insert(*key))
+            .filter_map(|key| {
+                let value = self.active_memtable[key].value.clone()?;
+                Some(utf8_string(value).map(|value| (key.clone(), value)))
+            })
+            .take(n)
+            .collect()
+    }
+
+    pub async fn set(
+        &mut self,
+        key: String,
+        value: String,
+    ) -> glommio::Result<Option<String>, ()> {
+        self.check_utf8_previous(&key)?;
+        Ok(utf8_value(self.set_bytes(key, value.into_bytes()).await?)?)
+    }
+
+    // Same as set, but the value is any bytes, stored as they are (a string
+    // value is stored as its utf-8 bytes), for values encoded some other way,
+    // like the bincode values of typed::Bincode.
+    // Reading a value that is not utf-8 as a string (like with get) fails
+    // with InvalidData, see get_bytes, and so does overwriting it with a
+    // method that returns the previous value as a string (like set), before
+    // anything is written.
+    pub async fn set_bytes(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        self.check_user_key(&key)?;
+        self.write_value(key, Value::Inline(value)).await
+    }
+
+    // Same as get, with the value as it is stored, see set_bytes.
+    pub async fn get_bytes(
+        &self,
+        key: &String,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        let (entry, _) = self.get_entry(key).await?;
+        match entry {
+            Some(entry) => Ok(entry.value.into_inline()?),
+            None => Ok(None),
+        }
+    }
+
+    // Deletes the key by writing a tombstone for it, which hides the versions
+    // of the key in the sstables until compactions drop them, returning the
+    // previous value of the key in the memtable, like set.
+    pub async fn delete(
+        &mut self,
+        key: String,
+    ) -> glommio::Result<Option<String>, ()> {
+        self.check_utf8_previous(&key)?;
+        Ok(utf8_value(self.delete_bytes(key).await?)?)
+    }
+
+    // Same as delete, with the previous value as it is stored, see set_bytes.
+    pub async fn delete_bytes(
+        &mut self,
+        key: String,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        self.check_user_key(&key)?;
+        self.write_value(key, Value::Tombstone).await
+    }
+
+    // The methods that return the previous value of a key as a string check it
+    // before writing, so that a previous value that is not utf-8 fails the
+    // write, instead of being returned altered after it.
+    fn check_utf8_previous(&self, key: &String) -> std::io::Result<()> {
+        match self.active_memtable.get(key) {
+            Some(MemtableValue {
+                value: Some(value), ..
+            }) => utf8_str(value).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes the value of a key as a new entry, with the next sequence number.
+    async fn write_value(
+        &mut self,
+        key: String,
+        value: Value,
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let entry = Entry {
+            key,
+            value,
+            seq,
+            timestamp: nanos_since_epoch(),
+        };
+        let entry_encoded = self.options.bincode_config.serialize(&entry);
+        self.write_entry(entry, &entry_encoded).await
+    }
+
+    // Sets the key to new only if its value is expected, where None means the
+    // key must not be in the tree, returning whether it was set.
+    // Atomic because the tree has a single writer: the &mut self borrow keeps
+    // any other write from running between the get and the set, even while
+    // the get awaits on reads from disk.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> glommio::Result<bool, ()> {
+        if self.get(&key).await? != expected {
+            return Ok(false);
+        }
+        self.set(key, new).await?;
+        Ok(true)
+    }
+
+    // Write an entry encoded by get_raw_entry on another tree, keeping its
+    // sequence number, so entries must be applied in the order they were
+    // written.
+    pub async fn apply_raw_entry(
+        &mut self,
+        entry_encoded: &[u8],
+    ) -> glommio::Result<Option<String>, ()> {
+        let entry: Entry = self
+            .options
+            .bincode_config
+            .deserialize(entry_encoded)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("raw entry is malformed: {}", e),
+                )
+            })?;
+        if Self::is_field_key(&entry.key) {
+            self.check_fields_supported()?;
+        } else {
+            self.check_user_key(&entry.key)?;
+        }
+        if let Value::Log(_) = entry.value {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "raw entry points to a value log",
+            )
+            .into());
+        }
+
+        self.check_utf8_previous(&entry.key)?;
+        self.next_seq = self.next_seq.max(entry.seq + 1);
+        Ok(utf8_value(self.write_entry(entry, entry_encoded).await?)?)
+    }
+
+    // Set a value too big to buffer in a memtable, streaming it from the
+    // reader straight to a new value log, pointed to by a new sstable of a
+    // single entry, without going through the memtable and the WAL.
+    // As no WAL backs it, the value log and sstable are fdatasynced (and the
+    // directory too) before returning, so the write survives a crash once it
+    // returns, and is lost (and its files removed on open) when it crashes
+    // before.
+    // A version of the key in the active memtable would be found before the
+    // sstable, so the memtable is flushed first when it holds the key.
+    // Every call adds an sstable, for occasional huge values.
+    pub async fn set_large(
+        &mut self,
+        key: String,
+        mut value: impl AsyncRead + Unpin,
+    ) -> glommio::Result<(), ()> {
+        self.check_user_key(&key)?;
+        if self.active_memtable.get(&key).is_some() {
+            self.flush().await?;
+        }
+        if self.is_flushing() {
+            self.wait_for_flush().await;
+        }
+
+        let index = self.write_sstable_index;
+        let log = self.next_value_log();
+        let value_log_path = Self::get_value_log_path(self.dir.clone(), log);
+        let temp_paths = Self::get_flush_file_paths(self.dir.clone(), index);
+        let sstable_paths = self.sstable_file_paths(index);
+        let mut paths = vec![value_log_path.clone()];
+        paths.extend(temp_paths.files());
+        paths.extend(sstable_paths.files());
+
+        let seq = self.next_seq;
+        let storage = &**self.options.storage();
+        let result = async {
+            // Values are strings, so the bytes are checked to be UTF-8 as they
+            // are written, keeping the bytes of a character that is cut at
+            // the end of a read for the next one.
+            let mut writer = storage.create(&value_log_path).await?;
+            let mut pending = Vec::new();
+            let mut buf = vec![0; WAL_READ_SIZE];
+            let mut size = 0;
+            loop {
+                let read = value.read(&mut buf).await?;
+                pending.extend_from_slice(&buf[..read]);
+                let valid = match std::str::from_utf8(&pending) {
+                    Ok(_) => pending.len(),
+                    Err(e) if read > 0 && e.error_len().is_none() => {
+                        e.valid_up_to()
+                    }
+                    Err(e) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("value is not UTF-8: {}", e),
+                        )
+                        .into());
+                    }
+                };
+                writer.write_all(&pending[..valid]).await?;
+                pending.drain(..valid);
+                size += valid as u64;
+                if read == 0 {
+                    break;
+                }
+            }
+            writer.close().await?;
+
+            let timestamp = nanos_since_epoch();
+            let header = IndexHeader {
+                version: FORMAT_VERSION,
+                entries: 1,
+                keys: 1,
+                max_seq: seq,
+                max_timestamp: timestamp,
+                restart_interval: self.options.restart_interval,
+                created_at: timestamp,
+                tombstones: 0,
+                tombstone_rewrite: false,
+            };
+            let pointer = ValuePointer {
+                log,
+                offset: 0,
+                size,
+            };
+            let flushed = Self::write_sstable(
+                header,
+                [(&key, Value::Log(pointer), seq, timestamp)].into_iter(),
+                storage.create(&temp_paths.data_path).await?,
+                storage.create(&temp_paths.index_path).await?,
+                (storage, &temp_paths.meta_path),
+                self.options.sstable_layout(),
+                self.options.bincode_config,
+            )
+            .await?;
+            Self::rename_sstable_files(storage, &temp_paths, &sstable_paths)?;
+            Self::sync_files(storage, &self.dir, &paths).await?;
+            Ok((flushed, size))
+        }
+        .await;
+        let ((header, meta), size) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                Self::remove_files_of_failed_flush(storage, &paths);
+                return Err(e);
+            }
+        };
+
+        self.next_seq += 1;
+        self.update_stats(|stats| {
+            stats.bytes_set += key.len() as u64 + size;
+        });
+        self.read_sstable_indices.push(index);
+        self.sstable_headers.insert(index, header);
+        self.sstable_metas.insert(index, Rc::new(meta));
+        self.write_sstable_index += 2;
+        Ok(())
+    }
+
+    // Keys starting with a NUL character are reserved for fields, see
+    // field_key.
+    fn check_user_key(&self, key: &str) -> glommio::Result<(), ()> {
+        if key.starts_with('\0') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "key {:?} starts with a NUL character, which is reserved \
+                     for fields",
+                    key
+                ),
+            )
+            .into());
+        }
+        self.check_key(key)
+    }
+
+    fn check_key(&self, key: &str) -> glommio::Result<(), ()> {
+        if let Some(key_size) = self.options.fixed_key_size {
+            if key.len() != key_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "key '{}' is {} bytes, expected a fixed size of {}",
+                        key,
+                        key.len(),
+                        key_size
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_entry(
+        &mut self,
+        entry: Entry,
+        entry_encoded: &[u8],
+    ) -> glommio::Result<Option<Vec<u8>>, ()> {
+        let value = entry.value.into_inline()?;
+        let bytes_set =
+            (entry.key.len() + value.as_ref().map_or(0, Vec::len)) as u64;
+
+        // The memtable is only full here when its flush failed, the flush must
+        // succeed before accepting more writes. With manual flushes, only
+        // overwrites fit in it until it's flushed.
+        if self.active_memtable.capacity() == self.active_memtable.len() {
+            if !self.options.manual_flush_only {
+                let stall_start = Instant::now();
+                self.flush().await?;
+                self.record_write_stall(stall_start);
+                self.maybe_compact().await?;
+            } else if self.active_memtable.get(&entry.key).is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::OutOfMemory,
+                    format!(
+                        "memtable is full ({} keys), flush it before writing \
+                         new keys",
+                        self.active_memtable.len()
+                    ),
+                )
+                .into());
+            }
+        }
+
+        // Write to memtable in memory, which has room for the key after the
+        // check above, the error is never expected.
+        let result = self
+            .active_memtable
+            .set(
+                entry.key.clone(),
+                MemtableValue {
+                    value,
+                    seq: entry.seq,
+                    timestamp: entry.timestamp,
+                },
+            )
+            .map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::OutOfMemory, e)
+            })?
+            .and_then(|previous| previous.value);
+
+        if self.recent_writes.len() == self.active_memtable.capacity() {
+            self.recent_writes.pop_front();
+        }
+        self.recent_writes.push_back(entry.key);
+        self.last_write = Some(Instant::now());
+
+        // Write to WAL for persistance.
+        self.wal.append(entry_encoded).await?;
+        // Only writes that reached the WAL are counted.
+        self.update_stats(|stats| {
+            stats.bytes_set += bytes_set;
+            stats.wal_bytes_written +=
+                (WAL_RECORD_HEADER_SIZE + entry_encoded.len()) as u64;
+        });
+
+        // Capacity is full, flush the active tree to disk.
+        // When the flush fails, the write is still in the memtable and in the
+        // WAL, and the flush is tried again by the next write.
+        if self.active_memtable.capacity() == self.active_memtable.len()
+            && !self.options.manual_flush_only
+        {
+            let stall_start = Instant::now();
+            self.flush().await?;
+            self.record_write_stall(stall_start);
+            self.maybe_compact().await?;
+        }
+
+        Ok(result)
+    }
+
+    // fdatasync the WAL, so that the writes to the active memtable so far
+    // survive a power loss, and not only a crash of the process.
+    pub async fn sync_wal(&mut self) -> std::io::Result<()> {
+        self.wal.sync().await
+    }
+
+    // Flush the active memtable to a new sstable, returning its index, or None
+    // when the active memtable is empty and nothing was written.
+    pub async fn flush(&mut self) -> glommio::Result<Option<usize>, ()> {
+        if self.active_memtable.len() == 0 {
+            return Ok(None);
+        }
+
+        if self.is_flushing() {
+            let stall_start = Instant::now();
+            self.wait_for_flush().await;
+            self.record_write_stall(stall_start);
+        }
+
+        let flush_wal_path =
+            Self::get_wal_path(self.dir.clone(), self.memtable_index);
+
+        let next_memtable_index = self.memtable_index + 2;
+        let next_wal_path =
+            Self::get_wal_path(self.dir.clone(), next_memtable_index);
+
+        // Written to temporary paths, and renamed to the paths of the sstable
+        // once complete, so a crash during the flush never leaves a partial
+        // sstable behind.
+        let sstable_paths = self.sstable_file_paths(self.write_sstable_index);
+        let temp_paths = Self::get_flush_file_paths(
+            self.dir.clone(),
+            self.write_sstable_index,
+        );
+        let value_log = self.options.value_log_threshold.map(|threshold| {
+            let log = self.next_value_log();
+            let path = Self::get_value_log_path(self.dir.clone(), log);
+            (path, log, threshold)
+        });
+        let mut flush_paths = vec![next_wal_path.clone()];
+        flush_paths.extend(temp_paths.files());
+        flush_paths.extend(sstable_paths.files());
+        flush_paths.extend(value_log.iter().map(|(path, _, _)| path.clone()));
+
+        // Nothing is changed until all files are created, so that on failure
+        // the tree is left as it was, and the flush can be tried again.
+        let retry_policy = &self.options.retry_policy;
+        let storage = self.options.storage().clone();
+        let files: glommio::Result<_, ()> = async {
+            let wal_file = with_retries(retry_policy, || {
+                BufferedFile::create(&next_wal_path)
+            })
+            .await?;
+            let data_file = with_retries(retry_policy, || async {
+                Ok(storage.create(&temp_paths.data_path).await?)
+            })
+            .await?;
+            let index_file = with_retries(retry_policy, || async {
+                Ok(storage.create(&temp_paths.index_path).await?)
+            })
+            .await?;
+            Ok((wal_file, data_file, index_file))
+        }
+        .await;
+        let (wal_file, data_file, index_file) = match files {
+            Ok(files) => files,
+            Err(e) => {
+                Self::remove_files_of_failed_flush(&*storage, &flush_paths);
+                return Err(e);
+            }
+        };
+
+        let previous_wal = std::mem::replace(
+            &mut self.wal,
+            self.options.wal(wal_file, next_wal_path.clone()),
+        );
+        self.memtable_index = next_memtable_index;
+        let mut memtable_to_flush =
+            RedBlackTree::with_capacity(self.active_memtable.capacity());
+        std::mem::swap(&mut memtable_to_flush, &mut self.active_memtable);
+        self.flush_memtable = Some(memtable_to_flush);
+        let recent_writes = std::mem::take(&mut self.recent_writes);
+        let last_write = self.last_write.take();
+
+        let result = Self::flush_memtable_to_disk(
+            &*storage,
+            self.flush_memtable.as_ref().unwrap(),
+            (data_file, index_file, &temp_paths.meta_path),
+            value_log,
+            self.options.sstable_layout(),
+            self.options.bincode_config,
+        )
+        .await;
+        let result = match result {
+            Ok(flushed) => async {
+                Self::rename_sstable_files(
+                    &*storage,
+                    &temp_paths,
+                    &sstable_paths,
+                )?;
+                if self.options.sync_on_flush {
+                    Self::sync_files(&*storage, &self.dir, &flush_paths[1..])
+                        .await?;
+                }
+                Ok(flushed)
+            }
+            .await
+            .map_err(|e: std::io::Error| e.into()),
+            result => result,
+        };
+        let (header, meta) = match result {
+            Ok(flushed) => flushed,
+            Err(e) => {
+                // Back to before the flush, the entries are still in the
+                // active memtable and in its WAL.
+                self.wal = previous_wal;
+                self.memtable_index -= 2;
+                self.active_memtable = self.flush_memtable.take().unwrap();
+                self.recent_writes = recent_writes;
+                self.last_write = last_write;
+                Self::remove_files_of_failed_flush(&*storage, &flush_paths);
+                return Err(e);
+            }
+        };
+
+        // Nothing can fail from here until the flushed memtable is dropped, so
+        // it's never left behind by an error.
+        let flushed_bytes: u64 = flush_paths[1..]
+            .iter()
+            .map(|path| storage.size(path).unwrap_or(0))
+            .sum();
+        self.update_stats(|stats| stats.flush_bytes_written += flushed_bytes);
+
+        // The sstable is queried from before the flushed memtable is dropped,
+        // so there is no point in between where a get can't find its keys.
+        // Its files were closed and renamed to their paths above, and as gets
+        // borrow the tree, none can run in between anyway.
+        let flushed_index = self.write_sstable_index;
+        self.read_sstable_indices.push(flushed_index);
+        self.sstable_headers.insert(flushed_index, header);
+        self.sstable_metas.insert(flushed_index, Rc::new(meta));
+        self.flush_memtable = None;
+        self.write_sstable_index += 2;
+
+        Self::remove_or_archive_wal(
+            &self.dir,
+            &flush_wal_path,
+            self.options.wal_archive,
+        )?;
+
+        Ok(Some(flushed_index))
+    }
+
+    // Flush the active memtable and merge it with the newest sstable into a
+    // single sstable, instead of adding another sstable to the tree, returning
+    // the index of the merged sstable, or None when the active memtable is
+    // empty.
+    // The memtable is written to an sstable by a regular flush first (its
+    // WAL can only be removed once its writes are in an sstable), which is
+    // then compacted with the newest sstable, so a crash at any point leaves
+    // either both sstables or the merged one.
+    // Without other sstables, this is a regular flush.
+    pub async fn flush_into_newest(
+        &mut self,
+    ) -> glommio::Result<Option<usize>, ()> {
+        let newest = self
+            .read_sstable_indices
+            .iter()
+            .max_by_key(|i| self.sstable_headers[i].max_seq)
+            .copied();
+        let Some(flushed_index) = self.flush().await? else {
+            return Ok(None);
+        };
+        let Some(newest) = newest else {
+            return Ok(Some(flushed_index));
+        };
+
+        let output_index = self.unused_sstable_indices(1)[0];
+        self.compact(vec![newest, flushed_index], output_index)
+            .await?;
+        Ok(Some(output_index))
+    }
+
+    // fdatasync the given files that exist, and then the directory they are in,
+    // for their entries in it.
+    async fn sync_files(
+        storage: &dyn Storage,
+        dir: &Path,
+        paths: &[PathBuf],
+    ) -> std::io::Result<()> {
+        for path in paths.iter().filter(|path| storage.exists(path)) {
+            storage.sync(path).await?;
+        }
+        storage.sync(dir).await
+    }
+
+    // A sorted copy of the entries of the active memtable (without the deleted
+    // keys), meant for tests and debugging, as it clones every entry.
+    // Fails with InvalidData when one of the values is not utf-8, see
+    // set_bytes.
+    pub fn memtable_entries(&self) -> std::io::Result<Vec<(String, String)>> {
+        Self::memtable_snapshot(&self.active_memtable)
+    }
+
+    // A sorted copy of the entries of the memtable being flushed, empty when
+    // no flush is in progress. Clones like memtable_entries.
+    pub fn flush_memtable_entries(
+        &self,
+    ) -> std::io::Result<Vec<(String, String)>> {
+        match &self.flush_memtable {
+            Some(memtable) => Self::memtable_snapshot(memtable),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn memtable_snapshot(
+        memtable: &RedBlackTree<String, MemtableValue>,
+    ) -> std::io::Result<Vec<(String, String)>> {
+        memtable
+            .iter()
+            .filter_map(|(key, value)| {
+                let value = value.value.clone()?;
+                Some(utf8_string(value).map(|value| (key.clone(), value)))
+            })
+            .collect()
+    }
+
+    // Whether a memtable is being written to an sstable.
+    pub fn is_flushing(&self) -> bool {
+        self.flush_memtable.is_some()
+    }
+
+    // Resolves once no flush is in progress.
+    pub async fn wait_for_flush(&self) {
+        while self.is_flushing() {
+            futures_lite::future::yield_now().await;
+        }
+    }
+
+    // The time at which the active memtable should be flushed for being idle,
+    // None when there is no idle flush timeout or nothing to flush.
+    pub fn idle_flush_deadline(&self) -> Option<Instant> {
+        let timeout = self.options.idle_flush_timeout?;
+        Some(self.last_write? + timeout)
+    }
+
+    // Flush the active memtable if its idle flush deadline has passed,
+    // returning the index of the written sstable.
+    pub async fn flush_if_idle(
+        &mut self,
+    ) -> glommio::Result<Option<usize>, ()> {
+        match self.idle_flush_deadline() {
+            Some(deadline) if deadline <= Instant::now() => self.flush().await,
+            _ => Ok(None),
+        }
+    }
+
+    // The values of at least the threshold of the value log (given with its
+    // path and id) are written to it, it's only created when there is such a
+    // value.
+    async fn flush_memtable_to_disk(
+        storage: &dyn Storage,
+        memtable: &RedBlackTree<String, MemtableValue>,
+        (data_file, index_file, meta_path): (
+            StorageWriter,
+            StorageWriter,
+            &PathBuf,
+        ),
+        value_log: Option<(PathBuf, usize, usize)>,
+        layout: SstableLayout,
+        config: BincodeConfig,
+    ) -> glommio::Result<(IndexHeader, SstableMeta), ()> {
+        let header = IndexHeader {
+            version: FORMAT_VERSION,
+            entries: memtable.len() as u64,
+            keys: memtable.len() as u64,
+            max_seq: Self::memtable_max_seq(memtable).unwrap_or(0),
+            max_timestamp: memtable
+                .iter()
+                .map(|(_, value)| value.timestamp)
+                .max()
+                .unwrap_or(0),
+            restart_interval: layout.restart_interval,
+            created_at: nanos_since_epoch(),
+            tombstones: memtable
+                .iter()
+                .filter(|(_, value)| value.value.is_none())
+                .count() as u64,
+            tombstone_rewrite: false,
+        };
+
+        let mut pointers = vec![None; memtable.len()];
+        if let Some((path, log, threshold)) = value_log {
+            let mut writer = None;
+            let mut offset = 0;
+            for (i, (_, value)) in memtable.iter().enumerate() {
+                let Some(value) = value
+                    .value
+                    .as_ref()
+                    .filter(|value| value.len() >= threshold)
+                else {
+                    continue;
+                };
+                if writer.is_none() {
+                    writer = Some(storage.create(&path).await?);
+                }
+                writer.as_mut().unwrap().write_all(value).await?;
+                let size = value.len() as u64;
+                pointers[i] = Some(ValuePointer { log, offset, size });
+                offset += size;
+            }
+            if let Some(mut writer) = writer {
+                writer.close().await?;
+            }
+        }
+
+        let entries =
+            memtable
+                .iter()
+                .zip(pointers)
+                .map(|((key, value), pointer)| {
+                    let stored = match pointer {
+                        Some(pointer) => Value::Log(pointer),
+                        None => value.stored(),
+                    };
+                    (key, stored, value.seq, value.timestamp)
+                });
+        Self::write_sstable(
+            header,
+            entries,
+            data_file,
+            index_file,
+            (storage, meta_path),
+            layout,
+            config,
+        )
+        .await
+    }
+
+    // Write the given entries, sorted by key with the newest version of a key
+    // first, to a new sstable described by the given header.
+    async fn write_sstable<'a>(
+        header: IndexHeader,
+        entries: impl Iterator<Item = (&'a String, Value, u64, u64)>,
+        mut data_write_stream: StorageWriter,
+        mut index_write_stream: StorageWriter,
+        (storage, meta_path): (&dyn Storage, &PathBuf),
+        layout: SstableLayout,
+        config: BincodeConfig,
+    ) -> glommio::Result<(IndexHeader, SstableMeta), ()> {
+        let meta = Self::write_sstable_entries(
+            header,
+            entries,
+            &mut data_write_stream,
+            &mut index_write_stream,
+            layout,
+            config,
+        )
+        .await?;
+        // Closing completes the files (for local files, it writes the buffers
+        // that are left, waits for all writes behind, and truncates the files
+        // to the bytes written, as DMA writes are padded to the alignment), so
+        // the sstable is readable once it returns.
+        data_write_stream.close().await?;
+        index_write_stream.close().await?;
+        Self::write_sstable_meta(storage, meta_path, &meta).await?;
+
+        Ok((header, meta))
+    }
+
+    // Same as write_sstable, to any writers, which are not closed.
+    // The restart interval is the one of the header, not of the layout.
+    async fn write_sstable_entries<'a>(
+        header: IndexHeader,
+        entries: impl Iterator<Item = (&'a String, Value, u64, u64)>,
+        data_writer: &mut (impl AsyncWrite + Unpin),
+        index_writer: &mut (impl AsyncWrite + Unpin),
+        layout: SstableLayout,
+        config: BincodeConfig,
+    ) -> std::io::Result<SstableMeta> {
+        index_writer.write_all(&header.encode()).await?;
+
+        let mut meta =
+            SstableMeta::new(header.keys as usize, layout.false_positive_rate);
+        let mut encoder = EntryEncoder::new(config, header.restart_interval);
+        let mut entry_offset = 0;
+        for (key, value, seq, timestamp) in entries {
+            meta.insert(key, &value);
+            let entry = Entry {
+                key: key.to_string(),
+                value,
+                seq,
+                timestamp,
+            };
+            let entry_encoded = encoder.encode(&entry);
+            let entry_size = entry_encoded.len();
+            let entry_index = EntryOffset {
+                entry_offset,
+                entry_size,
+            };
+            entry_offset += entry_size as u64;
+            let index_encoded =
+                encode_index_item(&entry_index, key, layout.fixed_key_size);
+
+            // The data and index files are independent, so write to both at
+            // once.
+            futures_lite::future::try_zip(
+                data_writer.write_all(&entry_encoded),
+                index_writer.write_all(&index_encoded),
+            )
+            .await?;
+        }
+
+        Ok(meta)
+    }
+
+    // Write the newest version of every key in a WAL to an sstable at the given
+    // data, index and meta paths, without reading the whole WAL to memory.
+    async fn flush_wal_to_disk(
+        storage: &Rc<dyn Storage>,
+        dir: &Path,
+        wal_path: &PathBuf,
+        sstable_paths: SstablePaths,
+        layout: SstableLayout,
+        config: BincodeConfig,
+    ) -> std::io::Result<()> {
+        let mut reader = WalReader::open(wal_path, config).await?;
+        {
+            let entries =
+                futures_lite::stream::unfold(&mut reader, |reader| async {
+                    let entry = reader.next().await.transpose()?;
+                    Some((entry, reader))
+                });
+            futures_lite::pin!(entries);
+            Self::external_sort(
+                storage,
+                entries,
+                WAL_SORT_MEMORY_BUDGET,
+                &dir.join(wal_path.file_name().unwrap()),
+                sstable_paths,
+                layout,
+                config,
+            )
+            .await?;
+        }
+        reader.close().await?;
+        Ok(())
+    }
+
+    // Write the newest version of every key of entries in any order to an
+    // sstable at the given data, index and meta paths, in memory bounded by
+    // memory_budget bytes (of keys and values).
+    // The entries are sorted in runs of up to memory_budget bytes, each
+    // written to a temporary sstable at temp_prefix with the number of the
+    // run and a run_* extension, which are then merged like the inputs of a
+    // compaction (to temp_prefix with a merged_* extension).
+    // temp_prefix should be named after a file of the tree, so the temporary
+    // files of a crashed sort are removed on open, see FileKind::of.
+    async fn external_sort(
+        storage: &Rc<dyn Storage>,
+        mut entries: impl Stream<Item = std::io::Result<Entry>> + Unpin,
+        memory_budget: usize,
+        temp_prefix: &Path,
+        sstable_paths: SstablePaths,
+        layout: SstableLayout,
+        config: BincodeConfig,
+    ) -> std::io::Result<()> {
+        let temp_path = |name: String| {
+            let mut path = temp_prefix.as_os_str().to_owned();
+            path.push(name);
+            PathBuf::from(path)
+        };
+
+        let mut run_paths: Vec<SstablePaths> = Vec::new();
+        let mut done = false;
+        while !done {
+            let mut run = Vec::new();
+            let mut run_bytes = 0;
+            while run_bytes < memory_budget {
+                match entries.next().await {
+                    Some(entry) => {
+                        let entry = entry?;
+                        run_bytes += entry.key.len() + entry.value.len();
+                        run.push(entry);
+                    }
+                    None => {
+                        done = true;
+                        break;
+                    }
+                }
+            }
+            if run.is_empty() && !run_paths.is_empty() {
+                break;
+            }
+
+            // The newest version of every key first, then drop the others.
+            run.sort_by(|a, b| a.key.cmp(&b.key).then(b.seq.cmp(&a.seq)));
+            run.dedup_by(|a, b| a.key == b.key);
+
+            let run_path = |extension: &str| {
+                temp_path(format!(".{}.{}", run_paths.len(), extension))
+            };
+            let paths = SstablePaths {
+                data_path: run_path("run_data"),
+                index_path: run_path("run_index"),
+                meta_path: run_path("run_meta"),
+            };
+            let header = IndexHeader {
+                version: FORMAT_VERSION,
+                entries: run.len() as u64,
+                keys: run.len() as u64,
+                max_seq: run.iter().map(|entry| entry.seq).max().unwrap_or(0),
+                max_timestamp: run
+                    .iter()
+                    .map(|entry| entry.timestamp)
+                    .max()
+                    .unwrap_or(0),
+                restart_interval: layout.restart_interval,
+                created_at: nanos_since_epoch(),
+                tombstones: run
+                    .iter()
+                    .filter(|entry| entry.value == Value::Tombstone)
+                    .count() as u64,
+                tombstone_rewrite: false,
+            };
+            Self::write_sstable(
+                header,
+                run.iter().map(|entry| {
+                    (
+                        &entry.key,
+                        entry.value.clone(),
+                        entry.seq,
+                        entry.timestamp,
+                    )
+                }),
+                storage.create(&paths.data_path).await?,
+                storage.create(&paths.index_path).await?,
+                (&**storage, &paths.meta_path),
+                layout,
+                config,
+            )
+            .await?;
+            run_paths.push(paths);
+        }
+
+        if run_paths.len() == 1 {
+            return Self::rename_sstable_files(
+                &**storage,
+                &run_paths[0],
+                &sstable_paths,
+            );
+        }
+
+        let merged_path =
+            |extension: &str| temp_path(format!(".{}", extension));
+        let merged_paths = SstablePaths {
+            data_path: merged_path("merged_data"),
+            index_path: merged_path("merged_index"),
+            meta_path: merged_path("merged_meta"),
+        };
+        Self::write_compaction_output(
+            CompactionInputs {
+                storage: storage.clone(),
+                sstables: run_paths
+                    .iter()
+                    .map(|paths| {
+                        (paths.data_path.clone(), paths.index_path.clone(), 0)
+                    })
+                    .collect(),
+            },
+            merged_paths.clone(),
+            layout,
+            config,
+            MergeSettings::all_keys(1, TombstonePolicy::Keep),
+            None,
+        )
+        .await?;
+        Self::rename_sstable_files(&**storage, &merged_paths, &sstable_paths)?;
+        for paths in run_paths {
+            for path in paths.files() {
+                Self::remove_file_log_on_err(&**storage, &path);
+            }
+        }
+        Ok(())
+    }
+
+    // Compact all sstables in the given list of sstable files, write the result
+    // to the output file given.
+    pub async fn compact(
+        &mut self,
+        indices_to_compact: Vec<usize>,
+        output_index: usize,
+    ) -> std::io::Result<()> {
+        let mut compaction =
+            self.start_compaction(indices_to_compact, output_index)?;
+        compaction.run(&PauseToken::new()).await?;
+        self.finish_compaction_job(compaction).await
+    }
+
+    // Compact the sstables created more than age ago (and not being compacted
+    // already) together, returning the index of the output, or None when
+    // there are less than 2 of them.
+    // For data that is rarely read once it's old, like time series, so the
+    // old sstables are merged into a cold sstable, apart from the recent ones.
+    // The output keeps the creation time of its oldest input (see
+    // IndexHeader::created_at), so it's compacted again with the sstables that
+    // grow old later on.
+    pub async fn compact_older_than(
+        &mut self,
+        age: Duration,
+    ) -> std::io::Result<Option<usize>> {
+        let oldest = nanos_since_epoch().saturating_sub(age.as_nanos() as u64);
+        let mut indices: Vec<usize> = {
+            let compacting = self.compacting.borrow();
+            self.read_sstable_indices
+                .iter()
+                .filter(|i| !compacting.contains(i))
+                .filter(|i| self.sstable_headers[i].created_at < oldest)
+                .copied()
+                .collect()
+        };
+        if indices.len() < 2 {
+            return Ok(None);
+        }
+        indices.sort();
+
+        let output_index = self.unused_sstable_indices(1)[0];
+        self.compact(indices, output_index).await?;
+        Ok(Some(output_index))
+    }
+
+    // The sstables to compact together and the index of the output, when the
+    // min_sstables_to_compact option is set and there are at least that many
+    // sstables that are not being compacted already, or when the gets search
+    // too many sstables (see LSMTreeOptions::with_read_amplification_limit)
+    // and there are at least 2.
+    pub fn pick_compaction(&self) -> Option<(Vec<usize>, usize)> {
+        let read_amplified = self
+            .options
+            .read_amplification_limit
+            .zip(self.read_amplification())
+            .is_some_and(|((_, max_searched), average)| average > max_searched);
+        let min_sstables = if read_amplified {
+            2
+        } else {
+            self.options.min_sstables_to_compact?.max(2)
+        };
+        let compacting = self.compacting.borrow();
+        let candidates: Vec<usize> = self
+            .read_sstable_indices
+            .iter()
+            .filter(|i| !compacting.contains(i))
+            .copied()
+            .collect();
+        if candidates.len() < min_sstables {
+            return None;
+        }
+
+        Some((candidates, self.unused_sstable_indices(1)[0]))
+    }
+
+    // Compact the sstables picked by the policy, out of the sstables that are
+    // not being compacted already, into a new sstable, returning what was
+    // compacted, or None when the policy picked nothing.
+    pub async fn run_compaction(
+        &mut self,
+        policy: CompactionPolicy,
+    ) -> std::io::Result<Option<CompactionResult>> {
+        let sstables: Vec<SstableInfo> = {
+            let compacting = self.compacting.borrow();
+            self.sstable_info()?
+                .into_iter()
+                .filter(|sstable| !compacting.contains(&sstable.index))
+                .collect()
+        };
+        let Some(mut inputs) =
+            policy.pick(&sstables, self.options.min_sstables_to_compact)
+        else {
+            return Ok(None);
+        };
+        inputs.sort();
+        inputs.dedup();
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+        if let Some(index) = inputs
+            .iter()
+            .find(|i| !sstables.iter().any(|sstable| sstable.index == **i))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "sstable {} was picked, but is not live or is being \
+                     compacted",
+                    index
+                ),
+            ));
+        }
+
+        let output = self.unused_sstable_indices(1)[0];
+        let before = self.stats();
+        self.compact(inputs.clone(), output).await?;
+        let after = self.stats();
+        Ok(Some(CompactionResult {
+            inputs,
+            output,
+            bytes_read: after.compaction_bytes_read
+                - before.compaction_bytes_read,
+            bytes_written: after.compaction_bytes_written
+                - before.compaction_bytes_written,
+        }))
+    }
+
+    // Sstable indices above every index in use, and of the other parity than
+    // the flushed sstables, so that no future flush writes to them.
+    fn unused_sstable_indices(&self, n: usize) -> Vec<usize> {
+        let compacting = self.compacting.borrow();
+        let mut first = self
+            .read_sstable_indices
+            .iter()
+            .chain(compacting.iter())
+            .chain([&self.write_sstable_index])
+            .max()
+            .unwrap()
+            + 1;
+        if first % 2 == self.write_sstable_index % 2 {
+            first += 1;
+        }
+        (0..n).map(|i| first + i * 2).collect()
+    }
+
+    // Compact the sstables picked by pick_compaction, then rewrite the oldest
+    // sstable when it's mostly tombstones, returns whether there was anything
+    // to compact.
+    // Called after every flush of a full memtable, a tree that is mostly read
+    // from should call it too, to compact once its gets search too many
+    // sstables.
+    pub async fn maybe_compact(&mut self) -> std::io::Result<bool> {
+        let mut compacted = false;
+        if let Some((indices_to_compact, output_index)) = self.pick_compaction()
+        {
+            self.compact(indices_to_compact, output_index).await?;
+            compacted = true;
+        }
+        // After the compaction, as its output might be the oldest sstable.
+        if let Some((index, output_index)) = self.pick_tombstone_rewrite() {
+            let mut compaction =
+                self.start_compaction(vec![index], output_index)?;
+            compaction.run(&PauseToken::new()).await?;
+            compaction.mark_tombstone_rewrite().await?;
+            self.finish_compaction_job(compaction).await?;
+            compacted = true;
+        }
+        Ok(compacted)
+    }
+
+    // The oldest sstable and the index of its rewrite, when more than the
+    // threshold of its entries are tombstones, see
+    // LSMTreeOptions::with_tombstone_rewrite_threshold.
+    // An sstable written by a rewrite is not rewritten alone again (see
+    // IndexHeader::tombstone_rewrite), as the tombstones the rewrite kept are
+    // kept again until other sstables are compacted.
+    fn pick_tombstone_rewrite(&self) -> Option<(usize, usize)> {
+        let threshold = self.options.tombstone_rewrite_threshold?;
+        let index = self.oldest_sstable()?;
+        let header = &self.sstable_headers[&index];
+        let fraction = header.tombstones as f64 / header.entries.max(1) as f64;
+        // Once every tombstone in it is past the grace period, so that the
+        // rewrite can drop them all.
+        let (seq, timestamp) = self.tombstone_grace_bounds(self.next_seq);
+        if fraction <= threshold
+            || header.max_seq >= seq
+            || header.max_timestamp >= timestamp
+            || header.tombstone_rewrite
+            || self.compacting.borrow().contains(&index)
+        {
+            return None;
+        }
+        Some((index, self.unused_sstable_indices(1)[0]))
+    }
+
+    // Same as compact, but split so that the merge, which is the long part, can
+    // run (and be paused) while the tree is used, see Compaction::run.
+    // Compactions of disjoint sstables can run concurrently, starting one that
+    // shares an input or an output with a running compaction fails, until the
+    // running compaction is finished or dropped.
+    pub fn start_compaction(
+        &self,
+        indices_to_compact: Vec<usize>,
+        output_index: usize,
+    ) -> std::io::Result<Compaction> {
+        let reservation = self.reserve_for_compaction(
+            indices_to_compact.iter().copied().chain([output_index]),
+        )?;
+        let sstable_paths = indices_to_compact
+            .iter()
+            .map(|i| self.sstable_paths(*i))
+            .collect();
+        let files_guard = self.hold_sstables(&indices_to_compact);
+        let tombstones = self.tombstone_policy(&indices_to_compact);
+        Ok(Compaction {
+            indices_to_compact,
+            output_index,
+            sstable_paths,
+            compact_paths: Self::get_compaction_output_paths(
+                self.compaction_output_dir(),
+                output_index,
+            ),
+            layout: self.options.sstable_layout(),
+            config: self.options.bincode_config,
+            versions_to_keep: self.options.versions_to_keep(),
+            tombstones,
+            storage: self.options.storage().clone(),
+            output: None,
+            _files_guard: files_guard,
+            _reservation: reservation,
+        })
+    }
+
+    // Tombstones are dropped by a merge of the given live sstables unless
+    // another live sstable might hold their key. The sstables written while
+    // the merge runs only hold newer versions.
+    fn tombstone_policy(&self, indices_to_merge: &[usize]) -> TombstonePolicy {
+        TombstonePolicy::DropUnlessIn(
+            self.read_sstable_indices
+                .iter()
+                .filter(|i| !indices_to_merge.contains(i))
+                .map(|i| self.sstable_metas.get(i).cloned())
+                .collect(),
+            self.tombstone_grace_bounds(self.next_seq),
+        )
+    }
+
+    // The sequence number and the time (in nanoseconds since the unix epoch)
+    // a tombstone must be written before to be past the grace period, given
+    // the next sequence number of the merged entries, see
+    // LSMTreeOptions::with_tombstone_grace_period.
+    fn tombstone_grace_bounds(&self, next_seq: u64) -> (u64, u64) {
+        match self.options.tombstone_grace_period {
+            None => (u64::MAX, u64::MAX),
+            Some(TombstoneGracePeriod::Writes(writes)) => {
+                (next_seq.saturating_sub(writes), u64::MAX)
+            }
+            Some(TombstoneGracePeriod::For(period)) => (
+                u64::MAX,
+                nanos_since_epoch().saturating_sub(period.as_nanos() as u64),
+            ),
+        }
+    }
+
+    fn reserve_for_compaction(
+        &self,
+        indices: impl IntoIterator<Item = usize>,
+    ) -> std::io::Result<CompactionReservation> {
+        let indices: Vec<usize> = indices.into_iter().collect();
+        let mut compacting = self.compacting.borrow_mut();
+        if let Some(index) = indices.iter().find(|i| compacting.contains(i)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("sstable {} is being compacted", index),
+            ));
+        }
+        compacting.extend(&indices);
+        Ok(CompactionReservation {
+            indices,
+            compacting: self.compacting.clone(),
+        })
+    }
+
+    // Replace the inputs of a compaction that finished running with its output.
+    // Fails when the compaction didn't run, or when one of its inputs was
+    // compacted by another compaction in the meantime.
+    pub async fn finish_compaction_job(
+        &mut self,
+        compaction: Compaction,
+    ) -> std::io::Result<()> {
+        let Some((header, meta)) = compaction.output else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "compaction did not run",
+            ));
+        };
+        if let Some(index) = compaction
+            .indices_to_compact
+            .iter()
+            .find(|i| !self.read_sstable_indices.contains(i))
+        {
+            let storage = self.options.storage();
+            for path in compaction.compact_paths.files() {
+                if storage.exists(&path) {
+                    Self::remove_file_log_on_err(&**storage, &path);
+                }
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "sstable {} was compacted during the compaction",
+                    index
+                ),
+            ));
+        }
+
+        // The compaction is done reading its inputs, so they can be deleted
+        // right away when nothing else reads them.
+        drop(compaction._files_guard);
+        self.finish_compaction(
+            &compaction.indices_to_compact,
+            vec![(compaction.output_index, header, meta)],
+        )
+        .await
+    }
+
+    // Same as compact, but the merged output is partitioned by key range into
+    // up to output_indices.len() sstables, which are all written concurrently.
+    // The key ranges are chosen by sampling the input index files, so the
+    // outputs are only approximately equal in size, and when there are less
+    // distinct keys than outputs, the last output indices are left unused.
+    pub async fn compact_parallel(
+        &mut self,
+        indices_to_compact: Vec<usize>,
+        output_indices: Vec<usize>,
+    ) -> std::io::Result<()> {
+        if output_indices.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no output indices to compact to",
+            ));
+        }
+        let _reservation = self.reserve_for_compaction(
+            indices_to_compact.iter().chain(&output_indices).copied(),
+        )?;
+        let sstable_paths: Vec<(PathBuf, PathBuf)> = indices_to_compact
+            .iter()
+            .map(|i| self.sstable_paths(*i))
+            .collect();
+
+        let fixed_key_size = self.options.fixed_key_size;
+        let config = self.options.bincode_config;
+        let versions_to_keep = self.options.versions_to_keep();
+        let storage = self.options.storage();
+        let split_keys = Self::sample_split_keys(
+            &**storage,
+            &sstable_paths,
+            output_indices.len(),
+            fixed_key_size,
+            config,
+        )
+        .await?;
+        let output_indices: Vec<usize> = output_indices
+            .into_iter()
+            .take(split_keys.len() + 1)
+            .collect();
+        let tombstones = self.tombstone_policy(&indices_to_compact);
+
+        let mut tasks = Vec::with_capacity(output_indices.len());
+        for (i, output_index) in output_indices.iter().enumerate() {
+            let start = if i > 0 {
+                Some(split_keys[i - 1].clone())
+            } else {
+                None
+            };
+            let end = split_keys.get(i).cloned();
+            tasks.push(glommio::spawn_local(Self::write_compaction_output(
+                CompactionInputs {
+                    storage: storage.clone(),
+                    sstables: without_seq_offsets(&sstable_paths),
+                },
+                Self::get_compaction_output_paths(
+                    self.compaction_output_dir(),
+                    *output_index,
+                ),
+                self.options.sstable_layout(),
+                config,
+                MergeSettings {
+                    start,
+                    end,
+                    versions_to_keep,
+                    filter: None,
+                    tombstones: tombstones.clone(),
+                },
+                None,
+            )));
+        }
+
+        let mut result = Ok(());
+        let mut outputs = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            match task.await {
+                Ok(output) => outputs.push(output),
+                Err(e) => result = Err(e),
+            }
+        }
+        if let Err(e) = result {
+            // Nothing points to the partial outputs yet, so on failure it is
+            // enough to just remove them.
+            for output_index in &output_indices {
+                let paths = Self::get_compaction_output_paths(
+                    self.compaction_output_dir(),
+                    *output_index,
+                );
+                for path in paths.files() {
+                    if storage.exists(&path) {
+                        Self::remove_file_log_on_err(&**storage, &path);
+                    }
+                }
+            }
+            return Err(e);
+        }
+
+        let outputs = output_indices
+            .into_iter()
+            .zip(outputs)
+            .map(|(index, (header, meta))| (index, header, meta))
+            .collect();
+        self.finish_compaction(&indices_to_compact, outputs).await
+    }
+
+    // A histogram of the sizes of the values in the live sstables, reading
+    // every sample_every'th entry of each sstable (all entries when it's 1).
+    // Sampling reads about 1 / sample_every of the entries, and the error of
+    // a bucket grows as it holds fewer samples, so rare sizes are only seen
+    // without sampling.
+    // Every version of a key is counted, values in value logs are counted by
+    // their size without reading them, and the memtables are ignored.
+    pub async fn value_size_histogram(
+        &self,
+        sample_every: u64,
+    ) -> std::io::Result<ValueSizeHistogram> {
+        let sample_every = sample_every.max(1);
+        let mut histogram = ValueSizeHistogram {
+            sample_every,
+            buckets: Vec::new(),
+        };
+
+        let _guard = self.hold_sstable_files();
+        for i in &self.read_sstable_indices {
+            let header = self.sstable_headers[i];
+            let (data_path, _) = self.sstable_paths(*i);
+            let data_file = self.options.storage().open(&data_path).await?;
+            let index = self.open_index(*i).await?;
+            let mut position = 0;
+            while position < header.entries {
+                let entry = read_entry_at(
+                    &data_file,
+                    &index,
+                    position,
+                    header.restart_interval,
+                    self.options.fixed_key_size,
+                    self.options.bincode_config,
+                )
+                .await?;
+                match entry.value {
+                    Value::Inline(value) => {
+                        histogram.insert(value.len() as u64)
+                    }
+                    Value::Log(pointer) => histogram.insert(pointer.size),
+                    Value::Tombstone => {}
+                }
+                position += sample_every;
+            }
+            data_file.close().await?;
+            if let IndexSource::File(index_file) = index {
+                index_file.close().await?;
+            }
+        }
+        Ok(histogram)
+    }
+
+    // Up to n - 1 ascending keys that split the keys of the live sstables into
+    // up to n ranges of roughly the same number of entries, for example to scan
+    // the ranges concurrently.
+    // The split is approximate, as it samples a fixed number of entries per
+    // range from the index files, and the keys in the memtables are ignored.
+    pub async fn split_points(&self, n: usize) -> std::io::Result<Vec<String>> {
+        let _guard = self.hold_sstable_files();
+        let sstable_paths: Vec<(PathBuf, PathBuf)> = self
+            .read_sstable_indices
+            .iter()
+            .map(|i| self.sstable_paths(*i))
+            .collect();
+        Self::sample_split_keys(
+            &**self.options.storage(),
+            &sstable_paths,
+            n,
+            self.options.fixed_key_size,
+            self.options.bincode_config,
+        )
+        .await
+    }
+
+    // Sample keys from the given sstables to split all of their keys into up to
+    // n ranges of roughly the same number of entries.
+    async fn sample_split_keys(
+        storage: &dyn Storage,
+        sstable_paths: &[(PathBuf, PathBuf)],
+        n: usize,
+        fixed_key_size: Option<usize>,
+        config: BincodeConfig,
+    ) -> std::io::Result<Vec<String>> {
+        const SAMPLES_PER_RANGE: u64 = 16;
+
+        if n <= 1 {
+            return Ok(Vec::new());
+        }
+
+        let mut headers = Vec::with_capacity(sstable_paths.len());
+        for (_, index_path) in sstable_paths {
+            headers
+                .push(IndexHeader::read_from_path(storage, index_path).await?);
+        }
+        let total_length: u64 = headers.iter().map(|h| h.entries).sum();
+        // The same stride for all sstables, so that each sstable is sampled
+        // proportionally to its number of entries.
+        let stride = (total_length / (n as u64 * SAMPLES_PER_RANGE)).max(1);
+
+        let mut samples = Vec::new();
+        for ((data_path, index_path), header) in
+            sstable_paths.iter().zip(headers)
+        {
+            let data_file = storage.open(data_path).await?;
+            let index = IndexSource::File(storage.open(index_path).await?);
+            let mut position = 0;
+            while position < header.entries {
+                let entry = read_entry_at(
+                    &data_file,
+                    &index,
+                    position,
+                    header.restart_interval,
+                    fixed_key_size,
+                    config,
+                )
+                .await?;
+                samples.push(entry.key);
+                position += stride;
+            }
+            data_file.close().await?;
+            if let IndexSource::File(index_file) = index {
+                index_file.close().await?;
+            }
+        }
+        samples.sort();
+        samples.dedup();
+
+        // Never split on the smallest sample, to not have the first range
+        // possibly empty.
+        let candidates = samples.get(1..).unwrap_or_default();
+        let mut split_keys: Vec<String> = (1..n)
+            .filter_map(|i| candidates.get(i * candidates.len() / n).cloned())
+            .collect();
+        split_keys.dedup();
+        Ok(split_keys)
+    }
+
+    // Stream readers of the data and index files of the given sstables, with
+    // the decoders of their entries, that start at the first entry whose key
+    // is not less than start.
+    async fn open_sstable_readers(
+        storage: &Rc<dyn Storage>,
+        sstable_paths: &[(PathBuf, PathBuf)],
+        start: Option<&String>,
+        fixed_key_size: Option<usize>,
+        config: BincodeConfig,
+    ) -> std::io::Result<Vec<SstableReader>> {
+        // Opened concurrently, up to SSTABLE_OPEN_CONCURRENCY at a time.
+        let mut sstable_readers = Vec::with_capacity(sstable_paths.len());
+        for chunk in sstable_paths.chunks(SSTABLE_OPEN_CONCURRENCY) {
+            let tasks: Vec<_> = chunk
+                .iter()
+                .map(|(data_path, index_path)| {
+                    glommio::spawn_local(Self::open_sstable_reader(
+                        storage.clone(),
+                        (data_path.clone(), index_path.clone()),
+                        start.cloned(),
+                        fixed_key_size,
+                        config,
+                    ))
+                })
+                .collect();
+            let mut result = Ok(());
+            for ((data_path, _), task) in chunk.iter().zip(tasks) {
+                match task.await {
+                    Ok(readers) => sstable_readers.push(readers),
+                    Err(e) if result.is_ok() => {
+                        result = Err(std::io::Error::new(
+                            e.kind(),
+                            format!(
+                                "failed to open sstable '{}': {}",
+                                data_path.display(),
+                                e
+                            ),
+                        ));
+                    }
+                    Err(_) => {}
+                }
+            }
+            result?;
+        }
+
+        Ok(sstable_readers)
+    }
+
+    async fn open_sstable_reader(
+        storage: Rc<dyn Storage>,
+        (data_path, index_path): (PathBuf, PathBuf),
+        start: Option<String>,
+        fixed_key_size: Option<usize>,
+        config: BincodeConfig,
+    ) -> std::io::Result<SstableReader> {
+        let header =
+            IndexHeader::read_from_path(&*storage, &index_path).await?;
+        let (position, restart_point, data_start) = match &start {
+            Some(start) => {
+                let data_file = storage.open(&data_path).await?;
+                let index = IndexSource::File(storage.open(&index_path).await?);
+                let position = lower_bound(
+                    &data_file,
+                    &index,
+                    start,
+                    fixed_key_size,
+                    config,
+                )
+                .await?;
+                // When keys are prefix compressed, the readers start at
+                // the restart point before the position, so that the keys
+                // up to it are decoded.
+                let restart_point = match header.restart_interval {
+                    0 => position,
+                    interval => position - position % interval,
+                };
+                let data_start = if restart_point < header.entries {
+                    index
+                        .read_item(restart_point, fixed_key_size)
+                        .await?
+                        .0
+                        .entry_offset
+                } else {
+                    data_file.file_size().await?
+                };
+                data_file.close().await?;
+                if let IndexSource::File(index_file) = index {
+                    index_file.close().await?;
+                }
+                (position, restart_point, data_start)
+            }
+            None => (0, 0, 0),
+        };
+
+        let mut data_reader =
+            FileReader::new(storage.open(&data_path).await?, data_start);
+        let mut index_reader = FileReader::new(
+            storage.open(&index_path).await?,
+            index_item_offset(restart_point, fixed_key_size),
+        );
+        let mut decoder = EntryDecoder::new(config, header.restart_interval);
+        let mut offset_bytes =
+            vec![0; index_item_size(fixed_key_size) as usize];
+        let mut data_bytes = Vec::new();
+        for _ in restart_point..position {
+            Self::read_next_entry(
+                &mut data_reader,
+                &mut index_reader,
+                &mut offset_bytes,
+                &mut data_bytes,
+                fixed_key_size,
+                &mut decoder,
+            )
+            .await?;
+        }
+        Ok(SstableReader {
+            data_reader,
+            index_reader,
+            decoder,
+            remaining: header.entries - position,
+        })
+    }
+
+    // Merge the entries of the given sstables that the settings keep (see
+    // MergeSettings, compact_with_filter for the filter and TombstonePolicy)
+    // into a new sstable at the given data, index and meta paths.
+    // The output is a function of the inputs alone, nothing of the time of
+    // the compaction is written (the creation time is of the inputs), so
+    // compacting the same inputs writes the same bytes, for golden files and
+    // for deduplicating backups by content.
+    // Each sstable is given with the offset to add to the sequence numbers of
+    // its entries, see merge_from.
+    async fn write_compaction_output(
+        CompactionInputs { storage, sstables }: CompactionInputs,
+        output: SstablePaths,
+        SstableLayout {
+            fixed_key_size,
+            restart_interval,
+            false_positive_rate,
+        }: SstableLayout,
+        config: BincodeConfig,
+        MergeSettings {
+            start,
+            end,
+            versions_to_keep,
+            filter,
+            tombstones,
+        }: MergeSettings<'_>,
+        pause: Option<&PauseToken>,
+    ) -> std::io::Result<(IndexHeader, SstableMeta)> {
+        let item_size = index_item_size(fixed_key_size);
+        let (sstable_paths, seq_offsets): (Vec<_>, Vec<_>) = sstables
+            .into_iter()
+            .map(|(data_path, index_path, offset)| {
+                ((data_path, index_path), offset)
+            })
+            .unzip();
+
+        let mut created_at = u64::MAX;
+        for (_, index_path) in &sstable_paths {
+            let header =
+                IndexHeader::read_from_path(&*storage, index_path).await?;
+            created_at = created_at.min(header.created_at);
+        }
+        // The number of keys written is unknown until the merge is done (the
+        // range, the versions to keep and the filter drop entries), so the
+        // filter is built at the end from the hashes of the keys written.
+        let mut meta = SstableMeta::new(0, false_positive_rate);
+        let mut key_hashes = Vec::new();
+
+        // No stable AsyncIterator yet...
+        // If there was, itertools::kmerge would probably solve it all.
+        let mut sstable_readers = Self::open_sstable_readers(
+            &storage,
+            &sstable_paths,
+            start.as_ref(),
+            fixed_key_size,
+            config,
+        )
+        .await?;
+
+        let mut compact_data_writer = storage.create(&output.data_path).await?;
+        let mut compact_index_writer =
+            storage.create(&output.index_path).await?;
+        // Rewritten once the number of entries is known.
+        let mut header = IndexHeader {
+            version: FORMAT_VERSION,
+            restart_interval,
+            created_at,
+            ..Default::default()
+        };
+        compact_index_writer.write_all(&header.encode()).await?;
+        let mut encoder = EntryEncoder::new(config, restart_interval);
+
+        let in_range = |entry: &Entry| match &end {
+            Some(end) => entry.key < *end,
+            None => true,
+        };
+
+        let mut offset_bytes = vec![0; item_size as usize];
+        let mut data_bytes = Vec::new();
+        let mut heap = BinaryHeap::new();
+
+        for (index, sstable_reader) in sstable_readers.iter_mut().enumerate() {
+            // A failed read fails the compaction, before any input is deleted.
+            let next = sstable_reader
+                .next_entry(&mut offset_bytes, &mut data_bytes, fixed_key_size)
+                .await?;
+            if let Some(mut entry) = next {
+                entry.seq += seq_offsets[index];
+                if in_range(&entry) {
+                    heap.push(CompactionItem { entry, index });
+                }
+            }
+        }
+
+        let mut entry_offset = 0u64;
+        let mut last_key: Option<String> = None;
+        let mut last_key_versions = 0;
+        // The key of the last tombstone dropped, to drop its older versions.
+        let mut deleted_key: Option<String> = None;
+
+        while let Some(next) = heap.pop() {
+            if let Some(pause) = pause {
+                pause.wait_while_paused().await;
+            }
+            let index = next.index;
+
+            let new_key = last_key.as_ref() != Some(&next.entry.key);
+            if new_key {
+                last_key_versions = 0;
+            }
+            let deleted = deleted_key.as_ref() == Some(&next.entry.key)
+                || (new_key
+                    && next.entry.value == Value::Tombstone
+                    && tombstones.drops(&next.entry));
+            if deleted {
+                deleted_key = Some(next.entry.key.clone());
+            }
+            // Values in value logs are read for the filter from the value logs
+            // next to the output. Tombstones are always kept by the filter.
+            let keep = match filter {
+                Some(filter) if !deleted => {
+                    match Self::read_value_in_dir(
+                        &*storage,
+                        output.data_path.parent().unwrap(),
+                        next.entry.value.clone(),
+                    )
+                    .await?
+                    {
+                        // The filter takes the value as a string, a value
+                        // that is not utf-8 fails the compaction.
+                        Some(value) => {
+                            filter(&next.entry.key, utf8_str(&value)?)
+                        }
+                        None => true,
+                    }
+                }
+                _ => !deleted,
+            };
+            // The newest version of a key is popped first, skip the older ones.
+            if keep && last_key_versions < versions_to_keep {
+                let next_data_encoded = encoder.encode(&next.entry);
+                let entry_size = next_data_encoded.len();
+                let entry_index = EntryOffset {
+                    entry_offset,
+                    entry_size,
+                };
+                entry_offset += entry_size as u64;
+                let next_index_encoded = encode_index_item(
+                    &entry_index,
+                    &next.entry.key,
+                    fixed_key_size,
+                );
+
+                compact_data_writer.write_all(&next_data_encoded).await?;
+                compact_index_writer.write_all(&next_index_encoded).await?;
+                meta.record(&next.entry.key, &next.entry.value);
+                header.entries += 1;
+                if next.entry.value == Value::Tombstone {
+                    header.tombstones += 1;
+                }
+                if new_key {
+                    header.keys += 1;
+                    key_hashes.push(bloom::hash(next.entry.key.as_bytes()));
+                }
+                header.max_seq = header.max_seq.max(next.entry.seq);
+                header.max_timestamp =
+                    header.max_timestamp.max(next.entry.timestamp);
+                last_key_versions += 1;
+                last_key = Some(next.entry.key);
+            }
+
+            let next = sstable_readers[index]
+                .next_entry(&mut offset_bytes, &mut data_bytes, fixed_key_size)
+                .await?;
+            if let Some(mut entry) = next {
+                entry.seq += seq_offsets[index];
+                if in_range(&entry) {
+                    heap.push(CompactionItem { entry, index });
+                }
+            }
+        }
+
+        compact_data_writer.close().await?;
+        compact_index_writer.close().await?;
+        storage
+            .write_at(&output.index_path, 0, &header.encode())
+            .await?;
+        meta.filter =
+            Some(BloomFilter::from_hashes(&key_hashes, false_positive_rate));
+        Self::write_sstable_meta(&*storage, &output.meta_path, &meta).await?;
+
+        Ok((header, meta))
+    }
+
+    // Atomically replace the compacted sstables with the compaction outputs,
+    // that were already written to their compaction file paths.
+    async fn finish_compaction(
+        &mut self,
+        indices_to_compact: &[usize],
+        outputs: Vec<(usize, IndexHeader, SstableMeta)>,
+    ) -> std::io::Result<()> {
+        let output_indices: Vec<usize> =
+            outputs.iter().map(|(index, _, _)| *index).collect();
+        let action =
+            self.compaction_action(indices_to_compact, &output_indices);
+
+        // Before the action is written, so that a missing output fails the
+        // compaction without running the action (deleting the inputs) on the
+        // next open.
+        let storage = self.options.storage().clone();
+        let mut bytes_read = 0;
+        for index in indices_to_compact {
+            let (data_path, index_path) = self.sstable_paths(*index);
+            bytes_read += storage.size(&data_path)?;
+            bytes_read += storage.size(&index_path)?;
+        }
+        let mut bytes_written = 0;
+        for (source_path, _) in &action.renames {
+            bytes_written += storage.size(source_path)?;
+        }
+
+        let compact_action_path = Self::write_compaction_action(
+            self.dir.clone(),
+            &action,
+            output_indices[0],
+        )
+        .await?;
+
+        // The outputs are renamed before they're read from, and the inputs
+        // are only deleted after they're no longer read from (below), so a
+        // get never opens a path that doesn't exist.
+        // A rename that fails leaves the tree reading the inputs, so the
+        // compaction is undone, the action first, as running it on the next
+        // open would delete the inputs.
+        for (source_path, destination_path) in &action.renames {
+            if let Err(e) = storage.rename(source_path, destination_path) {
+                Self::remove_file_log_on_err(&*storage, &compact_action_path);
+                for (source_path, destination_path) in &action.renames {
+                    for path in [source_path, destination_path] {
+                        if storage.exists(path) {
+                            Self::remove_file_log_on_err(&*storage, path);
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        }
+
+        self.update_stats(|stats| {
+            stats.compaction_bytes_read += bytes_read;
+            stats.compaction_bytes_written += bytes_written;
+        });
+
+        let reads = self.retire_sstable_reads(indices_to_compact);
+
+        let index_cache = self.index_cache.get_mut();
+        let pinned = indices_to_compact
+            .iter()
+            .any(|index| index_cache.pinned.contains(index));
+        for index in indices_to_compact {
+            self.sstable_headers.remove(index);
+            self.sstable_metas.remove(index);
+            index_cache.retire(*index);
+        }
+        if pinned {
+            index_cache.pinned.extend(&output_indices);
+        }
+        for (index, header, meta) in outputs {
+            self.sstable_headers.insert(index, header);
+            self.sstable_metas.insert(index, Rc::new(meta));
+        }
+        self.read_sstable_indices
+            .retain(|x| !indices_to_compact.contains(x));
+        self.read_sstable_indices.extend(&output_indices);
+        self.cold_sstables
+            .retain(|x| !indices_to_compact.contains(x));
+        if self.options.compaction_dir.is_some() {
+            self.cold_sstables.extend(output_indices);
+        }
+        *self.search_window.get_mut() = SearchWindow::default();
+
+        // The outputs are now live, but the inputs could still be read from,
+        // so only delete them (and the value logs only they pointed into) once
+        // there are no more reads to them.
+        let mut files = action.deletes;
+        files.extend(Self::unreferenced_value_logs(
+            &**self.options.storage(),
+            &self.dir,
+            &self.read_sstable_indices,
+            &self.sstable_metas,
+        )?);
+        self.pending_deletes.push(PendingDelete {
+            files,
+            compact_action_path,
+            reads,
+        });
+        self.gc();
+
+        Ok(())
+    }
+
+    // Replace the contents of the tree with the sstables of the tree at the
+    // staging directory, which is opened (with the same options) and flushed
+    // first. The memtables and the WAL of the tree are cleared.
+    // The sstables are moved out of the staging directory, which must be on
+    // the same file system, by the same action a compaction runs, so a crash
+    // leaves either the old contents or the new ones on the next open.
+    pub async fn replace_with(
+        &mut self,
+        staging: PathBuf,
+    ) -> std::io::Result<()> {
+        let mut staging_tree =
+            LSMTree::with_options(staging.clone(), self.options.clone())
+                .await?;
+        staging_tree.flush().await?;
+        let mut staged_indices = staging_tree.read_sstable_indices.clone();
+        staged_indices.sort();
+        let staged_headers: Vec<IndexHeader> = staged_indices
+            .iter()
+            .map(|i| staging_tree.sstable_headers[i])
+            .collect();
+        let mut staged_metas = std::mem::take(&mut staging_tree.sstable_metas);
+        drop(staging_tree);
+        let storage = self.options.storage().clone();
+        let staged_value_logs = Self::value_log_ids(&*storage, &staging)?;
+        if let Some(log) = staged_value_logs.iter().find(|log| {
+            storage.exists(&Self::get_value_log_path(self.dir.clone(), **log))
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("value log {} is in both trees", log),
+            ));
+        }
+
+        let output_indices =
+            self.unused_sstable_indices(staged_indices.len().max(1));
+        let wal_path =
+            Self::get_wal_path(self.dir.clone(), self.memtable_index);
+        let action = self.replace_action(
+            &staging,
+            (&staged_indices, &staged_value_logs),
+            &output_indices,
+            wal_path.clone(),
+        );
+        let compact_action_path = Self::write_compaction_action(
+            self.dir.clone(),
+            &action,
+            output_indices[0],
+        )
+        .await?;
+
+        let reads =
+            self.retire_sstable_reads(&self.read_sstable_indices.clone());
+
+        self.sstable_headers.clear();
+        self.sstable_metas.clear();
+        *self.index_cache.get_mut() = IndexCache::default();
+        self.read_sstable_indices.clear();
+        self.cold_sstables.clear();
+        for ((staged_index, header), output_index) in staged_indices
+            .iter()
+            .zip(staged_headers)
+            .zip(&output_indices)
+        {
+            self.read_sstable_indices.push(*output_index);
+            self.sstable_headers.insert(*output_index, header);
+            if let Some(meta) = staged_metas.remove(staged_index) {
+                self.sstable_metas.insert(*output_index, meta);
+            }
+            self.next_seq = self.next_seq.max(header.max_seq + 1);
+        }
+        if let Some(log) = staged_value_logs.iter().max() {
+            self.last_value_log = self.last_value_log.max(*log);
+        }
+
+        for (source_path, destination_path) in &action.renames {
+            storage.rename(source_path, destination_path)?;
+        }
+
+        self.reset_memtables(&wal_path).await?;
+        self.retire_replaced_files(action.deletes, compact_action_path, reads)
+    }
+
+    // Empties the tree: the memtables, the WAL and the sstables (with the
+    // value logs they point into) are all dropped, leaving an empty tree in
+    // the same directory.
+    // The files are deleted by the same action a compaction runs, so a crash
+    // leaves either the old contents or an empty tree on the next open.
+    pub async fn clear(&mut self) -> std::io::Result<()> {
+        let _all_keys = self.range_locks.acquire(&String::new(), None).await;
+        let wal_path =
+            Self::get_wal_path(self.dir.clone(), self.memtable_index);
+        let action = CompactionAction {
+            renames: vec![],
+            deletes: self.files_to_replace(wal_path.clone()),
+        };
+        let compact_action_path = Self::write_compaction_action(
+            self.dir.clone(),
+            &action,
+            self.unused_sstable_indices(1)[0],
+        )
+        .await?;
+
+        let reads =
+            self.retire_sstable_reads(&self.read_sstable_indices.clone());
+        self.sstable_headers.clear();
+        self.sstable_metas.clear();
+        *self.index_cache.get_mut() = IndexCache::default();
+        self.read_sstable_indices.clear();
+        self.cold_sstables.clear();
+        self.flush_memtable = None;
+
+        self.reset_memtables(&wal_path).await?;
+        self.retire_replaced_files(action.deletes, compact_action_path, reads)
+    }
+
+    // Empties the active memtable, starting a new WAL in place of the one at
+    // wal_path.
+    async fn reset_memtables(
+        &mut self,
+        wal_path: &Path,
+    ) -> std::io::Result<()> {
+        self.active_memtable =
+            RedBlackTree::with_capacity(self.active_memtable.capacity());
+        self.recent_writes.clear();
+        self.last_write = None;
+        self.memtable_index += 2;
+        let next_wal_path =
+            Self::get_wal_path(self.dir.clone(), self.memtable_index);
+        let retry_policy = &self.options.retry_policy;
+        let wal_file =
+            with_retries(retry_policy, || BufferedFile::create(&next_wal_path))
+                .await?;
+        self.wal = self.options.wal(wal_file, next_wal_path);
+        std::fs::remove_file(wal_path)?;
+        Ok(())
+    }
+
+    fn retire_replaced_files(
+        &mut self,
+        deletes: Vec<PathBuf>,
+        compact_action_path: PathBuf,
+        reads: Vec<Rc<()>>,
+    ) -> std::io::Result<()> {
+        // The old sstables could still be read from, like the inputs of a
+        // compaction.
+        let mut files = deletes;
+        files.extend(Self::unreferenced_value_logs(
+            &**self.options.storage(),
+            &self.dir,
+            &self.read_sstable_indices,
+            &self.sstable_metas,
+        )?);
+        self.pending_deletes.push(PendingDelete {
+            files,
+            compact_action_path,
+            reads,
+        });
+        self.gc();
+
+        Ok(())
+    }
+
+    // Same as compact, but drops the entries for which predicate returns false
+    // (given their key and value), as a bulk cleanup, like removing all keys
+    // with a prefix. Values in value logs are read for the predicate, and a
+    // value that is not utf-8 (see set_bytes) fails the compaction.
+    // Only entries of the inputs are dropped: a dropped key is still found in
+    // the memtables and in the sstables that are not compacted, and when the
+    // newest version of a key is dropped, an older version that is kept (see
+    // LSMTreeOptions::with_versions_to_keep) becomes the newest.
+    pub async fn compact_with_filter(
+        &mut self,
+        indices_to_compact: Vec<usize>,
+        output_index: usize,
+        predicate: impl Fn(&str, &str) -> bool,
+    ) -> std::io::Result<()> {
+        let _all_keys = self.range_locks.acquire(&String::new(), None).await;
+        let _reservation = self.reserve_for_compaction(
+            indices_to_compact.iter().copied().chain([output_index]),
+        )?;
+        let sstable_paths: Vec<(PathBuf, PathBuf)> = indices_to_compact
+            .iter()
+            .map(|i| self.sstable_paths(*i))
+            .collect();
+        let files_guard = self.hold_sstables(&indices_to_compact);
+
+        let compact_paths = Self::get_compaction_output_paths(
+            self.compaction_output_dir(),
+            output_index,
+        );
+        let storage = self.options.storage().clone();
+        let result = Self::write_compaction_output(
+            CompactionInputs {
+                storage: storage.clone(),
+                sstables: without_seq_offsets(&sstable_paths),
+            },
+            compact_paths.clone(),
+            self.options.sstable_layout(),
+            self.options.bincode_config,
+            MergeSettings {
+                filter: Some(&predicate),
+                ..MergeSettings::all_keys(
+                    self.options.versions_to_keep(),
+                    self.tombstone_policy(&indices_to_compact),
+                )
+            },
+            None,
+        )
+        .await;
+        let (header, meta) = match result {
+            Ok(output) => output,
+            Err(e) => {
+                for path in compact_paths
+                    .files()
+                    .iter()
+                    .filter(|path| storage.exists(path))
+                {
+                    Self::remove_file_log_on_err(&*storage, path);
+                }
+                return Err(e);
+            }
+        };
+        drop(files_guard);
+        self.finish_compaction(
+            &indices_to_compact,
+            vec![(output_index, header, meta)],
+        )
+        .await
+    }
+
+    // Merge the contents of the tree at other_dir (opened with the same
+    // options and flushed first, and otherwise left as is) into this tree,
+    // which is flushed first too, replacing all of its sstables with a single
+    // sstable of the merged entries.
+    // The sequence numbers of the two trees are unrelated, so the versions of
+    // a key in the winner tree are made newer than all of the versions in the
+    // other tree, by offsetting their sequence numbers by the next sequence
+    // number of the other tree. Within each tree versions keep their order,
+    // and versions_to_keep applies to the merged versions.
+    // Value logs of the other tree are copied to this tree.
+    pub async fn merge_from(
+        &mut self,
+        other_dir: PathBuf,
+        winner: MergeWinner,
+    ) -> std::io::Result<()> {
+        let mut other_tree =
+            LSMTree::with_options(other_dir.clone(), self.options.clone())
+                .await?;
+        other_tree.flush().await?;
+        self.flush().await?;
+
+        let indices_to_compact = self.read_sstable_indices.clone();
+        let output_index = self.unused_sstable_indices(1)[0];
+        let _reservation = self.reserve_for_compaction(
+            indices_to_compact.iter().copied().chain([output_index]),
+        )?;
+
+        let (current_offset, other_offset) = match winner {
+            MergeWinner::Current => (other_tree.next_seq, 0),
+            MergeWinner::Other => (0, self.next_seq),
+        };
+        let mut sstables = Vec::with_capacity(
+            indices_to_compact.len() + other_tree.read_sstable_indices.len(),
+        );
+        for index in &indices_to_compact {
+            let (data_path, index_path) = self.sstable_paths(*index);
+            sstables.push((data_path, index_path, current_offset));
+        }
+        for index in &other_tree.read_sstable_indices {
+            let (data_path, index_path) =
+                Self::get_data_file_paths(other_dir.clone(), *index);
+            sstables.push((data_path, index_path, other_offset));
+        }
+        let next_seq = self.next_seq + other_tree.next_seq;
+        drop(other_tree);
+
+        let storage = self.options.storage().clone();
+        for log in Self::value_log_ids(&*storage, &other_dir)? {
+            let path = Self::get_value_log_path(self.dir.clone(), log);
+            if storage.exists(&path) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("value log {} is in both trees", log),
+                ));
+            }
+            Self::copy_file(
+                &*storage,
+                &Self::get_value_log_path(other_dir.clone(), log),
+                &path,
+            )
+            .await?;
+            self.last_value_log = self.last_value_log.max(log);
+        }
+
+        let compact_paths = Self::get_compaction_output_paths(
+            self.compaction_output_dir(),
+            output_index,
+        );
+        let result = Self::write_compaction_output(
+            CompactionInputs {
+                storage: storage.clone(),
+                sstables,
+            },
+            compact_paths.clone(),
+            self.options.sstable_layout(),
+            self.options.bincode_config,
+            // All the sstables of both trees are merged.
+            MergeSettings::all_keys(
+                self.options.versions_to_keep(),
+                TombstonePolicy::DropUnlessIn(
+                    Vec::new(),
+                    self.tombstone_grace_bounds(next_seq),
+                ),
+            ),
+            None,
+        )
+        .await;
+        let (header, meta) = match result {
+            Ok(output) => output,
+            Err(e) => {
+                for path in compact_paths
+                    .files()
+                    .iter()
+                    .filter(|path| storage.exists(path))
+                {
+                    Self::remove_file_log_on_err(&*storage, path);
+                }
+                return Err(e);
+            }
+        };
+        self.finish_compaction(
+            &indices_to_compact,
+            vec![(output_index, header, meta)],
+        )
+        .await?;
+        self.next_seq = next_seq;
+        Ok(())
+    }
+
+    // Moves the staged sstables to the output indices and the staged value logs
+    // as they are, and deletes the live sstables and the given WAL.
+    fn replace_action(
+        &self,
+        staging: &Path,
+        (staged_indices, staged_value_logs): (&[usize], &[usize]),
+        output_indices: &[usize],
+        wal_path: PathBuf,
+    ) -> CompactionAction {
+        let mut renames = Vec::with_capacity(staged_indices.len() * 3);
+        for (staged_index, output_index) in
+            staged_indices.iter().zip(output_indices)
+        {
+            let (staged_data_path, staged_index_path) =
+                Self::get_data_file_paths(staging.to_path_buf(), *staged_index);
+            let (output_data_path, output_index_path) =
+                self.sstable_paths(*output_index);
+            renames.push((staged_data_path, output_data_path));
+            renames.push((staged_index_path, output_index_path));
+            let staged_meta_path =
+                Self::get_meta_file_path(staging.to_path_buf(), *staged_index);
+            let storage = self.options.storage();
+            if storage.exists(&staged_meta_path) {
+                let output_meta_path = self.sstable_meta_path(*output_index);
+                // Built on the next open when missing.
+                let staged_filter_path = filter_path(&staged_meta_path);
+                if storage.exists(&staged_filter_path) {
+                    renames.push((
+                        staged_filter_path,
+                        filter_path(&output_meta_path),
+                    ));
+                }
+                renames.push((staged_meta_path, output_meta_path));
+            }
+        }
+        for log in staged_value_logs {
+            renames.push((
+                Self::get_value_log_path(staging.to_path_buf(), *log),
+                Self::get_value_log_path(self.dir.clone(), *log),
+            ));
+        }
+
+        CompactionAction {
+            renames,
+            deletes: self.files_to_replace(wal_path),
+        }
+    }
+
+    // The files of the live sstables, and the WAL at wal_path, deleted when
+    // the contents of the tree are replaced.
+    fn files_to_replace(&self, wal_path: PathBuf) -> Vec<PathBuf> {
+        let mut deletes =
+            Vec::with_capacity(self.read_sstable_indices.len() * 3 + 1);
+        for index in &self.read_sstable_indices {
+            let (data_path, index_path) = self.sstable_paths(*index);
+            deletes.push(data_path);
+            deletes.push(index_path);
+            let meta_path = self.sstable_meta_path(*index);
+            deletes.push(filter_path(&meta_path));
+            deletes.push(meta_path);
+        }
+        deletes.push(wal_path);
+        deletes
+    }
+
+    // Delete the files retired by compactions that are no longer read from,
+    // returns the number of files deleted.
+    // Called after every compaction, files still held by a read or a guard are
+    // deleted on a later call.
+    pub fn gc(&mut self) -> usize {
+        let (releasable, pending): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pending_deletes)
+                .into_iter()
+                .partition(|pending| {
+                    pending
+                        .reads
+                        .iter()
+                        .all(|reads| Rc::strong_count(reads) == 1)
+                });
+        self.pending_deletes = pending;
+
+        let storage = &**self.options.storage();
+        let mut deleted = 0;
+        for pending in releasable {
+            for path_to_delete in &pending.files {
+                if file_storage(storage, path_to_delete).exists(path_to_delete)
+                {
+                    Self::remove_file_log_on_err(storage, path_to_delete);
+                    deleted += 1;
+                }
+            }
+            Self::remove_file_log_on_err(storage, &pending.compact_action_path);
+        }
+        deleted
+    }
+
+    // Hold the sstable files that are currently live on disk until the guard is
+    // dropped, useful for reading them outside of the tree.
+    // Compactions of sstables that are created after the guard are not held
+    // back by it.
+    pub fn hold_sstable_files(&self) -> SstableFilesGuard {
+        self.hold_sstables(&self.read_sstable_indices)
+    }
+
+    // Hold the keys in [start, end) until the guard is dropped, waiting for
+    // the held ranges that overlap it to be released first, so that callers
+    // changing a range over multiple awaits (like deleting every key in it,
+    // or a read-modify-write of it) don't interleave with others on the same
+    // keys. Disjoint ranges are held at the same time.
+    // Methods that change the values of many keys at once hold the ranges
+    // they change while they do, and wait for the guards that overlap them:
+    // clear and compact_with_filter hold all keys. So a compact_with_filter
+    // dropping keys never runs in the middle of a range deleted key by key
+    // under a guard, it runs before or after it.
+    // Methods that write single keys (like set) don't wait for guards, and
+    // neither do reads, holding a range only excludes the others holding it.
+    // Awaiting a method that holds an overlapping range while holding a guard
+    // never returns.
+    pub async fn acquire_range(
+        &self,
+        start: &String,
+        end: &String,
+    ) -> RangeGuard {
+        self.range_locks.acquire(start, Some(end)).await
+    }
+
+    // Same as acquire_range, without waiting: None when an overlapping range
+    // is held.
+    pub fn try_acquire_range(
+        &self,
+        start: &String,
+        end: &String,
+    ) -> Option<RangeGuard> {
+        self.range_locks.try_acquire(start, Some(end))
+    }
+
+    fn hold_sstables(&self, indices: &[usize]) -> SstableFilesGuard {
+        let mut sstable_reads = self.sstable_reads.borrow_mut();
+        SstableFilesGuard {
+            _reads: indices
+                .iter()
+                .map(|index| sstable_reads.entry(*index).or_default().clone())
+                .collect(),
+        }
+    }
+
+    // The reads counters of sstables that are no longer live, to delete their
+    // files once nothing holds them.
+    fn retire_sstable_reads(&mut self, indices: &[usize]) -> Vec<Rc<()>> {
+        let sstable_reads = self.sstable_reads.get_mut();
+        indices
+            .iter()
+            .filter_map(|index| sstable_reads.remove(index))
+            .collect()
+    }
+
+    fn compaction_action(
+        &self,
+        indices_to_compact: &[usize],
+        output_indices: &[usize],
+    ) -> CompactionAction {
+        let mut files_to_delete =
+            Vec::with_capacity(indices_to_compact.len() * 3);
+        for index in indices_to_compact {
+            let (data_path, index_path) = self.sstable_paths(*index);
+            files_to_delete.push(data_path);
+            files_to_delete.push(index_path);
+            let meta_path = self.sstable_meta_path(*index);
+            files_to_delete.push(filter_path(&meta_path));
+            files_to_delete.push(meta_path);
+        }
+
+        // The outputs are written to the directory they are renamed in, so
+        // the renames stay atomic when it's on another file system, see
+        // LSMTreeOptions::with_compaction_dir.
+        let dir = self.compaction_output_dir();
+        let mut renames = Vec::with_capacity(output_indices.len() * 3);
+        for output_index in output_indices {
+            let (compact_data_path, compact_index_path) =
+                Self::get_compaction_file_paths(dir.clone(), *output_index);
+            let (output_data_path, output_index_path) =
+                Self::get_data_file_paths(dir.clone(), *output_index);
+            renames.push((compact_data_path, output_data_path));
+            renames.push((compact_index_path, output_index_path));
+            let compact_meta_path =
+                Self::get_compaction_meta_file_path(dir.clone(), *output_index);
+            let output_meta_path =
+                Self::get_meta_file_path(dir.clone(), *output_index);
+            renames.push((
+                filter_path(&compact_meta_path),
+                filter_path(&output_meta_path),
+            ));
+            renames.push((compact_meta_path, output_meta_path));
+        }
+
+        CompactionAction {
+            renames,
+            deletes: files_to_delete,
+        }
+    }
+
+    // Once the action is written, the compaction is going to be completed even
+    // in case of a crash, by running the action again on the next open.
+    // All outputs of a compaction are renamed by a single action, named after
+    // the first output, so a crash never exposes only part of them.
+    async fn write_compaction_action(
+        dir: PathBuf,
+        action: &CompactionAction,
+        index: usize,
+    ) -> std::io::Result<PathBuf> {
+        let action_encoded = bincode_options().serialize(action).unwrap();
+
+        let compact_action_path =
+            numbered_file_path(&dir, index, COMPACTION_ACTION_EXTENSION);
+        let compact_action_file =
+            BufferedFile::create(&compact_action_path).await?;
+        let mut compact_action_writer =
+            StreamWriterBuilder::new(compact_action_file).build();
+        compact_action_writer.write_all(&action_encoded).await?;
+        compact_action_writer.close().await?;
+
+        Ok(compact_action_path)
+    }
+
+    async fn read_next_entry(
+        data_reader: &mut (impl AsyncRead + Unpin),
+        index_reader: &mut (impl AsyncRead + Unpin),
+        offset_bytes: &mut [u8],
+        data_bytes: &mut Vec<u8>,
+        fixed_key_size: Option<usize>,
+        decoder: &mut EntryDecoder,
+    ) -> std::io::Result<Entry> {
+        index_reader.read_exact(offset_bytes).await?;
+        let (entry_offset, _) =
+            decode_index_item(offset_bytes, fixed_key_size)?;
+        // The buffer is reused across the entries read, and only grows, so
+        // reading an sstable allocates it about once instead of per entry.
+        let entry_size = entry_offset.entry_size;
+        if data_bytes.len() < entry_size {
+            data_bytes.resize(entry_size, 0);
+        }
+        let data_bytes = &mut data_bytes[..entry_size];
+        data_reader.read_exact(data_bytes).await?;
+        decoder.decode(data_bytes)
+    }
+
+    // Only files are removed, a failed flush could fail on creating a file
+    // because something else is at its path.
+    fn remove_files_of_failed_flush(storage: &dyn Storage, paths: &[PathBuf]) {
+        for path in paths {
+            let storage = file_storage(storage, path);
+            if path.is_dir() || !storage.exists(path) {
+                continue;
+            }
+            if let Err(e) = storage.remove(path) {
+                eprintln!(
+                    "Failed to remove file '{}' of a failed flush: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn remove_file_log_on_err(storage: &dyn Storage, file_path: &Path) {
+        if let Err(e) = file_storage(storage, file_path).remove(file_path) {
+            eprintln!(
+                "Failed to remove file '{}', that is irrelevant after \
+                 compaction: {}",
+                file_path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{IoFuture, MemoryStorage};
+    use glommio::LocalExecutor;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("dbil-test-{}", name));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn fixed_key_size() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("fixed_key_size");
+            let options = LSMTreeOptions::new().with_fixed_key_size(8);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+
+            for i in 0..TREE_CAPACITY * 2 + 10 {
+                let key = format!("{:08}", i);
+                tree.set(key, i.to_string()).await.unwrap();
+            }
+            assert!(tree
+                .set("short".to_string(), "".to_string())
+                .await
+                .is_err());
+            assert_eq!(tree.read_sstable_indices.len(), 2);
+
+            for i in 0..TREE_CAPACITY * 2 + 10 {
+                let key = format!("{:08}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+            assert_eq!(tree.get(&"99999999".to_string()).await.unwrap(), None);
+
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            drop(tree);
+
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            for i in 0..TREE_CAPACITY * 2 + 10 {
+                let key = format!("{:08}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn compact_parallel() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compact_parallel");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..300 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            assert_eq!(tree.read_sstable_indices, vec![0, 2, 4]);
+
+            tree.compact_parallel(vec![0, 2, 4], vec![10, 12, 14])
+                .await
+                .unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![10, 12, 14]);
+            drop(tree);
+
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![10, 12, 14]);
+            for i in 0..300 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+
+            let error = tree
+                .compact_parallel(vec![10, 12, 14], vec![])
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(tree.read_sstable_indices, vec![10, 12, 14]);
+            // The inputs were not reserved by the failed call.
+            tree.compact_parallel(vec![10, 12, 14], vec![16])
+                .await
+                .unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![16]);
+        });
+    }
+
+    #[test]
+    fn compact_parallel_crash_before_rename() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compact_parallel_crash_before_rename");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            // Do everything a parallel compaction does, up until renaming the
+            // outputs to their final paths.
+            let sstable_paths: Vec<(PathBuf, PathBuf)> = [0, 2]
+                .iter()
+                .map(|i| LSMTree::get_data_file_paths(dir.clone(), *i))
+                .collect();
+            let split_keys = LSMTree::sample_split_keys(
+                &LocalStorage,
+                &sstable_paths,
+                2,
+                None,
+                BincodeConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(split_keys.len(), 1);
+            let ranges = [
+                (None, Some(split_keys[0].clone())),
+                (Some(split_keys[0].clone()), None),
+            ];
+            for ((start, end), output_index) in ranges.into_iter().zip([7, 9]) {
+                LSMTree::write_compaction_output(
+                    CompactionInputs {
+                        storage: Rc::new(LocalStorage),
+                        sstables: without_seq_offsets(&sstable_paths),
+                    },
+                    LSMTree::get_compaction_output_paths(
+                        dir.clone(),
+                        output_index,
+                    ),
+                    SstableLayout {
+                        fixed_key_size: None,
+                        restart_interval: 0,
+                        false_positive_rate: DEFAULT_FALSE_POSITIVE_RATE,
+                    },
+                    BincodeConfig::default(),
+                    MergeSettings {
+                        start,
+                        end,
+                        ..MergeSettings::all_keys(1, TombstonePolicy::Keep)
+                    },
+                    None,
+                )
+                .await
+                .unwrap();
+            }
+            let action = tree.compaction_action(&[0, 2], &[7, 9]);
+            LSMTree::write_compaction_action(dir.clone(), &action, 7)
+                .await
+                .unwrap();
+            drop(tree);
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![7, 9]);
+            assert_eq!(tree.sstable_metas.len(), 2);
+            for i in 0..200 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn recent() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("recent");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for key in ["c", "a", "b", "a"] {
+                tree.set(key.to_string(), key.to_uppercase()).await.unwrap();
+            }
+            let expected = vec![
+                ("a".to_string(), "A".to_string()),
+                ("b".to_string(), "B".to_string()),
+            ];
+            assert_eq!(tree.recent(2).unwrap(), expected);
+            assert_eq!(tree.recent(10).unwrap().len(), 3);
+            drop(tree);
+
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.recent(2).unwrap(), expected);
+
+            tree.flush().await.unwrap();
+            assert!(tree.recent(10).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn newest_seq_wins() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("newest_seq_wins");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            let key = "key".to_string();
+            for value in ["1", "2", "3"] {
+                tree.set(key.clone(), value.to_string()).await.unwrap();
+                tree.set(value.to_string(), value.to_string())
+                    .await
+                    .unwrap();
+                tree.flush().await.unwrap();
+            }
+            assert_eq!(tree.read_sstable_indices, vec![0, 2, 4]);
+
+            // The oldest sstable is compacted to the highest index.
+            tree.compact(vec![0], 10).await.unwrap();
+            assert_eq!(tree.get(&key).await.unwrap(), Some("3".to_string()));
+
+            // Inputs are not given from the oldest to the newest.
+            tree.compact(vec![4, 10, 2], 7).await.unwrap();
+            assert_eq!(tree.get(&key).await.unwrap(), Some("3".to_string()));
+            assert_eq!(tree.sstable_headers[&7].entries, 4);
+            drop(tree);
+
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.next_seq, 6);
+            tree.set(key.clone(), "4".to_string()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.compact(vec![tree.write_sstable_index - 2, 7], 1)
+                .await
+                .unwrap();
+            assert_eq!(tree.get(&key).await.unwrap(), Some("4".to_string()));
+        });
+    }
+
+    #[test]
+    fn flush_returns_sstable_index() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("flush_returns_sstable_index");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.flush().await.unwrap(), None);
+
+            tree.set("a".to_string(), "1".to_string()).await.unwrap();
+            tree.set("b".to_string(), "2".to_string()).await.unwrap();
+            let index = tree.flush().await.unwrap().unwrap();
+            assert_eq!(tree.flush().await.unwrap(), None);
+
+            let info = tree.sstable_info().unwrap();
+            assert_eq!(info.len(), 1);
+            assert_eq!(info[0].index, index);
+            assert_eq!(info[0].entries, 2);
+        });
+    }
+
+    #[test]
+    fn fields() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("fields");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            let user = "user".to_string();
+            tree.set(user.clone(), "plain".to_string()).await.unwrap();
+            tree.set_field(user.clone(), "name".into(), "tony".into())
+                .await
+                .unwrap();
+            tree.set_field(user.clone(), "age".into(), "24".into())
+                .await
+                .unwrap();
+            tree.flush().await.unwrap();
+            tree.set_field(user.clone(), "age".into(), "25".into())
+                .await
+                .unwrap();
+            tree.flush().await.unwrap();
+
+            let name = tree.get_field(&user, "name").await.unwrap();
+            assert_eq!(name, Some("tony".to_string()));
+            let age = tree.get_field(&user, "age").await.unwrap();
+            assert_eq!(age, Some("25".to_string()));
+            assert_eq!(tree.get_field(&user, "email").await.unwrap(), None);
+            let plain = tree.get(&user).await.unwrap();
+            assert_eq!(plain, Some("plain".to_string()));
+
+            // The key length prefix keeps keys and fields from mixing.
+            assert_eq!(tree.get_field("usern", "ame").await.unwrap(), None);
+            tree.set_field("users".into(), "name".into(), "all".into())
+                .await
+                .unwrap();
+            let fields = tree.get_fields(&user).await.unwrap();
+            assert_eq!(
+                fields.into_iter().collect::<Vec<_>>(),
+                [
+                    ("age".to_string(), "25".to_string()),
+                    ("name".to_string(), "tony".to_string()),
+                ]
+            );
+            assert!(tree.get_fields("nobody").await.unwrap().is_empty());
+
+            // Writes can't reach the keys of fields, replicated fields can.
+            let field_key = LSMTree::field_key(&user, "age");
+            assert!(tree.set(field_key.clone(), "0".into()).await.is_err());
+            assert!(tree.delete(field_key.clone()).await.is_err());
+            let raw = tree.get_raw_entry(&field_key).await.unwrap().unwrap();
+            let mut replica =
+                LSMTree::new(test_dir("fields_replica")).await.unwrap();
+            replica.apply_raw_entry(&raw).await.unwrap();
+            let age = replica.get_field(&user, "age").await.unwrap();
+            assert_eq!(age, Some("25".to_string()));
+            let mut entry: Entry = bincode_options().deserialize(&raw).unwrap();
+            entry.key = "\0not a field".into();
+            let raw = bincode_options().serialize(&entry).unwrap();
+            assert!(replica.apply_raw_entry(&raw).await.is_err());
+            assert!(!LSMTree::is_field_key("\x004\x00use"));
+            assert!(!LSMTree::is_field_key("\x0004\x00user"));
+
+            let options = LSMTreeOptions::new().with_fixed_key_size(4);
+            let mut fixed = LSMTree::with_options(
+                test_dir("fields_fixed_key_size"),
+                options,
+            )
+            .await
+            .unwrap();
+            let error = fixed
+                .set_field(user.clone(), "name".into(), "tony".into())
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains("fixed key size"), "{}", error);
+        });
+    }
+
+    #[test]
+    fn gc_waits_for_held_files() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("gc_waits_for_held_files");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..2 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            let (data_path, index_path) =
+                LSMTree::get_data_file_paths(dir.clone(), 0);
+
+            let guard = tree.hold_sstable_files();
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            assert!(data_path.exists() && index_path.exists());
+            assert_eq!(tree.gc(), 0);
+            assert_eq!(
+                tree.get(&"0".to_string()).await.unwrap(),
+                Some("0".into())
+            );
+
+            drop(guard);
+            assert_eq!(tree.gc(), 8);
+            assert!(!data_path.exists() && !index_path.exists());
+            assert_eq!(tree.gc(), 0);
+        });
+    }
+
+    #[test]
+    fn pending_deletes_are_deleted_on_open() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("pending_deletes_are_deleted_on_open");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..2 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+
+            let guard = tree.hold_sstable_files();
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            std::mem::forget(guard);
+            drop(tree);
+            assert!(data_path.exists());
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert!(!data_path.exists());
+            assert_eq!(tree.read_sstable_indices, vec![5]);
+            assert_eq!(
+                tree.get(&"1".to_string()).await.unwrap(),
+                Some("1".into())
+            );
+        });
+    }
+
+    #[test]
+    fn idle_flush() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("idle_flush");
+            let options = LSMTreeOptions::new()
+                .with_idle_flush_timeout(Duration::from_millis(50));
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(tree.idle_flush_deadline(), None);
+
+            tree.set("a".into(), "1".into()).await.unwrap();
+            let deadline = tree.idle_flush_deadline().unwrap();
+            assert_eq!(tree.flush_if_idle().await.unwrap(), None);
+
+            glommio::timer::sleep(deadline - Instant::now()).await;
+            assert_eq!(tree.flush_if_idle().await.unwrap(), Some(0));
+            assert_eq!(tree.idle_flush_deadline(), None);
+            assert_eq!(tree.flush_if_idle().await.unwrap(), None);
+            assert_eq!(
+                tree.get(&"a".to_string()).await.unwrap(),
+                Some("1".into())
+            );
+        });
+    }
+
+    #[test]
+    fn get_with_source() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_with_source");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            let key = "a".to_string();
+            assert_eq!(
+                tree.get_with_source(&key).await.unwrap(),
+                (None, ValueSource::NotFound)
+            );
+
+            tree.set(key.clone(), "1".into()).await.unwrap();
+            assert_eq!(
+                tree.get_with_source(&key).await.unwrap(),
+                (Some("1".into()), ValueSource::ActiveMemtable)
+            );
+
+            tree.flush().await.unwrap();
+            tree.set(key.clone(), "2".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            assert_eq!(
+                tree.get_with_source(&key).await.unwrap(),
+                (Some("2".into()), ValueSource::Sstable(2))
+            );
+
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            assert_eq!(
+                tree.get_with_source(&key).await.unwrap(),
+                (Some("2".into()), ValueSource::Sstable(5))
+            );
+        });
+    }
+
+    #[test]
+    fn compaction_writes_meta() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_writes_meta");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i * 2), i.to_string())
+                    .await
+                    .unwrap();
+                if i % 50 == 49 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            tree.compact(vec![0, 2], 5).await.unwrap();
+
+            let meta = &tree.sstable_metas[&5];
+            assert_eq!(
+                meta.key_range,
+                Some(("000".to_string(), "198".to_string()))
+            );
+            for i in 0..100 {
+                assert!(meta.may_contain(&format!("{:03}", i * 2)));
+            }
+            let false_positives = (0..100)
+                .filter(|i| meta.may_contain(&format!("{:03}", i * 2 + 1)))
+                .count();
+            assert!(false_positives < 10);
+            assert!(!meta.may_contain("199"));
+            assert_eq!(tree.sstable_metas.len(), 1);
+
+            drop(tree);
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert!(tree.sstable_metas[&5].may_contain("100"));
+            assert!(!LSMTree::get_meta_file_path(dir, 0).exists());
+            assert_eq!(
+                tree.get(&"100".to_string()).await.unwrap(),
+                Some("50".into())
+            );
+            assert_eq!(tree.get(&"101".to_string()).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn retries() {
+        LocalExecutor::default().run(async {
+            let transient = || -> glommio::GlommioError<()> {
+                std::io::Error::from(std::io::ErrorKind::Interrupted).into()
+            };
+            let policy = RetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(1),
+            };
+
+            let mut attempts = 0;
+            let result = with_retries(&policy, || {
+                attempts += 1;
+                let result = if attempts < 3 {
+                    Err(transient())
+                } else {
+                    Ok(attempts)
+                };
+                async move { result }
+            })
+            .await;
+            assert_eq!(result.unwrap(), 3);
+
+            let mut attempts = 0;
+            let result: glommio::Result<(), ()> = with_retries(&policy, || {
+                attempts += 1;
+                let error = transient();
+                async move { Err(error) }
+            })
+            .await;
+            assert!(result.is_err());
+            assert_eq!(attempts, 3);
+
+            // Permanent errors are not retried.
+            let mut attempts = 0;
+            let result = with_retries(&policy, || {
+                attempts += 1;
+                glommio::io::DmaFile::open("/does/not/exist")
+            })
+            .await;
+            assert!(result.is_err());
+            assert_eq!(attempts, 1);
+        });
+    }
+
+    #[test]
+    fn retries_of_tree() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("retries_of_tree");
+            let storage = FailingStorage::default();
+            let options = LSMTreeOptions::new()
+                .with_retry_policy(RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Duration::from_millis(1),
+                })
+                .with_storage(storage.clone());
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+
+            // The data file is created on the third attempt.
+            storage.failures.set(2);
+            tree.flush().await.unwrap();
+            assert_eq!(storage.failures.get(), 0);
+            assert_eq!(tree.sstable_count(), 1);
+
+            // The sstable is opened on the third attempt.
+            storage.failures.set(2);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            assert_eq!(storage.failures.get(), 0);
+            storage.failures.set(2);
+            assert_eq!(
+                tree.get_many(&["a".into()]).await.unwrap(),
+                vec![Some("1".into())]
+            );
+            assert_eq!(storage.failures.get(), 0);
+        });
+    }
+
+    #[test]
+    fn retries_of_tree_exhausted() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("retries_of_tree_exhausted");
+            let storage = FailingStorage::default();
+            let options = LSMTreeOptions::new()
+                .with_retry_policy(RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Duration::from_millis(1),
+                })
+                .with_storage(storage.clone());
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+
+            // Every attempt to create the data file fails.
+            storage.failures.set(3);
+            let error = tree.flush().await.unwrap_err();
+            assert_eq!(
+                io_error_kind(&error),
+                Some(std::io::ErrorKind::Interrupted)
+            );
+            assert_eq!(storage.failures.get(), 0);
+            assert_eq!(tree.sstable_count(), 0);
+
+            // The tree is left as it was, so the flush can be tried again.
+            tree.flush().await.unwrap();
+            storage.failures.set(3);
+            let error = tree.get(&"a".into()).await.unwrap_err();
+            assert_eq!(
+                io_error_kind(&error),
+                Some(std::io::ErrorKind::Interrupted)
+            );
+            assert_eq!(storage.failures.get(), 0);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+        });
+    }
+
+    #[test]
+    fn get_memtable() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_memtable");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            let key = "a".to_string();
+            tree.set(key.clone(), "1".into()).await.unwrap();
+            assert_eq!(tree.get_memtable(&key).unwrap(), Some("1".into()));
+            assert_eq!(tree.get_memtable_ref(&key).unwrap(), Some("1"));
+
+            tree.flush().await.unwrap();
+            assert_eq!(tree.get_memtable(&key).unwrap(), None);
+            assert_eq!(tree.get_memtable_ref(&key).unwrap(), None);
+            assert_eq!(tree.get(&key).await.unwrap(), Some("1".into()));
+        });
+    }
+
+    #[test]
+    fn raw_entries() {
+        LocalExecutor::default().run(async {
+            let mut source =
+                LSMTree::new(test_dir("raw_entries_source")).await.unwrap();
+            let mut replica =
+                LSMTree::new(test_dir("raw_entries_replica")).await.unwrap();
+            let (a, b) = ("a".to_string(), "b".to_string());
+            source.set(a.clone(), "1".into()).await.unwrap();
+            source.set(b.clone(), "2".into()).await.unwrap();
+            source.flush().await.unwrap();
+            source.set(a.clone(), "3".into()).await.unwrap();
+            assert_eq!(source.get_raw_entry(&"c".into()).await.unwrap(), None);
+
+            for key in [&b, &a] {
+                let raw = source.get_raw_entry(key).await.unwrap().unwrap();
+                replica.apply_raw_entry(&raw).await.unwrap();
+                assert_eq!(
+                    replica.get_raw_entry(key).await.unwrap(),
+                    Some(raw)
+                );
+            }
+            assert_eq!(replica.get(&a).await.unwrap(), Some("3".into()));
+            assert_eq!(replica.next_seq, 3);
+
+            assert!(replica.apply_raw_entry(&[1, 2, 3]).await.is_err());
+        });
+    }
+
+    // Appends to the file WAL, and forwards the entries to a replica.
+    struct ForwardingWal {
+        file: FileWal,
+        forwarded: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl WriteAheadLog for ForwardingWal {
+        fn append<'a>(&'a mut self, entry_encoded: &'a [u8]) -> WalFuture<'a> {
+            Box::pin(async move {
+                self.file.append(entry_encoded).await?;
+                self.forwarded.borrow_mut().push(entry_encoded.to_vec());
+                Ok(())
+            })
+        }
+
+        fn sync(&mut self) -> WalFuture<'_> {
+            self.file.sync()
+        }
+    }
+
+    #[test]
+    fn forwarding_wal() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("forwarding_wal_source");
+            let sent = Rc::new(RefCell::new(Vec::new()));
+            let forwarded = sent.clone();
+            let options = LSMTreeOptions::default().with_wal(move |file| {
+                Box::new(ForwardingWal {
+                    file,
+                    forwarded: forwarded.clone(),
+                })
+            });
+            let mut source =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            let mut replica = LSMTree::new(test_dir("forwarding_wal_replica"))
+                .await
+                .unwrap();
+            source.set("a".into(), "1".into()).await.unwrap();
+            source.set("b".into(), "2".into()).await.unwrap();
+            source.sync_wal().await.unwrap();
+            // The WAL after a flush is wrapped too.
+            source.flush().await.unwrap();
+            source.set("a".into(), "3".into()).await.unwrap();
+            source.set("b".into(), "4".into()).await.unwrap();
+
+            let entries = std::mem::take(&mut *sent.borrow_mut());
+            assert_eq!(entries.len(), 4);
+            for entry in entries {
+                replica.apply_raw_entry(&entry).await.unwrap();
+            }
+            assert_eq!(
+                replica.get(&"a".into()).await.unwrap(),
+                Some("3".into())
+            );
+            assert_eq!(
+                replica.get(&"b".into()).await.unwrap(),
+                Some("4".into())
+            );
+
+            // The entries are in the WAL file too, to recover from.
+            drop(source);
+            let source = LSMTree::new(dir).await.unwrap();
+            assert_eq!(
+                source.get(&"a".into()).await.unwrap(),
+                Some("3".into())
+            );
+            assert_eq!(
+                source.get(&"b".into()).await.unwrap(),
+                Some("4".into())
+            );
+        });
+    }
+
+    #[test]
+    fn delete() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("delete");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            let (a, b) = ("a".to_string(), "b".to_string());
+            tree.set(a.clone(), "1".into()).await.unwrap();
+            tree.set(b.clone(), "2".into()).await.unwrap();
+            let oldest = tree.flush().await.unwrap().unwrap();
+
+            assert_eq!(tree.delete(a.clone()).await.unwrap(), None);
+            assert_eq!(tree.get(&a).await.unwrap(), None);
+            assert!(!tree.contains_key(&a).await.unwrap());
+            assert!(!tree.probably_contains(&a));
+            assert_eq!(tree.get_memtable(&a).unwrap(), None);
+            let newest = tree.flush().await.unwrap().unwrap();
+
+            // Deleted when only an sstable holds the tombstone.
+            assert_eq!(tree.get(&a).await.unwrap(), None);
+            assert_eq!(
+                tree.get_many(&[a.clone(), b.clone()]).await.unwrap(),
+                [None, Some("2".into())]
+            );
+            assert_eq!(
+                tree.get_version(&a, 1).await.unwrap(),
+                Some("1".into())
+            );
+            let mut pairs = Vec::new();
+            tree.for_each_range(&String::new(), &"z".into(), |pair| {
+                pairs.push(pair);
+                std::future::ready(ControlFlow::Continue(()))
+            })
+            .await
+            .unwrap();
+            assert_eq!(pairs, [(b.clone(), "2".to_string())]);
+
+            // A replica deletes the key too.
+            let mut replica =
+                LSMTree::new(test_dir("delete_replica")).await.unwrap();
+            replica.set(a.clone(), "1".into()).await.unwrap();
+            let raw = tree.get_raw_entry(&a).await.unwrap().unwrap();
+            replica.apply_raw_entry(&raw).await.unwrap();
+            assert_eq!(replica.get(&a).await.unwrap(), None);
+
+            // The tombstone is kept while an sstable outside the compaction
+            // holds the key.
+            let output = tree.unused_sstable_indices(1)[0];
+            tree.compact(vec![newest], output).await.unwrap();
+            assert_eq!(tree.sstable_headers[&output].entries, 1);
+            assert_eq!(tree.get(&a).await.unwrap(), None);
+
+            // Dropped with the older versions when all of them are compacted.
+            let result = tree
+                .run_compaction(CompactionPolicy::Full)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(result.inputs.len(), 2);
+            assert!(result.inputs.contains(&oldest));
+            assert_eq!(tree.sstable_headers[&result.output].entries, 1);
+            assert_eq!(tree.get(&a).await.unwrap(), None);
+            assert_eq!(tree.get(&b).await.unwrap(), Some("2".into()));
+
+            // Deletes survive a reopen from the WAL.
+            tree.delete(b.clone()).await.unwrap();
+            drop(tree);
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.get(&b).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn tombstone_rewrite() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("tombstone_rewrite");
+            let options =
+                LSMTreeOptions::new().with_tombstone_rewrite_threshold(0.5);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            // Keys deleted soon after they are written, like a queue, so both
+            // sstables are mostly tombstones.
+            for sstable in 0..2 {
+                for i in sstable * 100..sstable * 100 + 100 {
+                    tree.set(format!("{:03}", i), "v".into()).await.unwrap();
+                }
+                for i in sstable * 100..sstable * 100 + 80 {
+                    tree.delete(format!("{:03}", i)).await.unwrap();
+                }
+                tree.flush().await.unwrap();
+            }
+            let tombstones = |tree: &LSMTree| {
+                tree.sstable_info()
+                    .unwrap()
+                    .into_iter()
+                    .map(|info| (info.index, info.entries, info.tombstones))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(tombstones(&tree), [(0, 100, 80), (2, 100, 80)]);
+
+            // Only the oldest sstable is rewritten, once.
+            assert!(tree.maybe_compact().await.unwrap());
+            assert_eq!(tombstones(&tree), [(2, 100, 80), (5, 20, 0)]);
+            assert!(!tree.maybe_compact().await.unwrap());
+
+            for i in 0..200 {
+                let expected = (i % 100 >= 80).then(|| "v".to_string());
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), expected, "{}", key);
+            }
+        });
+    }
+
+    #[test]
+    fn tombstone_grace_period() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("tombstone_grace_period");
+            let options = LSMTreeOptions::new()
+                .with_tombstone_grace_period(TombstoneGracePeriod::Writes(10))
+                .with_tombstone_rewrite_threshold(0.5);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.delete("a".into()).await.unwrap();
+            tree.flush().await.unwrap();
+
+            // Nothing outside of the merge holds the key, but the tombstone
+            // is in its grace period.
+            tree.compact(vec![0, 2], 3).await.unwrap();
+            assert_eq!(tree.sstable_headers[&3].tombstones, 1);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), None);
+            assert!(!tree.maybe_compact().await.unwrap());
+
+            for i in 0..9 {
+                tree.set(format!("b{}", i), "v".into()).await.unwrap();
+            }
+            assert!(!tree.maybe_compact().await.unwrap());
+            tree.set("b9".into(), "v".into()).await.unwrap();
+            // Dropped by the rewrite once 10 writes were made after it.
+            assert!(tree.maybe_compact().await.unwrap());
+            assert_eq!(tree.read_sstable_indices, vec![5]);
+            assert_eq!(tree.sstable_headers[&5].entries, 0);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), None);
+
+            let dir = test_dir("tombstone_grace_period_for");
+            let options = LSMTreeOptions::new().with_tombstone_grace_period(
+                TombstoneGracePeriod::For(Duration::from_secs(3600)),
+            );
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.delete("a".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.compact(vec![0, 2], 3).await.unwrap();
+            assert_eq!(tree.sstable_headers[&3].tombstones, 1);
+        });
+    }
+
+    #[test]
+    fn range() {
+        LocalExecutor::default().run(async {
+            let mut tree = LSMTree::new(test_dir("range")).await.unwrap();
+            for i in 0..10 {
+                tree.set(i.to_string(), "old".into()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in [2, 4, 6] {
+                tree.set(i.to_string(), "new".into()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            tree.set("4".into(), "newest".into()).await.unwrap();
+            tree.delete("5".into()).await.unwrap();
+
+            let (start, end) = ("2".to_string(), "7".to_string());
+            assert_eq!(
+                tree.range(&start, &end).await.unwrap(),
+                [
+                    ("2".to_string(), "new".to_string()),
+                    ("3".to_string(), "old".to_string()),
+                    ("4".to_string(), "newest".to_string()),
+                    ("6".to_string(), "new".to_string()),
+                ]
+            );
+            assert!(tree.range(&start, &start).await.unwrap().is_empty());
+            assert!(tree.range(&end, &start).await.unwrap().is_empty());
+            assert!(tree
+                .range(&"a".into(), &"b".into())
+                .await
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn content_digest() {
+        LocalExecutor::default().run(async {
+            let mut compacted =
+                LSMTree::new(test_dir("content_digest_compacted"))
+                    .await
+                    .unwrap();
+            let mut memtable =
+                LSMTree::new(test_dir("content_digest_memtable"))
+                    .await
+                    .unwrap();
+            let empty = compacted.content_digest().await.unwrap();
+
+            for i in 0..20 {
+                compacted.set(i.to_string(), "old".into()).await.unwrap();
+            }
+            compacted.flush().await.unwrap();
+            for i in 10..30 {
+                compacted.set(i.to_string(), i.to_string()).await.unwrap();
+            }
+            compacted.flush().await.unwrap();
+            compacted
+                .run_compaction(CompactionPolicy::Full)
+                .await
+                .unwrap()
+                .unwrap();
+            compacted.set("5".into(), "new".into()).await.unwrap();
+
+            for i in (0..30).rev() {
+                let value = match i {
+                    5 => "new".to_string(),
+                    0..10 => "old".to_string(),
+                    _ => i.to_string(),
+                };
+                memtable.set(i.to_string(), value).await.unwrap();
+            }
+
+            let digest = compacted.content_digest().await.unwrap();
+            assert_ne!(digest, empty);
+            assert_eq!(memtable.content_digest().await.unwrap(), digest);
+
+            memtable.set("5".into(), "newer".into()).await.unwrap();
+            assert_ne!(memtable.content_digest().await.unwrap(), digest);
+        });
+    }
+
+    #[test]
+    fn pause_compaction() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("pause_compaction");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            let pause = PauseToken::new();
+            pause.pause();
+            let mut compaction = tree.start_compaction(vec![0, 2], 5).unwrap();
+            let task = glommio::spawn_local({
+                let pause = pause.clone();
+                async move {
+                    compaction.run(&pause).await.unwrap();
+                    compaction
+                }
+            });
+            let (compact_data_path, _) =
+                LSMTree::get_compaction_file_paths(dir.clone(), 5);
+            while !compact_data_path.exists() {
+                glommio::timer::sleep(Duration::from_millis(1)).await;
+            }
+
+            // The tree is usable while the compaction is paused.
+            assert_eq!(
+                tree.get(&"150".to_string()).await.unwrap(),
+                Some("150".into())
+            );
+            tree.set("150".into(), "new".into()).await.unwrap();
+
+            pause.resume();
+            let compaction = task.await;
+            tree.finish_compaction_job(compaction).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![5]);
+            assert_eq!(
+                tree.get(&"150".to_string()).await.unwrap(),
+                Some("new".into())
+            );
+            for i in 0..150 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn pause_compaction_mid_merge() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("pause_compaction_mid_merge");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            // Big enough values for the merge to write a good part of its
+            // output to the file well before it's done.
+            let value = |version: &str, i: usize| {
+                format!("{}{:04}{}", version, i, "x".repeat(1000))
+            };
+            for i in 0..1000 {
+                tree.set(format!("{:04}", i), value("a", i)).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in 500..1500 {
+                tree.set(format!("{:04}", i), value("b", i)).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+
+            let pause = PauseToken::new();
+            let done = Rc::new(Cell::new(false));
+            let mut compaction = tree.start_compaction(vec![0, 2], 5).unwrap();
+            let task = glommio::spawn_local({
+                let pause = pause.clone();
+                let done = done.clone();
+                async move {
+                    compaction.run(&pause).await.unwrap();
+                    done.set(true);
+                    compaction
+                }
+            });
+            let (compact_data_path, _) =
+                LSMTree::get_compaction_file_paths(dir.clone(), 5);
+            let written =
+                || std::fs::metadata(&compact_data_path).map_or(0, |m| m.len());
+            // About 200 entries in.
+            while written() < 200 * 1000 {
+                glommio::timer::sleep(Duration::from_millis(1)).await;
+            }
+            pause.pause();
+            let written_when_paused = written();
+
+            // Still paused after a while, writing nothing more, with the
+            // reads and writes of the tree going on.
+            glommio::timer::sleep(Duration::from_millis(20)).await;
+            let written_while_paused = written();
+            glommio::timer::sleep(Duration::from_millis(20)).await;
+            assert_eq!(written(), written_while_paused);
+            assert!(!done.get());
+            assert_eq!(
+                tree.get(&"0700".to_string()).await.unwrap(),
+                Some(value("b", 700))
+            );
+            tree.set("0100".into(), "new".into()).await.unwrap();
+            assert!(!done.get());
+
+            pause.resume();
+            let compaction = task.await;
+            assert!(written() > written_when_paused);
+            tree.finish_compaction_job(compaction).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![5]);
+
+            // Every key once, with its newest version.
+            let header = &tree.sstable_headers[&5];
+            assert_eq!((header.entries, header.keys), (1500, 1500));
+            let mut keys = 0;
+            tree.for_each_range(&"0000".into(), &"1500".into(), |(key, v)| {
+                let i: usize = key.parse().unwrap();
+                let expected = match i {
+                    100 => "new".to_string(),
+                    0..=499 => value("a", i),
+                    _ => value("b", i),
+                };
+                assert_eq!(v, expected, "{}", key);
+                keys += 1;
+                std::future::ready(ControlFlow::Continue(()))
+            })
+            .await
+            .unwrap();
+            assert_eq!(keys, 1500);
+        });
+    }
+
+    #[test]
+    fn get_many_skips_sstables_by_filter() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_many_skips_sstables_by_filter");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            tree.set("050".into(), "new".into()).await.unwrap();
+
+            let absent: Vec<String> =
+                (0..100).map(|i| format!("{:03}a", i)).collect();
+            let values = tree.get_many(&absent).await.unwrap();
+            assert!(values.iter().all(Option::is_none));
+            let stats = tree.stats();
+            assert!(stats.sstable_opens <= 1, "{:?}", stats);
+            assert!(stats.sstables_skipped_by_filter >= 1, "{:?}", stats);
+
+            let keys: Vec<String> =
+                ["050", "150", "x", "000"].map(String::from).to_vec();
+            assert_eq!(
+                tree.get_many(&keys).await.unwrap(),
+                vec![
+                    Some("new".into()),
+                    Some("150".into()),
+                    None,
+                    Some("0".into())
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn adversarial_keys_round_trip() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        const PIECES: [&str; 9] =
+            ["a", "/", "\n", "\0", "\r\n", "é", "日本", "🦀", "\u{fffd}"];
+
+        let mut rng = StdRng::seed_from_u64(117);
+        let mut random_string = |max_pieces: usize| -> String {
+            let length = rng.gen_range(0..=max_pieces);
+            (0..length)
+                .map(|_| PIECES[rng.gen_range(0..PIECES.len())])
+                .collect()
+        };
+        let mut pairs: Vec<(String, String)> = (0..300)
+            .map(|_| (random_string(8), random_string(8)))
+            .collect();
+        pairs.push(("k".repeat(100_000), "v".repeat(100_000)));
+        pairs.push((String::new(), String::new()));
+        pairs.push(("a\0".to_string(), "\0\0".to_string()));
+        pairs.push(("\0".to_string(), "\0\0".to_string()));
+        // Keys starting with a NUL character are reserved for fields.
+        let (reserved, pairs): (Vec<_>, Vec<_>) = pairs
+            .into_iter()
+            .partition(|(key, _)| key.starts_with('\0'));
+        // Keys repeat, the last write wins.
+        let expected: HashMap<String, String> = pairs.iter().cloned().collect();
+
+        LocalExecutor::default().run(async {
+            let dir = test_dir("adversarial_keys_round_trip");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for (key, value) in &reserved {
+                assert!(tree.set(key.clone(), value.clone()).await.is_err());
+            }
+            let (first_half, second_half) = pairs.split_at(pairs.len() / 2);
+            for (key, value) in first_half {
+                tree.set(key.clone(), value.clone()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for (key, value) in second_half {
+                tree.set(key.clone(), value.clone()).await.unwrap();
+            }
+
+            // Read the second half back from the WAL.
+            drop(tree);
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for (key, value) in &expected {
+                assert_eq!(tree.get(key).await.unwrap().as_ref(), Some(value));
+            }
+
+            tree.flush().await.unwrap();
+            for (key, value) in &expected {
+                assert_eq!(tree.get(key).await.unwrap().as_ref(), Some(value));
+            }
+
+            let indices = tree.read_sstable_indices.clone();
+            tree.compact(indices, 99).await.unwrap();
+            for (key, value) in &expected {
+                assert_eq!(tree.get(key).await.unwrap().as_ref(), Some(value));
+            }
+            assert_eq!(tree.get(&"missing".to_string()).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn warm_index_cache() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("warm_index_cache");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..300 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            drop(tree);
+
+            // Room for exactly 2 of the 3 index files.
+            let index_size = std::fs::metadata(
+                LSMTree::get_data_file_paths(dir.clone(), 0).1,
+            )
+            .unwrap()
+            .len() as usize;
+            let options =
+                LSMTreeOptions::new().with_index_cache_budget(index_size * 2);
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            tree.warm().await.unwrap();
+            let mut cached: Vec<usize> =
+                tree.index_cache.borrow().indices.keys().copied().collect();
+            cached.sort();
+            assert_eq!(cached, vec![2, 4]);
+
+            // Searching the oldest sstable evicts the least recently used.
+            assert_eq!(
+                tree.get(&"250".to_string()).await.unwrap(),
+                Some("250".into())
+            );
+            assert_eq!(
+                tree.get(&"050".to_string()).await.unwrap(),
+                Some("50".into())
+            );
+            let mut cached: Vec<usize> =
+                tree.index_cache.borrow().indices.keys().copied().collect();
+            cached.sort();
+            assert_eq!(cached, vec![0, 4]);
+            assert_eq!(tree.index_cache.borrow().bytes, index_size * 2);
+            for i in 0..300 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn split_points() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("split_points");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert!(tree.split_points(4).await.unwrap().is_empty());
+            for i in 0..400 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            let points = tree.split_points(4).await.unwrap();
+            assert_eq!(points.len(), 3);
+            assert!(points.windows(2).all(|pair| pair[0] < pair[1]));
+            for (point, expected) in points.iter().zip([100, 200, 300]) {
+                let point: i32 = point.parse().unwrap();
+                assert!((point - expected).abs() <= 25, "{}", point);
+            }
+            assert!(tree.split_points(1).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn bincode_config() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("bincode_config");
+            let config = BincodeConfig {
+                int_encoding: IntEncoding::Varint,
+                endian: Endian::Big,
+            };
+            let options = LSMTreeOptions::new().with_bincode_config(config);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            tree.set("wal".into(), "only".into()).await.unwrap();
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            drop(tree);
+
+            assert!(LSMTree::new(dir.clone()).await.is_err());
+
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(
+                tree.get(&"wal".into()).await.unwrap(),
+                Some("only".into())
+            );
+            for i in 0..200 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+
+            // A directory from before the format file is of the first format
+            // version, that can't be opened.
+            let dir = test_dir("bincode_config_default");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            drop(tree);
+            std::fs::remove_file(dir.join("format")).unwrap();
+            assert!(LSMTree::new(dir).await.is_err());
+        });
+    }
+
+    #[test]
+    fn write_stalls() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("write_stalls");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            for i in 0..TREE_CAPACITY - 1 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+            }
+            // Explicit flushes are not writes.
+            tree.flush().await.unwrap();
+            assert_eq!(tree.stats().write_stalls, 0);
+
+            for i in 0..TREE_CAPACITY {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+            }
+            let stats = tree.stats();
+            assert_eq!(stats.write_stalls, 1);
+            assert!(stats.write_stall_duration > Duration::ZERO);
+            assert_eq!(tree.read_sstable_indices.len(), 2);
+        });
+    }
+
+    #[test]
+    fn open_without_wal_replay() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("open_without_wal_replay");
+            let options = || LSMTreeOptions::new().with_fixed_key_size(1);
+            let mut tree =
+                LSMTree::with_options(dir.clone(), options()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            drop(tree);
+
+            let old_wal_path = LSMTree::get_wal_path(dir.clone(), 2);
+            assert!(old_wal_path.exists());
+            // The tree was written with other options than the defaults.
+            assert!(LSMTree::open_without_wal_replay(
+                dir.clone(),
+                LSMTreeOptions::default()
+            )
+            .await
+            .is_err());
+            let mut tree =
+                LSMTree::open_without_wal_replay(dir.clone(), options())
+                    .await
+                    .unwrap();
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), None);
+
+            tree.set("c".into(), "3".into()).await.unwrap();
+            assert_eq!(tree.memtable_index, 4);
+            assert!(std::fs::metadata(&old_wal_path).unwrap().len() > 0);
+        });
+    }
+
+    #[test]
+    fn index_header() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("index_header");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("b".into(), "3".into()).await.unwrap();
+            tree.set("c".into(), "4".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            assert_eq!(tree.sstable_headers[&0].entries, 2);
+            assert_eq!(tree.sstable_headers[&0].max_seq, 1);
+            assert_eq!(tree.sstable_headers[&2].max_seq, 3);
+
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            let header = tree.sstable_headers[&5];
+            assert_eq!(
+                (header.entries, header.keys, header.max_seq),
+                (3, 3, 3)
+            );
+            drop(tree);
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.sstable_headers[&5], header);
+            let info = tree.sstable_info().unwrap();
+            assert_eq!(info.iter().map(|i| i.entries).sum::<u64>(), 3);
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), Some("3".into()));
+            drop(tree);
+
+            // A header of another format version is refused.
+            let (_, index_path) = LSMTree::get_data_file_paths(dir.clone(), 5);
+            let mut bytes = std::fs::read(&index_path).unwrap();
+            bytes[0] = 1;
+            std::fs::write(&index_path, bytes).unwrap();
+            assert!(LSMTree::new(dir).await.is_err());
+        });
+    }
+
+    #[test]
+    fn concurrent_disjoint_compactions() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("concurrent_disjoint_compactions");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..400 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            assert_eq!(tree.read_sstable_indices, vec![0, 2, 4, 6]);
+
+            let mut first = tree.start_compaction(vec![0, 2], 9).unwrap();
+            let mut second = tree.start_compaction(vec![4, 6], 11).unwrap();
+            // Overlapping compactions are refused while these run.
+            assert!(tree.start_compaction(vec![2, 4], 13).is_err());
+            assert!(tree.start_compaction(vec![8], 9).is_err());
+
+            let pause = PauseToken::new();
+            let first_task = glommio::spawn_local({
+                let pause = pause.clone();
+                async move {
+                    first.run(&pause).await.unwrap();
+                    first
+                }
+            });
+            second.run(&pause).await.unwrap();
+            let first = first_task.await;
+            tree.finish_compaction_job(second).await.unwrap();
+            tree.finish_compaction_job(first).await.unwrap();
+
+            let mut indices = tree.read_sstable_indices.clone();
+            indices.sort();
+            assert_eq!(indices, vec![9, 11]);
+            for i in 0..400 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+
+            // The reservations are released once the compactions are done.
+            tree.compact(vec![9, 11], 13).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![13]);
+        });
+    }
+
+    #[test]
+    fn for_each_range() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("for_each_range");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..300 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            // Newer versions in a newer sstable and in the memtable.
+            for i in (0..300).step_by(10) {
+                tree.set(format!("{:03}", i), "new".into()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            tree.set("150".into(), "newest".into()).await.unwrap();
+
+            let mut entries = Vec::new();
+            tree.for_each_range(&"095".into(), &"205".into(), |entry| {
+                entries.push(entry);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            let expected: Vec<(String, String)> = (95..205)
+                .map(|i| {
+                    let value = match i {
+                        150 => "newest".into(),
+                        _ if i % 10 == 0 => "new".into(),
+                        _ => i.to_string(),
+                    };
+                    (format!("{:03}", i), value)
+                })
+                .collect();
+            assert_eq!(entries, expected);
+
+            let mut keys = Vec::new();
+            tree.for_each_range(&"000".into(), &"999".into(), |(key, _)| {
+                keys.push(key);
+                let stop = keys.len() == 5;
+                async move {
+                    if stop {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(keys, vec!["000", "001", "002", "003", "004"]);
+        });
+    }
+
+    #[test]
+    fn min_sstables_to_compact() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("min_sstables_to_compact");
+            let options = LSMTreeOptions::new().with_min_sstables_to_compact(3);
+            let mut tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            for i in 0..2 {
+                tree.set(format!("{}", i), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            assert_eq!(tree.pick_compaction(), None);
+            assert!(!tree.maybe_compact().await.unwrap());
+
+            tree.set("2".into(), "2".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            assert_eq!(tree.pick_compaction(), Some((vec![0, 2, 4], 7)));
+            assert!(tree.maybe_compact().await.unwrap());
+            assert_eq!(tree.read_sstable_indices, vec![7]);
+
+            // The output is compacted again together with newer sstables.
+            for i in 3..5 {
+                tree.set(format!("{}", i), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            assert_eq!(tree.pick_compaction(), Some((vec![7, 6, 8], 11)));
+            assert!(tree.maybe_compact().await.unwrap());
+            for i in 0..5 {
+                let key = format!("{}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn failed_flush_is_recoverable() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("failed_flush_is_recoverable");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+
+            // A directory at the meta path fails the flush after the data and
+            // index files are written.
+            let meta_path = LSMTree::get_meta_file_path(dir.clone(), 0);
+            std::fs::create_dir(&meta_path).unwrap();
+            for i in 0..TREE_CAPACITY - 1 {
+                tree.set(format!("{:04}", i), i.to_string()).await.unwrap();
+            }
+            let last_key = format!("{:04}", TREE_CAPACITY - 1);
+            assert!(tree.set(last_key.clone(), "last".into()).await.is_err());
+            assert!(tree.read_sstable_indices.is_empty());
+            assert_eq!(tree.get(&last_key).await.unwrap(), Some("last".into()));
+            assert!(tree.flush().await.is_err());
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+            assert!(!data_path.exists());
+
+            // The write was accepted, it's recovered from the WAL.
+            drop(tree);
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.get(&last_key).await.unwrap(), Some("last".into()));
+            assert_eq!(tree.active_memtable.len(), TREE_CAPACITY);
+
+            // The next write flushes the full memtable before it's accepted,
+            // it's not inserted when the flush fails, even as an overwrite.
+            assert!(tree.set("new".into(), "1".into()).await.is_err());
+            assert!(tree.set(last_key.clone(), "2".into()).await.is_err());
+            assert_eq!(tree.active_memtable.len(), TREE_CAPACITY);
+            assert_eq!(tree.get(&"new".into()).await.unwrap(), None);
+            std::fs::remove_dir(&meta_path).unwrap();
+            tree.set("new".into(), "1".into()).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![0]);
+            assert_eq!(tree.get(&last_key).await.unwrap(), Some("last".into()));
+            assert_eq!(
+                tree.get(&"0000".into()).await.unwrap(),
+                Some("0".into())
+            );
+
+            drop(tree);
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(
+                tree.get(&"new".into()).await.unwrap(),
+                Some("1".into())
+            );
+            assert_eq!(tree.get(&last_key).await.unwrap(), Some("last".into()));
+        });
+    }
+
+    #[test]
+    fn versions_to_keep() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("versions_to_keep");
+            let options = LSMTreeOptions::new().with_versions_to_keep(2);
+            let mut tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            for version in 0..3 {
+                for i in 0..50 {
+                    tree.set(format!("{:02}", i), format!("{}-{}", i, version))
+                        .await
+                        .unwrap();
+                }
+                tree.flush().await.unwrap();
+            }
+            tree.set("07".into(), "7-3".into()).await.unwrap();
+
+            tree.compact(vec![0, 2, 4], 7).await.unwrap();
+            assert_eq!(tree.sstable_headers[&7].entries, 100);
+            assert_eq!(tree.sstable_headers[&7].keys, 50);
+
+            for i in 0..50 {
+                let key = format!("{:02}", i);
+                let newest = if i == 7 { 3 } else { 2 };
+                assert_eq!(
+                    tree.get(&key).await.unwrap(),
+                    Some(format!("{}-{}", i, newest))
+                );
+                for n in 0..2 {
+                    assert_eq!(
+                        tree.get_version(&key, n).await.unwrap(),
+                        Some(format!("{}-{}", i, newest - n))
+                    );
+                }
+            }
+            assert_eq!(
+                tree.get_version(&"07".into(), 2).await.unwrap(),
+                Some("7-1".into())
+            );
+            assert_eq!(tree.get_version(&"08".into(), 2).await.unwrap(), None);
+
+            let mut entries = Vec::new();
+            tree.for_each_range(&"00".into(), &"03".into(), |entry| {
+                entries.push(entry);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                entries,
+                vec![
+                    ("00".into(), "0-2".into()),
+                    ("01".into(), "1-2".into()),
+                    ("02".into(), "2-2".into()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn replace_with() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("replace_with");
+            let staging = test_dir("replace_with_staging");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..10 {
+                tree.set(format!("old{}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            tree.set("old".into(), "memtable".into()).await.unwrap();
+
+            let mut staging_tree = LSMTree::new(staging.clone()).await.unwrap();
+            for i in 0..10 {
+                staging_tree
+                    .set(format!("new{}", i), i.to_string())
+                    .await
+                    .unwrap();
+                if i == 4 {
+                    staging_tree.flush().await.unwrap();
+                }
+            }
+            // The last writes are only in the WAL of the staging tree.
+            drop(staging_tree);
+
+            tree.replace_with(staging.clone()).await.unwrap();
+            assert_eq!(tree.get(&"old".into()).await.unwrap(), None);
+            assert_eq!(tree.get(&"old1".into()).await.unwrap(), None);
+            tree.set("new1".into(), "newer".into()).await.unwrap();
+            drop(tree);
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.get(&"old1".into()).await.unwrap(), None);
+            assert_eq!(
+                tree.get(&"new1".into()).await.unwrap(),
+                Some("newer".into())
+            );
+            for i in 2..10 {
+                let key = format!("new{}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn replace_with_crash_after_action() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("replace_with_crash_after_action");
+            let staging = test_dir("replace_with_crash_after_action_staging");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("old".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("old_wal".into(), "1".into()).await.unwrap();
+
+            let mut staging_tree = LSMTree::new(staging.clone()).await.unwrap();
+            staging_tree.set("new".into(), "1".into()).await.unwrap();
+            staging_tree.flush().await.unwrap();
+            drop(staging_tree);
+
+            // Crash right after the action is written.
+            let output_indices = tree.unused_sstable_indices(1);
+            let wal_path =
+                LSMTree::get_wal_path(dir.clone(), tree.memtable_index);
+            let action = tree.replace_action(
+                &staging,
+                (&[0], &[]),
+                &output_indices,
+                wal_path,
+            );
+            LSMTree::write_compaction_action(
+                dir.clone(),
+                &action,
+                output_indices[0],
+            )
+            .await
+            .unwrap();
+            drop(tree);
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, output_indices);
+            assert_eq!(
+                tree.get(&"new".into()).await.unwrap(),
+                Some("1".into())
+            );
+            assert_eq!(tree.get(&"old".into()).await.unwrap(), None);
+            assert_eq!(tree.get(&"old_wal".into()).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn is_flushing() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("is_flushing");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            assert!(!tree.is_flushing());
+            tree.flush().await.unwrap();
+            assert!(!tree.is_flushing());
+            tree.wait_for_flush().await;
+        });
+    }
+
+    #[test]
+    fn recover_unflushed_wal() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("recover_unflushed_wal");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("active".into(), "1".into()).await.unwrap();
+            drop(tree);
+
+            // A WAL of a flush that didn't finish, bigger than a single run,
+            // and overwrites spread across runs.
+            const KEYS: usize = 1024;
+            let padding = "x".repeat(500);
+            let wal_path = LSMTree::get_wal_path(dir.clone(), 0);
+            std::fs::rename(&wal_path, LSMTree::get_wal_path(dir.clone(), 2))
+                .unwrap();
+            let config = BincodeConfig::default();
+            let mut wal = Vec::new();
+            let mut seq = 100;
+            for round in 0..3 {
+                for i in 0..KEYS {
+                    let entry = Entry {
+                        key: format!("{:04}", i),
+                        value: Value::Inline(
+                            format!("{}-{}{}", i, round, padding).into_bytes(),
+                        ),
+                        seq,
+                        timestamp: seq,
+                    };
+                    wal.extend(wal_record(&config.serialize(&entry)));
+                    seq += 1;
+                }
+            }
+            // A torn write at the end.
+            wal.extend(
+                &wal_record(&config.serialize(&Entry {
+                    key: "torn".into(),
+                    value: Value::Inline("torn".into()),
+                    seq,
+                    timestamp: seq,
+                }))[..WAL_RECORD_HEADER_SIZE + 5],
+            );
+            std::fs::write(&wal_path, wal).unwrap();
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![0]);
+            assert_eq!(tree.sstable_headers[&0].entries, KEYS as u64);
+            assert_eq!(tree.next_seq, seq);
+            for i in (0..KEYS).step_by(97) {
+                assert_eq!(
+                    tree.get(&format!("{:04}", i)).await.unwrap(),
+                    Some(format!("{}-2{}", i, padding))
+                );
+            }
+            assert_eq!(tree.get(&"torn".into()).await.unwrap(), None);
+            assert_eq!(
+                tree.get(&"active".into()).await.unwrap(),
+                Some("1".into())
+            );
+            let leftovers = std::fs::read_dir(&dir)
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(|entry| {
+                    entry.file_name().to_string_lossy().contains("run")
+                })
+                .count();
+            assert_eq!(leftovers, 0);
+        });
+    }
+
+    #[test]
+    fn amplification_stats() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("amplification_stats");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..10 {
+                tree.set(format!("k{}", i), format!("v{}x", i))
+                    .await
+                    .unwrap();
+            }
+            // The header of the WAL record, then fixint encoding, a u64 length
+            // before the key and the value, a u32 tag of the value being
+            // inline, a u64 sequence number and a u64 timestamp.
+            let stats = tree.stats();
+            assert_eq!(stats.bytes_set, 10 * 5);
+            assert_eq!(
+                stats.wal_bytes_written,
+                10 * (WAL_RECORD_HEADER_SIZE as u64
+                    + 8
+                    + 2
+                    + 4
+                    + 8
+                    + 3
+                    + 8
+                    + 8)
+            );
+
+            tree.flush().await.unwrap();
+            tree.set("k0".into(), "new".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            let sstable_bytes = |index: usize| {
+                let (data_path, index_path) =
+                    LSMTree::get_data_file_paths(dir.clone(), index);
+                std::fs::metadata(data_path).unwrap().len()
+                    + std::fs::metadata(index_path).unwrap().len()
+            };
+            let meta_bytes = |index: usize| {
+                let meta_path = LSMTree::get_meta_file_path(dir.clone(), index);
+                std::fs::metadata(filter_path(&meta_path)).unwrap().len()
+                    + std::fs::metadata(meta_path).unwrap().len()
+            };
+            let inputs_bytes = sstable_bytes(0) + sstable_bytes(2);
+            assert_eq!(
+                tree.stats().flush_bytes_written,
+                inputs_bytes + meta_bytes(0) + meta_bytes(2)
+            );
+
+            // Nothing is read from disk for a key in the memtable.
+            tree.set("mem".into(), "1".into()).await.unwrap();
+            tree.get(&"mem".into()).await.unwrap();
+            assert_eq!(tree.stats().get_bytes_read, 0);
+            tree.get(&"k5".into()).await.unwrap();
+            assert!(tree.stats().get_bytes_read > 0);
+
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            let stats = tree.stats();
+            assert_eq!(stats.compaction_bytes_read, inputs_bytes);
+            assert_eq!(
+                stats.compaction_bytes_written,
+                sstable_bytes(5) + meta_bytes(5)
+            );
+        });
+    }
+
+    // An sstable file in memory.
+    struct MemoryFile(Vec<u8>);
+
+    impl AsyncFile for MemoryFile {
+        type Buffer = Vec<u8>;
+
+        async fn read_at(
+            &self,
+            pos: u64,
+            size: usize,
+        ) -> std::io::Result<Vec<u8>> {
+            let start = (pos as usize).min(self.0.len());
+            let end = (start + size).min(self.0.len());
+            Ok(self.0[start..end].to_vec())
+        }
+
+        async fn file_size(&self) -> std::io::Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        async fn close(self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The local disk, failing the next opens and creates of files with a
+    // transient error, for testing the retries of the tree.
+    #[derive(Clone, Default)]
+    struct FailingStorage {
+        failures: Rc<Cell<u32>>,
+    }
+
+    impl FailingStorage {
+        fn fail(&self) -> std::io::Result<()> {
+            if self.failures.get() == 0 {
+                return Ok(());
+            }
+            self.failures.set(self.failures.get() - 1);
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "injected failure",
+            ))
+        }
+    }
+
+    impl Storage for FailingStorage {
+        fn open<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageFile> {
+            Box::pin(async move {
+                self.fail()?;
+                LocalStorage.open(path).await
+            })
+        }
+
+        fn create<'a>(&'a self, path: &'a Path) -> IoFuture<'a, StorageWriter> {
+            Box::pin(async move {
+                self.fail()?;
+                LocalStorage.create(path).await
+            })
+        }
+
+        fn write_at<'a>(
+            &'a self,
+            path: &'a Path,
+            pos: u64,
+            bytes: &'a [u8],
+        ) -> IoFuture<'a, ()> {
+            LocalStorage.write_at(path, pos, bytes)
+        }
+
+        fn sync<'a>(&'a self, path: &'a Path) -> IoFuture<'a, ()> {
+            LocalStorage.sync(path)
+        }
+
+        fn size(&self, path: &Path) -> std::io::Result<u64> {
+            LocalStorage.size(path)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            LocalStorage.exists(path)
+        }
+
+        fn list(&self, dir: &Path) -> std::io::Result<Vec<String>> {
+            LocalStorage.list(dir)
+        }
+
+        fn remove(&self, path: &Path) -> std::io::Result<()> {
+            LocalStorage.remove(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            LocalStorage.rename(from, to)
+        }
+    }
+
+    #[test]
+    fn search_files_in_memory() {
+        LocalExecutor::default().run(async {
+            let config = BincodeConfig::default();
+            let keys: Vec<String> =
+                (0..100).map(|i| format!("{:03}", i)).collect();
+            for restart_interval in [0, 4] {
+                let header = IndexHeader {
+                    version: FORMAT_VERSION,
+                    entries: keys.len() as u64,
+                    keys: keys.len() as u64,
+                    max_seq: keys.len() as u64 - 1,
+                    max_timestamp: 0,
+                    restart_interval,
+                    created_at: 0,
+                    tombstones: 0,
+                    tombstone_rewrite: false,
+                };
+                let mut data = futures_lite::io::Cursor::new(Vec::new());
+                let mut index = futures_lite::io::Cursor::new(Vec::new());
+                let meta = LSMTree::write_sstable_entries(
+                    header,
+                    keys.iter().enumerate().map(|(i, key)| {
+                        (
+                            key,
+                            Value::Inline(key.clone().into_bytes()),
+                            i as u64,
+                            0,
+                        )
+                    }),
+                    &mut data,
+                    &mut index,
+                    SstableLayout {
+                        fixed_key_size: None,
+                        restart_interval,
+                        false_positive_rate: DEFAULT_FALSE_POSITIVE_RATE,
+                    },
+                    config,
+                )
+                .await
+                .unwrap();
+                assert!(meta.may_contain("042"));
+
+                let data = MemoryFile(data.into_inner());
+                let index = IndexSource::File(MemoryFile(index.into_inner()));
+                let counts = ReadCounts::default();
+                for (key, expected) in
+                    [("0415", 42), ("", 0), ("100", 100), ("042", 42)]
+                {
+                    let position =
+                        lower_bound(&data, &index, &key.into(), None, config)
+                            .await
+                            .unwrap();
+                    assert_eq!(position, expected);
+                    let (position, entry) = binary_search_position(
+                        &data,
+                        &index,
+                        &key.into(),
+                        None,
+                        config,
+                        None,
+                        &counts,
+                    )
+                    .await
+                    .unwrap();
+                    assert_eq!(position, expected);
+                    assert_eq!(
+                        entry.map(|e| e.key),
+                        (key == "042").then(|| key.into())
+                    );
+                }
+
+                for (i, key) in keys.iter().enumerate() {
+                    let entry = binary_search(
+                        &data, &index, key, None, config, None, &counts,
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+                    assert_eq!(entry.key, *key);
+                    assert_eq!(
+                        entry.value,
+                        Value::Inline(key.clone().into_bytes())
+                    );
+                    assert_eq!(entry.seq, i as u64);
+                }
+                let missing = binary_search(
+                    &data,
+                    &index,
+                    &"0415".into(),
+                    None,
+                    config,
+                    None,
+                    &counts,
+                )
+                .await
+                .unwrap();
+                assert!(missing.is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn search_empty_and_single_entry_files() {
+        LocalExecutor::default().run(async {
+            let config = BincodeConfig::default();
+            for (keys, restart_interval) in
+                [(vec![], 0), (vec!["b"], 0), (vec![], 4), (vec!["b"], 4)]
+            {
+                let header = IndexHeader {
+                    version: FORMAT_VERSION,
+                    entries: keys.len() as u64,
+                    keys: keys.len() as u64,
+                    restart_interval,
+                    ..Default::default()
+                };
+                let keys: Vec<String> =
+                    keys.into_iter().map(String::from).collect();
+                let mut data = futures_lite::io::Cursor::new(Vec::new());
+                let mut index = futures_lite::io::Cursor::new(Vec::new());
+                LSMTree::write_sstable_entries(
+                    header,
+                    keys.iter().map(|key| {
+                        (key, Value::Inline(key.clone().into_bytes()), 0, 0)
+                    }),
+                    &mut data,
+                    &mut index,
+                    SstableLayout {
+                        fixed_key_size: None,
+                        restart_interval,
+                        false_positive_rate: DEFAULT_FALSE_POSITIVE_RATE,
+                    },
+                    config,
+                )
+                .await
+                .unwrap();
+
+                let data = MemoryFile(data.into_inner());
+                let index = IndexSource::File(MemoryFile(index.into_inner()));
+                let counts = ReadCounts::default();
+                for key in ["a", "b", "c"] {
+                    let (position, entry) = binary_search_position(
+                        &data,
+                        &index,
+                        &key.into(),
+                        None,
+                        config,
+                        None,
+                        &counts,
+                    )
+                    .await
+                    .unwrap();
+                    let less = keys.iter().filter(|k| k.as_str() < key).count();
+                    assert_eq!(position, less as u64);
+                    assert_eq!(
+                        entry.map(|e| e.key),
+                        keys.iter().find(|k| k.as_str() == key).cloned()
+                    );
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn compaction_plan() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_plan");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in 50..150 {
+                tree.set(format!("{:03}", i), "new".into()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+
+            let estimate =
+                tree.compaction_plan(vec![0, 2], false).await.unwrap();
+            assert!(!estimate.scanned);
+            assert_eq!(estimate.input_entries, 200);
+            assert_eq!(estimate.output_entries, 200);
+
+            let plan = tree.compaction_plan(vec![0, 2], true).await.unwrap();
+            assert_eq!(plan.input_bytes, estimate.input_bytes);
+            assert_eq!(plan.output_entries, 150);
+            assert!(plan.output_bytes < estimate.output_bytes);
+            assert!(tree.compaction_plan(vec![0, 4], false).await.is_err());
+
+            // Nothing was written, the plan matches the compaction.
+            assert_eq!(tree.read_sstable_indices, vec![0, 2]);
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            let (data_path, index_path) =
+                LSMTree::get_data_file_paths(dir.clone(), 5);
+            let output_bytes = std::fs::metadata(data_path).unwrap().len()
+                + std::fs::metadata(index_path).unwrap().len();
+            assert_eq!(plan.output_bytes, output_bytes);
+            assert_eq!(tree.sstable_headers[&5].entries, plan.output_entries);
+
+            // A scan that fails to read an entry fails the plan, instead of
+            // planning a smaller output.
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 5);
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&data_path)
+                .unwrap()
+                .set_len(output_bytes / 4)
+                .unwrap();
+            assert!(tree.compaction_plan(vec![5], false).await.is_ok());
+            assert!(tree.compaction_plan(vec![5], true).await.is_err());
+        });
+    }
+
+    #[test]
+    fn keys_visible_through_flush_steps() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("keys_visible_through_flush_steps");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..50 {
+                tree.set(format!("{:02}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in 25..75 {
+                tree.set(format!("{:02}", i), "new".into()).await.unwrap();
+            }
+
+            let (data_path, index_path) =
+                LSMTree::get_data_file_paths(dir.clone(), 2);
+            let meta_path = LSMTree::get_meta_file_path(dir.clone(), 2);
+            let wal_path = LSMTree::get_wal_path(dir.clone(), 4);
+            async fn assert_all_found(tree: &LSMTree) {
+                for i in 0..75 {
+                    let key = format!("{:02}", i);
+                    let expected =
+                        if i < 25 { i.to_string() } else { "new".into() };
+                    assert_eq!(tree.get(&key).await.unwrap(), Some(expected));
+                }
+            }
+
+            // Fail the flush on every step that writes a file, the keys are
+            // found after each failure, and after the flush that succeeds.
+            for blocked_path in [&wal_path, &data_path, &index_path, &meta_path]
+            {
+                std::fs::create_dir(blocked_path).unwrap();
+                assert!(tree.flush().await.is_err());
+                std::fs::remove_dir(blocked_path).unwrap();
+                assert_all_found(&tree).await;
+            }
+            assert_eq!(tree.flush().await.unwrap(), Some(2));
+            assert_all_found(&tree).await;
+            assert!(!tree.is_flushing());
+        });
+    }
+
+    #[test]
+    fn with_prefix() {
+        assert_eq!(prefix_end("a:"), Some("a;".to_string()));
+        assert_eq!(prefix_end("a\u{10ffff}"), Some("b".to_string()));
+        assert_eq!(prefix_end("\u{d7ff}"), Some("\u{e000}".to_string()));
+        assert_eq!(prefix_end("\u{10ffff}"), None);
+
+        LocalExecutor::default().run(async {
+            let dir = test_dir("with_prefix");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            // Keys right outside of the namespace of "t1:", on both sides.
+            tree.set("t1".to_string(), "before".to_string())
+                .await
+                .unwrap();
+            tree.set("t1;".to_string(), "after".to_string())
+                .await
+                .unwrap();
+            tree.set("t10".to_string(), "other".to_string())
+                .await
+                .unwrap();
+
+            let mut t1 = tree.with_prefix("t1:");
+            for key in ["a", "b", "ba", "c"] {
+                t1.set(key, format!("t1-{}", key)).await.unwrap();
+            }
+            assert_eq!(t1.get("b").await.unwrap(), Some("t1-b".to_string()));
+            assert_eq!(t1.get("t1").await.unwrap(), None);
+
+            let mut t2 = tree.with_prefix("t2:");
+            t2.set("b", "t2-b".to_string()).await.unwrap();
+            assert_eq!(t2.get("a").await.unwrap(), None);
+            assert_eq!(t2.get("b").await.unwrap(), Some("t2-b".to_string()));
+
+            tree.flush().await.unwrap();
+            assert_eq!(
+                tree.get(&"t1:a".to_string()).await.unwrap(),
+                Some("t1-a".to_string())
+            );
+
+            let t1 = tree.with_prefix("t1:");
+            let pairs = |keys: &[&str]| {
+                keys.iter()
+                    .map(|key| (key.to_string(), format!("t1-{}", key)))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(t1.range("b", "c").await.unwrap(), pairs(&["b", "ba"]));
+            assert_eq!(
+                t1.range("", "\u{10ffff}").await.unwrap(),
+                pairs(&["a", "b", "ba", "c"])
+            );
+            assert_eq!(t1.scan_prefix("b").await.unwrap(), pairs(&["b", "ba"]));
+            assert_eq!(
+                t1.scan_prefix("").await.unwrap(),
+                pairs(&["a", "b", "ba", "c"])
+            );
+            assert_eq!(t1.scan_prefix("d").await.unwrap(), pairs(&[]));
+        })
+    }
+
+    #[test]
+    fn orphaned_compaction_files_removed_on_open() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("orphaned_compaction_files_removed_on_open");
+            {
+                let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+                tree.set("a".into(), "1".into()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            let (data_path, index_path) =
+                LSMTree::get_compaction_file_paths(dir.clone(), 1);
+            let meta_path =
+                LSMTree::get_compaction_meta_file_path(dir.clone(), 1);
+            for path in [&data_path, &index_path, &meta_path] {
+                std::fs::write(path, b"partial").unwrap();
+            }
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            for path in [&data_path, &index_path, &meta_path] {
+                assert!(!path.exists());
+            }
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+        });
+    }
+
+    #[test]
+    fn value_log() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("value_log");
+            let options = LSMTreeOptions::new().with_value_log_threshold(100);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            let big =
+                |i: usize, round: usize| format!("{}-{}", i, round).repeat(50);
+
+            tree.set("small".into(), "s".into()).await.unwrap();
+            for i in 0..10 {
+                tree.set(format!("big{}", i), big(i, 0)).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            let value_logs =
+                LSMTree::value_log_ids(&LocalStorage, &dir).unwrap();
+            assert_eq!(value_logs.len(), 1);
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+            assert!(std::fs::metadata(data_path).unwrap().len() < 1000);
+
+            assert_eq!(
+                tree.get(&"small".into()).await.unwrap(),
+                Some("s".into())
+            );
+            assert_eq!(
+                tree.get(&"big3".into()).await.unwrap(),
+                Some(big(3, 0))
+            );
+            let keys: Vec<String> =
+                (0..3).map(|i| format!("big{}", i)).collect();
+            let expected: Vec<Option<String>> =
+                (0..3).map(|i| Some(big(i, 0))).collect();
+            assert_eq!(tree.get_many(&keys).await.unwrap(), expected);
+            let mut entries = Vec::new();
+            tree.for_each_range(&"big8".into(), &"c".into(), |entry| {
+                entries.push(entry);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                entries,
+                vec![("big8".into(), big(8, 0)), ("big9".into(), big(9, 0))]
+            );
+
+            // Once the values are overwritten and compacted away, nothing
+            // points into the first value log anymore.
+            for i in 0..10 {
+                tree.set(format!("big{}", i), big(i, 1)).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            let output_index = tree.unused_sstable_indices(1)[0];
+            tree.compact(vec![0, 2], output_index).await.unwrap();
+            tree.gc();
+            let remaining =
+                LSMTree::value_log_ids(&LocalStorage, &dir).unwrap();
+            assert_eq!(remaining.len(), 1);
+            assert!(!remaining.contains(&value_logs[0]));
+            assert_eq!(
+                tree.get(&"big3".into()).await.unwrap(),
+                Some(big(3, 1))
+            );
+            drop(tree);
+
+            let tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            assert_eq!(
+                tree.get(&"big5".into()).await.unwrap(),
+                Some(big(5, 1))
+            );
+            assert_eq!(
+                tree.get(&"small".into()).await.unwrap(),
+                Some("s".into())
+            );
+        });
+    }
+
+    #[test]
+    fn sync_on_flush() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("sync_on_flush");
+            let options = LSMTreeOptions::new()
+                .with_sync_on_flush(true)
+                .with_value_log_threshold(10);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "2".repeat(10)).await.unwrap();
+            assert_eq!(tree.flush().await.unwrap(), Some(0));
+            drop(tree);
+
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(
+                tree.get_with_source(&"a".into()).await.unwrap(),
+                (Some("1".into()), ValueSource::Sstable(0))
+            );
+            assert_eq!(
+                tree.get(&"b".into()).await.unwrap(),
+                Some("2".repeat(10))
+            );
+        });
+    }
+
+    #[test]
+    fn pin_sstable() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("pin_sstable");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            drop(tree);
+
+            // Room for only 1 of the 2 index files.
+            let index_size = std::fs::metadata(
+                LSMTree::get_data_file_paths(dir.clone(), 0).1,
+            )
+            .unwrap()
+            .len() as usize;
+            let options =
+                LSMTreeOptions::new().with_index_cache_budget(index_size);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            let cached = |tree: &LSMTree| {
+                let mut cached: Vec<usize> =
+                    tree.index_cache.borrow().indices.keys().copied().collect();
+                cached.sort();
+                cached
+            };
+            assert!(tree.pin_sstable(1).await.is_err());
+
+            tree.pin_sstable(0).await.unwrap();
+            assert_eq!(
+                tree.get(&"150".to_string()).await.unwrap(),
+                Some("150".into())
+            );
+            assert_eq!(cached(&tree), vec![0]);
+
+            assert!(tree.unpin_sstable(0));
+            assert!(!tree.unpin_sstable(0));
+            tree.get(&"150".to_string()).await.unwrap();
+            assert_eq!(cached(&tree), vec![2]);
+
+            // The pin moves to the output of the compaction.
+            tree.pin_sstable(0).await.unwrap();
+            assert_eq!(cached(&tree), vec![0]);
+            let output_index = tree.unused_sstable_indices(1)[0];
+            tree.compact(vec![0, 2], output_index).await.unwrap();
+            assert!(cached(&tree).is_empty());
+            assert_eq!(tree.index_cache.borrow().bytes, 0);
+            assert_eq!(
+                tree.get(&"050".to_string()).await.unwrap(),
+                Some("50".into())
+            );
+            assert_eq!(cached(&tree), vec![output_index]);
+            assert!(tree.unpin_sstable(output_index));
+        });
+    }
+
+    #[test]
+    fn wal_replay_threshold() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("wal_replay_threshold");
+            drop(LSMTree::new(dir.clone()).await.unwrap());
+
+            // More distinct keys than the memtable can hold, and overwrites.
+            let config = BincodeConfig::default();
+            let mut wal = Vec::new();
+            let keys = TREE_CAPACITY * 3;
+            for seq in 0..keys as u64 + 100 {
+                let i = seq as usize % keys;
+                let entry = Entry {
+                    key: format!("{:05}", i),
+                    value: Value::Inline(seq.to_string().into_bytes()),
+                    seq,
+                    timestamp: seq,
+                };
+                wal.extend(wal_record(&config.serialize(&entry)));
+            }
+            let wal_size = wal.len() as u64;
+            let wal_path = LSMTree::get_wal_path(dir.clone(), 0);
+            std::fs::write(&wal_path, wal).unwrap();
+
+            let options =
+                LSMTreeOptions::new().with_wal_replay_threshold(wal_size - 1);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![0]);
+            assert_eq!(tree.sstable_headers[&0].entries, keys as u64);
+            assert_eq!(tree.active_memtable.len(), 0);
+            assert_eq!(tree.next_seq, keys as u64 + 100);
+            assert!(!wal_path.exists());
+            for i in (0..keys).step_by(101) {
+                let seq = if i < 100 { i + keys } else { i };
+                assert_eq!(
+                    tree.get(&format!("{:05}", i)).await.unwrap(),
+                    Some(seq.to_string())
+                );
+            }
+
+            tree.set("new".into(), "1".into()).await.unwrap();
+            drop(tree);
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(
+                tree.get(&"new".into()).await.unwrap(),
+                Some("1".into())
+            );
+            assert_eq!(tree.active_memtable.len(), 1);
+        });
+    }
+
+    #[test]
+    fn merge_from() {
+        LocalExecutor::default().run(async {
+            for winner in [MergeWinner::Current, MergeWinner::Other] {
+                let dir = test_dir(&format!("merge_from_{:?}", winner));
+                let other_dir =
+                    test_dir(&format!("merge_from_{:?}_other", winner));
+                let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+                let mut other = LSMTree::new(other_dir.clone()).await.unwrap();
+                // Overlapping key ranges, with versions both flushed and only
+                // in the memtables.
+                for i in 0..100 {
+                    tree.set(format!("{:03}", i * 2), "current".into())
+                        .await
+                        .unwrap();
+                    other
+                        .set(format!("{:03}", i * 3), "other".into())
+                        .await
+                        .unwrap();
+                }
+                tree.flush().await.unwrap();
+                other.flush().await.unwrap();
+                tree.set("shared".into(), "current".into()).await.unwrap();
+                for _ in 0..5 {
+                    other.set("shared".into(), "other".into()).await.unwrap();
+                }
+                drop(other);
+
+                tree.merge_from(other_dir, winner).await.unwrap();
+                assert_eq!(tree.read_sstable_indices.len(), 1);
+                let winner_value = match winner {
+                    MergeWinner::Current => "current",
+                    MergeWinner::Other => "other",
+                };
+                for i in 0..300 {
+                    let expected = match (i % 2 == 0 && i < 200, i % 3 == 0) {
+                        (true, true) => Some(winner_value),
+                        (true, false) => Some("current"),
+                        (false, true) => Some("other"),
+                        (false, false) => None,
+                    };
+                    assert_eq!(
+                        tree.get(&format!("{:03}", i)).await.unwrap(),
+                        expected.map(String::from)
+                    );
+                }
+                assert_eq!(
+                    tree.get(&"shared".into()).await.unwrap(),
+                    Some(winner_value.into())
+                );
+
+                // Writes after the merge are newer than all merged versions.
+                tree.set("shared".into(), "new".into()).await.unwrap();
+                tree.flush().await.unwrap();
+                drop(tree);
+                let tree = LSMTree::new(dir).await.unwrap();
+                assert_eq!(
+                    tree.get(&"shared".into()).await.unwrap(),
+                    Some("new".into())
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn get_with_timestamp() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_with_timestamp");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            let before = SystemTime::now();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "1".into()).await.unwrap();
+            let (value, a_time) =
+                tree.get_with_timestamp(&"a".into()).await.unwrap().unwrap();
+            assert_eq!(value, "1");
+            assert!(before <= a_time && a_time <= SystemTime::now());
+            assert_eq!(
+                tree.get_with_timestamp(&"c".into()).await.unwrap(),
+                None
+            );
+
+            // The timestamp of the surviving version is kept through flushes,
+            // compactions and reopens.
+            tree.flush().await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            let (_, b_time) =
+                tree.get_with_timestamp(&"b".into()).await.unwrap().unwrap();
+            assert!(a_time < b_time);
+            tree.flush().await.unwrap();
+            let output_index = tree.unused_sstable_indices(1)[0];
+            tree.compact(vec![0, 2], output_index).await.unwrap();
+            drop(tree);
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(
+                tree.get_with_timestamp(&"a".into()).await.unwrap(),
+                Some(("1".into(), a_time))
+            );
+            assert_eq!(
+                tree.get_with_timestamp(&"b".into()).await.unwrap(),
+                Some(("2".into(), b_time))
+            );
+        });
+    }
+
+    #[test]
+    fn migrate() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("migrate");
+            let options = LSMTreeOptions::new().with_value_log_threshold(50);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..200 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            tree.set("big".into(), "b".repeat(100)).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("wal".into(), "only".into()).await.unwrap();
+            drop(tree);
+
+            let config = BincodeConfig {
+                int_encoding: IntEncoding::Varint,
+                endian: Endian::Big,
+            };
+            let migrated_options = options.clone().with_bincode_config(config);
+            let tree = LSMTree::migrate(dir.clone(), migrated_options.clone())
+                .await
+                .unwrap();
+            let check = |tree: LSMTree| async move {
+                for i in 0..200 {
+                    let key = format!("{:03}", i);
+                    assert_eq!(
+                        tree.get(&key).await.unwrap(),
+                        Some(i.to_string())
+                    );
+                }
+                assert_eq!(
+                    tree.get(&"big".into()).await.unwrap(),
+                    Some("b".repeat(100))
+                );
+                assert_eq!(
+                    tree.get(&"wal".into()).await.unwrap(),
+                    Some("only".into())
+                );
+            };
+            check(tree).await;
+            let leftovers = std::fs::read_dir(&dir)
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.contains("compact") || name.contains("migrated")
+                })
+                .count();
+            assert_eq!(leftovers, 0);
+
+            assert!(LSMTree::with_options(dir.clone(), options).await.is_err());
+            // Migrating to the config the tree is already in just opens it.
+            let tree = LSMTree::migrate(dir.clone(), migrated_options.clone())
+                .await
+                .unwrap();
+            check(tree).await;
+            let tree =
+                LSMTree::with_options(dir, migrated_options).await.unwrap();
+            check(tree).await;
+        });
+    }
+
+    #[test]
+    fn crash_during_flush() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("crash_during_flush");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i), "new".into()).await.unwrap();
+            }
+            drop(tree);
+
+            // A flush of the memtable to sstable 2 that crashed midway: the
+            // next WAL is created, and the sstable is partially written.
+            std::fs::write(LSMTree::get_wal_path(dir.clone(), 4), b"").unwrap();
+            let temp_paths = LSMTree::get_flush_file_paths(dir.clone(), 2);
+            std::fs::write(&temp_paths.data_path, b"partial").unwrap();
+            std::fs::write(&temp_paths.index_path, b"partial").unwrap();
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert!(!temp_paths.data_path.exists());
+            assert!(!temp_paths.index_path.exists());
+            let mut indices = tree.read_sstable_indices.clone();
+            indices.sort();
+            assert_eq!(indices, vec![0, 2]);
+            for i in 0..100 {
+                assert_eq!(
+                    tree.get(&format!("{:03}", i)).await.unwrap(),
+                    Some("new".into())
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn probably_contains() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("probably_contains");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            for i in 0..500 {
+                tree.set(format!("{:04}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            tree.set("memtable".into(), "1".into()).await.unwrap();
+
+            assert!(tree.probably_contains(&"memtable".into()));
+            assert!(tree.contains_key(&"memtable".into()).await.unwrap());
+            for i in 0..500 {
+                let key = format!("{:04}", i);
+                assert!(tree.probably_contains(&key));
+                assert!(tree.contains_key(&key).await.unwrap());
+            }
+
+            let opens = tree.stats().sstable_opens;
+            // Keys in the key range of the sstable, that only its filter
+            // rules out.
+            let false_positives = (0..499)
+                .filter(|i| tree.probably_contains(&format!("{:04}x", i)))
+                .count();
+            assert!(
+                false_positives < 50,
+                "{} false positives",
+                false_positives
+            );
+            assert_eq!(tree.stats().sstable_opens, opens);
+            assert!(!tree.contains_key(&"9999".into()).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn read_block_size() {
+        LocalExecutor::default().run(async {
+            let mut reads = vec![];
+            for block_size in [None, Some(4096)] {
+                let dir =
+                    test_dir(&format!("read_block_size_{:?}", block_size));
+                let mut options = LSMTreeOptions::new();
+                if let Some(block_size) = block_size {
+                    options = options.with_read_block_size(block_size);
+                }
+                let mut tree =
+                    LSMTree::with_options(dir, options).await.unwrap();
+                for i in 0..1000 {
+                    tree.set(format!("{:04}", i), i.to_string()).await.unwrap();
+                }
+                tree.flush().await.unwrap();
+
+                for i in (0..1000).step_by(7) {
+                    assert_eq!(
+                        tree.get(&format!("{:04}", i)).await.unwrap(),
+                        Some(i.to_string())
+                    );
+                }
+                reads.push(tree.stats().get_reads);
+            }
+            // The last probes of each search are served from the blocks read
+            // by the first ones.
+            assert!(reads[1] * 2 < reads[0], "{:?}", reads);
+        });
+    }
+
+    #[test]
+    fn corruption_policy() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("corruption_policy");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("key".into(), "old".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("key".into(), "new".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            drop(tree);
+
+            // Corrupt the data file of the newest sstable.
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 2);
+            let size = std::fs::metadata(&data_path).unwrap().len();
+            std::fs::write(&data_path, vec![0xff; size as usize]).unwrap();
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert!(tree.get(&"key".into()).await.is_err());
+            drop(tree);
+
+            let tree = LSMTree::with_options(
+                dir,
+                LSMTreeOptions::new()
+                    .with_corruption_policy(CorruptionPolicy::SkipAndLog),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                tree.get(&"key".into()).await.unwrap(),
+                Some("old".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn flush_into_newest() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("flush_into_newest");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.flush_into_newest().await.unwrap(), None);
+
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "1".into()).await.unwrap();
+            assert_eq!(tree.flush_into_newest().await.unwrap(), Some(0));
+
+            for i in 2..5 {
+                tree.set("b".into(), i.to_string()).await.unwrap();
+                tree.set(format!("c{}", i), i.to_string()).await.unwrap();
+                tree.flush_into_newest().await.unwrap().unwrap();
+                assert_eq!(tree.read_sstable_indices.len(), 1);
+            }
+            drop(tree);
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.read_sstable_indices.len(), 1);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), Some("4".into()));
+            for i in 2..5 {
+                assert_eq!(
+                    tree.get(&format!("c{}", i)).await.unwrap(),
+                    Some(i.to_string())
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn gc_waits_only_for_held_sstables() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("gc_waits_only_for_held_sstables");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..3 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            let (held_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+            let (other_path, _) = LSMTree::get_data_file_paths(dir.clone(), 2);
+
+            let guard = tree.hold_sstables(&[0]);
+            // A compaction of other sstables deletes its inputs right away.
+            tree.compact(vec![2, 4], 5).await.unwrap();
+            assert!(!other_path.exists());
+            assert!(held_path.exists());
+
+            // The files of a held sstable are deleted once it's released.
+            let mut guard_of_output = Some(tree.hold_sstable_files());
+            tree.compact(vec![0, 5], 7).await.unwrap();
+            assert!(held_path.exists());
+            drop(guard);
+            assert_eq!(tree.gc(), 0);
+            guard_of_output.take();
+            assert_eq!(tree.gc(), 8);
+            assert!(!held_path.exists());
+            assert_eq!(
+                tree.get(&"0".to_string()).await.unwrap(),
+                Some("0".into())
+            );
+        });
+    }
+
+    #[test]
+    fn key_prefix_compression() {
+        LocalExecutor::default().run(async {
+            let mut data_sizes = vec![];
+            for restart_interval in [0, 16] {
+                let dir = test_dir(&format!(
+                    "key_prefix_compression_{}",
+                    restart_interval
+                ));
+                let options = LSMTreeOptions::new()
+                    .with_key_prefix_compression(restart_interval)
+                    .with_versions_to_keep(2);
+                let mut tree =
+                    LSMTree::with_options(dir.clone(), options).await.unwrap();
+                for (i, field) in ["name", "email"].iter().enumerate() {
+                    for user in 0..200 {
+                        tree.set(
+                            format!("tenant:acme:user:{:04}:{}", user, field),
+                            i.to_string(),
+                        )
+                        .await
+                        .unwrap();
+                    }
+                    tree.flush().await.unwrap();
+                }
+                tree.set("tenant:acme:user:0007:name".into(), "new".into())
+                    .await
+                    .unwrap();
+                tree.flush().await.unwrap();
+                tree.compact(vec![0, 2, 4], 5).await.unwrap();
+                let (data_path, _) = LSMTree::get_data_file_paths(dir, 5);
+                data_sizes.push(std::fs::metadata(data_path).unwrap().len());
+
+                for user in 0..200 {
+                    let key = format!("tenant:acme:user:{:04}:email", user);
+                    assert_eq!(
+                        tree.get(&key).await.unwrap(),
+                        Some("1".to_string())
+                    );
+                }
+                assert_eq!(
+                    tree.get(&"tenant:acme:user:0007:name".into())
+                        .await
+                        .unwrap(),
+                    Some("new".to_string())
+                );
+                assert_eq!(
+                    tree.get_version(&"tenant:acme:user:0007:name".into(), 1)
+                        .await
+                        .unwrap(),
+                    Some("0".to_string())
+                );
+                assert_eq!(
+                    tree.get(&"tenant:acme:user:0007:x".into()).await.unwrap(),
+                    None
+                );
+                let mut keys = vec![];
+                tree.for_each_range(
+                    &"tenant:acme:user:0010:".into(),
+                    &"tenant:acme:user:0012:".into(),
+                    |(key, _)| {
+                        keys.push(key);
+                        async { ControlFlow::Continue(()) }
+                    },
+                )
+                .await
+                .unwrap();
+                assert_eq!(
+                    keys,
+                    vec![
+                        "tenant:acme:user:0010:email",
+                        "tenant:acme:user:0010:name",
+                        "tenant:acme:user:0011:email",
+                        "tenant:acme:user:0011:name"
+                    ]
+                );
+            }
+            assert!(data_sizes[1] * 10 < data_sizes[0] * 8, "{:?}", data_sizes);
+        });
+    }
+
+    #[test]
+    fn live_files() -> std::io::Result<()> {
+        let dir = test_dir("live_files");
+        LocalExecutor::default().run(async {
+            let mut tree = LSMTree::with_options(
+                dir.clone(),
+                LSMTreeOptions::default().with_value_log_threshold(8),
+            )
+            .await?;
+            for i in 0..3 {
+                tree.set(format!("key{}", i), "a long enough value".into())
+                    .await?;
+                tree.flush().await?;
+            }
+            tree.set("unflushed".into(), "value".into()).await?;
+            std::fs::write(dir.join("notes.txt"), "not of the tree")?;
+
+            let files = tree.live_files()?;
+            for (kind, path) in &files {
+                assert!(path.exists(), "{:?}", path);
+                let name = path.file_name().unwrap().to_str().unwrap();
+                assert_eq!(FileKind::of(name).unwrap().0, *kind);
+            }
+            let count = |kind| files.iter().filter(|(k, _)| *k == kind).count();
+            assert_eq!(count(FileKind::Format), 1);
+            assert_eq!(count(FileKind::Data), 3);
+            assert_eq!(count(FileKind::Index), 3);
+            assert_eq!(count(FileKind::Meta), 3);
+            assert_eq!(count(FileKind::ValueLog), 3);
+            assert_eq!(count(FileKind::Wal), 1);
+            assert_eq!(files, tree.live_files()?);
+
+            assert_eq!(FileKind::of("notes.txt"), None);
+            assert_eq!(
+                FileKind::of("00000000000000000004.flush_data"),
+                Some((FileKind::Temporary, Some(4)))
+            );
+            assert_eq!(
+                FileKind::of("00000000000000000006.memtable.1.run_data"),
+                Some((FileKind::Temporary, Some(6)))
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn wal_archive() -> std::io::Result<()> {
+        let dir = test_dir("wal_archive");
+        LocalExecutor::default().run(async {
+            let options = LSMTreeOptions::default()
+                .with_wal_archive(WalRetention::Last(2));
+            let mut tree =
+                LSMTree::with_options(dir.clone(), options.clone()).await?;
+            for i in 0..3 {
+                tree.set("a".into(), format!("{}", i)).await?;
+                tree.set(format!("key{}", i), "value".into()).await?;
+                tree.flush().await?;
+            }
+
+            let archived = tree.archived_wals()?;
+            assert_eq!(archived.len(), 2);
+            let mut writes = Vec::new();
+            for path in &archived {
+                writes.extend(
+                    LSMTree::read_archived_wal(path, BincodeConfig::default())
+                        .await?
+                        .into_iter()
+                        .map(|write| (write.key, write.value)),
+                );
+            }
+            assert_eq!(
+                writes,
+                vec![
+                    ("a".into(), Some("1".into())),
+                    ("key1".into(), Some("value".into())),
+                    ("a".into(), Some("2".into())),
+                    ("key2".into(), Some("value".into())),
+                ]
+            );
+
+            // Replaying the archived writes on top of the first sstable
+            // recovers the tree.
+            let recovered = test_dir("wal_archive_recovered");
+            let mut replica = LSMTree::new(recovered).await?;
+            replica.set("a".into(), "0".into()).await?;
+            replica.set("key0".into(), "value".into()).await?;
+            for (key, value) in writes {
+                match value {
+                    Some(value) => replica.set(key, value).await?,
+                    None => replica.delete(key).await?,
+                };
+            }
+            for key in ["a", "key0", "key1", "key2"] {
+                assert_eq!(
+                    replica.get(&key.to_string()).await?,
+                    tree.get(&key.to_string()).await?
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reads_during_compaction() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("reads_during_compaction");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..300 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            let mut compaction = tree.start_compaction(vec![0, 2], 5).unwrap();
+            let done = Rc::new(Cell::new(false));
+            let task = glommio::spawn_local({
+                let done = done.clone();
+                async move {
+                    compaction.run(&PauseToken::new()).await.unwrap();
+                    done.set(true);
+                    compaction
+                }
+            });
+            let mut reads = 0;
+            while !done.get() {
+                let key = format!("{:03}", reads % 300);
+                assert_eq!(
+                    tree.get(&key).await.unwrap(),
+                    Some((reads % 300).to_string())
+                );
+                reads += 1;
+                glommio::yield_if_needed().await;
+            }
+            assert!(reads > 0);
+            tree.finish_compaction_job(task.await).await.unwrap();
+            for i in 0..300 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+
+            // A rename that fails undoes the compaction, the inputs are still
+            // read from, now and after reopening.
+            let mut compaction = tree.start_compaction(vec![4, 5], 7).unwrap();
+            compaction.run(&PauseToken::new()).await.unwrap();
+            let (_, index_path) = LSMTree::get_data_file_paths(dir.clone(), 7);
+            std::fs::create_dir(&index_path).unwrap();
+            assert!(tree.finish_compaction_job(compaction).await.is_err());
+            std::fs::remove_dir(&index_path).unwrap();
+            let mut expected = tree.read_sstable_indices.clone();
+            expected.sort();
+            assert_eq!(expected, vec![4, 5]);
+            for i in 0..300 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+
+            drop(tree);
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..300 {
+                let key = format!("{:03}", i);
+                assert_eq!(tree.get(&key).await.unwrap(), Some(i.to_string()));
+            }
+        });
+    }
+
+    #[test]
+    fn external_sort() {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        LocalExecutor::default().run(async {
+            let dir = test_dir("external_sort");
+            drop(LSMTree::new(dir.clone()).await.unwrap());
+
+            // 2 versions of every key, in random order.
+            let mut entries: Vec<Entry> = (0..4000)
+                .map(|seq| Entry {
+                    key: format!("{:05}", seq % 2000),
+                    value: Value::Inline(seq.to_string().into_bytes()),
+                    seq,
+                    timestamp: 0,
+                })
+                .collect();
+            entries.shuffle(&mut StdRng::seed_from_u64(160));
+
+            // A few kilobytes, so the entries are sorted in many runs.
+            LSMTree::external_sort(
+                &(Rc::new(LocalStorage) as Rc<dyn Storage>),
+                futures_lite::stream::iter(entries.into_iter().map(Ok)),
+                4096,
+                &LSMTree::get_wal_path(dir.clone(), 0),
+                LSMTree::get_sstable_file_paths(dir.clone(), 0),
+                SstableLayout {
+                    fixed_key_size: None,
+                    restart_interval: 0,
+                    false_positive_rate: DEFAULT_FALSE_POSITIVE_RATE,
+                },
+                BincodeConfig::default(),
+            )
+            .await
+            .unwrap();
+            let mut names: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.contains(".memtable."))
+                .collect();
+            names.sort();
+            assert_eq!(names, Vec::<String>::new());
+
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            let mut entries = vec![];
+            tree.for_each_range(&"".into(), &"a".into(), |entry| {
+                entries.push(entry);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            let expected: Vec<(String, String)> = (0..2000)
+                .map(|i| (format!("{:05}", i), (i + 2000).to_string()))
+                .collect();
+            assert_eq!(entries, expected);
+        });
+    }
+
+    #[test]
+    fn get_or() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_or");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.flush().await.unwrap();
+
+            assert_eq!(
+                tree.get_or(&"a".into(), "0".into()).await.unwrap(),
+                "1"
+            );
+            assert_eq!(
+                tree.get_or(&"c".into(), "0".into()).await.unwrap(),
+                "0"
+            );
+            assert_eq!(
+                tree.get_or_else(&"b".into(), || unreachable!())
+                    .await
+                    .unwrap(),
+                "2"
+            );
+            assert_eq!(
+                tree.get_or_else(&"c".into(), || "lazy".into())
+                    .await
+                    .unwrap(),
+                "lazy"
+            );
+        });
+    }
+
+    #[test]
+    fn compact_older_than() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compact_older_than");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            for i in 0..3 {
+                tree.set(format!("old{}", i), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            glommio::timer::sleep(Duration::from_millis(200)).await;
+            tree.set("new".into(), "new".into()).await.unwrap();
+            tree.flush().await.unwrap();
+
+            let before = tree.sstable_info().unwrap();
+            let output = tree
+                .compact_older_than(Duration::from_millis(100))
+                .await
+                .unwrap()
+                .unwrap();
+            let after = tree.sstable_info().unwrap();
+            assert_eq!(after.len(), 2);
+            assert_eq!(after[0], before[3]);
+            assert_eq!(after[1].index, output);
+            assert_eq!(after[1].entries, 3);
+            assert_eq!(after[1].created_at, before[0].created_at);
+            for i in 0..3 {
+                assert_eq!(
+                    tree.get(&format!("old{}", i)).await.unwrap(),
+                    Some(i.to_string())
+                );
+            }
+
+            // Only a single old sstable is left.
+            assert_eq!(
+                tree.compact_older_than(Duration::from_millis(100))
+                    .await
+                    .unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn verify_wal_consistency() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("verify_wal_consistency");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert!(tree.verify_wal_consistency().await.unwrap());
+            for i in 0..TREE_CAPACITY + 10 {
+                tree.set(format!("{:04}", i % 700), i.to_string())
+                    .await
+                    .unwrap();
+                if i % 100 == 0 {
+                    assert!(tree.verify_wal_consistency().await.unwrap());
+                }
+            }
+            tree.flush().await.unwrap();
+            assert!(tree.verify_wal_consistency().await.unwrap());
+            tree.set("a".into(), "1".into()).await.unwrap();
+            assert!(tree.verify_wal_consistency().await.unwrap());
+
+            // A write to the memtable alone would be lost by a crash.
+            tree.active_memtable
+                .set(
+                    "b".into(),
+                    MemtableValue {
+                        value: Some("2".into()),
+                        seq: tree.next_seq,
+                        timestamp: 0,
+                    },
+                )
+                .unwrap();
+            assert!(!tree.verify_wal_consistency().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn compact_with_filter() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compact_with_filter");
+            let mut tree = LSMTree::with_options(
+                dir.clone(),
+                LSMTreeOptions::default().with_value_log_threshold(16),
+            )
+            .await
+            .unwrap();
+            for i in 0..300 {
+                let prefix = if i % 3 == 0 { "drop" } else { "keep" };
+                let value = if i % 2 == 0 {
+                    i.to_string()
+                } else {
+                    format!("{:032}", i)
+                };
+                tree.set(format!("{}:{:03}", prefix, i), value)
+                    .await
+                    .unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            tree.compact_with_filter(vec![0, 2, 4], 5, |key, value| {
+                !key.starts_with("drop:") && value != format!("{:032}", 1)
+            })
+            .await
+            .unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![5]);
+            assert_eq!(tree.sstable_headers[&5].entries, 199);
+            for i in 0..300 {
+                let prefix = if i % 3 == 0 { "drop" } else { "keep" };
+                let value = tree.get(&format!("{}:{:03}", prefix, i)).await;
+                assert_eq!(
+                    value.unwrap().is_some(),
+                    i % 3 != 0 && i != 1,
+                    "{}",
+                    i
+                );
+            }
+
+            // The index has a record for every entry left.
+            let mut keys = vec![];
+            tree.for_each_range(&"".into(), &"z".into(), |(key, _)| {
+                keys.push(key);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(keys.len(), 199);
+            assert!(keys.iter().all(|key| key.starts_with("keep:")));
+        });
+    }
+
+    #[test]
+    fn read_flushed_keys_right_after_flush() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("read_flushed_keys_right_after_flush");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for round in 0..3 {
+                for i in 0..100 {
+                    tree.set(format!("{}:{:03}", round, i), i.to_string())
+                        .await
+                        .unwrap();
+                }
+                let index = tree.flush().await.unwrap().unwrap();
+                assert!(!tree.is_flushing());
+
+                // The closed index file is whole, and every flushed key is
+                // found in the new sstable.
+                let (data_path, index_path) =
+                    LSMTree::get_data_file_paths(dir.clone(), index);
+                let header =
+                    IndexHeader::read_from_path(&LocalStorage, &index_path)
+                        .await
+                        .unwrap();
+                assert_eq!(header, tree.sstable_headers[&index]);
+                assert_eq!(
+                    std::fs::metadata(&index_path).unwrap().len(),
+                    IndexHeader::size() + 100 * index_item_size(None)
+                );
+                assert!(std::fs::metadata(&data_path).unwrap().len() > 0);
+                for i in 0..100 {
+                    let key = format!("{}:{:03}", round, i);
+                    assert_eq!(
+                        tree.get_with_source(&key).await.unwrap(),
+                        (Some(i.to_string()), ValueSource::Sstable(index))
+                    );
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn compact_opens_many_inputs() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compact_opens_many_inputs");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..SSTABLE_OPEN_CONCURRENCY * 2 + 3 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+                tree.set("last".into(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            let mut indices = tree.read_sstable_indices.clone();
+            indices.sort();
+
+            // A missing input is named by the error.
+            let (data_path, _) =
+                LSMTree::get_data_file_paths(dir.clone(), indices[20]);
+            let moved_path = dir.join("moved");
+            std::fs::rename(&data_path, &moved_path).unwrap();
+            let output_index = tree.unused_sstable_indices(1)[0];
+            let error = tree
+                .compact(indices.clone(), output_index)
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+            assert!(
+                error.to_string().contains(&data_path.display().to_string()),
+                "{}",
+                error
+            );
+            std::fs::rename(&moved_path, &data_path).unwrap();
+
+            tree.compact(indices.clone(), output_index).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![output_index]);
+            for i in 0..indices.len() {
+                assert_eq!(
+                    tree.get(&format!("{:03}", i)).await.unwrap(),
+                    Some(i.to_string())
+                );
+            }
+            assert_eq!(
+                tree.get(&"last".into()).await.unwrap(),
+                Some((indices.len() - 1).to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn rank() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("rank");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.rank(&"a".into()).await.unwrap(), 0.0);
+            for i in 0..1000 {
+                tree.set(format!("{:04}", (i * 7) % 1000), i.to_string())
+                    .await
+                    .unwrap();
+                if i % 300 == 299 {
+                    tree.flush().await.unwrap();
+                }
+            }
+
+            assert_eq!(tree.rank(&"".into()).await.unwrap(), 0.0);
+            assert_eq!(tree.rank(&"0000".into()).await.unwrap(), 0.0);
+            assert_eq!(tree.rank(&"9".into()).await.unwrap(), 1.0);
+            for i in [1, 250, 500, 999] {
+                let rank = tree.rank(&format!("{:04}", i)).await.unwrap();
+                let expected = i as f64 / 1000.0;
+                assert!((rank - expected).abs() < 0.01, "{} {}", i, rank);
+            }
+        });
+    }
+
+    #[test]
+    fn empty_keys_and_values() {
+        LocalExecutor::default().run(async {
+            // Every value is in a value log with a threshold of 0.
+            for (restart_interval, threshold) in [(0, None), (4, Some(0))] {
+                let dir = test_dir(&format!(
+                    "empty_keys_and_values_{}",
+                    restart_interval
+                ));
+                let mut options = LSMTreeOptions::default()
+                    .with_key_prefix_compression(restart_interval);
+                if let Some(threshold) = threshold {
+                    options = options.with_value_log_threshold(threshold);
+                }
+                let expected: Vec<(String, String)> = vec![
+                    ("".into(), "".into()),
+                    ("a".into(), "".into()),
+                    ("b".into(), "b".into()),
+                    ("c".into(), "".into()),
+                ];
+                let check = |tree: LSMTree| {
+                    let expected = expected.clone();
+                    async move {
+                        for (key, value) in &expected {
+                            assert_eq!(
+                                tree.get(key).await.unwrap().as_ref(),
+                                Some(value),
+                                "{:?}",
+                                key
+                            );
+                        }
+                        let mut entries = vec![];
+                        tree.for_each_range(&"".into(), &"z".into(), |entry| {
+                            entries.push(entry);
+                            async { ControlFlow::Continue(()) }
+                        })
+                        .await
+                        .unwrap();
+                        assert_eq!(entries, expected);
+                        tree
+                    }
+                };
+
+                let mut tree =
+                    LSMTree::with_options(dir.clone(), options.clone())
+                        .await
+                        .unwrap();
+                for (key, value) in &expected {
+                    tree.set(key.clone(), value.clone()).await.unwrap();
+                }
+                let tree = check(tree).await;
+
+                // From the WAL.
+                drop(tree);
+                let mut tree =
+                    LSMTree::with_options(dir.clone(), options.clone())
+                        .await
+                        .unwrap();
+                tree = check(tree).await;
+
+                // From an sstable, and from an sstable of a compaction of an
+                // older version.
+                tree.flush().await.unwrap();
+                let mut tree = check(tree).await;
+                tree.set("".into(), "old".into()).await.unwrap();
+                tree.set("".into(), "".into()).await.unwrap();
+                tree.flush().await.unwrap();
+                tree.compact(vec![0, 2], 5).await.unwrap();
+                check(tree).await;
+            }
+        });
+    }
+
+    #[test]
+    fn set_large() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("set_large");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("big".into(), "small".into()).await.unwrap();
+            tree.set("other".into(), "1".into()).await.unwrap();
+
+            // Characters cut between reads.
+            let large = "日本🦀".repeat(200_000);
+            tree.set_large(
+                "big".into(),
+                futures_lite::io::Cursor::new(large.clone().into_bytes()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(tree.active_memtable.len(), 0);
+            let wal_path =
+                LSMTree::get_wal_path(dir.clone(), tree.memtable_index);
+            assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+            assert_eq!(
+                tree.get(&"big".into()).await.unwrap(),
+                Some(large.clone())
+            );
+            assert_eq!(
+                tree.get(&"other".into()).await.unwrap(),
+                Some("1".into())
+            );
+
+            let error = tree
+                .set_large(
+                    "bad".into(),
+                    futures_lite::io::Cursor::new(vec![b'a', 0xff, b'b']),
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(
+                std::io::Error::from(error).kind(),
+                std::io::ErrorKind::InvalidData
+            );
+            assert_eq!(tree.get(&"bad".into()).await.unwrap(), None);
+
+            drop(tree);
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert_eq!(tree.get(&"big".into()).await.unwrap(), Some(large));
+            tree.set("big".into(), "newer".into()).await.unwrap();
+            assert_eq!(
+                tree.get(&"big".into()).await.unwrap(),
+                Some("newer".into())
+            );
+            assert_eq!(tree.read_sstable_indices.len(), 2);
+        });
+    }
+
+    #[test]
+    fn compaction_output_is_reproducible() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_output_is_reproducible");
+            let mut tree = LSMTree::with_options(
+                dir.clone(),
+                LSMTreeOptions::default().with_versions_to_keep(2),
+            )
+            .await
+            .unwrap();
+            for i in 0..300 {
+                tree.set(format!("{:03}", i % 120), i.to_string())
+                    .await
+                    .unwrap();
+                if i % 100 == 99 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            drop(tree);
+
+            let mut outputs = vec![];
+            for run in 0..2 {
+                let copy = test_dir(&format!(
+                    "compaction_output_is_reproducible_{}",
+                    run
+                ));
+                std::fs::create_dir(&copy).unwrap();
+                for entry in std::fs::read_dir(&dir).unwrap() {
+                    let entry = entry.unwrap();
+                    std::fs::copy(entry.path(), copy.join(entry.file_name()))
+                        .unwrap();
+                }
+                // Sometime later.
+                glommio::timer::sleep(Duration::from_millis(5)).await;
+                let mut tree = LSMTree::with_options(
+                    copy.clone(),
+                    LSMTreeOptions::default().with_versions_to_keep(2),
+                )
+                .await
+                .unwrap();
+                tree.compact(vec![0, 2, 4], 7).await.unwrap();
+                let (data_path, index_path) =
+                    LSMTree::get_data_file_paths(copy.clone(), 7);
+                let meta_path = LSMTree::get_meta_file_path(copy.clone(), 7);
+                outputs.push(
+                    [data_path, index_path, meta_path]
+                        .map(|path| std::fs::read(path).unwrap()),
+                );
+            }
+            assert!(outputs[0] == outputs[1]);
+        });
+    }
+
+    #[test]
+    fn memtable_entries_snapshot() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("memtable_entries_snapshot");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "3".into()).await.unwrap();
+            assert_eq!(
+                tree.memtable_entries().unwrap(),
+                vec![("a".into(), "1".into()), ("b".into(), "3".into())]
+            );
+            assert!(tree.flush_memtable_entries().unwrap().is_empty());
+
+            tree.flush().await.unwrap();
+            assert!(tree.memtable_entries().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compare_and_swap");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert!(tree
+                .compare_and_swap("a".into(), None, "1".into())
+                .await
+                .unwrap());
+            assert!(!tree
+                .compare_and_swap("a".into(), None, "2".into())
+                .await
+                .unwrap());
+            tree.flush().await.unwrap();
+
+            assert!(!tree
+                .compare_and_swap("a".into(), Some("2".into()), "3".into())
+                .await
+                .unwrap());
+            assert!(tree
+                .compare_and_swap("a".into(), Some("1".into()), "3".into())
+                .await
+                .unwrap());
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("3".into()));
+        });
+    }
+
+    #[test]
+    fn clear() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("clear");
+            let options = LSMTreeOptions::new().with_value_log_threshold(8);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..10 {
+                tree.set(format!("{}", i), "x".repeat(i * 2)).await.unwrap();
+                if i % 3 == 0 {
+                    tree.flush().await.unwrap();
+                }
+            }
+            tree.clear().await.unwrap();
+            for i in 0..10 {
+                assert_eq!(tree.get(&format!("{}", i)).await.unwrap(), None);
+            }
+            assert!(tree.sstable_info().unwrap().is_empty());
+
+            tree.set("a".into(), "1".into()).await.unwrap();
+            drop(tree);
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(tree.get(&"0".into()).await.unwrap(), None);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            let kinds: Vec<_> = tree
+                .live_files()
+                .unwrap()
+                .into_iter()
+                .map(|(kind, _)| kind)
+                .collect();
+            assert!(!kinds.contains(&FileKind::ValueLog));
+            assert!(!kinds.contains(&FileKind::Data));
+        });
+    }
+
+    #[test]
+    fn for_each_change_since() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("for_each_change_since");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.set("c".into(), "3".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("d".into(), "4".into()).await.unwrap();
+            tree.set("a".into(), "5".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            tree.set("b".into(), "6".into()).await.unwrap();
+
+            let mut changes = vec![];
+            tree.for_each_change_since(2, |change| {
+                changes.push(change);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                changes,
+                vec![
+                    ("a".into(), "5".into(), 4),
+                    ("b".into(), "6".into(), 5),
+                    ("d".into(), "4".into(), 3),
+                ]
+            );
+
+            changes.clear();
+            tree.for_each_change_since(4, |change| {
+                changes.push(change);
+                async { ControlFlow::Continue(()) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(changes, vec![("b".into(), "6".into(), 5)]);
+        });
+    }
+
+    #[test]
+    fn open_existing_and_create() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("open_existing_and_create");
+            let options = LSMTreeOptions::new();
+            let error = LSMTree::open_existing(dir.clone(), options.clone())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+            let mut tree =
+                LSMTree::create(dir.clone(), options.clone()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            drop(tree);
+
+            let error = LSMTree::create(dir.clone(), options.clone())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+            let tree = LSMTree::open_existing(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            drop(tree);
+
+            std::fs::write(dir.join("notes.txt"), "").unwrap();
+            let error = LSMTree::open_existing(dir.clone(), options.clone())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            std::fs::remove_file(dir.join("notes.txt")).unwrap();
+
+            std::fs::remove_file(dir.join(FORMAT_FILE_NAME)).unwrap();
+            let error = LSMTree::open_existing(dir.clone(), options.clone())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+            let empty = test_dir("open_existing_and_create_empty");
+            std::fs::create_dir(&empty).unwrap();
+            LSMTree::open_existing(empty, options).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn value_size_histogram() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("value_size_histogram");
+            let options = LSMTreeOptions::new().with_value_log_threshold(64);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            for i in 0..100 {
+                let size = match i % 4 {
+                    0 => 0,
+                    1 => 3,
+                    2 => 10,
+                    _ => 100,
+                };
+                tree.set(format!("{:03}", i), "x".repeat(size))
+                    .await
+                    .unwrap();
+            }
+            tree.flush().await.unwrap();
+
+            let histogram = tree.value_size_histogram(1).await.unwrap();
+            assert_eq!(
+                histogram,
+                ValueSizeHistogram {
+                    sample_every: 1,
+                    buckets: vec![25, 0, 25, 0, 25, 0, 0, 25],
+                }
+            );
+
+            let sampled = tree.value_size_histogram(2).await.unwrap();
+            assert_eq!(sampled.buckets, vec![25, 0, 0, 0, 25]);
+        });
+    }
+
+    #[test]
+    fn fixed_key_size_must_match() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("fixed_key_size_must_match");
+            let options = LSMTreeOptions::new().with_fixed_key_size(4);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.set("abcd".into(), "1".into()).await.unwrap();
+            tree.flush().await.unwrap();
+            drop(tree);
+
+            for other in [
+                LSMTreeOptions::new(),
+                LSMTreeOptions::new().with_fixed_key_size(8),
+            ] {
+                let error = LSMTree::with_options(dir.clone(), other)
+                    .await
+                    .err()
+                    .unwrap();
+                assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            }
+
+            // Options that don't change the layout of files can change.
+            let tree = LSMTree::with_options(
+                dir,
+                options
+                    .with_index_cache_budget(0)
+                    .with_value_log_threshold(1),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                tree.get(&"abcd".into()).await.unwrap(),
+                Some("1".into())
+            );
+        });
+    }
+
+    #[test]
+    fn get_range() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("get_range");
+            let options = LSMTreeOptions::new().with_value_log_threshold(64);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            let large: String = (0..100_000)
+                .map(|i| char::from(b'a' + (i % 26) as u8))
+                .collect();
+            tree.set("large".into(), large.clone()).await.unwrap();
+            tree.set("small".into(), "hello".into()).await.unwrap();
+
+            for flushed in [false, true] {
+                if flushed {
+                    tree.flush().await.unwrap();
+                }
+                for (offset, len) in
+                    [(0, 100), (4093, 10), (99_990, 100), (200_000, 1)]
+                {
+                    let start = offset.min(large.len());
+                    let end = (offset + len).min(large.len());
+                    assert_eq!(
+                        tree.get_range(
+                            &"large".into(),
+                            offset as u64,
+                            len as u64
+                        )
+                        .await
+                        .unwrap(),
+                        Some(large.as_bytes()[start..end].to_vec())
+                    );
+                }
+                assert_eq!(
+                    tree.get_range(&"small".into(), 1, 3).await.unwrap(),
+                    Some(b"ell".to_vec())
+                );
+                assert_eq!(
+                    tree.get_range(&"missing".into(), 0, 1).await.unwrap(),
+                    None
+                );
+            }
+            // Far less than the large value.
+            assert!(tree.stats().get_bytes_read < 10_000);
+        });
+    }
+
+    #[test]
+    fn drop_keeps_returned_writes() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("drop_keeps_returned_writes");
+            let options = LSMTreeOptions::new().with_value_log_threshold(64);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..TREE_CAPACITY + 10 {
+                tree.set(format!("{:05}", i), "x".repeat(i % 100))
+                    .await
+                    .unwrap();
+            }
+            drop(tree);
+
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            for i in 0..TREE_CAPACITY + 10 {
+                assert_eq!(
+                    tree.get(&format!("{:05}", i)).await.unwrap(),
+                    Some("x".repeat(i % 100))
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn oldest_and_newest_sstable() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("oldest_and_newest_sstable");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.sstable_count(), 0);
+            assert_eq!(tree.oldest_sstable(), None);
+            assert_eq!(tree.newest_sstable(), None);
+
+            for i in 0..4 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            assert_eq!(tree.sstable_count(), 4);
+            assert_eq!(tree.oldest_sstable(), Some(0));
+            assert_eq!(tree.newest_sstable(), Some(6));
+
+            // The output holds the newest writes, at a lower index than 2.
+            tree.compact(vec![4, 6], 1).await.unwrap();
+            assert_eq!(tree.sstable_count(), 3);
+            assert_eq!(tree.oldest_sstable(), Some(0));
+            assert_eq!(tree.newest_sstable(), Some(1));
+        });
+    }
+
+    #[test]
+    fn recover_unflushed_wal_remove_failure() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("recover_unflushed_wal_remove_failure");
+            let options =
+                LSMTreeOptions::new().with_wal_archive(WalRetention::All);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            drop(tree);
+            // A flush that didn't finish.
+            std::fs::rename(
+                LSMTree::get_wal_path(dir.clone(), 0),
+                LSMTree::get_wal_path(dir.clone(), 2),
+            )
+            .unwrap();
+            std::fs::copy(
+                LSMTree::get_wal_path(dir.clone(), 2),
+                LSMTree::get_wal_path(dir.clone(), 0),
+            )
+            .unwrap();
+            std::fs::write(LSMTree::get_wal_path(dir.clone(), 2), "").unwrap();
+
+            // Archiving the flushed WAL fails, as the archive is a file.
+            let archive = dir.join(WAL_ARCHIVE_DIR_NAME);
+            std::fs::write(&archive, "").unwrap();
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            assert!(LSMTree::get_wal_path(dir.clone(), 0).exists());
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            tree.set("b".into(), "2".into()).await.unwrap();
+            drop(tree);
+
+            std::fs::remove_file(&archive).unwrap();
+            let tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            assert!(!LSMTree::get_wal_path(dir, 0).exists());
+            assert_eq!(tree.read_sstable_indices, vec![0]);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), Some("2".into()));
+        });
+    }
+
+    #[test]
+    fn read_amplification_limit() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("read_amplification_limit");
+            let options =
+                LSMTreeOptions::new().with_read_amplification_limit(10, 2.5);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..4 {
+                tree.set(format!("{}", i), "v".into()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            drop(tree);
+            // Without their filters and key ranges, no sstable is skipped.
+            for (_, path) in
+                numbered_files(&LocalStorage, &dir, FileKind::Meta).unwrap()
+            {
+                std::fs::remove_file(path).unwrap();
+            }
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+
+            // Every get finds its key in the first sstable it searches.
+            for _ in 0..10 {
+                tree.get(&"3".into()).await.unwrap();
+            }
+            assert_eq!(tree.read_amplification(), Some(1.0));
+            assert!(!tree.maybe_compact().await.unwrap());
+
+            // Each get searches all sstables, from the newest to the oldest.
+            for _ in 0..10 {
+                tree.get(&"0".into()).await.unwrap();
+            }
+            assert_eq!(tree.read_amplification(), Some(4.0));
+            assert!(tree.maybe_compact().await.unwrap());
+            assert_eq!(tree.sstable_count(), 1);
+            assert_eq!(tree.read_amplification(), None);
+        });
+    }
+
+    #[test]
+    fn corrupt_wal_record() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("corrupt_wal_record");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            let first_record_size = tree.stats().wal_bytes_written as usize;
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.set("c".into(), "3".into()).await.unwrap();
+            drop(tree);
+
+            // Flip a byte of the entry of the second record.
+            let wal_path = LSMTree::get_wal_path(dir.clone(), 0);
+            let mut wal = std::fs::read(&wal_path).unwrap();
+            wal[first_record_size + WAL_RECORD_HEADER_SIZE + 2] ^= 0xff;
+            std::fs::write(&wal_path, wal).unwrap();
+
+            let tree = LSMTree::new(dir).await.unwrap();
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("1".into()));
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), None);
+            assert_eq!(tree.get(&"c".into()).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn compaction_dir() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_dir");
+            let cold = test_dir("compaction_dir_cold");
+            let options =
+                LSMTreeOptions::new().with_compaction_dir(cold.clone());
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for i in 0..3 {
+                tree.set(i.to_string(), i.to_string()).await.unwrap();
+                tree.flush().await.unwrap();
+            }
+            tree.compact(vec![0, 2], 5).await.unwrap();
+            assert!(LSMTree::get_data_file_paths(cold.clone(), 5).0.exists());
+            assert!(!LSMTree::get_data_file_paths(dir.clone(), 5).0.exists());
+            assert!(!LSMTree::get_data_file_paths(dir.clone(), 0).0.exists());
+            drop(tree);
+
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            let mut indices = tree.read_sstable_indices.clone();
+            indices.sort();
+            assert_eq!(indices, vec![4, 5]);
+            for i in 0..3 {
+                assert_eq!(
+                    tree.get(&i.to_string()).await.unwrap(),
+                    Some(i.to_string())
+                );
+            }
+
+            // Compacting a cold sstable with a hot one.
+            tree.compact(vec![4, 5], 7).await.unwrap();
+            assert_eq!(tree.read_sstable_indices, vec![7]);
+            assert!(!LSMTree::get_data_file_paths(cold.clone(), 5).0.exists());
+            assert!(!LSMTree::get_data_file_paths(dir.clone(), 4).0.exists());
+            let live: Vec<PathBuf> = tree
+                .live_files()
+                .unwrap()
+                .into_iter()
+                .filter(|(kind, _)| *kind == FileKind::Data)
+                .map(|(_, path)| path)
+                .collect();
+            assert_eq!(live, vec![LSMTree::get_data_file_paths(cold, 7).0]);
+            drop(tree);
+
+            let tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(tree.get(&"2".into()).await.unwrap(), Some("2".into()));
+        });
+    }
+
+    #[test]
+    fn manual_flush_only() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("manual_flush_only");
+            let options = LSMTreeOptions::new().with_manual_flush_only(true);
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            for i in 0..TREE_CAPACITY {
+                tree.set(format!("{:05}", i), "v".into()).await.unwrap();
+            }
+            assert!(tree.read_sstable_indices.is_empty());
+
+            // A failed write is not counted in the stats.
+            let stats = tree.stats();
+            assert!(tree.set("new".into(), "v".into()).await.is_err());
+            assert_eq!(tree.stats().bytes_set, stats.bytes_set);
+            assert_eq!(tree.stats().wal_bytes_written, stats.wal_bytes_written);
+            tree.set("00000".into(), "w".into()).await.unwrap();
+            assert_eq!(tree.stats().bytes_set, stats.bytes_set + 6);
+
+            assert_eq!(tree.flush().await.unwrap(), Some(0));
+            tree.set("new".into(), "v".into()).await.unwrap();
+            assert_eq!(
+                tree.get(&"00000".into()).await.unwrap(),
+                Some("w".into())
+            );
+            assert_eq!(
+                tree.get(&"new".into()).await.unwrap(),
+                Some("v".into())
+            );
+        });
+    }
+
+    #[test]
+    fn compaction_filter_sized_by_keys() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_filter_sized_by_keys");
+            let options = LSMTreeOptions::new().with_false_positive_rate(0.05);
+            let mut tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            // The same keys in every sstable.
+            for version in 0..4 {
+                for i in 0..1000 {
+                    let key = format!("{:04}", i);
+                    tree.set(key, version.to_string()).await.unwrap();
+                }
+                tree.flush().await.unwrap();
+            }
+            let meta_size = |index: usize| {
+                std::fs::metadata(LSMTree::get_meta_file_path(
+                    dir.clone(),
+                    index,
+                ))
+                .unwrap()
+                .len()
+            };
+            let input_meta_size = meta_size(0);
+
+            tree.compact(vec![0, 2, 4, 6], 7).await.unwrap();
+            // Sized for the 1000 keys written, not the 4000 entries read.
+            assert!(
+                meta_size(7) < input_meta_size * 3 / 2,
+                "{} > {}",
+                meta_size(7),
+                input_meta_size
+            );
+
+            // Keys in the key range of the sstable, that only its filter
+            // rules out.
+            let false_positives = (0..999)
+                .filter(|i| tree.probably_contains(&format!("{:04}x", i)))
+                .count();
+            assert!(
+                (20..100).contains(&false_positives),
+                "{} false positives",
+                false_positives
+            );
+        });
+    }
+
+    #[test]
+    fn acquire_range() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("acquire_range");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            let key = |k: &str| k.to_string();
+
+            let guard = tree.acquire_range(&key("b"), &key("d")).await;
+            // Disjoint ranges, touching ones included.
+            let before = tree.try_acquire_range(&key("a"), &key("b")).unwrap();
+            let after = tree.try_acquire_range(&key("d"), &key("f")).unwrap();
+            assert!(tree.try_acquire_range(&key("c"), &key("c")).is_some());
+            // Overlapping ranges.
+            for (start, end) in
+                [("a", "c"), ("c", "e"), ("a", "z"), ("c", "cc")]
+            {
+                assert!(
+                    tree.try_acquire_range(&key(start), &key(end)).is_none(),
+                    "[{}, {})",
+                    start,
+                    end
+                );
+            }
+            drop(before);
+            drop(after);
+
+            // An overlapping acquire waits for the guard to be dropped.
+            let acquired = Rc::new(Cell::new(false));
+            let task = {
+                let locks = tree.range_locks.clone();
+                let acquired = acquired.clone();
+                glommio::spawn_local(async move {
+                    let _guard = locks.acquire(&"a".into(), None).await;
+                    acquired.set(true);
+                })
+            };
+            futures_lite::future::yield_now().await;
+            assert!(!acquired.get());
+            drop(guard);
+            task.await;
+            assert!(acquired.get());
+
+            // Holds all keys.
+            let guard = tree.acquire_range(&key("x"), &key("y")).await;
+            let mut clear = std::pin::pin!(tree.clear());
+            assert!(futures_lite::future::poll_once(&mut clear)
+                .await
+                .is_none());
+            drop(guard);
+            clear.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn sstable_filter_files() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("sstable_filter_files");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            drop(tree);
+            let filter_path =
+                filter_path(&LSMTree::get_meta_file_path(dir.clone(), 0));
+            assert!(filter_path.exists());
+
+            // A filter placed next to the sstable is used as it is, here with
+            // a key that is not in the sstable.
+            let mut filter = BloomFilter::new(101, DEFAULT_FALSE_POSITIVE_RATE);
+            for i in 0..100 {
+                filter.insert(format!("{:03}", i).as_bytes());
+            }
+            filter.insert(b"050x");
+            LSMTree::write_sstable_filter(&LocalStorage, &filter_path, &filter)
+                .await
+                .unwrap();
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert!(tree.probably_contains(&"050x".into()));
+            drop(tree);
+
+            // A missing filter is built from the sstable on open.
+            std::fs::remove_file(&filter_path).unwrap();
+            let tree = LSMTree::new(dir.clone()).await.unwrap();
+            assert!(filter_path.exists());
+            assert!(!tree.probably_contains(&"050x".into()));
+            for i in 0..100 {
+                assert!(tree.probably_contains(&format!("{:03}", i)));
+            }
+        });
+    }
+
+    // Flushes an sstable of each size, in that order.
+    async fn flush_sstables_of_sizes(tree: &mut LSMTree, sizes: &[usize]) {
+        for (i, size) in sizes.iter().enumerate() {
+            for j in 0..*size {
+                tree.set(format!("{}-{:04}", i, j), "v".into())
+                    .await
+                    .unwrap();
+            }
+            tree.flush().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn run_compaction_size_tiered() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("run_compaction_size_tiered");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            flush_sstables_of_sizes(&mut tree, &[10, 500, 11, 10]).await;
+            assert_eq!(
+                tree.run_compaction(CompactionPolicy::SizeTiered)
+                    .await
+                    .unwrap(),
+                None
+            );
+
+            flush_sstables_of_sizes(&mut tree, &[12]).await;
+            let result = tree
+                .run_compaction(CompactionPolicy::SizeTiered)
+                .await
+                .unwrap()
+                .unwrap();
+            // The large sstable is left alone.
+            assert_eq!(result.inputs, vec![0, 4, 6, 8]);
+            assert!(result.bytes_read > 0 && result.bytes_written > 0);
+            assert_eq!(tree.sstable_count(), 2);
+            assert_eq!(
+                tree.get(&"0-0011".into()).await.unwrap(),
+                Some("v".into())
+            );
+        });
+    }
+
+    #[test]
+    fn run_compaction_oldest_n() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("run_compaction_oldest_n");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            flush_sstables_of_sizes(&mut tree, &[10, 10, 10]).await;
+            assert_eq!(
+                tree.run_compaction(CompactionPolicy::OldestN(4))
+                    .await
+                    .unwrap(),
+                None
+            );
+
+            let first = tree
+                .run_compaction(CompactionPolicy::OldestN(2))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(first.inputs, vec![0, 2]);
+            assert_eq!(tree.sstable_count(), 2);
+            // The output keeps the creation time of its oldest input.
+            let result = tree
+                .run_compaction(CompactionPolicy::OldestN(2))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(result.inputs, vec![4, first.output]);
+            assert_eq!(tree.sstable_count(), 1);
+        });
+    }
+
+    #[test]
+    fn run_compaction_full() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("run_compaction_full");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            flush_sstables_of_sizes(&mut tree, &[10, 200, 30]).await;
+            let result = tree
+                .run_compaction(CompactionPolicy::Full)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(result.inputs, vec![0, 2, 4]);
+            assert_eq!(tree.newest_sstable(), Some(result.output));
+            assert_eq!(tree.sstable_count(), 1);
+            assert_eq!(
+                tree.run_compaction(CompactionPolicy::Full).await.unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn run_compaction_custom() {
+        // Compacts the sstables of more than 20 entries.
+        struct Large;
+        impl CompactionPicker for Large {
+            fn pick(&self, sstables: &[SstableInfo]) -> Option<Vec<usize>> {
+                Some(
+                    sstables
+                        .iter()
+                        .filter(|sstable| sstable.entries > 20)
+                        .map(|sstable| sstable.index)
+                        .collect(),
+                )
+            }
+        }
+        struct Missing;
+        impl CompactionPicker for Missing {
+            fn pick(&self, _: &[SstableInfo]) -> Option<Vec<usize>> {
+                Some(vec![1])
+            }
+        }
+
+        LocalExecutor::default().run(async {
+            let dir = test_dir("run_compaction_custom");
+            let mut tree = LSMTree::new(dir).await.unwrap();
+            flush_sstables_of_sizes(&mut tree, &[10, 30, 10, 40]).await;
+            let result = tree
+                .run_compaction(CompactionPolicy::Custom(Box::new(Large)))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(result.inputs, vec![2, 6]);
+            assert_eq!(tree.sstable_count(), 3);
+
+            let error = tree
+                .run_compaction(CompactionPolicy::Custom(Box::new(Missing)))
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn sstables_in_storage() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("sstables_in_storage");
+            let storage = MemoryStorage::default();
+            let options = LSMTreeOptions::new()
+                .with_value_log_threshold(100)
+                .with_storage(storage.clone());
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            for round in 0..2 {
+                for i in 0..100 {
+                    tree.set(format!("{:03}", i), format!("{}-{}", i, round))
+                        .await
+                        .unwrap();
+                }
+                tree.flush().await.unwrap();
+            }
+            tree.set("big".into(), "b".repeat(1000)).await.unwrap();
+            tree.flush().await.unwrap();
+            let output_index = tree.unused_sstable_indices(1)[0];
+            tree.compact(tree.read_sstable_indices.clone(), output_index)
+                .await
+                .unwrap();
+            tree.gc();
+            drop(tree);
+
+            // Only the WAL and the format file are on the local disk.
+            let in_storage = storage.list(&dir).unwrap();
+            for kind in [FileKind::Data, FileKind::Index, FileKind::ValueLog] {
+                assert!(numbered_files(&LocalStorage, &dir, kind)
+                    .unwrap()
+                    .is_empty());
+                assert!(!numbered_files(&storage, &dir, kind)
+                    .unwrap()
+                    .is_empty());
+            }
+            assert!(in_storage.iter().all(|name| !matches!(
+                FileKind::of(name),
+                Some((FileKind::Wal | FileKind::Format, _))
+            )));
+            assert!(dir.join(FORMAT_FILE_NAME).exists());
+
+            // Gets read ranges of the objects, not whole objects.
+            let tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            let object_bytes: u64 = in_storage
+                .iter()
+                .map(|name| storage.size(&dir.join(name)).unwrap())
+                .sum();
+            let read_bytes = storage.read_counts().bytes.get();
+            assert_eq!(
+                tree.get(&"042".into()).await.unwrap(),
+                Some("42-1".into())
+            );
+            let get_bytes = storage.read_counts().bytes.get() - read_bytes;
+            assert!(get_bytes > 0 && get_bytes < object_bytes / 4);
+            assert_eq!(
+                tree.get(&"big".into()).await.unwrap(),
+                Some("b".repeat(1000))
+            );
+            assert_eq!(tree.get(&"100".into()).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn bytes_values() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("bytes_values");
+            let options = LSMTreeOptions::new().with_value_log_threshold(100);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            let small = vec![0xff, 0, 0xfe];
+            let big = vec![0xff; 1000];
+            tree.set_bytes("small".into(), small.clone()).await.unwrap();
+            tree.set_bytes("big".into(), big.clone()).await.unwrap();
+            tree.set("string".into(), "s".into()).await.unwrap();
+            assert_eq!(
+                tree.get_bytes(&"small".into()).await.unwrap(),
+                Some(small.clone())
+            );
+            tree.flush().await.unwrap();
+            drop(tree);
+
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert_eq!(
+                tree.get_bytes(&"small".into()).await.unwrap(),
+                Some(small.clone())
+            );
+            assert_eq!(
+                tree.get_bytes(&"big".into()).await.unwrap(),
+                Some(big.clone())
+            );
+            assert_eq!(
+                tree.get_bytes(&"string".into()).await.unwrap(),
+                Some(b"s".to_vec())
+            );
+
+            // The string API only reads values that are utf-8.
+            let error = tree.get(&"small".into()).await.unwrap_err();
+            assert_eq!(
+                io_error_kind(&error),
+                Some(std::io::ErrorKind::InvalidData)
+            );
+            let error = tree
+                .range(&"small".into(), &"smallx".into())
+                .await
+                .unwrap_err();
+            assert_eq!(
+                io_error_kind(&error),
+                Some(std::io::ErrorKind::InvalidData)
+            );
+            let mut pairs = Vec::new();
+            tree.for_each_range_bytes(&"a".into(), &"z".into(), |pair| {
+                pairs.push(pair);
+                std::future::ready(ControlFlow::Continue(()))
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                pairs,
+                vec![
+                    ("big".into(), big),
+                    ("small".into(), small.clone()),
+                    ("string".into(), b"s".to_vec()),
+                ]
+            );
+
+            assert_eq!(tree.delete_bytes("small".into()).await.unwrap(), None);
+            assert_eq!(tree.get_bytes(&"small".into()).await.unwrap(), None);
+
+            // Nor returns a previous value that is not utf-8, and fails before
+            // writing instead.
+            let key = "memtable".to_string();
+            tree.set_bytes(key.clone(), small.clone()).await.unwrap();
+            let is_invalid_data = |error: &glommio::GlommioError<()>| {
+                io_error_kind(error) == Some(std::io::ErrorKind::InvalidData)
+            };
+            let error = tree.set(key.clone(), "s".into()).await.unwrap_err();
+            assert!(is_invalid_data(&error));
+            let error = tree.delete(key.clone()).await.unwrap_err();
+            assert!(is_invalid_data(&error));
+            assert_eq!(
+                tree.get_bytes(&key).await.unwrap(),
+                Some(small.clone())
+            );
+            for result in [
+                tree.get_memtable(&key).map(|_| ()),
+                tree.recent(10).map(|_| ()),
+                tree.memtable_entries().map(|_| ()),
+            ] {
+                assert_eq!(
+                    result.unwrap_err().kind(),
+                    std::io::ErrorKind::InvalidData
+                );
+            }
+
+            tree.flush().await.unwrap();
+            let indices = tree.read_sstable_indices.clone();
+            let error = tree
+                .compact_with_filter(indices, 9, |_, _| true)
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn compaction_fails_on_truncated_input() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("compaction_fails_on_truncated_input");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..TREE_CAPACITY * 2 + 10 {
+                tree.set(format!("{:08}", i), i.to_string()).await.unwrap();
+            }
+            assert_eq!(tree.read_sstable_indices, vec![0, 2]);
+
+            // The entries of the second half of the sstable are lost.
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&data_path)
+                .unwrap();
+            let size = file.metadata().unwrap().len();
+            file.set_len(size / 2).unwrap();
+
+            assert!(tree.compact(vec![0, 2], 5).await.is_err());
+            assert_eq!(tree.read_sstable_indices, vec![0, 2]);
+            assert!(data_path.exists());
+            let key = format!("{:08}", TREE_CAPACITY + 1);
+            assert_eq!(
+                tree.get(&key).await.unwrap(),
+                Some((TREE_CAPACITY + 1).to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn scans_fail_on_truncated_sstable() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("scans_fail_on_truncated_sstable");
+            let mut tree = LSMTree::new(dir.clone()).await.unwrap();
+            for i in 0..100 {
+                tree.set(format!("{:03}", i), i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            let start = "000".to_string();
+            let end = "999".to_string();
+            assert_eq!(tree.range(&start, &end).await.unwrap().len(), 100);
+
+            let (data_path, _) = LSMTree::get_data_file_paths(dir.clone(), 0);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&data_path)
+                .unwrap();
+            let size = file.metadata().unwrap().len();
+            file.set_len(size / 2).unwrap();
+
+            assert!(tree.range(&start, &end).await.is_err());
+            let mut seen = 0;
+            let result = tree
+                .for_each_range(&start, &end, |_| {
+                    seen += 1;
+                    std::future::ready(ControlFlow::Continue(()))
+                })
+                .await;
+            assert!(result.is_err());
+            assert!(seen < 100);
+            assert!(tree.content_digest().await.is_err());
+        });
+    }
+
+    #[test]
+    fn recover_stale_and_unflushed_wals() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("recover_stale_and_unflushed_wals");
+            let options =
+                LSMTreeOptions::new().with_wal_archive(WalRetention::All);
+            let wal_path = |index| LSMTree::get_wal_path(dir.clone(), index);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.set("a".into(), "1".into()).await.unwrap();
+            drop(tree);
+            // A flush that didn't finish, whose WAL fails to be archived on
+            // open, as the archive is a file.
+            std::fs::write(wal_path(2), "").unwrap();
+            let archive = dir.join(WAL_ARCHIVE_DIR_NAME);
+            std::fs::write(&archive, "").unwrap();
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.set("b".into(), "2".into()).await.unwrap();
+            tree.set("a".into(), "3".into()).await.unwrap();
+            drop(tree);
+
+            // The flush of the next WAL didn't finish either, leaving 3 WALs.
+            std::fs::write(wal_path(4), "").unwrap();
+            for _ in 0..2 {
+                let tree = LSMTree::with_options(dir.clone(), options.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(tree.read_sstable_indices, vec![0, 2]);
+                assert!(wal_path(0).exists() && wal_path(2).exists());
+                assert_eq!(
+                    tree.get(&"a".into()).await.unwrap(),
+                    Some("3".into())
+                );
+                assert_eq!(
+                    tree.get(&"b".into()).await.unwrap(),
+                    Some("2".into())
+                );
+            }
+
+            // Once they are compacted away, the stale WALs are flushed again.
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            tree.compact(vec![0, 2], 1).await.unwrap();
+            drop(tree);
+            std::fs::remove_file(&archive).unwrap();
+            let tree =
+                LSMTree::with_options(dir.clone(), options).await.unwrap();
+            assert!(!wal_path(0).exists() && !wal_path(2).exists());
+            assert_eq!(tree.read_sstable_indices, vec![0, 1, 2]);
+            assert_eq!(tree.get(&"a".into()).await.unwrap(), Some("3".into()));
+            assert_eq!(tree.get(&"b".into()).await.unwrap(), Some("2".into()));
+        });
+    }
+
+    #[test]
+    fn tombstone_rewrite_across_reopen() {
+        LocalExecutor::default().run(async {
+            let dir = test_dir("tombstone_rewrite_across_reopen");
+            let options =
+                LSMTreeOptions::new().with_tombstone_rewrite_threshold(0.5);
+            let mut tree = LSMTree::with_options(dir.clone(), options.clone())
+                .await
+                .unwrap();
+            // The tombstones of the oldest sstable can't be dropped, as the
+            // newer sstable holds their keys.
+            for i in 0..10 {
+                tree.delete(i.to_string()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+            for i in 0..10 {
+                tree.set(i.to_string(), "v".into()).await.unwrap();
+            }
+            tree.flush().await.unwrap();
+
+            assert!(tree.maybe_compact().await.unwrap());
+            assert_eq!(tree.read_sstable_indices, vec![2, 5]);
+            assert_eq!(tree.sstable_headers[&5].tombstones, 10);
+            assert!(tree.sstable_headers[&5].tombstone_rewrite);
+            assert!(!tree.maybe_compact().await.unwrap());
+            drop(tree);
+
+            let mut tree = LSMTree::with_options(dir, options).await.unwrap();
+            assert!(!tree.maybe_compact().await.unwrap());
+            assert_eq!(tree.read_sstable_indices, vec![2, 5]);
+            assert_eq!(tree.get(&"0".into()).await.unwrap(), Some("v".into()));
+        });
+    }
 }
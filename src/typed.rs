@@ -0,0 +1,258 @@
+use std::{io::ErrorKind, marker::PhantomData, ops::ControlFlow};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::lsm_tree::{bincode_options, LSMTree};
+
+// A key stored in a tree as a string that sorts like the key, so that ranges
+// of typed keys are ranges of their strings.
+pub trait Key: Ord + Sized {
+    fn encode_key(&self) -> String;
+
+    fn decode_key(key: &str) -> std::io::Result<Self>;
+}
+
+// A value stored in a tree as bytes, see LSMTree::set_bytes.
+pub trait Value: Sized {
+    fn encode_value(&self) -> Vec<u8>;
+
+    fn decode_value(value: Vec<u8>) -> std::io::Result<Self>;
+}
+
+impl Key for String {
+    fn encode_key(&self) -> String {
+        self.clone()
+    }
+
+    fn decode_key(key: &str) -> std::io::Result<Self> {
+        Ok(key.to_string())
+    }
+}
+
+impl Value for String {
+    fn encode_value(&self) -> Vec<u8> {
+        self.clone().into_bytes()
+    }
+
+    fn decode_value(value: Vec<u8>) -> std::io::Result<Self> {
+        String::from_utf8(value).map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("value is not utf-8: {}", e),
+            )
+        })
+    }
+}
+
+// Integers are stored as fixed width big endian hex, so they sort by value
+// (and fit LSMTreeOptions::with_fixed_key_size), signed integers with their
+// sign bit flipped, so that negative integers sort first.
+macro_rules! integer_key {
+    ($($integer:ty => $unsigned:ty),*) => {$(
+        impl Key for $integer {
+            fn encode_key(&self) -> String {
+                let flip = <$integer>::MIN as $unsigned;
+                format!(
+                    "{:0width$x}",
+                    (*self as $unsigned) ^ flip,
+                    width = std::mem::size_of::<$integer>() * 2
+                )
+            }
+
+            fn decode_key(key: &str) -> std::io::Result<Self> {
+                let flip = <$integer>::MIN as $unsigned;
+                match <$unsigned>::from_str_radix(key, 16) {
+                    Ok(bits)
+                        if key.len() == std::mem::size_of::<$integer>() * 2 =>
+                    {
+                        Ok((bits ^ flip) as $integer)
+                    }
+                    _ => Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "key '{}' is not a {} key",
+                            key,
+                            stringify!($integer)
+                        ),
+                    )),
+                }
+            }
+        }
+    )*};
+}
+
+integer_key!(
+    u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128,
+    i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128
+);
+
+// A value stored as its bincode encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bincode<T>(pub T);
+
+impl<T: Serialize + DeserializeOwned> Value for Bincode<T> {
+    fn encode_value(&self) -> Vec<u8> {
+        bincode_options().serialize(&self.0).unwrap()
+    }
+
+    fn decode_value(value: Vec<u8>) -> std::io::Result<Self> {
+        bincode_options()
+            .deserialize(&value)
+            .map(Bincode)
+            .map_err(|e| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bincode value is malformed: {}", e),
+                )
+            })
+    }
+}
+
+// A handle that works with typed keys and values, encoding them to the
+// strings and bytes the tree stores, see LSMTree::typed.
+// Keys and values of other types than K and V in the same tree fail to decode
+// when read, so a tree (or a prefix of it, see LSMTree::with_prefix) is best
+// kept to a single pair of types.
+// The tree itself is not generic: its entries, memtable and sstables hold
+// string keys, which the index, the filters, the range locks and the
+// compaction filter all compare and hash as strings. So a key type needs a
+// Key encoding that sorts like the key, serde alone is not enough, as the
+// bincode encoding of a key does not sort like it.
+pub struct TypedLSMTree<'a, K, V> {
+    tree: &'a mut LSMTree,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<'a, K: Key, V: Value> TypedLSMTree<'a, K, V> {
+    pub fn new(tree: &'a mut LSMTree) -> Self {
+        Self {
+            tree,
+            _types: PhantomData,
+        }
+    }
+
+    pub async fn get(&self, key: &K) -> glommio::Result<Option<V>, ()> {
+        match self.tree.get_bytes(&key.encode_key()).await? {
+            Some(value) => Ok(Some(V::decode_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set(
+        &mut self,
+        key: &K,
+        value: &V,
+    ) -> glommio::Result<Option<V>, ()> {
+        let previous = self
+            .tree
+            .set_bytes(key.encode_key(), value.encode_value())
+            .await?;
+        Ok(previous.map(V::decode_value).transpose()?)
+    }
+
+    pub async fn delete(&mut self, key: &K) -> glommio::Result<Option<V>, ()> {
+        let previous = self.tree.delete_bytes(key.encode_key()).await?;
+        Ok(previous.map(V::decode_value).transpose()?)
+    }
+
+    // The keys and values in [start, end), in ascending key order.
+    pub async fn range(
+        &self,
+        start: &K,
+        end: &K,
+    ) -> std::io::Result<Vec<(K, V)>> {
+        let mut pairs = Vec::new();
+        let mut result = Ok(());
+        self.tree
+            .for_each_range_bytes(
+                &start.encode_key(),
+                &end.encode_key(),
+                |(key, value)| {
+                    let pair = K::decode_key(&key)
+                        .and_then(|key| Ok((key, V::decode_value(value)?)));
+                    std::future::ready(match pair {
+                        Ok(pair) => {
+                            pairs.push(pair);
+                            ControlFlow::Continue(())
+                        }
+                        Err(e) => {
+                            result = Err(e);
+                            ControlFlow::Break(())
+                        }
+                    })
+                },
+            )
+            .await?;
+        result.map(|()| pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glommio::LocalExecutor;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[test]
+    fn typed_keys_and_values() {
+        LocalExecutor::default().run(async {
+            let mut dir = std::env::temp_dir();
+            dir.push("dbil-typed_keys_and_values");
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).unwrap();
+            }
+            let mut tree = LSMTree::new(dir).await.unwrap();
+
+            let mut points = tree.typed::<i64, Bincode<Point>>();
+            for i in [3, -20, 100, 0, -1] {
+                let point = Bincode(Point { x: i as i32, y: 1 });
+                assert_eq!(points.set(&i, &point).await.unwrap(), None);
+            }
+            points.delete(&0).await.unwrap();
+            assert_eq!(
+                points.get(&100).await.unwrap(),
+                Some(Bincode(Point { x: 100, y: 1 }))
+            );
+            assert_eq!(points.get(&0).await.unwrap(), None);
+
+            // Stored as the bincode bytes, two i32s.
+            let stored = tree.get_bytes(&100i64.encode_key()).await.unwrap();
+            assert_eq!(stored.unwrap().len(), 8);
+            let points = tree.typed::<i64, Bincode<Point>>();
+
+            // Sorted by value, not by the digits of the keys.
+            let keys: Vec<i64> = points
+                .range(&-20, &100)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(keys, [-20, -1, 3]);
+
+            for key in [u64::MIN, 1, u64::MAX] {
+                assert_eq!(u64::decode_key(&key.encode_key()).unwrap(), key);
+            }
+            assert!(u64::decode_key("12").is_err());
+            assert!(Bincode::<Point>::decode_value(vec![1, 2, 3]).is_err());
+            assert!(String::decode_value(vec![0xff]).is_err());
+
+            // Keys of other types don't decode.
+            tree.set("1234".into(), "value".into()).await.unwrap();
+            let points = tree.typed::<i64, Bincode<Point>>();
+            assert!(points
+                .range(&i64::MIN, &i64::MAX)
+                .await
+                .unwrap_err()
+                .to_string()
+                .contains("is not a i64 key"));
+        });
+    }
+}